@@ -0,0 +1,232 @@
+//! ACPI tables which describe the virtual hardware to the guest.
+
+use apic;
+use irqchip::{Polarity, TriggerMode};
+
+/// Size of the header common to all description tables.
+const HEADER_SIZE: usize = 36;
+
+/// The OEM ID of the tables.
+const OEM_ID: &[u8; 6] = b"VMRS  ";
+
+/// The highest APIC ID which fits in a local APIC entry, since 0xFF is a broadcast.
+const MAX_XAPIC_ID: u32 = 0xFE;
+
+/// The highest processor UID which fits in a local APIC entry.
+const MAX_XAPIC_UID: usize = 0xFF;
+
+/// The default address of the I/O APIC.
+const IOAPIC_DEFAULT_BASE: u32 = 0xFEC0_0000;
+
+/// Builds a description table: the header, followed by its contents.
+fn table(signature: &[u8; 4], revision: u8, table_id: &[u8; 8], contents: &[u8]) -> Vec<u8> {
+    let length = (HEADER_SIZE + contents.len()) as u32;
+
+    let mut bytes = Vec::with_capacity(length as usize);
+    bytes.extend_from_slice(signature);
+    bytes.extend_from_slice(&length.to_le_bytes());
+    bytes.push(revision);
+    // The checksum is computed once the table is complete.
+    bytes.push(0);
+    bytes.extend_from_slice(OEM_ID);
+    bytes.extend_from_slice(table_id);
+    // OEM revision, creator ID and creator revision.
+    bytes.extend_from_slice(&1u32.to_le_bytes());
+    bytes.extend_from_slice(b"VMRS");
+    bytes.extend_from_slice(&1u32.to_le_bytes());
+    bytes.extend_from_slice(contents);
+
+    bytes[9] = checksum(&bytes);
+    bytes
+}
+
+/// Returns the byte which makes the sum of all of the bytes zero.
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)).wrapping_neg()
+}
+
+/// An ISA interrupt which is not connected to the GSI with the same number,
+/// or which is not active-high and edge-triggered.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct InterruptOverride {
+    /// The ISA IRQ.
+    pub irq: u8,
+    /// The GSI the IRQ is connected to.
+    pub gsi: u32,
+    /// The level which asserts the interrupt.
+    pub polarity: Polarity,
+    /// When the interrupt is raised.
+    pub trigger_mode: TriggerMode,
+}
+
+impl InterruptOverride {
+    /// The MPS INTI flags of the interrupt.
+    fn flags(&self) -> u16 {
+        let polarity = match self.polarity {
+            Polarity::ActiveHigh => 0b01,
+            Polarity::ActiveLow => 0b11,
+        };
+
+        let trigger_mode = match self.trigger_mode {
+            TriggerMode::Edge => 0b01,
+            TriggerMode::Level => 0b11,
+        };
+
+        polarity | trigger_mode << 2
+    }
+}
+
+/// The Multiple APIC Description Table, which lists the interrupt controllers.
+///
+/// Processors with an APIC ID or UID which does not fit in 8 bits are listed
+/// with x2APIC entries, and the others with local APIC entries. The guest must
+/// use x2APIC mode to address the former.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Madt {
+    /// The APIC IDs of the processors. The UID of each processor is its index.
+    pub apic_ids: Vec<u32>,
+    /// The physical address of the local APICs.
+    pub lapic_address: u32,
+    /// The APIC ID of the I/O APIC.
+    pub ioapic_id: u8,
+    /// The physical address of the I/O APIC.
+    pub ioapic_address: u32,
+    /// The ISA interrupts which are connected differently.
+    pub overrides: Vec<InterruptOverride>,
+}
+
+impl Madt {
+    /// Creates the table of a PC with the given processors.
+    ///
+    /// The interrupt controllers are at their default addresses, the timer's IRQ 0
+    /// is connected to GSI 2, and the ACPI SCI on IRQ 9 is level-triggered.
+    pub fn new(apic_ids: Vec<u32>) -> Self {
+        Madt {
+            apic_ids,
+            lapic_address: apic::DEFAULT_BASE as u32,
+            ioapic_id: 0,
+            ioapic_address: IOAPIC_DEFAULT_BASE,
+            overrides: vec![
+                InterruptOverride {
+                    irq: 0,
+                    gsi: 2,
+                    polarity: Polarity::ActiveHigh,
+                    trigger_mode: TriggerMode::Edge,
+                },
+                InterruptOverride {
+                    irq: 9,
+                    gsi: 9,
+                    polarity: Polarity::ActiveHigh,
+                    trigger_mode: TriggerMode::Level,
+                },
+            ],
+        }
+    }
+
+    /// Checks if some of the processors are listed with x2APIC entries.
+    pub fn needs_x2apic(&self) -> bool {
+        self.apic_ids.len() > MAX_XAPIC_UID + 1 || self.apic_ids.iter().any(|&id| id > MAX_XAPIC_ID)
+    }
+
+    /// Builds the table.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        /// The processor is enabled.
+        const ENABLED: u32 = 1 << 0;
+        /// The system has 8259 PICs, which must be masked to use the APICs.
+        const PCAT_COMPAT: u32 = 1 << 0;
+
+        let mut contents = Vec::new();
+        contents.extend_from_slice(&self.lapic_address.to_le_bytes());
+        contents.extend_from_slice(&PCAT_COMPAT.to_le_bytes());
+
+        for (uid, &apic_id) in self.apic_ids.iter().enumerate() {
+            if apic_id > MAX_XAPIC_ID || uid > MAX_XAPIC_UID {
+                // Processor local x2APIC.
+                contents.extend_from_slice(&[9, 16, 0, 0]);
+                contents.extend_from_slice(&apic_id.to_le_bytes());
+                contents.extend_from_slice(&ENABLED.to_le_bytes());
+                contents.extend_from_slice(&(uid as u32).to_le_bytes());
+            } else {
+                // Processor local APIC.
+                contents.extend_from_slice(&[0, 8, uid as u8, apic_id as u8]);
+                contents.extend_from_slice(&ENABLED.to_le_bytes());
+            }
+        }
+
+        // I/O APIC, whose first pin is GSI 0.
+        contents.extend_from_slice(&[1, 12, self.ioapic_id, 0]);
+        contents.extend_from_slice(&self.ioapic_address.to_le_bytes());
+        contents.extend_from_slice(&0u32.to_le_bytes());
+
+        for o in &self.overrides {
+            // Interrupt source override, on the ISA bus.
+            contents.extend_from_slice(&[2, 10, 0, o.irq]);
+            contents.extend_from_slice(&o.gsi.to_le_bytes());
+            contents.extend_from_slice(&o.flags().to_le_bytes());
+        }
+
+        // LINT1 of all processors is connected to the NMI line.
+        if self.needs_x2apic() {
+            contents.extend_from_slice(&[0xA, 12, 0, 0]);
+            contents.extend_from_slice(&0xFFFF_FFFFu32.to_le_bytes());
+            contents.extend_from_slice(&[1, 0, 0, 0]);
+        } else {
+            contents.extend_from_slice(&[4, 6, 0xFF, 0, 0, 1]);
+        }
+
+        // Revision 3 is the first one with x2APIC entries.
+        table(b"APIC", 3, b"VMRSMADT", &contents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the type and the start of each entry.
+    fn entries(table: &[u8]) -> Vec<(u8, usize)> {
+        let mut entries = Vec::new();
+        let mut offset = HEADER_SIZE + 8;
+
+        while offset < table.len() {
+            entries.push((table[offset], offset));
+            offset += table[offset + 1] as usize;
+        }
+
+        assert_eq!(offset, table.len());
+        entries
+    }
+
+    #[test]
+    fn madt() {
+        let madt = Madt::new(vec![0, 1]);
+        assert!(!madt.needs_x2apic());
+
+        let table = madt.to_bytes();
+        assert_eq!(&table[..4], b"APIC");
+        assert_eq!(table.len(), 36 + 8 + 2 * 8 + 12 + 2 * 10 + 6);
+        assert_eq!(&table[4..8], &(table.len() as u32).to_le_bytes());
+        assert_eq!(checksum(&table), 0);
+
+        let types: Vec<_> = entries(&table).iter().map(|&(t, _)| t).collect();
+        assert_eq!(types, [0, 0, 1, 2, 2, 4]);
+
+        // The SCI is level-triggered and active high.
+        let (_, sci) = entries(&table)[4];
+        assert_eq!(&table[sci..sci + 10], &[2, 10, 0, 9, 9, 0, 0, 0, 0xD, 0]);
+
+        // Processors above 254 are only described by x2APIC entries.
+        let madt = Madt::new(vec![0, 254, 300]);
+        assert!(madt.needs_x2apic());
+
+        let table = madt.to_bytes();
+        assert_eq!(checksum(&table), 0);
+
+        let entries = entries(&table);
+        let types: Vec<_> = entries.iter().map(|&(t, _)| t).collect();
+        assert_eq!(types, [0, 0, 9, 1, 2, 2, 0xA]);
+
+        let (_, x2apic) = entries[2];
+        assert_eq!(&table[x2apic + 4..x2apic + 16], &[44, 1, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0]);
+    }
+}
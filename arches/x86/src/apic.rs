@@ -0,0 +1,454 @@
+//! The local APIC, which receives the interrupts of a processor.
+//!
+//! Its registers are mapped in a 4 KiB page at the address in the
+//! `APIC_BASE` MSR, each register being 16-byte aligned. Only the first
+//! 1 KiB contains registers. In x2APIC mode, they are accessed through
+//! the MSRs starting at `X2APIC_MSR_BASE` instead.
+
+use irqchip::{DeliveryMode, Polarity, TriggerMode};
+
+/// Size of the part of the page which contains registers.
+pub const PAGE_SIZE: usize = 1024;
+
+/// Physical address of the registers, after reset.
+pub const DEFAULT_BASE: u64 = 0xFEE0_0000;
+
+/// MSR of the first x2APIC register. Register `offset` is at MSR
+/// `X2APIC_MSR_BASE + offset / 16`.
+pub const X2APIC_MSR_BASE: u32 = 0x800;
+
+/// Local APIC ID.
+pub const ID: usize = 0x20;
+/// Version, and number of LVT entries.
+pub const VERSION: usize = 0x30;
+/// Task priority register.
+pub const TPR: usize = 0x80;
+/// Arbitration priority register.
+pub const APR: usize = 0x90;
+/// Processor priority register.
+pub const PPR: usize = 0xA0;
+/// End-of-interrupt register.
+pub const EOI: usize = 0xB0;
+/// Logical destination register.
+pub const LDR: usize = 0xD0;
+/// Destination format register.
+pub const DFR: usize = 0xE0;
+/// Spurious interrupt vector register, which also software-enables the APIC.
+pub const SVR: usize = 0xF0;
+/// First register of the in-service bitmap.
+pub const ISR: usize = 0x100;
+/// First register of the trigger mode bitmap.
+pub const TMR: usize = 0x180;
+/// First register of the interrupt request bitmap.
+pub const IRR: usize = 0x200;
+/// Error status register.
+pub const ESR: usize = 0x280;
+/// Low half of the interrupt command register.
+pub const ICR_LOW: usize = 0x300;
+/// High half of the interrupt command register, with the destination.
+pub const ICR_HIGH: usize = 0x310;
+/// Initial count of the timer.
+pub const TIMER_INITIAL_COUNT: usize = 0x380;
+/// Current count of the timer.
+pub const TIMER_CURRENT_COUNT: usize = 0x390;
+/// Divide configuration of the timer.
+pub const TIMER_DIVIDE: usize = 0x3E0;
+
+/// The spurious vector register's bit which software-enables the APIC.
+const SVR_ENABLED: u32 = 1 << 8;
+
+/// The entries of the local vector table, which configure
+/// the interrupts raised by the local APIC itself.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Lvt {
+    /// Corrected machine-check error interrupt.
+    Cmci,
+    /// The APIC timer.
+    Timer,
+    /// The thermal sensor.
+    Thermal,
+    /// Performance counter overflow.
+    PerformanceCounter,
+    /// The LINT0 pin, connected to the 8259 PIC on the boot processor.
+    Lint0,
+    /// The LINT1 pin, usually connected to the NMI line.
+    Lint1,
+    /// Errors detected by the APIC.
+    Error,
+}
+
+impl Lvt {
+    /// All of the entries.
+    pub const ALL: [Lvt; 7] = [
+        Lvt::Cmci,
+        Lvt::Timer,
+        Lvt::Thermal,
+        Lvt::PerformanceCounter,
+        Lvt::Lint0,
+        Lvt::Lint1,
+        Lvt::Error,
+    ];
+
+    /// The offset of the entry's register.
+    pub fn offset(self) -> usize {
+        match self {
+            Lvt::Cmci => 0x2F0,
+            Lvt::Timer => 0x320,
+            Lvt::Thermal => 0x330,
+            Lvt::PerformanceCounter => 0x340,
+            Lvt::Lint0 => 0x350,
+            Lvt::Lint1 => 0x360,
+            Lvt::Error => 0x370,
+        }
+    }
+}
+
+/// The counting mode of the APIC timer.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum TimerMode {
+    /// Counts down once from the initial count.
+    #[default]
+    OneShot,
+    /// Reloads the initial count when reaching zero.
+    Periodic,
+    /// Fires when the TSC reaches the value of the `TSC_DEADLINE` MSR.
+    TscDeadline,
+    /// The reserved mode.
+    Reserved,
+}
+
+/// An entry of the local vector table.
+///
+/// Not all of the fields are used by every entry: only LINT0 and LINT1
+/// have a polarity and trigger mode, and only the timer has a mode.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct LvtEntry {
+    /// The interrupt vector.
+    pub vector: u8,
+    /// How the interrupt is delivered.
+    pub delivery_mode: DeliveryMode,
+    /// The interrupt is waiting to be accepted by the processor. Read-only.
+    pub delivery_pending: bool,
+    /// The level which asserts the pin.
+    pub polarity: Polarity,
+    /// A level-triggered interrupt was accepted, and is waiting
+    /// for its end-of-interrupt. Read-only.
+    pub remote_irr: bool,
+    /// When the pin raises an interrupt.
+    pub trigger_mode: TriggerMode,
+    /// The interrupt is masked.
+    pub masked: bool,
+    /// The mode of the timer.
+    pub timer_mode: TimerMode,
+}
+
+impl From<u32> for LvtEntry {
+    fn from(value: u32) -> Self {
+        let bit = |n: u32| value & (1 << n) != 0;
+
+        LvtEntry {
+            vector: value as u8,
+            delivery_mode: DeliveryMode::from((value >> 8) as u8 & 0b111),
+            delivery_pending: bit(12),
+            polarity: if bit(13) { Polarity::ActiveLow } else { Polarity::ActiveHigh },
+            remote_irr: bit(14),
+            trigger_mode: if bit(15) { TriggerMode::Level } else { TriggerMode::Edge },
+            masked: bit(16),
+            timer_mode: match (value >> 17) & 0b11 {
+                0 => TimerMode::OneShot,
+                1 => TimerMode::Periodic,
+                2 => TimerMode::TscDeadline,
+                _ => TimerMode::Reserved,
+            },
+        }
+    }
+}
+
+impl From<LvtEntry> for u32 {
+    fn from(entry: LvtEntry) -> Self {
+        let bit = |set: bool, n: u32| if set { 1 << n } else { 0 };
+
+        let timer_mode = match entry.timer_mode {
+            TimerMode::OneShot => 0,
+            TimerMode::Periodic => 1,
+            TimerMode::TscDeadline => 2,
+            TimerMode::Reserved => 3,
+        };
+
+        u32::from(entry.vector)
+            | u32::from(u8::from(entry.delivery_mode) & 0b111) << 8
+            | bit(entry.delivery_pending, 12)
+            | bit(entry.polarity == Polarity::ActiveLow, 13)
+            | bit(entry.remote_irr, 14)
+            | bit(entry.trigger_mode == TriggerMode::Level, 15)
+            | bit(entry.masked, 16)
+            | timer_mode << 17
+    }
+}
+
+/// A bitmap with one bit for each of the 256 interrupt vectors,
+/// such as the ISR, TMR or IRR.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct VectorBitmap(pub [u32; 8]);
+
+impl VectorBitmap {
+    /// Checks if the bit of a vector is set.
+    pub fn get(&self, vector: u8) -> bool {
+        self.0[usize::from(vector / 32)] & (1 << (vector % 32)) != 0
+    }
+
+    /// Sets or clears the bit of a vector.
+    pub fn set(&mut self, vector: u8, value: bool) {
+        let word = &mut self.0[usize::from(vector / 32)];
+
+        if value {
+            *word |= 1 << (vector % 32);
+        } else {
+            *word &= !(1 << (vector % 32));
+        }
+    }
+
+    /// Returns the highest vector whose bit is set, which has the highest priority.
+    pub fn highest(&self) -> Option<u8> {
+        self.0
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|&(_, &word)| word != 0)
+            .map(|(i, &word)| (i * 32 + 31 - word.leading_zeros() as usize) as u8)
+    }
+}
+
+/// The state of a local APIC: the first 1 KiB of its register page.
+///
+/// Registers are read and written as in the page, without any side effects.
+#[derive(Copy, Clone)]
+pub struct LapicState {
+    page: [u8; PAGE_SIZE],
+}
+
+impl LapicState {
+    /// Creates the state from the contents of the register page.
+    pub fn from_bytes(page: &[u8; PAGE_SIZE]) -> Self {
+        LapicState { page: *page }
+    }
+
+    /// Returns the contents of the register page.
+    pub fn as_bytes(&self) -> &[u8; PAGE_SIZE] {
+        &self.page
+    }
+
+    /// Reads the register at `offset`.
+    pub fn read(&self, offset: usize) -> u32 {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&self.page[offset..offset + 4]);
+        u32::from_le_bytes(bytes)
+    }
+
+    /// Writes the register at `offset`.
+    pub fn write(&mut self, offset: usize, value: u32) {
+        self.page[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// The xAPIC ID, which is in the high byte of the ID register.
+    pub fn id(&self) -> u8 {
+        (self.read(ID) >> 24) as u8
+    }
+
+    /// Sets the xAPIC ID.
+    pub fn set_id(&mut self, id: u8) {
+        self.write(ID, u32::from(id) << 24);
+    }
+
+    /// The task priority: interrupts whose vector has a priority class
+    /// (the high 4 bits) not above the TPR's are blocked.
+    pub fn tpr(&self) -> u8 {
+        self.read(TPR) as u8
+    }
+
+    /// Sets the task priority.
+    pub fn set_tpr(&mut self, tpr: u8) {
+        self.write(TPR, u32::from(tpr));
+    }
+
+    /// The processor priority, computed from the TPR and the ISR.
+    pub fn ppr(&self) -> u8 {
+        self.read(PPR) as u8
+    }
+
+    /// Whether the APIC is software-enabled. When it is not,
+    /// all of the LVT entries are masked.
+    pub fn software_enabled(&self) -> bool {
+        self.read(SVR) & SVR_ENABLED != 0
+    }
+
+    /// The vector delivered when an interrupt disappears before
+    /// being accepted by the processor.
+    pub fn spurious_vector(&self) -> u8 {
+        self.read(SVR) as u8
+    }
+
+    /// Reads one of the vector bitmaps, starting at `offset`.
+    fn bitmap(&self, offset: usize) -> VectorBitmap {
+        let mut bitmap = VectorBitmap::default();
+
+        for (i, word) in bitmap.0.iter_mut().enumerate() {
+            *word = self.read(offset + i * 0x10);
+        }
+
+        bitmap
+    }
+
+    /// Writes one of the vector bitmaps, starting at `offset`.
+    fn set_bitmap(&mut self, offset: usize, bitmap: &VectorBitmap) {
+        for (i, &word) in bitmap.0.iter().enumerate() {
+            self.write(offset + i * 0x10, word);
+        }
+    }
+
+    /// The interrupts which were accepted by the processor,
+    /// and are waiting for their end-of-interrupt.
+    pub fn isr(&self) -> VectorBitmap {
+        self.bitmap(ISR)
+    }
+
+    /// Sets the in-service interrupts.
+    pub fn set_isr(&mut self, isr: &VectorBitmap) {
+        self.set_bitmap(ISR, isr)
+    }
+
+    /// The interrupts which are level-triggered.
+    pub fn tmr(&self) -> VectorBitmap {
+        self.bitmap(TMR)
+    }
+
+    /// Sets the level-triggered interrupts.
+    pub fn set_tmr(&mut self, tmr: &VectorBitmap) {
+        self.set_bitmap(TMR, tmr)
+    }
+
+    /// The interrupts which are pending.
+    pub fn irr(&self) -> VectorBitmap {
+        self.bitmap(IRR)
+    }
+
+    /// Sets the pending interrupts.
+    pub fn set_irr(&mut self, irr: &VectorBitmap) {
+        self.set_bitmap(IRR, irr)
+    }
+
+    /// Reads an entry of the local vector table.
+    pub fn lvt(&self, lvt: Lvt) -> LvtEntry {
+        self.read(lvt.offset()).into()
+    }
+
+    /// Writes an entry of the local vector table.
+    pub fn set_lvt(&mut self, lvt: Lvt, entry: LvtEntry) {
+        self.write(lvt.offset(), entry.into())
+    }
+
+    /// The count the timer starts from, 0 if it is stopped.
+    pub fn timer_initial_count(&self) -> u32 {
+        self.read(TIMER_INITIAL_COUNT)
+    }
+
+    /// Sets the initial count of the timer.
+    pub fn set_timer_initial_count(&mut self, count: u32) {
+        self.write(TIMER_INITIAL_COUNT, count)
+    }
+
+    /// The current count of the timer.
+    pub fn timer_current_count(&self) -> u32 {
+        self.read(TIMER_CURRENT_COUNT)
+    }
+
+    /// The number of bus clock ticks per tick of the timer,
+    /// from 1 to 128.
+    pub fn timer_divisor(&self) -> u32 {
+        let divide = self.read(TIMER_DIVIDE);
+        let value = (divide & 0b11) | (divide & 0b1000) >> 1;
+        1 << ((value + 1) & 0b111)
+    }
+
+    /// Sets the timer's divisor, which must be a power of two from 1 to 128.
+    pub fn set_timer_divisor(&mut self, divisor: u32) {
+        assert!(divisor.is_power_of_two() && divisor <= 128);
+
+        let value = (divisor.trailing_zeros() + 7) & 0b111;
+        self.write(TIMER_DIVIDE, (value & 0b11) | (value & 0b100) << 1)
+    }
+}
+
+impl Default for LapicState {
+    fn default() -> Self {
+        LapicState { page: [0; PAGE_SIZE] }
+    }
+}
+
+impl PartialEq for LapicState {
+    fn eq(&self, other: &Self) -> bool {
+        self.page[..] == other.page[..]
+    }
+}
+
+impl Eq for LapicState {}
+
+impl ::std::fmt::Debug for LapicState {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("LapicState")
+            .field("id", &self.id())
+            .field("tpr", &self.tpr())
+            .field("svr", &self.read(SVR))
+            .field("isr", &self.isr())
+            .field("irr", &self.irr())
+            .field("timer_initial_count", &self.timer_initial_count())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lapic_registers() {
+        let mut lapic = LapicState::default();
+
+        lapic.set_id(3);
+        assert_eq!(lapic.read(ID), 0x0300_0000);
+
+        let mut irr = VectorBitmap::default();
+        assert_eq!(irr.highest(), None);
+
+        irr.set(0x31, true);
+        irr.set(0xEC, true);
+        lapic.set_irr(&irr);
+
+        assert_eq!(lapic.read(IRR + 0x10), 1 << 17);
+        assert_eq!(lapic.read(IRR + 0x70), 1 << 12);
+        assert_eq!(lapic.irr().highest(), Some(0xEC));
+
+        let timer = LvtEntry {
+            vector: 0xEC,
+            timer_mode: TimerMode::Periodic,
+            ..Default::default()
+        };
+        lapic.set_lvt(Lvt::Timer, timer);
+        assert_eq!(lapic.read(0x320), 0x200EC);
+        assert_eq!(lapic.lvt(Lvt::Timer), timer);
+
+        // NMIs on LINT1, as set up by most guests.
+        let lint1 = LvtEntry::from(0x400);
+        assert_eq!(lint1.delivery_mode, DeliveryMode::Nmi);
+        assert!(!lint1.masked);
+
+        // Divide by 1 is encoded as 0b1011.
+        lapic.write(TIMER_DIVIDE, 0b1011);
+        assert_eq!(lapic.timer_divisor(), 1);
+
+        for &divisor in &[1, 2, 16, 128] {
+            lapic.set_timer_divisor(divisor);
+            assert_eq!(lapic.timer_divisor(), divisor);
+        }
+    }
+}
@@ -0,0 +1,570 @@
+//! Typed views of the standard and extended `CPUID` leaves.
+//!
+//! Each leaf can be decoded from the registers returned by `CPUID`,
+//! modified, and encoded back. Encoding only changes the fields which
+//! are known, so that reserved and unknown bits are kept.
+
+use super::Register;
+
+/// A leaf, or subleaf, which can be decoded from its registers.
+pub trait Leaf: Sized {
+    /// Decodes the leaf from the EAX, EBX, ECX and EDX registers.
+    fn decode(r: &[u32; 4]) -> Self;
+
+    /// Encodes the leaf into the registers.
+    fn encode(&self, r: &mut [u32; 4]);
+}
+
+/// Extracts a bit field from a register.
+fn bits(value: u32, shift: u32, width: u32) -> u32 {
+    (value >> shift) & ((1 << width) - 1)
+}
+
+/// Replaces a bit field of a register.
+fn set_bits(value: &mut u32, shift: u32, width: u32, field: u32) {
+    let mask = ((1 << width) - 1) << shift;
+    *value = *value & !mask | (field << shift) & mask;
+}
+
+macro_rules! feature_flags {
+    ($(#[$meta:meta])* pub struct $name:ident {
+        $($(#[$field_meta:meta])* $field:ident: $register:ident[$bit:expr],)*
+    }) => {
+        $(#[$meta])*
+        #[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+        pub struct $name {
+            $(
+                #[doc = concat!("The `", stringify!($field), "` feature.")]
+                $(#[$field_meta])*
+                pub $field: bool,
+            )*
+        }
+
+        impl Leaf for $name {
+            fn decode(r: &[u32; 4]) -> Self {
+                $name {
+                    $($field: r[Register::$register as usize] & 1 << $bit != 0,)*
+                }
+            }
+
+            fn encode(&self, r: &mut [u32; 4]) {
+                $(set_bits(&mut r[Register::$register as usize], $bit, 1, self.$field as u32);)*
+            }
+        }
+    };
+}
+
+/// The family, model and stepping of a processor, reported in EAX of leaf 1.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct Signature {
+    /// The family, including the extended family.
+    pub family: u32,
+    /// The model, including the extended model.
+    pub model: u32,
+    /// The stepping.
+    pub stepping: u32,
+    /// The processor type, which is 0 for the primary processor.
+    pub processor_type: u32,
+}
+
+impl Signature {
+    /// Decodes the signature from EAX.
+    pub fn decode(eax: u32) -> Self {
+        let mut family = bits(eax, 8, 4);
+        let mut model = bits(eax, 4, 4);
+
+        if family == 6 || family == 0xF {
+            model |= bits(eax, 16, 4) << 4;
+        }
+
+        if family == 0xF {
+            family += bits(eax, 20, 8);
+        }
+
+        Signature {
+            family,
+            model,
+            stepping: bits(eax, 0, 4),
+            processor_type: bits(eax, 12, 2),
+        }
+    }
+
+    /// Encodes the signature into the value of EAX.
+    pub fn encode(&self) -> u32 {
+        let (family, extended_family) = if self.family > 0xF {
+            (0xF, self.family - 0xF)
+        } else {
+            (self.family, 0)
+        };
+
+        let mut eax = 0;
+        set_bits(&mut eax, 0, 4, self.stepping);
+        set_bits(&mut eax, 4, 4, self.model);
+        set_bits(&mut eax, 8, 4, family);
+        set_bits(&mut eax, 12, 2, self.processor_type);
+        set_bits(&mut eax, 16, 4, self.model >> 4);
+        set_bits(&mut eax, 20, 8, extended_family);
+        eax
+    }
+}
+
+feature_flags! {
+    /// The features reported in ECX and EDX of leaf 1.
+    pub struct Leaf1Features {
+        sse3: Ecx[0],
+        pclmulqdq: Ecx[1],
+        dtes64: Ecx[2],
+        monitor: Ecx[3],
+        ds_cpl: Ecx[4],
+        vmx: Ecx[5],
+        smx: Ecx[6],
+        est: Ecx[7],
+        tm2: Ecx[8],
+        ssse3: Ecx[9],
+        cnxt_id: Ecx[10],
+        sdbg: Ecx[11],
+        fma: Ecx[12],
+        cx16: Ecx[13],
+        xtpr: Ecx[14],
+        pdcm: Ecx[15],
+        pcid: Ecx[17],
+        dca: Ecx[18],
+        sse4_1: Ecx[19],
+        sse4_2: Ecx[20],
+        x2apic: Ecx[21],
+        movbe: Ecx[22],
+        popcnt: Ecx[23],
+        tsc_deadline: Ecx[24],
+        aes: Ecx[25],
+        xsave: Ecx[26],
+        /// `XSAVE` is enabled by the operating system.
+        osxsave: Ecx[27],
+        avx: Ecx[28],
+        f16c: Ecx[29],
+        rdrand: Ecx[30],
+        /// The processor is virtualized.
+        hypervisor: Ecx[31],
+
+        fpu: Edx[0],
+        vme: Edx[1],
+        de: Edx[2],
+        pse: Edx[3],
+        tsc: Edx[4],
+        msr: Edx[5],
+        pae: Edx[6],
+        mce: Edx[7],
+        cx8: Edx[8],
+        apic: Edx[9],
+        sep: Edx[11],
+        mtrr: Edx[12],
+        pge: Edx[13],
+        mca: Edx[14],
+        cmov: Edx[15],
+        pat: Edx[16],
+        pse36: Edx[17],
+        psn: Edx[18],
+        clflush: Edx[19],
+        ds: Edx[21],
+        acpi: Edx[22],
+        mmx: Edx[23],
+        fxsr: Edx[24],
+        sse: Edx[25],
+        sse2: Edx[26],
+        ss: Edx[27],
+        /// There is more than one logical processor in the package.
+        htt: Edx[28],
+        tm: Edx[29],
+        pbe: Edx[31],
+    }
+}
+
+/// Leaf 1: version information and features.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct Leaf1 {
+    /// The family, model and stepping.
+    pub signature: Signature,
+    /// The index in the brand table.
+    pub brand_index: u8,
+    /// The size of the line flushed by `CLFLUSH`, in bytes.
+    pub clflush_size: u32,
+    /// The maximum number of addressable logical processors in the package.
+    pub logical_processors: u8,
+    /// The initial APIC ID of the processor.
+    pub apic_id: u8,
+    /// The feature flags.
+    pub features: Leaf1Features,
+}
+
+impl Leaf for Leaf1 {
+    fn decode(r: &[u32; 4]) -> Self {
+        Leaf1 {
+            signature: Signature::decode(r[0]),
+            brand_index: bits(r[1], 0, 8) as u8,
+            clflush_size: bits(r[1], 8, 8) * 8,
+            logical_processors: bits(r[1], 16, 8) as u8,
+            apic_id: bits(r[1], 24, 8) as u8,
+            features: Leaf1Features::decode(r),
+        }
+    }
+
+    fn encode(&self, r: &mut [u32; 4]) {
+        r[0] = self.signature.encode();
+        set_bits(&mut r[1], 0, 8, u32::from(self.brand_index));
+        set_bits(&mut r[1], 8, 8, self.clflush_size / 8);
+        set_bits(&mut r[1], 16, 8, u32::from(self.logical_processors));
+        set_bits(&mut r[1], 24, 8, u32::from(self.apic_id));
+        self.features.encode(r);
+    }
+}
+
+feature_flags! {
+    /// The features reported in subleaf 0 of leaf 7.
+    pub struct Leaf7Features {
+        fsgsbase: Ebx[0],
+        tsc_adjust: Ebx[1],
+        sgx: Ebx[2],
+        bmi1: Ebx[3],
+        hle: Ebx[4],
+        avx2: Ebx[5],
+        fdp_excptn_only: Ebx[6],
+        smep: Ebx[7],
+        bmi2: Ebx[8],
+        erms: Ebx[9],
+        invpcid: Ebx[10],
+        rtm: Ebx[11],
+        pqm: Ebx[12],
+        zero_fcs_fds: Ebx[13],
+        mpx: Ebx[14],
+        pqe: Ebx[15],
+        avx512f: Ebx[16],
+        avx512dq: Ebx[17],
+        rdseed: Ebx[18],
+        adx: Ebx[19],
+        smap: Ebx[20],
+        avx512ifma: Ebx[21],
+        clflushopt: Ebx[23],
+        clwb: Ebx[24],
+        intel_pt: Ebx[25],
+        avx512pf: Ebx[26],
+        avx512er: Ebx[27],
+        avx512cd: Ebx[28],
+        sha: Ebx[29],
+        avx512bw: Ebx[30],
+        avx512vl: Ebx[31],
+
+        prefetchwt1: Ecx[0],
+        avx512vbmi: Ecx[1],
+        umip: Ecx[2],
+        pku: Ecx[3],
+        /// Protection keys are enabled by the operating system.
+        ospke: Ecx[4],
+        waitpkg: Ecx[5],
+        avx512vbmi2: Ecx[6],
+        cet_ss: Ecx[7],
+        gfni: Ecx[8],
+        vaes: Ecx[9],
+        vpclmulqdq: Ecx[10],
+        avx512vnni: Ecx[11],
+        avx512bitalg: Ecx[12],
+        tme: Ecx[13],
+        avx512vpopcntdq: Ecx[14],
+        la57: Ecx[16],
+        rdpid: Ecx[22],
+        kl: Ecx[23],
+        bus_lock_detect: Ecx[24],
+        cldemote: Ecx[25],
+        movdiri: Ecx[27],
+        movdir64b: Ecx[28],
+        enqcmd: Ecx[29],
+        sgx_lc: Ecx[30],
+        pks: Ecx[31],
+
+        avx512_4vnniw: Edx[2],
+        avx512_4fmaps: Edx[3],
+        fsrm: Edx[4],
+        uintr: Edx[5],
+        avx512_vp2intersect: Edx[8],
+        md_clear: Edx[10],
+        serialize: Edx[14],
+        hybrid: Edx[15],
+        tsxldtrk: Edx[16],
+        pconfig: Edx[18],
+        cet_ibt: Edx[20],
+        amx_bf16: Edx[22],
+        avx512_fp16: Edx[23],
+        amx_tile: Edx[24],
+        amx_int8: Edx[25],
+        spec_ctrl: Edx[26],
+        stibp: Edx[27],
+        l1d_flush: Edx[28],
+        arch_capabilities: Edx[29],
+        core_capabilities: Edx[30],
+        ssbd: Edx[31],
+    }
+}
+
+/// The type of a level of the extended topology.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum LevelType {
+    /// Ends the list of levels.
+    #[default]
+    Invalid,
+    /// Threads in a core.
+    Smt,
+    /// Cores.
+    Core,
+    /// Modules, only reported by leaf 0x1F.
+    Module,
+    /// Tiles, only reported by leaf 0x1F.
+    Tile,
+    /// Dies, only reported by leaf 0x1F.
+    Die,
+    /// A type which is not known.
+    Other(u8),
+}
+
+impl From<u8> for LevelType {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => LevelType::Invalid,
+            1 => LevelType::Smt,
+            2 => LevelType::Core,
+            3 => LevelType::Module,
+            4 => LevelType::Tile,
+            5 => LevelType::Die,
+            _ => LevelType::Other(value),
+        }
+    }
+}
+
+impl From<LevelType> for u8 {
+    fn from(level_type: LevelType) -> Self {
+        match level_type {
+            LevelType::Invalid => 0,
+            LevelType::Smt => 1,
+            LevelType::Core => 2,
+            LevelType::Module => 3,
+            LevelType::Tile => 4,
+            LevelType::Die => 5,
+            LevelType::Other(value) => value,
+        }
+    }
+}
+
+/// A subleaf of the extended topology leaves, 0xB and 0x1F.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct TopologyLevel {
+    /// Shift of the x2APIC ID to get the ID of the next level.
+    pub shift: u8,
+    /// Number of logical processors at this level.
+    pub logical_processors: u16,
+    /// The number of the level, which is the subleaf.
+    pub level: u8,
+    /// The type of the level.
+    pub level_type: LevelType,
+    /// The x2APIC ID of the processor.
+    pub x2apic_id: u32,
+}
+
+impl Leaf for TopologyLevel {
+    fn decode(r: &[u32; 4]) -> Self {
+        TopologyLevel {
+            shift: bits(r[0], 0, 5) as u8,
+            logical_processors: bits(r[1], 0, 16) as u16,
+            level: bits(r[2], 0, 8) as u8,
+            level_type: LevelType::from(bits(r[2], 8, 8) as u8),
+            x2apic_id: r[3],
+        }
+    }
+
+    fn encode(&self, r: &mut [u32; 4]) {
+        set_bits(&mut r[0], 0, 5, u32::from(self.shift));
+        set_bits(&mut r[1], 0, 16, u32::from(self.logical_processors));
+        set_bits(&mut r[2], 0, 8, u32::from(self.level));
+        set_bits(&mut r[2], 8, 8, u32::from(u8::from(self.level_type)));
+        r[3] = self.x2apic_id;
+    }
+}
+
+/// Subleaf 0 of leaf 0xD: the state components supported by `XSAVE`.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct XsaveLeaf {
+    /// The bits which can be set in XCR0.
+    pub supported_xcr0: u64,
+    /// The size of the `XSAVE` area for the components enabled in XCR0.
+    pub enabled_size: u32,
+    /// The size of the `XSAVE` area for all of the supported components.
+    pub max_size: u32,
+}
+
+impl Leaf for XsaveLeaf {
+    fn decode(r: &[u32; 4]) -> Self {
+        XsaveLeaf {
+            supported_xcr0: u64::from(r[0]) | u64::from(r[3]) << 32,
+            enabled_size: r[1],
+            max_size: r[2],
+        }
+    }
+
+    fn encode(&self, r: &mut [u32; 4]) {
+        *r = [
+            self.supported_xcr0 as u32,
+            self.enabled_size,
+            self.max_size,
+            (self.supported_xcr0 >> 32) as u32,
+        ];
+    }
+}
+
+feature_flags! {
+    /// The `XSAVE` extensions, reported in EAX of subleaf 1 of leaf 0xD.
+    pub struct XsaveFeatures {
+        xsaveopt: Eax[0],
+        xsavec: Eax[1],
+        xgetbv1: Eax[2],
+        xsaves: Eax[3],
+        xfd: Eax[4],
+    }
+}
+
+/// Subleaves 2 and above of leaf 0xD: the location of a state component.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct XsaveComponent {
+    /// The size of the component, in bytes.
+    pub size: u32,
+    /// The offset of the component in the standard format.
+    pub offset: u32,
+    /// The component is managed through the XSS MSR instead of XCR0.
+    pub supervisor: bool,
+    /// The component is aligned to 64 bytes in the compacted format.
+    pub aligned: bool,
+}
+
+impl Leaf for XsaveComponent {
+    fn decode(r: &[u32; 4]) -> Self {
+        XsaveComponent {
+            size: r[0],
+            offset: r[1],
+            supervisor: r[2] & 1 != 0,
+            aligned: r[2] & 2 != 0,
+        }
+    }
+
+    fn encode(&self, r: &mut [u32; 4]) {
+        r[0] = self.size;
+        r[1] = self.offset;
+        set_bits(&mut r[2], 0, 1, self.supervisor as u32);
+        set_bits(&mut r[2], 1, 1, self.aligned as u32);
+    }
+}
+
+feature_flags! {
+    /// The extended features, reported in ECX and EDX of leaf 0x8000_0001.
+    pub struct ExtendedFeatures {
+        lahf_lm: Ecx[0],
+        cmp_legacy: Ecx[1],
+        svm: Ecx[2],
+        extapic: Ecx[3],
+        cr8_legacy: Ecx[4],
+        abm: Ecx[5],
+        sse4a: Ecx[6],
+        misalignsse: Ecx[7],
+        prefetchw: Ecx[8],
+        osvw: Ecx[9],
+        ibs: Ecx[10],
+        xop: Ecx[11],
+        skinit: Ecx[12],
+        wdt: Ecx[13],
+        lwp: Ecx[15],
+        fma4: Ecx[16],
+        tce: Ecx[17],
+        tbm: Ecx[21],
+        topoext: Ecx[22],
+        perfctr_core: Ecx[23],
+        perfctr_nb: Ecx[24],
+
+        syscall: Edx[11],
+        nx: Edx[20],
+        mmxext: Edx[22],
+        fxsr_opt: Edx[25],
+        pdpe1gb: Edx[26],
+        rdtscp: Edx[27],
+        lm: Edx[29],
+        amd_3dnowext: Edx[30],
+        amd_3dnow: Edx[31],
+    }
+}
+
+/// Leaf 0x8000_0008: the sizes of addresses.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct AddressSizes {
+    /// Number of bits in physical addresses.
+    pub physical: u8,
+    /// Number of bits in linear addresses.
+    pub linear: u8,
+    /// Number of bits in guest physical addresses, if different
+    /// from `physical`, or 0.
+    pub guest_physical: u8,
+}
+
+impl Leaf for AddressSizes {
+    fn decode(r: &[u32; 4]) -> Self {
+        AddressSizes {
+            physical: bits(r[0], 0, 8) as u8,
+            linear: bits(r[0], 8, 8) as u8,
+            guest_physical: bits(r[0], 16, 8) as u8,
+        }
+    }
+
+    fn encode(&self, r: &mut [u32; 4]) {
+        set_bits(&mut r[0], 0, 8, u32::from(self.physical));
+        set_bits(&mut r[0], 8, 8, u32::from(self.linear));
+        set_bits(&mut r[0], 16, 8, u32::from(self.guest_physical));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_and_encode() {
+        // A Skylake server processor.
+        let mut r = [0x0005_0654, 0x0010_0800, 0x7FFE_FBFF, 0xBFEB_FBFF];
+        let leaf = Leaf1::decode(&r);
+
+        assert_eq!(leaf.signature, Signature { family: 6, model: 85, stepping: 4, processor_type: 0 });
+        assert_eq!(leaf.clflush_size, 64);
+        assert_eq!(leaf.logical_processors, 16);
+        assert!(leaf.features.avx && leaf.features.sse4_2 && leaf.features.htt);
+        assert!(!leaf.features.hypervisor);
+
+        let mut copy = leaf;
+        copy.encode(&mut r);
+        assert_eq!(r, [0x0005_0654, 0x0010_0800, 0x7FFE_FBFF, 0xBFEB_FBFF]);
+
+        copy.features.avx = false;
+        copy.apic_id = 3;
+        copy.encode(&mut r);
+        assert_eq!(r, [0x0005_0654, 0x0310_0800, 0x6FFE_FBFF, 0xBFEB_FBFF]);
+
+        // AMD family 0x17 uses the extended family.
+        let epyc = Signature::decode(0x0080_0F12);
+        assert_eq!((epyc.family, epyc.model, epyc.stepping), (0x17, 1, 2));
+        assert_eq!(epyc.encode(), 0x0080_0F12);
+
+        let mut r = [0, 0xD19F_4FBB, 0x0000_0008, 0];
+        let mut features = Leaf7Features::decode(&r);
+        assert!(features.avx2 && features.avx512f && !features.sha);
+        features.avx2 = false;
+        features.encode(&mut r);
+        assert_eq!(r[1], 0xD19F_4F9B);
+
+        let level = TopologyLevel::decode(&[1, 2, 0x100, 5]);
+        assert_eq!(level.level_type, LevelType::Smt);
+        assert_eq!((level.shift, level.logical_processors, level.x2apic_id), (1, 2, 5));
+
+        let sizes = AddressSizes::decode(&[0x3027, 0, 0, 0]);
+        assert_eq!((sizes.physical, sizes.linear), (39, 48));
+    }
+}
@@ -0,0 +1,424 @@
+//! Configuration of the information returned by the `CPUID` instruction.
+//!
+//! A `Cpuid` table usually starts as the set of leaves supported by the
+//! accelerator, and is then adjusted for the guest: features are masked,
+//! the vendor and brand strings are changed, and each vCPU gets its own
+//! copy with its APIC ID and the topology of its package.
+
+pub mod leaves;
+pub mod models;
+
+use self::leaves::{Leaf, Leaf1, LevelType, TopologyLevel};
+
+/// One of the registers returned by `CPUID`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Register {
+    /// EAX.
+    Eax = 0,
+    /// EBX.
+    Ebx = 1,
+    /// ECX.
+    Ecx = 2,
+    /// EDX.
+    Edx = 3,
+}
+
+/// The values returned by `CPUID` for a leaf, or for one of its subleaves.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct CpuidEntry {
+    /// The leaf, given in EAX.
+    pub function: u32,
+    /// The subleaf, given in ECX, for leaves which have subleaves.
+    pub index: Option<u32>,
+    /// The returned EAX, EBX, ECX and EDX registers.
+    pub r: [u32; 4],
+}
+
+impl CpuidEntry {
+    /// Creates an entry for a leaf, or for one of its subleaves.
+    pub fn new(function: u32, index: Option<u32>, r: [u32; 4]) -> Self {
+        CpuidEntry { function, index, r }
+    }
+
+    /// Checks if this entry is returned for the given leaf and subleaf.
+    fn matches(&self, function: u32, index: u32) -> bool {
+        self.function == function && self.index.is_none_or(|i| i == index)
+    }
+
+    /// Checks if this entry describes exactly the given leaf and subleaf.
+    fn describes(&self, function: u32, index: Option<u32>) -> bool {
+        self.function == function && (index.is_none() || self.index == index)
+    }
+}
+
+/// The arrangement of the logical processors in a package.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Topology {
+    /// Number of hardware threads in each core.
+    pub threads_per_core: u32,
+    /// Number of cores in each package.
+    pub cores_per_package: u32,
+}
+
+impl Default for Topology {
+    fn default() -> Self {
+        Topology {
+            threads_per_core: 1,
+            cores_per_package: 1,
+        }
+    }
+}
+
+impl Topology {
+    /// Number of logical processors in each package.
+    pub fn logical_per_package(&self) -> u32 {
+        self.threads_per_core * self.cores_per_package
+    }
+
+    /// Number of APIC ID bits used for the thread number.
+    fn thread_bits(&self) -> u32 {
+        self.threads_per_core.next_power_of_two().trailing_zeros()
+    }
+
+    /// Number of APIC ID bits used for the thread and core numbers.
+    fn package_shift(&self) -> u32 {
+        self.thread_bits() + self.cores_per_package.next_power_of_two().trailing_zeros()
+    }
+
+    /// Computes the APIC ID of a logical processor.
+    pub fn apic_id(&self, package: u32, core: u32, thread: u32) -> u32 {
+        package << self.package_shift() | core << self.thread_bits() | thread
+    }
+
+    /// Computes the APIC ID of the `n`-th logical processor, counting
+    /// the threads of a core first, and then the cores of a package.
+    pub fn nth_apic_id(&self, n: u32) -> u32 {
+        let thread = n % self.threads_per_core;
+        let core = n / self.threads_per_core % self.cores_per_package;
+        let package = n / self.logical_per_package();
+
+        self.apic_id(package, core, thread)
+    }
+
+    /// Computes the APIC IDs of the first `count` logical processors.
+    pub fn apic_ids(&self, count: u32) -> Vec<u32> {
+        (0..count).map(|n| self.nth_apic_id(n)).collect()
+    }
+}
+
+/// A table of CPUID leaves, as seen by a vCPU.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct Cpuid {
+    entries: Vec<CpuidEntry>,
+}
+
+impl Cpuid {
+    /// Creates a table from a list of entries,
+    /// such as the leaves supported by an accelerator.
+    pub fn new(entries: Vec<CpuidEntry>) -> Self {
+        Cpuid { entries }
+    }
+
+    /// The entries in the table.
+    pub fn entries(&self) -> &[CpuidEntry] {
+        &self.entries
+    }
+
+    /// Looks up the entry returned for a leaf and subleaf.
+    ///
+    /// The subleaf is ignored for leaves which do not have any.
+    pub fn get(&self, function: u32, index: u32) -> Option<&CpuidEntry> {
+        self.entries.iter().find(|e| e.matches(function, index))
+    }
+
+    /// Looks up the entry returned for a leaf and subleaf, to modify it.
+    pub fn get_mut(&mut self, function: u32, index: u32) -> Option<&mut CpuidEntry> {
+        self.entries.iter_mut().find(|e| e.matches(function, index))
+    }
+
+    /// Adds an entry, replacing the one for the same leaf and subleaf.
+    pub fn set(&mut self, entry: CpuidEntry) {
+        match self.entries.iter_mut().find(|e| e.function == entry.function && e.index == entry.index) {
+            Some(existing) => *existing = entry,
+            None => self.entries.push(entry),
+        }
+    }
+
+    /// Removes a subleaf, or all of the subleaves of a leaf if `index` is `None`.
+    pub fn remove(&mut self, function: u32, index: Option<u32>) {
+        self.entries.retain(|e| !e.describes(function, index));
+    }
+
+    /// Clears bits of a register, in a subleaf or in all of the subleaves
+    /// of a leaf if `index` is `None`.
+    ///
+    /// This is used to hide features from the guest.
+    pub fn clear_bits(&mut self, function: u32, index: Option<u32>, register: Register, bits: u32) {
+        for entry in self.entries.iter_mut().filter(|e| e.describes(function, index)) {
+            entry.r[register as usize] &= !bits;
+        }
+    }
+
+    /// Sets bits of a register, in a subleaf or in all of the subleaves
+    /// of a leaf if `index` is `None`.
+    pub fn set_bits(&mut self, function: u32, index: Option<u32>, register: Register, bits: u32) {
+        for entry in self.entries.iter_mut().filter(|e| e.describes(function, index)) {
+            entry.r[register as usize] |= bits;
+        }
+    }
+
+    /// Decodes a leaf, or one of its subleaves.
+    pub fn decode<L: Leaf>(&self, function: u32, index: u32) -> Option<L> {
+        self.get(function, index).map(|entry| L::decode(&entry.r))
+    }
+
+    /// Encodes a leaf, or one of its subleaves.
+    ///
+    /// Does nothing if the table does not contain the leaf.
+    pub fn encode<L: Leaf>(&mut self, function: u32, index: u32, leaf: &L) {
+        if let Some(entry) = self.get_mut(function, index) {
+            leaf.encode(&mut entry.r);
+        }
+    }
+
+    /// The vendor string, such as `GenuineIntel`.
+    pub fn vendor(&self) -> Option<String> {
+        self.get(0, 0).map(|entry| {
+            let registers = [entry.r[1], entry.r[3], entry.r[2]];
+            string_from_registers(&registers)
+        })
+    }
+
+    /// Changes the vendor string.
+    ///
+    /// # Panics
+    /// If the vendor string is not exactly 12 bytes long.
+    pub fn set_vendor(&mut self, vendor: &str) {
+        assert_eq!(vendor.len(), 12, "the vendor string must have 12 bytes");
+
+        let r = registers_from_string(vendor.as_bytes());
+
+        let mut entry = self.get(0, 0).cloned().unwrap_or_else(|| CpuidEntry::new(0, None, [0; 4]));
+        entry.r[1] = r[0];
+        entry.r[3] = r[1];
+        entry.r[2] = r[2];
+
+        self.set(entry);
+    }
+
+    /// The processor brand string.
+    pub fn brand(&self) -> Option<String> {
+        let mut registers = Vec::with_capacity(12);
+
+        for function in 0x8000_0002..0x8000_0005 {
+            registers.extend_from_slice(&self.get(function, 0)?.r);
+        }
+
+        Some(string_from_registers(&registers))
+    }
+
+    /// Changes the processor brand string.
+    ///
+    /// The brand is truncated to 47 bytes, to leave room for the null terminator.
+    pub fn set_brand(&mut self, brand: &str) {
+        let brand = brand.as_bytes();
+        let brand = &brand[..brand.len().min(47)];
+
+        let mut bytes = [0; 48];
+        bytes[..brand.len()].copy_from_slice(brand);
+
+        for (i, function) in (0x8000_0002..0x8000_0005).enumerate() {
+            let r = registers_from_string(&bytes[16 * i..16 * (i + 1)]);
+            self.set(CpuidEntry::new(function, None, [r[0], r[1], r[2], r[3]]));
+        }
+
+        // The brand string leaves must not be above the highest extended leaf.
+        let mut max = self.get(0x8000_0000, 0).cloned().unwrap_or_else(|| CpuidEntry::new(0x8000_0000, None, [0; 4]));
+        max.r[0] = max.r[0].max(0x8000_0004);
+        self.set(max);
+    }
+
+    /// Advertises x2APIC mode, which is needed by APIC IDs above 254.
+    ///
+    /// The extended topology leaf is added if it is missing, since it is the
+    /// only one which reports the full x2APIC ID; `for_vcpu` fills it in.
+    pub fn enable_x2apic(&mut self) {
+        if let Some(mut leaf) = self.decode::<Leaf1>(1, 0) {
+            leaf.features.x2apic = true;
+            self.encode(1, 0, &leaf);
+        }
+
+        if self.get(0xB, 0).is_none() {
+            self.set(CpuidEntry::new(0xB, Some(0), [0; 4]));
+        }
+
+        // The topology leaf must not be above the highest basic leaf.
+        if let Some(max) = self.get_mut(0, 0) {
+            max.r[0] = max.r[0].max(0xB);
+        }
+    }
+
+    /// Creates the table of a single vCPU, which reports the given APIC ID
+    /// and the number of threads and cores in its package.
+    pub fn for_vcpu(&self, apic_id: u32, topology: &Topology) -> Cpuid {
+        let mut cpuid = self.clone();
+
+        let logical = topology.logical_per_package();
+
+        if let Some(mut leaf) = cpuid.decode::<Leaf1>(1, 0) {
+            // The initial APIC ID and the number of logical processors are 8-bit fields.
+            leaf.apic_id = apic_id as u8;
+            leaf.logical_processors = logical.min(0xFF) as u8;
+
+            // Hyper-threading indicates that there is more than one logical processor.
+            leaf.features.htt = logical > 1;
+
+            cpuid.encode(1, 0, &leaf);
+        }
+
+        // Deterministic cache parameters.
+        for entry in cpuid.entries.iter_mut().filter(|e| e.function == 4) {
+            let cores = (topology.cores_per_package - 1).min(0x3F);
+            entry.r[0] = entry.r[0] & 0x03FF_FFFF | cores << 26;
+        }
+
+        // Extended topology, and its V2 version.
+        for &function in &[0xB, 0x1F] {
+            if cpuid.get(function, 0).is_none() {
+                continue;
+            }
+
+            cpuid.remove(function, None);
+
+            let levels = [
+                (topology.thread_bits(), topology.threads_per_core, LevelType::Smt),
+                (topology.package_shift(), logical, LevelType::Core),
+                // Ends the list.
+                (0, 0, LevelType::Invalid),
+            ];
+
+            for (index, &(shift, count, level_type)) in levels.iter().enumerate() {
+                let level = TopologyLevel {
+                    shift: shift as u8,
+                    logical_processors: count as u16,
+                    level: index as u8,
+                    level_type,
+                    x2apic_id: apic_id,
+                };
+
+                let mut r = [0; 4];
+                level.encode(&mut r);
+                cpuid.set(CpuidEntry::new(function, Some(index as u32), r));
+            }
+        }
+
+        cpuid
+    }
+}
+
+/// Converts a string stored in registers to a Rust string,
+/// stopping at the first null character.
+fn string_from_registers(registers: &[u32]) -> String {
+    let bytes: Vec<u8> = registers
+        .iter()
+        .flat_map(|r| r.to_le_bytes().to_vec())
+        .take_while(|&b| b != 0)
+        .collect();
+
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Packs a string into registers, four bytes in each.
+fn registers_from_string(bytes: &[u8]) -> Vec<u32> {
+    bytes
+        .chunks(4)
+        .map(|chunk| {
+            let mut word = [0; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            u32::from_le_bytes(word)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vendor_and_brand() {
+        let mut cpuid = Cpuid::new(vec![
+            CpuidEntry::new(0, None, [0xD, 0x756E_6547, 0x6C65_746E, 0x4965_6E69]),
+            CpuidEntry::new(0x8000_0000, None, [0x8000_0001, 0, 0, 0]),
+        ]);
+
+        assert_eq!(cpuid.vendor().unwrap(), "GenuineIntel");
+        assert_eq!(cpuid.brand(), None);
+
+        cpuid.set_vendor("AuthenticAMD");
+        assert_eq!(cpuid.get(0, 0).unwrap().r, [0xD, 0x6874_7541, 0x444D_4163, 0x6974_6E65]);
+
+        cpuid.set_brand("vm-rs virtual CPU");
+        assert_eq!(cpuid.brand().unwrap(), "vm-rs virtual CPU");
+        assert_eq!(cpuid.get(0x8000_0000, 0).unwrap().r[0], 0x8000_0004);
+
+        cpuid.set_brand(&"x".repeat(60));
+        assert_eq!(cpuid.brand().unwrap().len(), 47);
+    }
+
+    #[test]
+    fn vcpu_topology() {
+        let mut cpuid = Cpuid::new(vec![
+            CpuidEntry::new(1, None, [0x906EA, 0x0010_0800, 0, 1 << 28]),
+            CpuidEntry::new(4, Some(0), [0x1C00_4121, 0, 0, 0]),
+            CpuidEntry::new(4, Some(1), [0x1C00_4122, 0, 0, 0]),
+            CpuidEntry::new(0xB, Some(0), [1, 2, 0x100, 0]),
+        ]);
+
+        cpuid.clear_bits(1, None, Register::Eax, 0xF);
+        assert_eq!(cpuid.get(1, 5).unwrap().r[0], 0x906E0);
+
+        let topology = Topology {
+            threads_per_core: 2,
+            cores_per_package: 3,
+        };
+
+        // Package, core and thread numbers take 0, 2 and 1 bits.
+        assert_eq!(topology.nth_apic_id(5), 0b0101);
+        assert_eq!(topology.nth_apic_id(6), 0b1000);
+
+        let vcpu = cpuid.for_vcpu(topology.nth_apic_id(5), &topology);
+
+        assert_eq!(vcpu.get(1, 0).unwrap().r[1], 0x0506_0800);
+        assert_eq!(vcpu.get(4, 1).unwrap().r[0], 0x0800_4122);
+
+        assert_eq!(vcpu.get(0xB, 0).unwrap().r, [1, 2, 0x100, 5]);
+        assert_eq!(vcpu.get(0xB, 1).unwrap().r, [3, 6, 0x201, 5]);
+        assert_eq!(vcpu.get(0xB, 2).unwrap().r, [0, 0, 2, 5]);
+        assert!(vcpu.get(0x1F, 0).is_none());
+
+        // A single logical processor does not have hyper-threading.
+        let single = cpuid.for_vcpu(0, &Topology::default());
+        assert_eq!(single.get(1, 0).unwrap().r[3], 0);
+    }
+
+    #[test]
+    fn x2apic_ids() {
+        let mut cpuid = Cpuid::new(vec![
+            CpuidEntry::new(0, None, [4, 0, 0, 0]),
+            CpuidEntry::new(1, None, [0x906EA, 0, 0, 0]),
+        ]);
+
+        cpuid.enable_x2apic();
+        assert_eq!(cpuid.get(0, 0).unwrap().r[0], 0xB);
+        assert_eq!(cpuid.get(1, 0).unwrap().r[2], 1 << 21);
+
+        let topology = Topology::default();
+        assert_eq!(topology.apic_ids(3), [0, 1, 2]);
+
+        // Only the low 8 bits of the APIC ID fit in leaf 1.
+        let vcpu = cpuid.for_vcpu(300, &topology);
+        assert_eq!(vcpu.get(1, 0).unwrap().r[1] >> 24, 300 & 0xFF);
+        assert_eq!(vcpu.get(0xB, 0).unwrap().r[3], 300);
+        assert_eq!(vcpu.get(0xB, 1).unwrap().r[3], 300);
+    }
+}
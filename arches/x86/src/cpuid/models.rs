@@ -0,0 +1,468 @@
+//! Named CPU models, which give guests a stable set of features
+//! regardless of the host they run on.
+//!
+//! The models are similar to the ones provided by QEMU. A model is applied
+//! to the table of leaves supported by the accelerator, after checking that
+//! the host supports all of its features.
+
+use super::leaves::Signature;
+use super::{Cpuid, Register};
+use std::fmt;
+
+/// A register of a CPUID leaf which contains feature flags.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FeatureWord {
+    /// Leaf 1, EDX.
+    Leaf1Edx,
+    /// Leaf 1, ECX.
+    Leaf1Ecx,
+    /// Leaf 7 subleaf 0, EBX.
+    Leaf7Ebx,
+    /// Leaf 7 subleaf 0, ECX.
+    Leaf7Ecx,
+    /// Leaf 7 subleaf 0, EDX.
+    Leaf7Edx,
+    /// Leaf 0xD subleaf 1, EAX.
+    LeafDEax,
+    /// Leaf 0x8000_0001, EDX.
+    Ext1Edx,
+    /// Leaf 0x8000_0001, ECX.
+    Ext1Ecx,
+}
+
+impl FeatureWord {
+    /// All of the feature words.
+    pub const ALL: [FeatureWord; 8] = [
+        FeatureWord::Leaf1Edx,
+        FeatureWord::Leaf1Ecx,
+        FeatureWord::Leaf7Ebx,
+        FeatureWord::Leaf7Ecx,
+        FeatureWord::Leaf7Edx,
+        FeatureWord::LeafDEax,
+        FeatureWord::Ext1Edx,
+        FeatureWord::Ext1Ecx,
+    ];
+
+    /// The leaf, subleaf and register containing this word.
+    pub fn location(self) -> (u32, u32, Register) {
+        match self {
+            FeatureWord::Leaf1Edx => (1, 0, Register::Edx),
+            FeatureWord::Leaf1Ecx => (1, 0, Register::Ecx),
+            FeatureWord::Leaf7Ebx => (7, 0, Register::Ebx),
+            FeatureWord::Leaf7Ecx => (7, 0, Register::Ecx),
+            FeatureWord::Leaf7Edx => (7, 0, Register::Edx),
+            FeatureWord::LeafDEax => (0xD, 1, Register::Eax),
+            FeatureWord::Ext1Edx => (0x8000_0001, 0, Register::Edx),
+            FeatureWord::Ext1Ecx => (0x8000_0001, 0, Register::Ecx),
+        }
+    }
+
+    /// Reads this word from a CPUID table.
+    ///
+    /// Missing leaves have no features.
+    pub fn read(self, cpuid: &Cpuid) -> u32 {
+        let (function, index, register) = self.location();
+        cpuid.get(function, index).map_or(0, |entry| entry.r[register as usize])
+    }
+}
+
+/// A processor feature, reported by a bit in a CPUID leaf.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Feature {
+    /// The name of the feature, as used by Linux and QEMU.
+    pub name: &'static str,
+    /// The register containing the feature.
+    pub word: FeatureWord,
+    /// The bit in the register.
+    pub bit: u8,
+}
+
+impl Feature {
+    /// Looks up a feature by its name.
+    pub fn find(name: &str) -> Option<Feature> {
+        FEATURES.iter().find(|f| f.name == name).cloned()
+    }
+
+    /// Checks if a CPUID table reports this feature.
+    pub fn is_supported(&self, cpuid: &Cpuid) -> bool {
+        self.word.read(cpuid) & 1 << self.bit != 0
+    }
+}
+
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (function, index, register) = self.word.location();
+        write!(f, "{} (leaf {:#x}.{} {:?} bit {})", self.name, function, index, register, self.bit)
+    }
+}
+
+macro_rules! features {
+    ($($word:ident: [$($name:expr => $bit:expr),* $(,)*],)*) => {
+        /// The features which can be part of a CPU model.
+        pub const FEATURES: &[Feature] = &[
+            $($(Feature { name: $name, word: FeatureWord::$word, bit: $bit },)*)*
+        ];
+    };
+}
+
+features! {
+    Leaf1Edx: [
+        "fpu" => 0, "vme" => 1, "de" => 2, "pse" => 3, "tsc" => 4, "msr" => 5, "pae" => 6,
+        "mce" => 7, "cx8" => 8, "apic" => 9, "sep" => 11, "mtrr" => 12, "pge" => 13, "mca" => 14,
+        "cmov" => 15, "pat" => 16, "pse36" => 17, "clflush" => 19, "mmx" => 23, "fxsr" => 24,
+        "sse" => 25, "sse2" => 26, "ss" => 27,
+    ],
+    Leaf1Ecx: [
+        "sse3" => 0, "pclmulqdq" => 1, "ssse3" => 9, "fma" => 12, "cx16" => 13, "pdcm" => 15,
+        "pcid" => 17, "sse4.1" => 19, "sse4.2" => 20, "x2apic" => 21, "movbe" => 22,
+        "popcnt" => 23, "tsc-deadline" => 24, "aes" => 25, "xsave" => 26, "avx" => 28,
+        "f16c" => 29, "rdrand" => 30, "hypervisor" => 31,
+    ],
+    Leaf7Ebx: [
+        "fsgsbase" => 0, "tsc-adjust" => 1, "bmi1" => 3, "hle" => 4, "avx2" => 5, "smep" => 7,
+        "bmi2" => 8, "erms" => 9, "invpcid" => 10, "rtm" => 11, "avx512f" => 16,
+        "avx512dq" => 17, "rdseed" => 18, "adx" => 19, "smap" => 20, "clflushopt" => 23,
+        "clwb" => 24, "avx512cd" => 28, "sha-ni" => 29, "avx512bw" => 30, "avx512vl" => 31,
+    ],
+    Leaf7Ecx: [
+        "avx512vbmi" => 1, "umip" => 2, "pku" => 3, "vaes" => 9, "vpclmulqdq" => 10,
+        "avx512vnni" => 11, "rdpid" => 22,
+    ],
+    Leaf7Edx: [
+        "md-clear" => 10, "spec-ctrl" => 26, "stibp" => 27, "arch-capabilities" => 29,
+        "ssbd" => 31,
+    ],
+    LeafDEax: [
+        "xsaveopt" => 0, "xsavec" => 1, "xgetbv1" => 2, "xsaves" => 3,
+    ],
+    Ext1Edx: [
+        "syscall" => 11, "nx" => 20, "mmxext" => 22, "fxsr-opt" => 25, "pdpe1gb" => 26,
+        "rdtscp" => 27, "lm" => 29,
+    ],
+    Ext1Ecx: [
+        "lahf-lm" => 0, "cmp-legacy" => 1, "svm" => 2, "abm" => 5, "sse4a" => 6,
+        "misalignsse" => 7, "3dnowprefetch" => 8, "topoext" => 22,
+    ],
+}
+
+/// Features of every 64-bit processor.
+const BASE: &[&str] = &[
+    "fpu", "de", "pse", "tsc", "msr", "pae", "mce", "cx8", "apic", "sep", "mtrr", "pge", "mca",
+    "cmov", "pat", "pse36", "clflush", "mmx", "fxsr", "sse", "sse2", "syscall", "nx", "lm",
+];
+
+const NEHALEM: &[&str] = &[
+    "vme", "ss", "sse3", "cx16", "ssse3", "sse4.1", "sse4.2", "popcnt", "lahf-lm",
+];
+
+const WESTMERE: &[&str] = &["aes", "pclmulqdq"];
+
+const SANDY_BRIDGE: &[&str] = &["x2apic", "tsc-deadline", "xsave", "avx", "rdtscp", "xsaveopt"];
+
+const HASWELL: &[&str] = &[
+    "fma", "pcid", "movbe", "f16c", "rdrand", "fsgsbase", "bmi1", "hle", "avx2", "smep", "bmi2",
+    "erms", "invpcid", "rtm", "abm",
+];
+
+const SKYLAKE: &[&str] = &["rdseed", "adx", "smap", "3dnowprefetch", "xsavec", "xgetbv1"];
+
+const SKYLAKE_SERVER: &[&str] = &[
+    "clflushopt", "clwb", "pku", "avx512f", "avx512dq", "avx512cd", "avx512bw", "avx512vl",
+    "pdpe1gb",
+];
+
+const EPYC: &[&str] = &[
+    "pclmulqdq", "ssse3", "fma", "cx16", "sse4.1", "sse4.2", "movbe", "popcnt", "aes", "xsave",
+    "avx", "f16c", "rdrand", "fsgsbase", "bmi1", "avx2", "smep", "bmi2", "rdseed", "adx", "smap",
+    "clflushopt", "sha-ni", "xsaveopt", "xsavec", "xgetbv1", "mmxext", "fxsr-opt", "pdpe1gb",
+    "rdtscp", "lahf-lm", "cmp-legacy", "svm", "abm", "sse4a", "misalignsse", "3dnowprefetch",
+    "sse3",
+];
+
+/// A named CPU model.
+#[derive(Debug)]
+pub struct CpuModel {
+    /// The name of the model, such as `Skylake-Server`.
+    pub name: &'static str,
+    /// The vendor string.
+    pub vendor: &'static str,
+    /// The brand string.
+    pub brand: &'static str,
+    /// The processor family.
+    pub family: u32,
+    /// The model number in the family.
+    pub model: u32,
+    /// The stepping of the model.
+    pub stepping: u32,
+    /// The names of the features of this model, in groups.
+    pub features: &'static [&'static [&'static str]],
+}
+
+/// The catalog of CPU models.
+pub const MODELS: &[CpuModel] = &[
+    CpuModel {
+        name: "qemu64",
+        vendor: "AuthenticAMD",
+        brand: "QEMU Virtual CPU version 2.5+",
+        family: 15,
+        model: 107,
+        stepping: 1,
+        features: &[BASE, &["sse3", "cx16", "lahf-lm"]],
+    },
+    CpuModel {
+        name: "Nehalem",
+        vendor: "GenuineIntel",
+        brand: "Intel Core i7 9xx (Nehalem Class Core i7)",
+        family: 6,
+        model: 26,
+        stepping: 3,
+        features: &[BASE, NEHALEM],
+    },
+    CpuModel {
+        name: "Westmere",
+        vendor: "GenuineIntel",
+        brand: "Westmere E56xx/L56xx/X56xx (Nehalem-C)",
+        family: 6,
+        model: 44,
+        stepping: 1,
+        features: &[BASE, NEHALEM, WESTMERE],
+    },
+    CpuModel {
+        name: "SandyBridge",
+        vendor: "GenuineIntel",
+        brand: "Intel Xeon E312xx (Sandy Bridge)",
+        family: 6,
+        model: 42,
+        stepping: 1,
+        features: &[BASE, NEHALEM, WESTMERE, SANDY_BRIDGE],
+    },
+    CpuModel {
+        name: "Haswell",
+        vendor: "GenuineIntel",
+        brand: "Intel Core Processor (Haswell)",
+        family: 6,
+        model: 60,
+        stepping: 4,
+        features: &[BASE, NEHALEM, WESTMERE, SANDY_BRIDGE, HASWELL],
+    },
+    CpuModel {
+        name: "Skylake-Client",
+        vendor: "GenuineIntel",
+        brand: "Intel Core Processor (Skylake)",
+        family: 6,
+        model: 94,
+        stepping: 3,
+        features: &[BASE, NEHALEM, WESTMERE, SANDY_BRIDGE, HASWELL, SKYLAKE],
+    },
+    CpuModel {
+        name: "Skylake-Server",
+        vendor: "GenuineIntel",
+        brand: "Intel Xeon Processor (Skylake)",
+        family: 6,
+        model: 85,
+        stepping: 4,
+        features: &[BASE, NEHALEM, WESTMERE, SANDY_BRIDGE, HASWELL, SKYLAKE, SKYLAKE_SERVER],
+    },
+    CpuModel {
+        name: "Cascadelake-Server",
+        vendor: "GenuineIntel",
+        brand: "Intel Xeon Processor (Cascadelake)",
+        family: 6,
+        model: 85,
+        stepping: 6,
+        features: &[BASE, NEHALEM, WESTMERE, SANDY_BRIDGE, HASWELL, SKYLAKE, SKYLAKE_SERVER, &["avx512vnni"]],
+    },
+    CpuModel {
+        name: "EPYC",
+        vendor: "AuthenticAMD",
+        brand: "AMD EPYC Processor",
+        family: 23,
+        model: 1,
+        stepping: 2,
+        features: &[BASE, EPYC],
+    },
+];
+
+impl CpuModel {
+    /// Looks up a model by its name.
+    ///
+    /// There is no model for the host's processor: the table of supported
+    /// leaves can be used directly instead.
+    pub fn find(name: &str) -> Option<&'static CpuModel> {
+        MODELS.iter().find(|model| model.name == name)
+    }
+
+    /// The features of this model.
+    pub fn feature_list(&self) -> Vec<Feature> {
+        self.features
+            .iter()
+            .flat_map(|group| group.iter())
+            .map(|&name| Feature::find(name).unwrap_or_else(|| panic!("unknown feature {}", name)))
+            .collect()
+    }
+
+    /// The feature bits of this model in a feature word.
+    pub fn bitmap(&self, word: FeatureWord) -> u32 {
+        self.feature_list()
+            .iter()
+            .filter(|f| f.word == word)
+            .fold(0, |bits, f| bits | 1 << f.bit)
+    }
+
+    /// Lists the features of this model which are not in the table
+    /// of supported leaves.
+    pub fn missing_features(&self, supported: &Cpuid) -> Vec<Feature> {
+        self.feature_list()
+            .into_iter()
+            .filter(|f| !f.is_supported(supported))
+            .collect()
+    }
+
+    /// Builds the table of leaves for this model, from the table
+    /// of supported leaves.
+    ///
+    /// Features which are not part of the model are hidden, and the
+    /// vendor, brand, family, model and stepping are changed. Fails with
+    /// the list of missing features if the host does not support the model.
+    pub fn cpuid(&self, supported: &Cpuid) -> Result<Cpuid, Vec<Feature>> {
+        let missing = self.missing_features(supported);
+
+        if !missing.is_empty() {
+            return Err(missing);
+        }
+
+        let mut cpuid = supported.clone();
+
+        for &word in &FeatureWord::ALL {
+            let (function, index, register) = word.location();
+            let mut bits = self.bitmap(word);
+
+            // These bits do not describe the model, but the virtual machine.
+            match word {
+                FeatureWord::Leaf1Ecx => bits |= 1 << 31,
+                FeatureWord::Leaf1Edx => bits |= 1 << 28,
+                _ => (),
+            }
+
+            if let Some(entry) = cpuid.get_mut(function, index) {
+                entry.r[register as usize] &= bits;
+            }
+        }
+
+        self.limit_xsave(&mut cpuid);
+
+        cpuid.set_vendor(self.vendor);
+        cpuid.set_brand(self.brand);
+
+        if let Some(entry) = cpuid.get_mut(1, 0) {
+            entry.r[0] = self.signature();
+        }
+
+        Ok(cpuid)
+    }
+
+    /// Encodes the family, model and stepping, as reported in EAX of leaf 1.
+    pub fn signature(&self) -> u32 {
+        Signature {
+            family: self.family,
+            model: self.model,
+            stepping: self.stepping,
+            processor_type: 0,
+        }
+        .encode()
+    }
+
+    /// Hides the `XSAVE` state components of features which are not part of the model.
+    fn limit_xsave(&self, cpuid: &mut Cpuid) {
+        let has = |name| self.feature_list().iter().any(|f| f.name == name);
+
+        if !has("xsave") {
+            cpuid.remove(0xD, None);
+            return;
+        }
+
+        // x87 and SSE state.
+        let mut components = 0b11;
+
+        if has("avx") {
+            components |= 1 << 2;
+        }
+
+        if has("avx512f") {
+            components |= 0b111 << 5;
+        }
+
+        if has("pku") {
+            components |= 1 << 9;
+        }
+
+        if let Some(entry) = cpuid.get_mut(0xD, 0) {
+            entry.r[Register::Eax as usize] &= components;
+            entry.r[Register::Edx as usize] = 0;
+        }
+
+        let hidden: Vec<u32> = cpuid
+            .entries()
+            .iter()
+            .filter_map(|e| e.index.filter(|&index| e.function == 0xD && (2..32).contains(&index)))
+            .filter(|&index| components & 1 << index == 0)
+            .collect();
+
+        for index in hidden {
+            cpuid.remove(0xD, Some(index));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpuid::CpuidEntry;
+
+    #[test]
+    fn models_and_missing_features() {
+        for model in MODELS {
+            // Every feature name must be known.
+            assert!(!model.feature_list().is_empty());
+        }
+
+        let haswell = CpuModel::find("Haswell").unwrap();
+        assert_eq!(haswell.signature(), 0x306C4);
+        assert_eq!(CpuModel::find("EPYC").unwrap().signature(), 0x800F12);
+        assert_eq!(haswell.bitmap(FeatureWord::Leaf7Ebx), 0xFB9);
+
+        // A host with all of the Haswell features, except for TSX, and with AVX-512.
+        let mut host = Cpuid::new(vec![
+            CpuidEntry::new(0, None, [0xD, 0, 0, 0]),
+            CpuidEntry::new(1, None, [0x50654, 0, 0xFFFF_FFFF, 0xFFFF_FFFF]),
+            CpuidEntry::new(7, Some(0), [0, !(1 << 4 | 1 << 11), 0, 0]),
+            CpuidEntry::new(0xD, Some(0), [0x2E7, 0x2C0, 0xA88, 0]),
+            CpuidEntry::new(0xD, Some(1), [0xF, 0, 0, 0]),
+            CpuidEntry::new(0xD, Some(2), [0x100, 0x240, 0, 0]),
+            CpuidEntry::new(0xD, Some(5), [0x40, 0x440, 0, 0]),
+            CpuidEntry::new(0x8000_0000, None, [0x8000_0008, 0, 0, 0]),
+            CpuidEntry::new(0x8000_0001, None, [0, 0, 0xFFFF_FFFF, 0xFFFF_FFFF]),
+        ]);
+
+        let missing: Vec<_> = haswell.missing_features(&host).iter().map(|f| f.name).collect();
+        assert_eq!(missing, ["hle", "rtm"]);
+
+        let error = haswell.cpuid(&host).unwrap_err();
+        assert_eq!(error[0].to_string(), "hle (leaf 0x7.0 Ebx bit 4)");
+
+        host.set_bits(7, Some(0), Register::Ebx, 1 << 4 | 1 << 11);
+        let cpuid = haswell.cpuid(&host).unwrap();
+
+        assert_eq!(cpuid.vendor().unwrap(), "GenuineIntel");
+        assert_eq!(cpuid.brand().unwrap(), "Intel Core Processor (Haswell)");
+        assert_eq!(cpuid.get(1, 0).unwrap().r[0], 0x306C4);
+        assert_eq!(FeatureWord::Leaf7Ebx.read(&cpuid), 0xFB9);
+        assert!(!Feature::find("avx512f").unwrap().is_supported(&cpuid));
+
+        // Only the AVX state remains.
+        assert_eq!(cpuid.get(0xD, 0).unwrap().r[0], 0x7);
+        assert_eq!(FeatureWord::LeafDEax.read(&cpuid), 0x1);
+        assert!(cpuid.get(0xD, 2).is_some());
+        assert!(cpuid.get(0xD, 5).is_none());
+    }
+}
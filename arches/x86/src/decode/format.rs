@@ -0,0 +1,199 @@
+//! Formatting of instructions using Intel syntax.
+
+use super::{mask, Instruction, Memory, Mnemonic, Operand, Prefixes, Register, SegmentRegister};
+use std::fmt;
+
+/// Formats an instruction as text.
+///
+/// If the address of the instruction is known, branch targets are printed as
+/// absolute addresses. Otherwise they are printed relative to the start of
+/// the instruction, as in `jmp $+0x10`.
+pub struct Disassembly<'a> {
+    insn: &'a Instruction,
+    ip: Option<u64>,
+}
+
+impl<'a> Disassembly<'a> {
+    /// Creates a formatter for an instruction located at `ip`, if known.
+    pub fn new(insn: &'a Instruction, ip: Option<u64>) -> Self {
+        Disassembly { insn, ip }
+    }
+}
+
+impl<'a> fmt::Display for Disassembly<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let insn = self.insn;
+
+        if insn.prefixes.contains(Prefixes::LOCK) {
+            f.write_str("lock ")?;
+        }
+
+        let compare = insn.mnemonic == Mnemonic::Cmps || insn.mnemonic == Mnemonic::Scas;
+
+        if insn.prefixes.contains(Prefixes::REP) {
+            f.write_str(if compare { "repe " } else { "rep " })?;
+        } else if insn.prefixes.contains(Prefixes::REPNE) {
+            f.write_str("repne ")?;
+        }
+
+        // The instructions which only exist with a VEX prefix already have the
+        // `v` in their name.
+        if insn.vex.is_some() && !mnemonic_name(insn).starts_with('v') {
+            f.write_str("v")?;
+        }
+
+        f.write_str(mnemonic_name(insn))?;
+
+        for (i, operand) in insn.operands.iter().enumerate() {
+            f.write_str(if i == 0 { " " } else { ", " })?;
+
+            match *operand {
+                Operand::Register(register) => write!(f, "{}", register)?,
+                Operand::Memory(ref memory) => {
+                    // Only show the segment if it was explicitly overridden.
+                    let show_segment = insn.segment == Some(memory.segment);
+                    format_memory(f, memory, show_segment, insn.address_size)?;
+                }
+                Operand::Immediate { value, .. } => write!(f, "{:#x}", value)?,
+                Operand::Relative(offset) => match self.ip {
+                    Some(ip) => write!(f, "{:#x}", insn.branch_target(ip).unwrap_or(0))?,
+                    None => {
+                        let offset = offset + insn.len as i64;
+                        if offset < 0 {
+                            write!(f, "$-{:#x}", -offset)?;
+                        } else {
+                            write!(f, "$+{:#x}", offset)?;
+                        }
+                    }
+                },
+                Operand::FarPointer { selector, offset } => write!(f, "{:#x}:{:#x}", selector, offset)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Disassembly::new(self, None).fmt(f)
+    }
+}
+
+/// Returns the name of an instruction's mnemonic, taking into account
+/// mnemonics which change with the operand or address size.
+fn mnemonic_name(insn: &Instruction) -> &'static str {
+    let by_size = |names: [&'static str; 3], size: u8| match size {
+        2 => names[0],
+        4 => names[1],
+        _ => names[2],
+    };
+
+    let size = insn.operand_size;
+
+    match insn.mnemonic {
+        Mnemonic::Cbw => by_size(["cbw", "cwde", "cdqe"], size),
+        Mnemonic::Cwd => by_size(["cwd", "cdq", "cqo"], size),
+        Mnemonic::Iret => by_size(["iret", "iretd", "iretq"], size),
+        Mnemonic::Pusha => by_size(["pusha", "pushad", "pushad"], size),
+        Mnemonic::Popa => by_size(["popa", "popad", "popad"], size),
+        Mnemonic::Pushf => by_size(["pushf", "pushfd", "pushfq"], size),
+        Mnemonic::Popf => by_size(["popf", "popfd", "popfq"], size),
+        Mnemonic::Jcxz => by_size(["jcxz", "jecxz", "jrcxz"], insn.address_size),
+        mnemonic => mnemonic.name(),
+    }
+}
+
+fn format_memory(f: &mut fmt::Formatter, memory: &Memory, show_segment: bool, address_size: u8) -> fmt::Result {
+    let size = match memory.size {
+        1 => "byte ptr ",
+        2 => "word ptr ",
+        4 => "dword ptr ",
+        6 => "fword ptr ",
+        8 => "qword ptr ",
+        10 => "tbyte ptr ",
+        16 => "xmmword ptr ",
+        32 => "ymmword ptr ",
+        _ => "",
+    };
+
+    f.write_str(size)?;
+
+    if show_segment {
+        write!(f, "{}:", Register::Segment(memory.segment))?;
+    }
+
+    f.write_str("[")?;
+
+    let mut empty = true;
+
+    if let Some(base) = memory.base {
+        write!(f, "{}", base)?;
+        empty = false;
+    }
+
+    if let Some(index) = memory.index {
+        if !empty {
+            f.write_str("+")?;
+        }
+
+        write!(f, "{}", index)?;
+
+        if memory.scale != 1 {
+            write!(f, "*{}", memory.scale)?;
+        }
+
+        empty = false;
+    }
+
+    let displacement = memory.displacement;
+
+    if empty {
+        write!(f, "{:#x}", displacement as u64 & mask(address_size))?;
+    } else if displacement < 0 {
+        write!(f, "-{:#x}", -displacement)?;
+    } else if displacement > 0 {
+        write!(f, "+{:#x}", displacement)?;
+    }
+
+    f.write_str("]")
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const GPR: [&str; 8] = ["ax", "cx", "dx", "bx", "sp", "bp", "si", "di"];
+
+        match *self {
+            Register::Gpr8(index) if index < 4 => write!(f, "{}l", &GPR[index as usize][..1]),
+            Register::Gpr8(index) if index < 8 => write!(f, "{}l", GPR[index as usize]),
+            Register::Gpr8(index) => write!(f, "r{}b", index),
+            Register::Gpr8High(index) => write!(f, "{}h", &GPR[index as usize][..1]),
+            Register::Gpr16(index) if index < 8 => f.write_str(GPR[index as usize]),
+            Register::Gpr16(index) => write!(f, "r{}w", index),
+            Register::Gpr32(index) if index < 8 => write!(f, "e{}", GPR[index as usize]),
+            Register::Gpr32(index) => write!(f, "r{}d", index),
+            Register::Gpr64(index) if index < 8 => write!(f, "r{}", GPR[index as usize]),
+            Register::Gpr64(index) => write!(f, "r{}", index),
+            Register::Segment(segment) => {
+                let name = match segment {
+                    SegmentRegister::Es => "es",
+                    SegmentRegister::Cs => "cs",
+                    SegmentRegister::Ss => "ss",
+                    SegmentRegister::Ds => "ds",
+                    SegmentRegister::Fs => "fs",
+                    SegmentRegister::Gs => "gs",
+                };
+                f.write_str(name)
+            }
+            Register::Control(index) => write!(f, "cr{}", index),
+            Register::Debug(index) => write!(f, "dr{}", index),
+            Register::Mmx(index) => write!(f, "mm{}", index),
+            Register::Xmm(index) => write!(f, "xmm{}", index),
+            Register::Ymm(index) => write!(f, "ymm{}", index),
+            Register::St(index) => write!(f, "st({})", index),
+            Register::Ip(2) => f.write_str("ip"),
+            Register::Ip(4) => f.write_str("eip"),
+            Register::Ip(_) => f.write_str("rip"),
+        }
+    }
+}
@@ -0,0 +1,434 @@
+//! Instruction mnemonics.
+
+macro_rules! mnemonics {
+    ($($variant:ident => $name:expr,)*) => {
+        /// The operation performed by an instruction.
+        ///
+        /// Instructions which have different names depending on their operand size
+        /// (such as `cbw` / `cwde` / `cdqe`) use the name of the 16-bit form.
+        #[allow(missing_docs)]
+        #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+        pub enum Mnemonic {
+            $($variant,)*
+        }
+
+        impl Mnemonic {
+            /// Returns the lowercase name of this mnemonic.
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(Mnemonic::$variant => $name,)*
+                }
+            }
+        }
+    };
+}
+
+mnemonics! {
+    Add => "add",
+    Push => "push",
+    Pop => "pop",
+    Or => "or",
+    Adc => "adc",
+    Sbb => "sbb",
+    And => "and",
+    Daa => "daa",
+    Sub => "sub",
+    Das => "das",
+    Xor => "xor",
+    Aaa => "aaa",
+    Cmp => "cmp",
+    Aas => "aas",
+    Inc => "inc",
+    Dec => "dec",
+    Pusha => "pusha",
+    Popa => "popa",
+    Bound => "bound",
+    Arpl => "arpl",
+    Movsxd => "movsxd",
+    Imul => "imul",
+    Ins => "ins",
+    Outs => "outs",
+    Jo => "jo",
+    Jno => "jno",
+    Jb => "jb",
+    Jae => "jae",
+    Je => "je",
+    Jne => "jne",
+    Jbe => "jbe",
+    Ja => "ja",
+    Js => "js",
+    Jns => "jns",
+    Jp => "jp",
+    Jnp => "jnp",
+    Jl => "jl",
+    Jge => "jge",
+    Jle => "jle",
+    Jg => "jg",
+    Test => "test",
+    Xchg => "xchg",
+    Mov => "mov",
+    Lea => "lea",
+    Nop => "nop",
+    Pause => "pause",
+    Cbw => "cbw",
+    Cwd => "cwd",
+    Callf => "call far",
+    Fwait => "fwait",
+    Pushf => "pushf",
+    Popf => "popf",
+    Sahf => "sahf",
+    Lahf => "lahf",
+    Movs => "movs",
+    Cmps => "cmps",
+    Stos => "stos",
+    Lods => "lods",
+    Scas => "scas",
+    Ret => "ret",
+    Les => "les",
+    Lds => "lds",
+    Enter => "enter",
+    Leave => "leave",
+    Retf => "retf",
+    Int3 => "int3",
+    Int => "int",
+    Into => "into",
+    Iret => "iret",
+    Aam => "aam",
+    Aad => "aad",
+    Xlat => "xlat",
+    Loopne => "loopne",
+    Loope => "loope",
+    Loop => "loop",
+    Jcxz => "jcxz",
+    In => "in",
+    Out => "out",
+    Call => "call",
+    Jmp => "jmp",
+    Jmpf => "jmp far",
+    Int1 => "int1",
+    Hlt => "hlt",
+    Cmc => "cmc",
+    Clc => "clc",
+    Stc => "stc",
+    Cli => "cli",
+    Sti => "sti",
+    Cld => "cld",
+    Std => "std",
+    Rol => "rol",
+    Ror => "ror",
+    Rcl => "rcl",
+    Rcr => "rcr",
+    Shl => "shl",
+    Shr => "shr",
+    Sar => "sar",
+    Not => "not",
+    Neg => "neg",
+    Mul => "mul",
+    Div => "div",
+    Idiv => "idiv",
+    Lar => "lar",
+    Lsl => "lsl",
+    Syscall => "syscall",
+    Clts => "clts",
+    Sysret => "sysret",
+    Invd => "invd",
+    Wbinvd => "wbinvd",
+    Ud2 => "ud2",
+    Movups => "movups",
+    Movupd => "movupd",
+    Movss => "movss",
+    Movsd => "movsd",
+    Movaps => "movaps",
+    Movapd => "movapd",
+    Ucomiss => "ucomiss",
+    Ucomisd => "ucomisd",
+    Comiss => "comiss",
+    Comisd => "comisd",
+    Wrmsr => "wrmsr",
+    Rdtsc => "rdtsc",
+    Rdmsr => "rdmsr",
+    Rdpmc => "rdpmc",
+    Sysenter => "sysenter",
+    Sysexit => "sysexit",
+    Cmovo => "cmovo",
+    Cmovno => "cmovno",
+    Cmovb => "cmovb",
+    Cmovae => "cmovae",
+    Cmove => "cmove",
+    Cmovne => "cmovne",
+    Cmovbe => "cmovbe",
+    Cmova => "cmova",
+    Cmovs => "cmovs",
+    Cmovns => "cmovns",
+    Cmovp => "cmovp",
+    Cmovnp => "cmovnp",
+    Cmovl => "cmovl",
+    Cmovge => "cmovge",
+    Cmovle => "cmovle",
+    Cmovg => "cmovg",
+    Sqrtps => "sqrtps",
+    Sqrtpd => "sqrtpd",
+    Sqrtss => "sqrtss",
+    Sqrtsd => "sqrtsd",
+    Andps => "andps",
+    Andpd => "andpd",
+    Andnps => "andnps",
+    Andnpd => "andnpd",
+    Orps => "orps",
+    Orpd => "orpd",
+    Xorps => "xorps",
+    Xorpd => "xorpd",
+    Addps => "addps",
+    Addpd => "addpd",
+    Addss => "addss",
+    Addsd => "addsd",
+    Mulps => "mulps",
+    Mulpd => "mulpd",
+    Mulss => "mulss",
+    Mulsd => "mulsd",
+    Subps => "subps",
+    Subpd => "subpd",
+    Subss => "subss",
+    Subsd => "subsd",
+    Divps => "divps",
+    Divpd => "divpd",
+    Divss => "divss",
+    Divsd => "divsd",
+    Movd => "movd",
+    Movq => "movq",
+    Movdqa => "movdqa",
+    Movdqu => "movdqu",
+    Emms => "emms",
+    Seto => "seto",
+    Setno => "setno",
+    Setb => "setb",
+    Setae => "setae",
+    Sete => "sete",
+    Setne => "setne",
+    Setbe => "setbe",
+    Seta => "seta",
+    Sets => "sets",
+    Setns => "setns",
+    Setp => "setp",
+    Setnp => "setnp",
+    Setl => "setl",
+    Setge => "setge",
+    Setle => "setle",
+    Setg => "setg",
+    Cpuid => "cpuid",
+    Bt => "bt",
+    Shld => "shld",
+    Rsm => "rsm",
+    Bts => "bts",
+    Shrd => "shrd",
+    Cmpxchg => "cmpxchg",
+    Lss => "lss",
+    Btr => "btr",
+    Lfs => "lfs",
+    Lgs => "lgs",
+    Movzx => "movzx",
+    Popcnt => "popcnt",
+    Ud1 => "ud1",
+    Btc => "btc",
+    Bsf => "bsf",
+    Tzcnt => "tzcnt",
+    Bsr => "bsr",
+    Lzcnt => "lzcnt",
+    Movsx => "movsx",
+    Xadd => "xadd",
+    Bswap => "bswap",
+    Pxor => "pxor",
+    Ud0 => "ud0",
+    Sldt => "sldt",
+    Str => "str",
+    Lldt => "lldt",
+    Ltr => "ltr",
+    Verr => "verr",
+    Verw => "verw",
+    Sgdt => "sgdt",
+    Sidt => "sidt",
+    Lgdt => "lgdt",
+    Lidt => "lidt",
+    Smsw => "smsw",
+    Lmsw => "lmsw",
+    Invlpg => "invlpg",
+    Vmcall => "vmcall",
+    Vmlaunch => "vmlaunch",
+    Vmresume => "vmresume",
+    Vmxoff => "vmxoff",
+    Monitor => "monitor",
+    Mwait => "mwait",
+    Clac => "clac",
+    Stac => "stac",
+    Xgetbv => "xgetbv",
+    Xsetbv => "xsetbv",
+    Swapgs => "swapgs",
+    Rdtscp => "rdtscp",
+    Endbr64 => "endbr64",
+    Endbr32 => "endbr32",
+    Prefetch => "prefetch",
+    Prefetchw => "prefetchw",
+    Cmpxchg8b => "cmpxchg8b",
+    Rdrand => "rdrand",
+    Rdseed => "rdseed",
+    Fxsave => "fxsave",
+    Fxrstor => "fxrstor",
+    Ldmxcsr => "ldmxcsr",
+    Stmxcsr => "stmxcsr",
+    Xsave => "xsave",
+    Xrstor => "xrstor",
+    Xsaveopt => "xsaveopt",
+    Clflush => "clflush",
+    Lfence => "lfence",
+    Mfence => "mfence",
+    Sfence => "sfence",
+    Prefetchnta => "prefetchnta",
+    Prefetcht0 => "prefetcht0",
+    Prefetcht1 => "prefetcht1",
+    Prefetcht2 => "prefetcht2",
+    Pshufb => "pshufb",
+    Movbe => "movbe",
+    Palignr => "palignr",
+    Fadd => "fadd",
+    Fmul => "fmul",
+    Fcom => "fcom",
+    Fcomp => "fcomp",
+    Fsub => "fsub",
+    Fsubr => "fsubr",
+    Fdiv => "fdiv",
+    Fdivr => "fdivr",
+    Fld => "fld",
+    Fst => "fst",
+    Fstp => "fstp",
+    Fldenv => "fldenv",
+    Fldcw => "fldcw",
+    Fnstenv => "fnstenv",
+    Fnstcw => "fnstcw",
+    Fiadd => "fiadd",
+    Fimul => "fimul",
+    Ficom => "ficom",
+    Ficomp => "ficomp",
+    Fisub => "fisub",
+    Fisubr => "fisubr",
+    Fidiv => "fidiv",
+    Fidivr => "fidivr",
+    Fild => "fild",
+    Fisttp => "fisttp",
+    Fist => "fist",
+    Fistp => "fistp",
+    Frstor => "frstor",
+    Fnsave => "fnsave",
+    Fnstsw => "fnstsw",
+    Fbld => "fbld",
+    Fbstp => "fbstp",
+    Fxch => "fxch",
+    Fcmovb => "fcmovb",
+    Fcmove => "fcmove",
+    Fcmovbe => "fcmovbe",
+    Fcmovu => "fcmovu",
+    Fcmovnb => "fcmovnb",
+    Fcmovne => "fcmovne",
+    Fcmovnbe => "fcmovnbe",
+    Fcmovnu => "fcmovnu",
+    Fucomi => "fucomi",
+    Fcomi => "fcomi",
+    Ffree => "ffree",
+    Fucom => "fucom",
+    Fucomp => "fucomp",
+    Faddp => "faddp",
+    Fmulp => "fmulp",
+    Fsubrp => "fsubrp",
+    Fsubp => "fsubp",
+    Fdivrp => "fdivrp",
+    Fdivp => "fdivp",
+    Fucomip => "fucomip",
+    Fcomip => "fcomip",
+    Fnop => "fnop",
+    Fchs => "fchs",
+    Fabs => "fabs",
+    Ftst => "ftst",
+    Fxam => "fxam",
+    Fld1 => "fld1",
+    Fldl2t => "fldl2t",
+    Fldl2e => "fldl2e",
+    Fldpi => "fldpi",
+    Fldlg2 => "fldlg2",
+    Fldln2 => "fldln2",
+    Fldz => "fldz",
+    F2xm1 => "f2xm1",
+    Fyl2x => "fyl2x",
+    Fptan => "fptan",
+    Fpatan => "fpatan",
+    Fxtract => "fxtract",
+    Fprem1 => "fprem1",
+    Fdecstp => "fdecstp",
+    Fincstp => "fincstp",
+    Fprem => "fprem",
+    Fyl2xp1 => "fyl2xp1",
+    Fsqrt => "fsqrt",
+    Fsincos => "fsincos",
+    Frndint => "frndint",
+    Fscale => "fscale",
+    Fsin => "fsin",
+    Fcos => "fcos",
+    Fucompp => "fucompp",
+    Fnclex => "fnclex",
+    Fninit => "fninit",
+    Fcompp => "fcompp",
+    Cmpxchg16b => "cmpxchg16b",
+    Vzeroupper => "vzeroupper",
+    Vzeroall => "vzeroall",
+    Vpermilps => "vpermilps",
+    Vpermilpd => "vpermilpd",
+    Vpermps => "vpermps",
+    Vbroadcastss => "vbroadcastss",
+    Vbroadcastsd => "vbroadcastsd",
+    Vpermd => "vpermd",
+    Vpsrlvd => "vpsrlvd",
+    Vpsrlvq => "vpsrlvq",
+    Vpsravd => "vpsravd",
+    Vpsllvd => "vpsllvd",
+    Vpsllvq => "vpsllvq",
+    Vpbroadcastd => "vpbroadcastd",
+    Vpbroadcastq => "vpbroadcastq",
+    Vpermq => "vpermq",
+    Vpermpd => "vpermpd",
+    Vpblendd => "vpblendd",
+    Vperm2f128 => "vperm2f128",
+    Vinsertf128 => "vinsertf128",
+    Vextractf128 => "vextractf128",
+    Vinserti128 => "vinserti128",
+    Vextracti128 => "vextracti128",
+    Vperm2i128 => "vperm2i128",
+}
+
+impl Mnemonic {
+    /// Returns the condition code tested by a conditional jump, move or set instruction.
+    ///
+    /// The condition codes use the same encoding as the low 4 bits of the opcodes.
+    pub fn condition(self) -> Option<u8> {
+        use self::Mnemonic::*;
+
+        let cc = match self {
+            Jo | Cmovo | Seto => 0x0,
+            Jno | Cmovno | Setno => 0x1,
+            Jb | Cmovb | Setb => 0x2,
+            Jae | Cmovae | Setae => 0x3,
+            Je | Cmove | Sete => 0x4,
+            Jne | Cmovne | Setne => 0x5,
+            Jbe | Cmovbe | Setbe => 0x6,
+            Ja | Cmova | Seta => 0x7,
+            Js | Cmovs | Sets => 0x8,
+            Jns | Cmovns | Setns => 0x9,
+            Jp | Cmovp | Setp => 0xA,
+            Jnp | Cmovnp | Setnp => 0xB,
+            Jl | Cmovl | Setl => 0xC,
+            Jge | Cmovge | Setge => 0xD,
+            Jle | Cmovle | Setle => 0xE,
+            Jg | Cmovg | Setg => 0xF,
+            _ => return None,
+        };
+
+        Some(cc)
+    }
+}
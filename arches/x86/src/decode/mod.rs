@@ -0,0 +1,1110 @@
+//! Decoder for x86 machine code.
+//!
+//! Instructions are decoded into an `Instruction`, which describes the prefixes,
+//! the opcode and the operands of the instruction. An instruction can be
+//! formatted as text using Intel syntax.
+//!
+//! The opcode maps only cover the instructions needed to emulate guest code,
+//! so most SSE and AVX instructions are not decoded. In particular, the
+//! three-byte maps (`0F 38` and `0F 3A`) contain `PSHUFB`, `MOVBE`, `PALIGNR`
+//! and the AVX and AVX2 permutes, broadcasts, variable shifts and 128-bit
+//! lane inserts and extracts, but none of the SSE4 instructions.
+
+mod format;
+mod mnemonic;
+mod table;
+
+pub use self::format::Disassembly;
+pub use self::mnemonic::Mnemonic;
+
+use self::table::{Attr, Entry, Spec};
+use state::{Efer, State};
+use std::{error, fmt};
+
+/// Maximum length of an instruction, in bytes.
+pub const MAX_LENGTH: usize = 15;
+
+/// The operating mode of the processor, which determines the default operand
+/// and address sizes.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Mode {
+    /// Real mode, virtual 8086 mode or 16-bit protected mode.
+    Bits16,
+    /// 32-bit protected mode or compatibility mode.
+    Bits32,
+    /// 64-bit mode.
+    Bits64,
+}
+
+impl Mode {
+    /// Determines the mode from the current code segment.
+    pub fn from_state(state: &State) -> Self {
+        let cs = &state.cs;
+
+        if state.efer.contains(Efer::LM_ACTIVE) && cs.long {
+            Mode::Bits64
+        } else if cs.op_size {
+            Mode::Bits32
+        } else {
+            Mode::Bits16
+        }
+    }
+}
+
+bitflags! {
+    /// Legacy prefixes which apply to an instruction.
+    ///
+    /// Prefixes which were used to select the instruction (mandatory prefixes)
+    /// are not included.
+    pub struct Prefixes: u8 {
+        /// The `LOCK` prefix (`F0`).
+        const LOCK = 1 << 0;
+        /// The `REP` / `REPE` prefix (`F3`).
+        const REP = 1 << 1;
+        /// The `REPNE` prefix (`F2`).
+        const REPNE = 1 << 2;
+        /// The operand-size override prefix (`66`).
+        const OPERAND_SIZE = 1 << 3;
+        /// The address-size override prefix (`67`).
+        const ADDRESS_SIZE = 1 << 4;
+    }
+}
+
+/// A REX prefix, which extends register fields in 64-bit mode.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Rex(pub u8);
+
+impl Rex {
+    /// Selects a 64-bit operand size.
+    pub fn w(self) -> bool {
+        self.0 & 0b1000 != 0
+    }
+
+    /// Extends the ModRM reg field.
+    pub fn r(self) -> bool {
+        self.0 & 0b0100 != 0
+    }
+
+    /// Extends the SIB index field.
+    pub fn x(self) -> bool {
+        self.0 & 0b0010 != 0
+    }
+
+    /// Extends the ModRM r/m field, the SIB base field, or the opcode register field.
+    pub fn b(self) -> bool {
+        self.0 & 0b0001 != 0
+    }
+}
+
+/// A VEX prefix, with the inverted fields already decoded.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Vex {
+    /// Extends the ModRM reg field.
+    pub r: bool,
+    /// Extends the SIB index field.
+    pub x: bool,
+    /// Extends the ModRM r/m or SIB base field.
+    pub b: bool,
+    /// Opcode-specific, usually selects a 64-bit operand size.
+    pub w: bool,
+    /// The opcode map of the instruction.
+    pub map: OpcodeMap,
+    /// An additional source register.
+    pub vvvv: u8,
+    /// Selects 256-bit vectors.
+    pub l: bool,
+    /// The implied mandatory prefix: none, `66`, `F3` or `F2`.
+    pub pp: u8,
+}
+
+/// The opcode map an instruction's opcode belongs to.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum OpcodeMap {
+    /// One-byte opcodes.
+    Primary,
+    /// Two-byte opcodes, starting with `0F`.
+    Map0F,
+    /// Three-byte opcodes, starting with `0F 38`.
+    Map0F38,
+    /// Three-byte opcodes, starting with `0F 3A`.
+    Map0F3A,
+}
+
+/// The fields of a ModRM byte.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ModRM {
+    /// Addressing mode. 3 means the r/m field is a register.
+    pub mode: u8,
+    /// Register operand, or opcode extension.
+    pub reg: u8,
+    /// Register or memory operand.
+    pub rm: u8,
+}
+
+impl ModRM {
+    fn new(byte: u8) -> Self {
+        ModRM {
+            mode: byte >> 6,
+            reg: (byte >> 3) & 0b111,
+            rm: byte & 0b111,
+        }
+    }
+}
+
+/// The fields of a SIB byte.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Sib {
+    /// The index is multiplied by `1 << scale`.
+    pub scale: u8,
+    /// The index register.
+    pub index: u8,
+    /// The base register.
+    pub base: u8,
+}
+
+impl Sib {
+    fn new(byte: u8) -> Self {
+        Sib {
+            scale: byte >> 6,
+            index: (byte >> 3) & 0b111,
+            base: byte & 0b111,
+        }
+    }
+}
+
+/// Segment registers, in the order used by instruction encodings.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SegmentRegister {
+    /// Extra segment.
+    Es,
+    /// Code segment.
+    Cs,
+    /// Stack segment.
+    Ss,
+    /// Data segment.
+    Ds,
+    /// Extra segment #2.
+    Fs,
+    /// Extra segment #3.
+    Gs,
+}
+
+impl SegmentRegister {
+    /// Returns the segment register with a given encoding.
+    pub fn from_index(index: u8) -> Option<Self> {
+        use self::SegmentRegister::*;
+
+        match index {
+            0 => Some(Es),
+            1 => Some(Cs),
+            2 => Some(Ss),
+            3 => Some(Ds),
+            4 => Some(Fs),
+            5 => Some(Gs),
+            _ => None,
+        }
+    }
+}
+
+/// A register used by an instruction.
+///
+/// General-purpose registers are numbered in encoding order, which is the
+/// same order as `State.r`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Register {
+    /// The low byte of a general-purpose register.
+    Gpr8(u8),
+    /// The second byte of the first four general-purpose registers (`AH` to `BH`).
+    Gpr8High(u8),
+    /// The low word of a general-purpose register.
+    Gpr16(u8),
+    /// The low doubleword of a general-purpose register.
+    Gpr32(u8),
+    /// A 64-bit general-purpose register.
+    Gpr64(u8),
+    /// A segment register.
+    Segment(SegmentRegister),
+    /// A control register.
+    Control(u8),
+    /// A debug register.
+    Debug(u8),
+    /// An MMX register.
+    Mmx(u8),
+    /// A 128-bit vector register.
+    Xmm(u8),
+    /// A 256-bit vector register.
+    Ymm(u8),
+    /// An x87 register, relative to the top of the stack.
+    St(u8),
+    /// The instruction pointer, with a size in bytes. Used for RIP-relative addressing.
+    Ip(u8),
+}
+
+impl Register {
+    /// Returns the general-purpose register with a given index and size in bytes.
+    ///
+    /// Byte registers 4 to 7 are `SPL` to `DIL`, as if a REX prefix were present.
+    pub fn gpr(index: u8, size: u8) -> Self {
+        match size {
+            1 => Register::Gpr8(index),
+            2 => Register::Gpr16(index),
+            4 => Register::Gpr32(index),
+            8 => Register::Gpr64(index),
+            _ => panic!("invalid register size: {}", size),
+        }
+    }
+
+    /// Returns the size of this register, in bytes.
+    pub fn size(self) -> u8 {
+        match self {
+            Register::Gpr8(_) | Register::Gpr8High(_) => 1,
+            Register::Gpr16(_) | Register::Segment(_) => 2,
+            Register::Gpr32(_) => 4,
+            Register::Gpr64(_) | Register::Control(_) | Register::Debug(_) | Register::Mmx(_) => 8,
+            Register::Xmm(_) => 16,
+            Register::Ymm(_) => 32,
+            Register::St(_) => 10,
+            Register::Ip(size) => size,
+        }
+    }
+
+    /// Returns the byte register with a given index, taking into account
+    /// whether a REX prefix is present.
+    fn byte(index: u8, rex: bool) -> Self {
+        if !rex && (4..8).contains(&index) {
+            Register::Gpr8High(index - 4)
+        } else {
+            Register::Gpr8(index)
+        }
+    }
+}
+
+/// A memory operand.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Memory {
+    /// The segment used for the access, after applying any override prefix.
+    pub segment: SegmentRegister,
+    /// The base register.
+    pub base: Option<Register>,
+    /// The index register.
+    pub index: Option<Register>,
+    /// The index is multiplied by this value (1, 2, 4 or 8).
+    pub scale: u8,
+    /// A constant added to the address.
+    pub displacement: i64,
+    /// The size of the access in bytes, or 0 if the access has no fixed size.
+    pub size: u16,
+}
+
+/// An operand of an instruction.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Operand {
+    /// A register.
+    Register(Register),
+    /// A memory location.
+    Memory(Memory),
+    /// An immediate value, sign- or zero-extended to `size` bytes.
+    Immediate {
+        /// The value of the operand.
+        value: u64,
+        /// The size of the operand, in bytes.
+        size: u8,
+    },
+    /// A branch target, relative to the next instruction.
+    Relative(i64),
+    /// A far pointer immediate.
+    FarPointer {
+        /// The code segment selector.
+        selector: u16,
+        /// The offset in the segment.
+        offset: u32,
+    },
+}
+
+/// A decoded instruction.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Instruction {
+    /// The mode the instruction was decoded in.
+    pub mode: Mode,
+    /// Legacy prefixes.
+    pub prefixes: Prefixes,
+    /// Segment override prefix.
+    pub segment: Option<SegmentRegister>,
+    /// REX prefix, only used in 64-bit mode.
+    pub rex: Option<Rex>,
+    /// VEX prefix.
+    pub vex: Option<Vex>,
+    /// The opcode map of the opcode.
+    pub map: OpcodeMap,
+    /// The opcode byte.
+    pub opcode: u8,
+    /// The ModRM byte, if present.
+    pub modrm: Option<ModRM>,
+    /// The SIB byte, if present.
+    pub sib: Option<Sib>,
+    /// The operation performed by the instruction.
+    pub mnemonic: Mnemonic,
+    /// The operands, with the destination first.
+    pub operands: Vec<Operand>,
+    /// The operand size, in bytes.
+    pub operand_size: u8,
+    /// The address size, in bytes.
+    pub address_size: u8,
+    /// The length of the instruction, in bytes.
+    pub len: usize,
+}
+
+impl Instruction {
+    /// Returns the target of a relative branch, given the address of this instruction.
+    pub fn branch_target(&self, ip: u64) -> Option<u64> {
+        self.operands.iter().filter_map(|op| match *op {
+            Operand::Relative(offset) => {
+                let target = ip.wrapping_add(self.len as u64).wrapping_add(offset as u64);
+                Some(target & mask(self.operand_size))
+            }
+            _ => None,
+        }).next()
+    }
+
+    /// Formats the instruction, using absolute addresses for branch targets.
+    pub fn display_at(&self, ip: u64) -> Disassembly<'_> {
+        Disassembly::new(self, Some(ip))
+    }
+}
+
+/// Errors returned by the decoder.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DecodeError {
+    /// The instruction continues past the end of the buffer.
+    Truncated,
+    /// The bytes do not encode a valid instruction.
+    InvalidOpcode,
+    /// The instruction is longer than the maximum length.
+    TooLong,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match *self {
+            DecodeError::Truncated => "instruction is truncated",
+            DecodeError::InvalidOpcode => "invalid opcode",
+            DecodeError::TooLong => "instruction is too long",
+        };
+
+        f.write_str(msg)
+    }
+}
+
+impl error::Error for DecodeError {}
+
+/// Returns a mask covering a value of `size` bytes.
+fn mask(size: u8) -> u64 {
+    if size >= 8 {
+        !0
+    } else {
+        (1 << (size * 8)) - 1
+    }
+}
+
+/// Reads bytes from the instruction stream.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn peek(&self) -> Result<u8, DecodeError> {
+        if self.pos >= MAX_LENGTH {
+            return Err(DecodeError::TooLong);
+        }
+
+        self.bytes.get(self.pos).cloned().ok_or(DecodeError::Truncated)
+    }
+
+    fn byte(&mut self) -> Result<u8, DecodeError> {
+        let byte = self.peek()?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    /// Reads a little-endian value of `size` bytes.
+    fn value(&mut self, size: u8) -> Result<u64, DecodeError> {
+        let mut value = 0;
+
+        for i in 0..size {
+            value |= u64::from(self.byte()?) << (i * 8);
+        }
+
+        Ok(value)
+    }
+
+    /// Reads a little-endian value of `size` bytes, and sign-extends it.
+    fn signed(&mut self, size: u8) -> Result<i64, DecodeError> {
+        let shift = 64 - u32::from(size) * 8;
+        Ok(((self.value(size)? << shift) as i64) >> shift)
+    }
+}
+
+/// Decodes the instruction at the start of `bytes`.
+pub fn decode(bytes: &[u8], mode: Mode) -> Result<Instruction, DecodeError> {
+    let mut reader = Reader { bytes, pos: 0 };
+
+    let mut prefixes = Prefixes::empty();
+    let mut segment = None;
+    let mut rex = None;
+
+    let mut byte = loop {
+        let byte = reader.byte()?;
+
+        match byte {
+            0xF0 => prefixes.insert(Prefixes::LOCK),
+            0xF2 => {
+                prefixes.remove(Prefixes::REP);
+                prefixes.insert(Prefixes::REPNE);
+            }
+            0xF3 => {
+                prefixes.remove(Prefixes::REPNE);
+                prefixes.insert(Prefixes::REP);
+            }
+            0x26 | 0x2E | 0x36 | 0x3E => segment = SegmentRegister::from_index((byte >> 3) & 0b11),
+            0x64 => segment = Some(SegmentRegister::Fs),
+            0x65 => segment = Some(SegmentRegister::Gs),
+            0x66 => prefixes.insert(Prefixes::OPERAND_SIZE),
+            0x67 => prefixes.insert(Prefixes::ADDRESS_SIZE),
+            0x40..=0x4F if mode == Mode::Bits64 => {
+                rex = Some(Rex(byte));
+                continue;
+            }
+            _ => break byte,
+        }
+
+        // A REX prefix is ignored unless it comes right before the opcode.
+        rex = None;
+    };
+
+    let mut vex = None;
+
+    let map = if (byte == 0xC4 || byte == 0xC5) && (mode == Mode::Bits64 || reader.peek()? & 0xC0 == 0xC0) {
+        let conflicting = Prefixes::LOCK | Prefixes::REP | Prefixes::REPNE | Prefixes::OPERAND_SIZE;
+        if rex.is_some() || prefixes.intersects(conflicting) {
+            return Err(DecodeError::InvalidOpcode);
+        }
+
+        let first = reader.byte()?;
+
+        let mut prefix = if byte == 0xC5 {
+            Vex {
+                r: first & 0x80 == 0,
+                x: false,
+                b: false,
+                w: false,
+                map: OpcodeMap::Map0F,
+                vvvv: (!first >> 3) & 0xF,
+                l: first & 0b100 != 0,
+                pp: first & 0b11,
+            }
+        } else {
+            let second = reader.byte()?;
+
+            let map = match first & 0x1F {
+                1 => OpcodeMap::Map0F,
+                2 => OpcodeMap::Map0F38,
+                3 => OpcodeMap::Map0F3A,
+                _ => return Err(DecodeError::InvalidOpcode),
+            };
+
+            Vex {
+                r: first & 0x80 == 0,
+                x: first & 0x40 == 0,
+                b: first & 0x20 == 0,
+                w: second & 0x80 != 0,
+                map,
+                vvvv: (!second >> 3) & 0xF,
+                l: second & 0b100 != 0,
+                pp: second & 0b11,
+            }
+        };
+
+        // Only 8 registers can be encoded outside of 64-bit mode.
+        if mode != Mode::Bits64 {
+            prefix.r = false;
+            prefix.x = false;
+            prefix.b = false;
+            prefix.vvvv &= 0b111;
+        }
+
+        vex = Some(prefix);
+        byte = reader.byte()?;
+
+        prefix.map
+    } else if byte == 0x0F {
+        byte = reader.byte()?;
+
+        match byte {
+            0x38 => {
+                byte = reader.byte()?;
+                OpcodeMap::Map0F38
+            }
+            0x3A => {
+                byte = reader.byte()?;
+                OpcodeMap::Map0F3A
+            }
+            _ => OpcodeMap::Map0F,
+        }
+    } else {
+        OpcodeMap::Primary
+    };
+
+    let opcode = byte;
+
+    let rex_w = rex.is_some_and(Rex::w) || vex.is_some_and(|v| v.w);
+    let rex_r = rex.is_some_and(Rex::r) || vex.is_some_and(|v| v.r);
+    let rex_x = rex.is_some_and(Rex::x) || vex.is_some_and(|v| v.x);
+    let rex_b = rex.is_some_and(Rex::b) || vex.is_some_and(|v| v.b);
+
+    let mut entry = match map {
+        // With REX.B, `90` is an exchange with R8 instead of a NOP.
+        OpcodeMap::Primary if opcode == 0x90 && rex_b => &table::ONE_BYTE[0x91],
+        OpcodeMap::Primary => &table::ONE_BYTE[opcode as usize],
+        OpcodeMap::Map0F => &table::TWO_BYTE[opcode as usize],
+        OpcodeMap::Map0F38 => table::three_byte_38(opcode),
+        OpcodeMap::Map0F3A => table::three_byte_3a(opcode),
+    };
+
+    let mut modrm_byte = None;
+    let mut fetch_modrm = |reader: &mut Reader| -> Result<u8, DecodeError> {
+        if modrm_byte.is_none() {
+            modrm_byte = Some(reader.byte()?);
+        }
+        Ok(modrm_byte.unwrap())
+    };
+
+    // Whether the operand-size prefix was used to select the instruction.
+    let mut mandatory_66 = false;
+
+    let (mut mnemonic, specs, attr) = loop {
+        entry = match *entry {
+            Entry::Invalid => return Err(DecodeError::InvalidOpcode),
+            Entry::Insn(mnemonic, specs, attr) => break (mnemonic, specs, attr),
+            Entry::Group(table) => &table[ModRM::new(fetch_modrm(&mut reader)?).reg as usize],
+            Entry::Prefixed(table) => {
+                let index = match vex {
+                    Some(vex) => vex.pp,
+                    None if prefixes.contains(Prefixes::REP) => 2,
+                    None if prefixes.contains(Prefixes::REPNE) => 3,
+                    None if prefixes.contains(Prefixes::OPERAND_SIZE) => 1,
+                    None => 0,
+                };
+
+                match index {
+                    1 => mandatory_66 = true,
+                    2 => prefixes.remove(Prefixes::REP),
+                    3 => prefixes.remove(Prefixes::REPNE),
+                    _ => (),
+                }
+
+                &table[index as usize]
+            }
+            Entry::Mode(legacy, long) => if mode == Mode::Bits64 { long } else { legacy },
+            Entry::MemReg(mem, reg) => {
+                if ModRM::new(fetch_modrm(&mut reader)?).mode == 3 {
+                    reg
+                } else {
+                    mem
+                }
+            }
+            Entry::RegRm(table) => {
+                let modrm = ModRM::new(fetch_modrm(&mut reader)?);
+                &table[modrm.reg as usize][modrm.rm as usize]
+            }
+            Entry::X87 => {
+                let raw = fetch_modrm(&mut reader)?;
+                let modrm = ModRM::new(raw);
+                let index = (opcode - 0xD8) as usize;
+
+                if modrm.mode != 3 {
+                    &table::X87_MEM[index][modrm.reg as usize]
+                } else {
+                    match table::X87_REG[index][modrm.reg as usize] {
+                        Entry::Invalid => table::x87_reg_fixed(opcode, raw),
+                        ref entry => entry,
+                    }
+                }
+            }
+        };
+    };
+
+    if (attr.contains(Attr::I64) && mode == Mode::Bits64) || (attr.contains(Attr::O64) && mode != Mode::Bits64) {
+        return Err(DecodeError::InvalidOpcode);
+    }
+
+    let vex_only = attr.intersects(Attr::VEX_ONLY | Attr::VEX_256);
+
+    match vex {
+        Some(_) if !attr.contains(Attr::VEX) && !vex_only => return Err(DecodeError::InvalidOpcode),
+        Some(vex) if attr.contains(Attr::VEX_256) && !vex.l => return Err(DecodeError::InvalidOpcode),
+        None if vex_only => return Err(DecodeError::InvalidOpcode),
+        _ => {}
+    }
+
+    let operand_size_prefix = prefixes.contains(Prefixes::OPERAND_SIZE) && !mandatory_66;
+
+    let operand_size = match mode {
+        Mode::Bits64 if rex_w || attr.contains(Attr::F64) => 8,
+        Mode::Bits64 if operand_size_prefix => 2,
+        Mode::Bits64 if attr.contains(Attr::D64) => 8,
+        Mode::Bits64 | Mode::Bits32 => if operand_size_prefix { 2 } else { 4 },
+        Mode::Bits16 => if operand_size_prefix { 4 } else { 2 },
+    };
+
+    let address_size_prefix = prefixes.contains(Prefixes::ADDRESS_SIZE);
+
+    let address_size = match mode {
+        Mode::Bits64 => if address_size_prefix { 4 } else { 8 },
+        Mode::Bits32 => if address_size_prefix { 2 } else { 4 },
+        Mode::Bits16 => if address_size_prefix { 4 } else { 2 },
+    };
+
+    if specs.iter().any(|spec| spec.uses_modrm()) {
+        fetch_modrm(&mut reader)?;
+    }
+
+    let modrm = modrm_byte.map(ModRM::new);
+
+    // Decode the memory operand, if any, which comes before the immediates.
+    let mut sib = None;
+    let memory = match modrm {
+        Some(modrm) if modrm.mode != 3 && specs.iter().any(|spec| spec.may_be_memory()) => {
+            let (memory, s) = decode_memory(&mut reader, modrm, address_size, mode, segment, rex_x, rex_b)?;
+            sib = s;
+            Some(memory)
+        }
+        _ => None,
+    };
+
+    // Any REX prefix changes the encoding of byte registers.
+    let has_rex = rex.is_some();
+    let reg_index = modrm.map_or(0, |m| m.reg | (rex_r as u8) << 3);
+    let rm_index = modrm.map_or(0, |m| m.rm | (rex_b as u8) << 3);
+    let opcode_index = (opcode & 0b111) | (rex_b as u8) << 3;
+
+    let gpr = |index: u8, size: u8| {
+        if size == 1 {
+            Register::byte(index, has_rex)
+        } else {
+            Register::gpr(index, size)
+        }
+    };
+
+    let rm = |size: u16, register: Register| match memory {
+        Some(memory) => Operand::Memory(Memory { size, ..memory }),
+        None => Operand::Register(register),
+    };
+
+    let memory_only = |size: u16| -> Result<Operand, DecodeError> {
+        match memory {
+            Some(memory) => Ok(Operand::Memory(Memory { size, ..memory })),
+            None => Err(DecodeError::InvalidOpcode),
+        }
+    };
+
+    let vector = |index: u8| {
+        if vex.is_some_and(|v| v.l) {
+            Register::Ymm(index)
+        } else {
+            Register::Xmm(index)
+        }
+    };
+
+    let vector_size = if vex.is_some_and(|v| v.l) { 32 } else { 16 };
+    let y_size = if mode == Mode::Bits64 && rex_w { 8 } else { 4 };
+    let z_size = if operand_size == 2 { 2 } else { 4 };
+
+    let string = |segment: SegmentRegister, index: u8, size: u8| {
+        Operand::Memory(Memory {
+            segment,
+            base: Some(Register::gpr(index, address_size)),
+            index: None,
+            scale: 1,
+            displacement: 0,
+            size: u16::from(size),
+        })
+    };
+
+    let mut operands = Vec::with_capacity(specs.len());
+
+    for &spec in specs {
+        let operand = match spec {
+            Spec::Eb => rm(1, gpr(rm_index, 1)),
+            Spec::Ew => rm(2, gpr(rm_index, 2)),
+            Spec::Ed => rm(4, gpr(rm_index, 4)),
+            Spec::Ev => rm(u16::from(operand_size), gpr(rm_index, operand_size)),
+            Spec::Ey => rm(u16::from(y_size), gpr(rm_index, y_size)),
+            Spec::MwRv => rm(2, gpr(rm_index, operand_size)),
+            Spec::Gb => Operand::Register(gpr(reg_index, 1)),
+            Spec::Gw => Operand::Register(gpr(reg_index, 2)),
+            Spec::Gv => Operand::Register(gpr(reg_index, operand_size)),
+            Spec::M => memory_only(0)?,
+            Spec::Mb => memory_only(1)?,
+            Spec::Mw => memory_only(2)?,
+            Spec::Md => memory_only(4)?,
+            Spec::Mq => memory_only(8)?,
+            Spec::Mt => memory_only(10)?,
+            Spec::Mv => memory_only(u16::from(operand_size))?,
+            Spec::Mp => memory_only(u16::from(operand_size) + 2)?,
+            Spec::Ms => memory_only(if mode == Mode::Bits64 { 10 } else { 6 })?,
+            Spec::Ma => memory_only(u16::from(operand_size) * 2)?,
+            Spec::Mdq => memory_only(if rex_w { 16 } else { 8 })?,
+            Spec::Rv => Operand::Register(gpr(rm_index, operand_size)),
+            Spec::Ry => Operand::Register(gpr(rm_index, if mode == Mode::Bits64 { 8 } else { 4 })),
+            Spec::Ib => Operand::Immediate { value: reader.value(1)?, size: 1 },
+            Spec::Ibs => Operand::Immediate {
+                value: reader.signed(1)? as u64 & mask(operand_size),
+                size: operand_size,
+            },
+            Spec::Iw => Operand::Immediate { value: reader.value(2)?, size: 2 },
+            Spec::Iz => Operand::Immediate {
+                value: reader.signed(z_size)? as u64 & mask(operand_size),
+                size: operand_size,
+            },
+            Spec::Iv => Operand::Immediate { value: reader.value(operand_size)?, size: operand_size },
+            Spec::One => Operand::Immediate { value: 1, size: 1 },
+            Spec::Jb => Operand::Relative(reader.signed(1)?),
+            Spec::Jz => Operand::Relative(reader.signed(z_size)?),
+            Spec::Ob | Spec::Ov => Operand::Memory(Memory {
+                segment: segment.unwrap_or(SegmentRegister::Ds),
+                base: None,
+                index: None,
+                scale: 1,
+                displacement: reader.value(address_size)? as i64,
+                size: if spec == Spec::Ob { 1 } else { u16::from(operand_size) },
+            }),
+            Spec::Ap => {
+                let offset = reader.value(z_size)? as u32;
+                let selector = reader.value(2)? as u16;
+                Operand::FarPointer { selector, offset }
+            }
+            Spec::Sw => match SegmentRegister::from_index(modrm.map_or(0, |m| m.reg)) {
+                Some(segment) => Operand::Register(Register::Segment(segment)),
+                None => return Err(DecodeError::InvalidOpcode),
+            },
+            Spec::Cd => Operand::Register(Register::Control(reg_index)),
+            Spec::Dd => Operand::Register(Register::Debug(reg_index)),
+            Spec::Zb => Operand::Register(gpr(opcode_index, 1)),
+            Spec::Zv => Operand::Register(gpr(opcode_index, operand_size)),
+            Spec::AL => Operand::Register(Register::Gpr8(0)),
+            Spec::CL => Operand::Register(Register::Gpr8(1)),
+            Spec::DX => Operand::Register(Register::Gpr16(2)),
+            Spec::AX => Operand::Register(Register::Gpr16(0)),
+            Spec::RAx => Operand::Register(gpr(0, operand_size)),
+            Spec::EAx => Operand::Register(gpr(0, z_size)),
+            Spec::SegEs => Operand::Register(Register::Segment(SegmentRegister::Es)),
+            Spec::SegCs => Operand::Register(Register::Segment(SegmentRegister::Cs)),
+            Spec::SegSs => Operand::Register(Register::Segment(SegmentRegister::Ss)),
+            Spec::SegDs => Operand::Register(Register::Segment(SegmentRegister::Ds)),
+            Spec::SegFs => Operand::Register(Register::Segment(SegmentRegister::Fs)),
+            Spec::SegGs => Operand::Register(Register::Segment(SegmentRegister::Gs)),
+            Spec::Xb => string(segment.unwrap_or(SegmentRegister::Ds), 6, 1),
+            Spec::Xv => string(segment.unwrap_or(SegmentRegister::Ds), 6, operand_size),
+            Spec::Xz => string(segment.unwrap_or(SegmentRegister::Ds), 6, z_size),
+            Spec::Yb => string(SegmentRegister::Es, 7, 1),
+            Spec::Yv => string(SegmentRegister::Es, 7, operand_size),
+            Spec::Yz => string(SegmentRegister::Es, 7, z_size),
+            Spec::Vx => Operand::Register(vector(reg_index)),
+            Spec::Hx => match vex {
+                Some(vex) => Operand::Register(vector(vex.vvvv)),
+                None => continue,
+            },
+            Spec::Wx => rm(vector_size, vector(rm_index)),
+            Spec::Wd => rm(4, Register::Xmm(rm_index)),
+            Spec::Wq => rm(8, Register::Xmm(rm_index)),
+            Spec::Wdq => rm(16, Register::Xmm(rm_index)),
+            Spec::Pq => Operand::Register(Register::Mmx(modrm.map_or(0, |m| m.reg))),
+            Spec::Qq => rm(8, Register::Mmx(modrm.map_or(0, |m| m.rm))),
+            Spec::St0 => Operand::Register(Register::St(0)),
+            Spec::Sti => Operand::Register(Register::St(modrm.map_or(0, |m| m.rm))),
+        };
+
+        operands.push(operand);
+    }
+
+    // Some instructions have a different name with a 64-bit operand size.
+    if rex_w {
+        mnemonic = match mnemonic {
+            Mnemonic::Movd => Mnemonic::Movq,
+            Mnemonic::Cmpxchg8b => Mnemonic::Cmpxchg16b,
+            Mnemonic::Vpsrlvd => Mnemonic::Vpsrlvq,
+            Mnemonic::Vpsllvd => Mnemonic::Vpsllvq,
+            other => other,
+        };
+    }
+
+    if let Some(vex) = vex {
+        if mnemonic == Mnemonic::Emms {
+            mnemonic = if vex.l { Mnemonic::Vzeroall } else { Mnemonic::Vzeroupper };
+        }
+    }
+
+    Ok(Instruction {
+        mode,
+        prefixes,
+        segment,
+        rex,
+        vex,
+        map,
+        opcode,
+        modrm,
+        sib,
+        mnemonic,
+        operands,
+        operand_size,
+        address_size,
+        len: reader.pos,
+    })
+}
+
+/// Decodes the memory operand described by a ModRM byte, and its SIB byte and displacement.
+fn decode_memory(
+    reader: &mut Reader,
+    modrm: ModRM,
+    address_size: u8,
+    mode: Mode,
+    segment: Option<SegmentRegister>,
+    rex_x: bool,
+    rex_b: bool,
+) -> Result<(Memory, Option<Sib>), DecodeError> {
+    let mut memory = Memory {
+        segment: SegmentRegister::Ds,
+        base: None,
+        index: None,
+        scale: 1,
+        displacement: 0,
+        size: 0,
+    };
+
+    let mut sib = None;
+    // Accesses relative to the stack pointer or the base pointer use the stack segment.
+    let mut stack = false;
+
+    if address_size == 2 {
+        let reg = Register::Gpr16;
+        let (base, index) = match modrm.rm {
+            0 => (3, Some(6)),
+            1 => (3, Some(7)),
+            2 => (5, Some(6)),
+            3 => (5, Some(7)),
+            4 => (6, None),
+            5 => (7, None),
+            6 => (5, None),
+            _ => (3, None),
+        };
+
+        if modrm.mode == 0 && modrm.rm == 6 {
+            memory.displacement = reader.value(2)? as i64;
+        } else {
+            memory.base = Some(reg(base));
+            memory.index = index.map(reg);
+            stack = base == 5;
+        }
+    } else {
+        let reg = |index| Register::gpr(index, address_size);
+        let mut no_base = false;
+
+        if modrm.rm == 4 {
+            let s = Sib::new(reader.byte()?);
+            sib = Some(s);
+
+            let index = s.index | (rex_x as u8) << 3;
+            if index != 4 {
+                memory.index = Some(reg(index));
+                memory.scale = 1 << s.scale;
+            }
+
+            if s.base == 5 && modrm.mode == 0 {
+                no_base = true;
+            } else {
+                let base = s.base | (rex_b as u8) << 3;
+                memory.base = Some(reg(base));
+                stack = base == 4 || base == 5;
+            }
+        } else if modrm.rm == 5 && modrm.mode == 0 {
+            no_base = true;
+
+            if mode == Mode::Bits64 {
+                memory.base = Some(Register::Ip(address_size));
+            }
+        } else {
+            let base = modrm.rm | (rex_b as u8) << 3;
+            memory.base = Some(reg(base));
+            stack = base == 5;
+        }
+
+        if no_base {
+            memory.displacement = reader.signed(4)?;
+        }
+    }
+
+    match modrm.mode {
+        1 => memory.displacement = reader.signed(1)?,
+        2 => memory.displacement = reader.signed(if address_size == 2 { 2 } else { 4 })?,
+        _ => (),
+    }
+
+    memory.segment = match segment {
+        Some(segment) => segment,
+        None if stack => SegmentRegister::Ss,
+        None => SegmentRegister::Ds,
+    };
+
+    Ok((memory, sib))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use state::Cr0;
+
+    fn disasm(bytes: &[u8], mode: Mode) -> String {
+        let insn = decode(bytes, mode).unwrap();
+        assert_eq!(insn.len, bytes.len(), "wrong length for {}", insn);
+        insn.to_string()
+    }
+
+    #[test]
+    fn decode_16bit() {
+        let mode = Mode::Bits16;
+
+        assert_eq!(disasm(&[0xB8, 0x34, 0x12], mode), "mov ax, 0x1234");
+        assert_eq!(disasm(&[0x8B, 0x46, 0xFC], mode), "mov ax, word ptr [bp-0x4]");
+        assert_eq!(disasm(&[0x88, 0x27], mode), "mov byte ptr [bx], ah");
+        assert_eq!(disasm(&[0xC6, 0x06, 0x10, 0x00, 0x42], mode), "mov byte ptr [0x10], 0x42");
+        assert_eq!(disasm(&[0x66, 0x31, 0xC0], mode), "xor eax, eax");
+        assert_eq!(disasm(&[0xEA, 0x00, 0x7C, 0x00, 0x00], mode), "jmp far 0x0:0x7c00");
+        assert_eq!(disasm(&[0xF3, 0xA4], mode), "rep movs byte ptr [di], byte ptr [si]");
+        assert_eq!(disasm(&[0x26, 0x8A, 0x00], mode), "mov al, byte ptr es:[bx+si]");
+        assert_eq!(disasm(&[0xEB, 0xFE], mode), "jmp $+0x0");
+    }
+
+    #[test]
+    fn decode_32bit() {
+        let mode = Mode::Bits32;
+
+        assert_eq!(disasm(&[0x8B, 0x44, 0x24, 0x08], mode), "mov eax, dword ptr [esp+0x8]");
+        assert_eq!(disasm(&[0x8D, 0x04, 0x8D, 0x00, 0x10, 0x00, 0x00], mode), "lea eax, [ecx*4+0x1000]");
+        assert_eq!(disasm(&[0x0F, 0xA2], mode), "cpuid");
+        assert_eq!(disasm(&[0x66, 0xB8, 0x34, 0x12], mode), "mov ax, 0x1234");
+        assert_eq!(disasm(&[0x83, 0xE8, 0x10], mode), "sub eax, 0x10");
+        assert_eq!(disasm(&[0x0F, 0x22, 0xC0], mode), "mov cr0, eax");
+        assert_eq!(disasm(&[0xF0, 0x0F, 0xB1, 0x0A], mode), "lock cmpxchg dword ptr [edx], ecx");
+        assert_eq!(disasm(&[0xDD, 0x45, 0xF8], mode), "fld qword ptr [ebp-0x8]");
+        assert_eq!(disasm(&[0xD9, 0xE8], mode), "fld1");
+        assert_eq!(disasm(&[0xDE, 0xC1], mode), "faddp st(1), st(0)");
+
+        let call = decode(&[0xE8, 0x10, 0x00, 0x00, 0x00], mode).unwrap();
+        assert_eq!(call.to_string(), "call $+0x15");
+        assert_eq!(call.branch_target(0x1000), Some(0x1015));
+        assert_eq!(call.display_at(0x1000).to_string(), "call 0x1015");
+    }
+
+    #[test]
+    fn decode_64bit() {
+        let mode = Mode::Bits64;
+
+        assert_eq!(disasm(&[0x48, 0x89, 0xE5], mode), "mov rbp, rsp");
+        assert_eq!(disasm(&[0x48, 0x8B, 0x05, 0x10, 0x00, 0x00, 0x00], mode), "mov rax, qword ptr [rip+0x10]");
+        assert_eq!(disasm(&[0x41, 0x50], mode), "push r8");
+        assert_eq!(disasm(&[0x50], mode), "push rax");
+        assert_eq!(disasm(&[0x40, 0x88, 0xF0], mode), "mov al, sil");
+        assert_eq!(disasm(&[0x49, 0x90], mode), "xchg r8, rax");
+        assert_eq!(disasm(&[0xF3, 0x90], mode), "pause");
+        assert_eq!(disasm(&[0xF3, 0x48, 0xAB], mode), "rep stos qword ptr [rdi], rax");
+        assert_eq!(
+            disasm(&[0x48, 0xB8, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11], mode),
+            "mov rax, 0x1122334455667788"
+        );
+        assert_eq!(disasm(&[0x48, 0x83, 0xEC, 0xF0], mode), "sub rsp, 0xfffffffffffffff0");
+        assert_eq!(disasm(&[0x4A, 0x8B, 0x04, 0xE5, 0x00, 0x00, 0x00, 0x00], mode), "mov rax, qword ptr [r12*8]");
+        assert_eq!(disasm(&[0x64, 0x48, 0x8B, 0x04, 0x25, 0x28, 0x00, 0x00, 0x00], mode), "mov rax, qword ptr fs:[0x28]");
+        assert_eq!(disasm(&[0x0F, 0x20, 0xC0], mode), "mov rax, cr0");
+        assert_eq!(disasm(&[0x0F, 0x01, 0xD0], mode), "xgetbv");
+        assert_eq!(disasm(&[0xF3, 0x0F, 0x1E, 0xFA], mode), "endbr64");
+        assert_eq!(disasm(&[0x66, 0x0F, 0x6F, 0x01], mode), "movdqa xmm0, xmmword ptr [rcx]");
+        assert_eq!(disasm(&[0x66, 0x48, 0x0F, 0x6E, 0xC0], mode), "movq xmm0, rax");
+        assert_eq!(disasm(&[0xC5, 0xF8, 0x77], mode), "vzeroupper");
+        assert_eq!(disasm(&[0xC5, 0xFD, 0x6F, 0x01], mode), "vmovdqa ymm0, ymmword ptr [rcx]");
+        assert_eq!(disasm(&[0xC5, 0xF1, 0xEF, 0xC2], mode), "vpxor xmm0, xmm1, xmm2");
+        assert_eq!(disasm(&[0xC4, 0x41, 0x34, 0x58, 0xC2], mode), "vaddps ymm8, ymm9, ymm10");
+        assert_eq!(disasm(&[0x48, 0x0F, 0xC7, 0x0F], mode), "cmpxchg16b xmmword ptr [rdi]");
+        assert_eq!(disasm(&[0x0F, 0x38, 0xF0, 0x07], mode), "movbe eax, dword ptr [rdi]");
+        assert_eq!(disasm(&[0x48, 0xCF], mode), "iretq");
+        assert_eq!(disasm(&[0x48, 0x98], mode), "cdqe");
+    }
+
+    #[test]
+    fn decode_vex_three_byte() {
+        let mode = Mode::Bits64;
+
+        assert_eq!(disasm(&[0xC4, 0xE2, 0x7D, 0x18, 0x07], mode), "vbroadcastss ymm0, dword ptr [rdi]");
+        assert_eq!(disasm(&[0xC4, 0xE2, 0x79, 0x18, 0xCA], mode), "vbroadcastss xmm1, xmm2");
+        assert_eq!(disasm(&[0xC4, 0xE2, 0x71, 0x45, 0xC2], mode), "vpsrlvd xmm0, xmm1, xmm2");
+        assert_eq!(disasm(&[0xC4, 0xE2, 0xF1, 0x45, 0xC2], mode), "vpsrlvq xmm0, xmm1, xmm2");
+        assert_eq!(disasm(&[0xC4, 0xE3, 0x75, 0x18, 0xC2, 0x01], mode), "vinsertf128 ymm0, ymm1, xmm2, 0x1");
+        assert_eq!(disasm(&[0xC4, 0xE3, 0x7D, 0x19, 0xC1, 0x01], mode), "vextractf128 xmm1, ymm0, 0x1");
+        assert_eq!(disasm(&[0xC4, 0xE3, 0xFD, 0x00, 0xC1, 0x4E], mode), "vpermq ymm0, ymm1, 0x4e");
+
+        // These instructions need a VEX prefix, and some of them 256-bit vectors.
+        assert_eq!(decode(&[0x66, 0x0F, 0x38, 0x18, 0x07], mode), Err(DecodeError::InvalidOpcode));
+        assert_eq!(decode(&[0xC4, 0xE3, 0x71, 0x18, 0xC2, 0x01], mode), Err(DecodeError::InvalidOpcode));
+    }
+
+    #[test]
+    fn decode_fields() {
+        let insn = decode(&[0x66, 0x41, 0x89, 0x44, 0x8B, 0x04], Mode::Bits64).unwrap();
+
+        assert_eq!(insn.prefixes, Prefixes::OPERAND_SIZE);
+        assert_eq!(insn.rex, Some(Rex(0x41)));
+        assert_eq!(insn.opcode, 0x89);
+        assert_eq!(insn.modrm, Some(ModRM { mode: 1, reg: 0, rm: 4 }));
+        assert_eq!(insn.sib, Some(Sib { scale: 2, index: 1, base: 3 }));
+        assert_eq!(insn.operand_size, 2);
+        assert_eq!(insn.address_size, 8);
+        assert_eq!(insn.mnemonic, Mnemonic::Mov);
+
+        let memory = Memory {
+            segment: SegmentRegister::Ds,
+            base: Some(Register::Gpr64(11)),
+            index: Some(Register::Gpr64(1)),
+            scale: 4,
+            displacement: 4,
+            size: 2,
+        };
+
+        assert_eq!(insn.operands, vec![Operand::Memory(memory), Operand::Register(Register::Gpr16(0))]);
+    }
+
+    #[test]
+    fn decode_errors() {
+        assert_eq!(decode(&[], Mode::Bits32), Err(DecodeError::Truncated));
+        assert_eq!(decode(&[0x8B, 0x44], Mode::Bits32), Err(DecodeError::Truncated));
+        assert_eq!(decode(&[0xB8, 0x00, 0x00], Mode::Bits32), Err(DecodeError::Truncated));
+        assert_eq!(decode(&[0x06], Mode::Bits64), Err(DecodeError::InvalidOpcode));
+        assert_eq!(decode(&[0x8D, 0xC0], Mode::Bits32), Err(DecodeError::InvalidOpcode));
+        assert_eq!(decode(&[0x0F, 0xFF, 0xC0], Mode::Bits32).map(|i| i.mnemonic), Ok(Mnemonic::Ud0));
+        assert_eq!(decode(&[0x66; 16], Mode::Bits32), Err(DecodeError::TooLong));
+    }
+
+    #[test]
+    fn mode_from_state() {
+        let mut state = State::default();
+        assert_eq!(Mode::from_state(&state), Mode::Bits16);
+
+        state.cr0.insert(Cr0::PROTECTED_MODE);
+        state.cs.op_size = true;
+        assert_eq!(Mode::from_state(&state), Mode::Bits32);
+
+        state.efer.insert(Efer::LM_ACTIVE);
+        state.cs.op_size = false;
+        state.cs.long = true;
+        assert_eq!(Mode::from_state(&state), Mode::Bits64);
+    }
+}
@@ -0,0 +1,739 @@
+//! Opcode tables used by the decoder.
+//!
+//! The operand notation follows the opcode maps in the appendix of the
+//! Intel Architecture Manual, Vol. 2.
+
+use super::Mnemonic;
+
+/// Describes how an operand is encoded, and its size.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Spec {
+    /// ModRM r/m, byte.
+    Eb,
+    /// ModRM r/m, word.
+    Ew,
+    /// ModRM r/m, doubleword.
+    Ed,
+    /// ModRM r/m, operand-sized.
+    Ev,
+    /// ModRM r/m, doubleword or quadword (with REX.W).
+    Ey,
+    /// ModRM r/m, word-sized memory or operand-sized register.
+    MwRv,
+    /// ModRM reg, byte.
+    Gb,
+    /// ModRM reg, word.
+    Gw,
+    /// ModRM reg, operand-sized.
+    Gv,
+    /// ModRM r/m, memory only, with no size.
+    M,
+    /// ModRM r/m, memory only, byte.
+    Mb,
+    /// ModRM r/m, memory only, word.
+    Mw,
+    /// ModRM r/m, memory only, doubleword.
+    Md,
+    /// ModRM r/m, memory only, quadword.
+    Mq,
+    /// ModRM r/m, memory only, 80-bit.
+    Mt,
+    /// ModRM r/m, memory only, operand-sized.
+    Mv,
+    /// ModRM r/m, memory only, far pointer.
+    Mp,
+    /// ModRM r/m, memory only, descriptor table register.
+    Ms,
+    /// ModRM r/m, memory only, two operand-sized values.
+    Ma,
+    /// ModRM r/m, memory only, 8 or 16 bytes (with REX.W).
+    Mdq,
+    /// ModRM r/m, register only, operand-sized.
+    Rv,
+    /// ModRM r/m, register only, 32 or 64 bits depending on the mode.
+    Ry,
+    /// Immediate byte.
+    Ib,
+    /// Immediate byte, sign-extended to the operand size.
+    Ibs,
+    /// Immediate word.
+    Iw,
+    /// Immediate of the operand size, but at most 32 bits.
+    Iz,
+    /// Immediate of the operand size.
+    Iv,
+    /// The constant 1, used by shifts.
+    One,
+    /// Relative offset, byte.
+    Jb,
+    /// Relative offset, operand-sized but at most 32 bits.
+    Jz,
+    /// Memory offset, byte.
+    Ob,
+    /// Memory offset, operand-sized.
+    Ov,
+    /// Far pointer immediate.
+    Ap,
+    /// Segment register, in ModRM reg.
+    Sw,
+    /// Control register, in ModRM reg.
+    Cd,
+    /// Debug register, in ModRM reg.
+    Dd,
+    /// General-purpose register in the low bits of the opcode, byte.
+    Zb,
+    /// General-purpose register in the low bits of the opcode, operand-sized.
+    Zv,
+    /// The AL register.
+    AL,
+    /// The CL register.
+    CL,
+    /// The DX register.
+    DX,
+    /// The AX register.
+    AX,
+    /// The accumulator, operand-sized.
+    RAx,
+    /// The accumulator, operand-sized but at most 32 bits.
+    EAx,
+    /// Segment registers.
+    SegEs,
+    SegCs,
+    SegSs,
+    SegDs,
+    SegFs,
+    SegGs,
+    /// String source, `DS:rSI`, byte.
+    Xb,
+    /// String source, `DS:rSI`, operand-sized.
+    Xv,
+    /// String source, `DS:rSI`, operand-sized but at most 32 bits.
+    Xz,
+    /// String destination, `ES:rDI`, byte.
+    Yb,
+    /// String destination, `ES:rDI`, operand-sized.
+    Yv,
+    /// String destination, `ES:rDI`, operand-sized but at most 32 bits.
+    Yz,
+    /// Vector register in ModRM reg, XMM or YMM (with VEX.L).
+    Vx,
+    /// Vector register in VEX.vvvv. Omitted for legacy encodings.
+    Hx,
+    /// Vector register or memory in ModRM r/m.
+    Wx,
+    /// Vector register or 32-bit memory in ModRM r/m.
+    Wd,
+    /// Vector register or 64-bit memory in ModRM r/m.
+    Wq,
+    /// XMM register or 128-bit memory in ModRM r/m.
+    Wdq,
+    /// MMX register in ModRM reg.
+    Pq,
+    /// MMX register or 64-bit memory in ModRM r/m.
+    Qq,
+    /// The top of the x87 register stack.
+    St0,
+    /// x87 register, in ModRM r/m.
+    Sti,
+}
+
+impl Spec {
+    /// Returns true if this operand is encoded in a ModRM byte.
+    pub fn uses_modrm(self) -> bool {
+        use self::Spec::*;
+
+        matches!(
+            self,
+            Eb | Ew | Ed | Ev | Ey | MwRv | Gb | Gw | Gv | M | Mb | Mw | Md | Mq | Mt | Mv | Mp | Ms | Ma
+                | Mdq | Rv | Ry | Sw | Cd | Dd | Vx | Wx | Wd | Wq | Wdq | Pq | Qq
+                | Sti
+        )
+    }
+
+    /// Returns true if this operand is encoded in the ModRM r/m field,
+    /// and can refer to memory.
+    pub fn may_be_memory(self) -> bool {
+        use self::Spec::*;
+
+        matches!(
+            self,
+            Eb | Ew | Ed | Ev | Ey | MwRv | M | Mb | Mw | Md | Mq | Mt | Mv | Mp | Ms | Ma | Mdq | Wx | Wd
+                | Wq | Wdq | Qq
+        )
+    }
+}
+
+bitflags! {
+    /// Attributes of an instruction.
+    pub struct Attr: u8 {
+        /// Defaults to 64-bit operand size in 64-bit mode.
+        const D64 = 1 << 0;
+        /// Always uses a 64-bit operand size in 64-bit mode.
+        const F64 = 1 << 1;
+        /// Invalid in 64-bit mode.
+        const I64 = 1 << 2;
+        /// Only valid in 64-bit mode.
+        const O64 = 1 << 3;
+        /// Can be encoded with a VEX prefix.
+        const VEX = 1 << 4;
+        /// Only valid with a VEX prefix.
+        const VEX_ONLY = 1 << 5;
+        /// Only valid with a VEX prefix which selects 256-bit vectors.
+        const VEX_256 = 1 << 6;
+    }
+}
+
+/// An entry in an opcode table.
+#[derive(Debug)]
+pub enum Entry {
+    /// The opcode is undefined.
+    Invalid,
+    /// An instruction.
+    Insn(Mnemonic, &'static [Spec], Attr),
+    /// The instruction is selected by the ModRM reg field.
+    Group(&'static [Entry; 8]),
+    /// The instruction is selected by the mandatory prefix: none, 66, F3 or F2.
+    Prefixed(&'static [Entry; 4]),
+    /// The instruction is selected by the mode: legacy or 64-bit.
+    Mode(&'static Entry, &'static Entry),
+    /// The instruction is selected by the ModRM mod field: memory or register.
+    MemReg(&'static Entry, &'static Entry),
+    /// The instruction is selected by the ModRM reg and r/m fields.
+    RegRm(&'static [[Entry; 8]; 8]),
+    /// An x87 floating-point instruction.
+    X87,
+}
+
+macro_rules! i {
+    ($m:ident $(: $($s:ident),+)* $(; $($a:ident),+)*) => {
+        Entry::Insn(
+            Mnemonic::$m,
+            &[$($(Spec::$s),+)*],
+            Attr::from_bits_truncate(0 $($(| Attr::$a.bits())+)*),
+        )
+    };
+}
+
+macro_rules! x {
+    () => {
+        Entry::Invalid
+    };
+}
+
+/// Entries for the one-byte opcode map.
+pub const ONE_BYTE: [Entry; 256] = [
+    // 0x00
+    i!(Add: Eb, Gb), i!(Add: Ev, Gv), i!(Add: Gb, Eb), i!(Add: Gv, Ev),
+    i!(Add: AL, Ib), i!(Add: RAx, Iz), i!(Push: SegEs; I64), i!(Pop: SegEs; I64),
+    i!(Or: Eb, Gb), i!(Or: Ev, Gv), i!(Or: Gb, Eb), i!(Or: Gv, Ev),
+    i!(Or: AL, Ib), i!(Or: RAx, Iz), i!(Push: SegCs; I64), x!(),
+    // 0x10
+    i!(Adc: Eb, Gb), i!(Adc: Ev, Gv), i!(Adc: Gb, Eb), i!(Adc: Gv, Ev),
+    i!(Adc: AL, Ib), i!(Adc: RAx, Iz), i!(Push: SegSs; I64), i!(Pop: SegSs; I64),
+    i!(Sbb: Eb, Gb), i!(Sbb: Ev, Gv), i!(Sbb: Gb, Eb), i!(Sbb: Gv, Ev),
+    i!(Sbb: AL, Ib), i!(Sbb: RAx, Iz), i!(Push: SegDs; I64), i!(Pop: SegDs; I64),
+    // 0x20
+    i!(And: Eb, Gb), i!(And: Ev, Gv), i!(And: Gb, Eb), i!(And: Gv, Ev),
+    i!(And: AL, Ib), i!(And: RAx, Iz), x!(), i!(Daa; I64),
+    i!(Sub: Eb, Gb), i!(Sub: Ev, Gv), i!(Sub: Gb, Eb), i!(Sub: Gv, Ev),
+    i!(Sub: AL, Ib), i!(Sub: RAx, Iz), x!(), i!(Das; I64),
+    // 0x30
+    i!(Xor: Eb, Gb), i!(Xor: Ev, Gv), i!(Xor: Gb, Eb), i!(Xor: Gv, Ev),
+    i!(Xor: AL, Ib), i!(Xor: RAx, Iz), x!(), i!(Aaa; I64),
+    i!(Cmp: Eb, Gb), i!(Cmp: Ev, Gv), i!(Cmp: Gb, Eb), i!(Cmp: Gv, Ev),
+    i!(Cmp: AL, Ib), i!(Cmp: RAx, Iz), x!(), i!(Aas; I64),
+    // 0x40
+    i!(Inc: Zv; I64), i!(Inc: Zv; I64), i!(Inc: Zv; I64), i!(Inc: Zv; I64),
+    i!(Inc: Zv; I64), i!(Inc: Zv; I64), i!(Inc: Zv; I64), i!(Inc: Zv; I64),
+    i!(Dec: Zv; I64), i!(Dec: Zv; I64), i!(Dec: Zv; I64), i!(Dec: Zv; I64),
+    i!(Dec: Zv; I64), i!(Dec: Zv; I64), i!(Dec: Zv; I64), i!(Dec: Zv; I64),
+    // 0x50
+    i!(Push: Zv; D64), i!(Push: Zv; D64), i!(Push: Zv; D64), i!(Push: Zv; D64),
+    i!(Push: Zv; D64), i!(Push: Zv; D64), i!(Push: Zv; D64), i!(Push: Zv; D64),
+    i!(Pop: Zv; D64), i!(Pop: Zv; D64), i!(Pop: Zv; D64), i!(Pop: Zv; D64),
+    i!(Pop: Zv; D64), i!(Pop: Zv; D64), i!(Pop: Zv; D64), i!(Pop: Zv; D64),
+    // 0x60
+    i!(Pusha; I64), i!(Popa; I64), i!(Bound: Gv, Ma; I64),
+    Entry::Mode(&i!(Arpl: Ew, Gw), &i!(Movsxd: Gv, Ed)),
+    x!(), x!(), x!(), x!(),
+    i!(Push: Iz; D64), i!(Imul: Gv, Ev, Iz), i!(Push: Ibs; D64), i!(Imul: Gv, Ev, Ibs),
+    i!(Ins: Yb, DX), i!(Ins: Yz, DX), i!(Outs: DX, Xb), i!(Outs: DX, Xz),
+    // 0x70
+    i!(Jo: Jb; F64), i!(Jno: Jb; F64), i!(Jb: Jb; F64), i!(Jae: Jb; F64),
+    i!(Je: Jb; F64), i!(Jne: Jb; F64), i!(Jbe: Jb; F64), i!(Ja: Jb; F64),
+    i!(Js: Jb; F64), i!(Jns: Jb; F64), i!(Jp: Jb; F64), i!(Jnp: Jb; F64),
+    i!(Jl: Jb; F64), i!(Jge: Jb; F64), i!(Jle: Jb; F64), i!(Jg: Jb; F64),
+    // 0x80
+    Entry::Group(&GROUP1_EB_IB), Entry::Group(&GROUP1_EV_IZ),
+    Entry::Mode(&Entry::Group(&GROUP1_EB_IB), &Entry::Invalid), Entry::Group(&GROUP1_EV_IBS),
+    i!(Test: Eb, Gb), i!(Test: Ev, Gv), i!(Xchg: Eb, Gb), i!(Xchg: Ev, Gv),
+    i!(Mov: Eb, Gb), i!(Mov: Ev, Gv), i!(Mov: Gb, Eb), i!(Mov: Gv, Ev),
+    i!(Mov: MwRv, Sw), i!(Lea: Gv, M), i!(Mov: Sw, Ew), Entry::Group(&GROUP1A),
+    // 0x90
+    Entry::Prefixed(&[i!(Nop), i!(Nop), i!(Pause), i!(Nop)]),
+    i!(Xchg: Zv, RAx), i!(Xchg: Zv, RAx), i!(Xchg: Zv, RAx),
+    i!(Xchg: Zv, RAx), i!(Xchg: Zv, RAx), i!(Xchg: Zv, RAx), i!(Xchg: Zv, RAx),
+    i!(Cbw), i!(Cwd), i!(Callf: Ap; I64), i!(Fwait),
+    i!(Pushf; D64), i!(Popf; D64), i!(Sahf), i!(Lahf),
+    // 0xA0
+    i!(Mov: AL, Ob), i!(Mov: RAx, Ov), i!(Mov: Ob, AL), i!(Mov: Ov, RAx),
+    i!(Movs: Yb, Xb), i!(Movs: Yv, Xv), i!(Cmps: Xb, Yb), i!(Cmps: Xv, Yv),
+    i!(Test: AL, Ib), i!(Test: RAx, Iz), i!(Stos: Yb, AL), i!(Stos: Yv, RAx),
+    i!(Lods: AL, Xb), i!(Lods: RAx, Xv), i!(Scas: AL, Yb), i!(Scas: RAx, Yv),
+    // 0xB0
+    i!(Mov: Zb, Ib), i!(Mov: Zb, Ib), i!(Mov: Zb, Ib), i!(Mov: Zb, Ib),
+    i!(Mov: Zb, Ib), i!(Mov: Zb, Ib), i!(Mov: Zb, Ib), i!(Mov: Zb, Ib),
+    i!(Mov: Zv, Iv), i!(Mov: Zv, Iv), i!(Mov: Zv, Iv), i!(Mov: Zv, Iv),
+    i!(Mov: Zv, Iv), i!(Mov: Zv, Iv), i!(Mov: Zv, Iv), i!(Mov: Zv, Iv),
+    // 0xC0
+    Entry::Group(&GROUP2_EB_IB), Entry::Group(&GROUP2_EV_IB), i!(Ret: Iw; F64), i!(Ret; F64),
+    i!(Les: Gv, Mp; I64), i!(Lds: Gv, Mp; I64), Entry::Group(&GROUP11_EB), Entry::Group(&GROUP11_EV),
+    i!(Enter: Iw, Ib; D64), i!(Leave; D64), i!(Retf: Iw), i!(Retf),
+    i!(Int3), i!(Int: Ib), i!(Into; I64), i!(Iret),
+    // 0xD0
+    Entry::Group(&GROUP2_EB_1), Entry::Group(&GROUP2_EV_1),
+    Entry::Group(&GROUP2_EB_CL), Entry::Group(&GROUP2_EV_CL),
+    i!(Aam: Ib; I64), i!(Aad: Ib; I64), x!(), i!(Xlat),
+    Entry::X87, Entry::X87, Entry::X87, Entry::X87,
+    Entry::X87, Entry::X87, Entry::X87, Entry::X87,
+    // 0xE0
+    i!(Loopne: Jb; F64), i!(Loope: Jb; F64), i!(Loop: Jb; F64), i!(Jcxz: Jb; F64),
+    i!(In: AL, Ib), i!(In: EAx, Ib), i!(Out: Ib, AL), i!(Out: Ib, EAx),
+    i!(Call: Jz; F64), i!(Jmp: Jz; F64), i!(Jmpf: Ap; I64), i!(Jmp: Jb; F64),
+    i!(In: AL, DX), i!(In: EAx, DX), i!(Out: DX, AL), i!(Out: DX, EAx),
+    // 0xF0
+    x!(), i!(Int1), x!(), x!(),
+    i!(Hlt), i!(Cmc), Entry::Group(&GROUP3_EB), Entry::Group(&GROUP3_EV),
+    i!(Clc), i!(Stc), i!(Cli), i!(Sti),
+    i!(Cld), i!(Std), Entry::Group(&GROUP4), Entry::Group(&GROUP5),
+];
+
+const GROUP1_EB_IB: [Entry; 8] = [
+    i!(Add: Eb, Ib), i!(Or: Eb, Ib), i!(Adc: Eb, Ib), i!(Sbb: Eb, Ib),
+    i!(And: Eb, Ib), i!(Sub: Eb, Ib), i!(Xor: Eb, Ib), i!(Cmp: Eb, Ib),
+];
+
+const GROUP1_EV_IZ: [Entry; 8] = [
+    i!(Add: Ev, Iz), i!(Or: Ev, Iz), i!(Adc: Ev, Iz), i!(Sbb: Ev, Iz),
+    i!(And: Ev, Iz), i!(Sub: Ev, Iz), i!(Xor: Ev, Iz), i!(Cmp: Ev, Iz),
+];
+
+const GROUP1_EV_IBS: [Entry; 8] = [
+    i!(Add: Ev, Ibs), i!(Or: Ev, Ibs), i!(Adc: Ev, Ibs), i!(Sbb: Ev, Ibs),
+    i!(And: Ev, Ibs), i!(Sub: Ev, Ibs), i!(Xor: Ev, Ibs), i!(Cmp: Ev, Ibs),
+];
+
+const GROUP1A: [Entry; 8] = [i!(Pop: Ev; D64), x!(), x!(), x!(), x!(), x!(), x!(), x!()];
+
+macro_rules! group2 {
+    ($name:ident, $dst:ident, $count:ident) => {
+        const $name: [Entry; 8] = [
+            i!(Rol: $dst, $count), i!(Ror: $dst, $count), i!(Rcl: $dst, $count), i!(Rcr: $dst, $count),
+            i!(Shl: $dst, $count), i!(Shr: $dst, $count), i!(Shl: $dst, $count), i!(Sar: $dst, $count),
+        ];
+    };
+}
+
+group2!(GROUP2_EB_IB, Eb, Ib);
+group2!(GROUP2_EV_IB, Ev, Ib);
+group2!(GROUP2_EB_1, Eb, One);
+group2!(GROUP2_EV_1, Ev, One);
+group2!(GROUP2_EB_CL, Eb, CL);
+group2!(GROUP2_EV_CL, Ev, CL);
+
+const GROUP3_EB: [Entry; 8] = [
+    i!(Test: Eb, Ib), i!(Test: Eb, Ib), i!(Not: Eb), i!(Neg: Eb),
+    i!(Mul: Eb), i!(Imul: Eb), i!(Div: Eb), i!(Idiv: Eb),
+];
+
+const GROUP3_EV: [Entry; 8] = [
+    i!(Test: Ev, Iz), i!(Test: Ev, Iz), i!(Not: Ev), i!(Neg: Ev),
+    i!(Mul: Ev), i!(Imul: Ev), i!(Div: Ev), i!(Idiv: Ev),
+];
+
+const GROUP4: [Entry; 8] = [i!(Inc: Eb), i!(Dec: Eb), x!(), x!(), x!(), x!(), x!(), x!()];
+
+const GROUP5: [Entry; 8] = [
+    i!(Inc: Ev), i!(Dec: Ev), i!(Call: Ev; F64), i!(Callf: Mp),
+    i!(Jmp: Ev; F64), i!(Jmpf: Mp), i!(Push: Ev; D64), x!(),
+];
+
+const GROUP11_EB: [Entry; 8] = [i!(Mov: Eb, Ib), x!(), x!(), x!(), x!(), x!(), x!(), x!()];
+
+const GROUP11_EV: [Entry; 8] = [i!(Mov: Ev, Iz), x!(), x!(), x!(), x!(), x!(), x!(), x!()];
+
+/// Entries for the two-byte opcode map, prefixed by `0F`.
+pub const TWO_BYTE: [Entry; 256] = [
+    // 0x00
+    Entry::Group(&GROUP6), Entry::MemReg(&Entry::Group(&GROUP7_MEM), &Entry::RegRm(&GROUP7_REG)),
+    i!(Lar: Gv, Ew), i!(Lsl: Gv, Ew),
+    x!(), i!(Syscall; O64), i!(Clts), i!(Sysret; O64),
+    i!(Invd), i!(Wbinvd), x!(), i!(Ud2),
+    x!(), Entry::Group(&GROUP_PREFETCH), x!(), x!(),
+    // 0x10
+    Entry::Prefixed(&[i!(Movups: Vx, Wx; VEX), i!(Movupd: Vx, Wx; VEX), i!(Movss: Vx, Wd; VEX), i!(Movsd: Vx, Wq; VEX)]),
+    Entry::Prefixed(&[i!(Movups: Wx, Vx; VEX), i!(Movupd: Wx, Vx; VEX), i!(Movss: Wd, Vx; VEX), i!(Movsd: Wq, Vx; VEX)]),
+    x!(), x!(), x!(), x!(), x!(), x!(),
+    Entry::MemReg(&Entry::Group(&GROUP16), &i!(Nop: Ev)),
+    i!(Nop: Ev), i!(Nop: Ev), i!(Nop: Ev), i!(Nop: Ev),
+    i!(Nop: Ev), Entry::Prefixed(&[i!(Nop: Ev), i!(Nop: Ev), Entry::RegRm(&GROUP_ENDBR), i!(Nop: Ev)]), i!(Nop: Ev),
+    // 0x20
+    i!(Mov: Ry, Cd), i!(Mov: Ry, Dd), i!(Mov: Cd, Ry), i!(Mov: Dd, Ry),
+    x!(), x!(), x!(), x!(),
+    Entry::Prefixed(&[i!(Movaps: Vx, Wx; VEX), i!(Movapd: Vx, Wx; VEX), x!(), x!()]),
+    Entry::Prefixed(&[i!(Movaps: Wx, Vx; VEX), i!(Movapd: Wx, Vx; VEX), x!(), x!()]),
+    x!(), x!(), x!(), x!(),
+    Entry::Prefixed(&[i!(Ucomiss: Vx, Wd; VEX), i!(Ucomisd: Vx, Wq; VEX), x!(), x!()]),
+    Entry::Prefixed(&[i!(Comiss: Vx, Wd; VEX), i!(Comisd: Vx, Wq; VEX), x!(), x!()]),
+    // 0x30
+    i!(Wrmsr), i!(Rdtsc), i!(Rdmsr), i!(Rdpmc),
+    i!(Sysenter), i!(Sysexit), x!(), x!(),
+    x!(), x!(), x!(), x!(), x!(), x!(), x!(), x!(),
+    // 0x40
+    i!(Cmovo: Gv, Ev), i!(Cmovno: Gv, Ev), i!(Cmovb: Gv, Ev), i!(Cmovae: Gv, Ev),
+    i!(Cmove: Gv, Ev), i!(Cmovne: Gv, Ev), i!(Cmovbe: Gv, Ev), i!(Cmova: Gv, Ev),
+    i!(Cmovs: Gv, Ev), i!(Cmovns: Gv, Ev), i!(Cmovp: Gv, Ev), i!(Cmovnp: Gv, Ev),
+    i!(Cmovl: Gv, Ev), i!(Cmovge: Gv, Ev), i!(Cmovle: Gv, Ev), i!(Cmovg: Gv, Ev),
+    // 0x50
+    x!(),
+    Entry::Prefixed(&[i!(Sqrtps: Vx, Wx; VEX), i!(Sqrtpd: Vx, Wx; VEX), i!(Sqrtss: Vx, Hx, Wd; VEX), i!(Sqrtsd: Vx, Hx, Wq; VEX)]),
+    x!(), x!(),
+    Entry::Prefixed(&[i!(Andps: Vx, Hx, Wx; VEX), i!(Andpd: Vx, Hx, Wx; VEX), x!(), x!()]),
+    Entry::Prefixed(&[i!(Andnps: Vx, Hx, Wx; VEX), i!(Andnpd: Vx, Hx, Wx; VEX), x!(), x!()]),
+    Entry::Prefixed(&[i!(Orps: Vx, Hx, Wx; VEX), i!(Orpd: Vx, Hx, Wx; VEX), x!(), x!()]),
+    Entry::Prefixed(&[i!(Xorps: Vx, Hx, Wx; VEX), i!(Xorpd: Vx, Hx, Wx; VEX), x!(), x!()]),
+    Entry::Prefixed(&[i!(Addps: Vx, Hx, Wx; VEX), i!(Addpd: Vx, Hx, Wx; VEX), i!(Addss: Vx, Hx, Wd; VEX), i!(Addsd: Vx, Hx, Wq; VEX)]),
+    Entry::Prefixed(&[i!(Mulps: Vx, Hx, Wx; VEX), i!(Mulpd: Vx, Hx, Wx; VEX), i!(Mulss: Vx, Hx, Wd; VEX), i!(Mulsd: Vx, Hx, Wq; VEX)]),
+    x!(), x!(),
+    Entry::Prefixed(&[i!(Subps: Vx, Hx, Wx; VEX), i!(Subpd: Vx, Hx, Wx; VEX), i!(Subss: Vx, Hx, Wd; VEX), i!(Subsd: Vx, Hx, Wq; VEX)]),
+    x!(),
+    Entry::Prefixed(&[i!(Divps: Vx, Hx, Wx; VEX), i!(Divpd: Vx, Hx, Wx; VEX), i!(Divss: Vx, Hx, Wd; VEX), i!(Divsd: Vx, Hx, Wq; VEX)]),
+    x!(),
+    // 0x60
+    x!(), x!(), x!(), x!(), x!(), x!(), x!(), x!(),
+    x!(), x!(), x!(), x!(), x!(), x!(),
+    Entry::Prefixed(&[i!(Movd: Pq, Ey), i!(Movd: Vx, Ey; VEX), x!(), x!()]),
+    Entry::Prefixed(&[i!(Movq: Pq, Qq), i!(Movdqa: Vx, Wx; VEX), i!(Movdqu: Vx, Wx; VEX), x!()]),
+    // 0x70
+    x!(), x!(), x!(), x!(), x!(), x!(), x!(), i!(Emms; VEX),
+    x!(), x!(), x!(), x!(), x!(), x!(),
+    Entry::Prefixed(&[i!(Movd: Ey, Pq), i!(Movd: Ey, Vx; VEX), i!(Movq: Vx, Wq; VEX), x!()]),
+    Entry::Prefixed(&[i!(Movq: Qq, Pq), i!(Movdqa: Wx, Vx; VEX), i!(Movdqu: Wx, Vx; VEX), x!()]),
+    // 0x80
+    i!(Jo: Jz; F64), i!(Jno: Jz; F64), i!(Jb: Jz; F64), i!(Jae: Jz; F64),
+    i!(Je: Jz; F64), i!(Jne: Jz; F64), i!(Jbe: Jz; F64), i!(Ja: Jz; F64),
+    i!(Js: Jz; F64), i!(Jns: Jz; F64), i!(Jp: Jz; F64), i!(Jnp: Jz; F64),
+    i!(Jl: Jz; F64), i!(Jge: Jz; F64), i!(Jle: Jz; F64), i!(Jg: Jz; F64),
+    // 0x90
+    i!(Seto: Eb), i!(Setno: Eb), i!(Setb: Eb), i!(Setae: Eb),
+    i!(Sete: Eb), i!(Setne: Eb), i!(Setbe: Eb), i!(Seta: Eb),
+    i!(Sets: Eb), i!(Setns: Eb), i!(Setp: Eb), i!(Setnp: Eb),
+    i!(Setl: Eb), i!(Setge: Eb), i!(Setle: Eb), i!(Setg: Eb),
+    // 0xA0
+    i!(Push: SegFs; D64), i!(Pop: SegFs; D64), i!(Cpuid), i!(Bt: Ev, Gv),
+    i!(Shld: Ev, Gv, Ib), i!(Shld: Ev, Gv, CL), x!(), x!(),
+    i!(Push: SegGs; D64), i!(Pop: SegGs; D64), i!(Rsm), i!(Bts: Ev, Gv),
+    i!(Shrd: Ev, Gv, Ib), i!(Shrd: Ev, Gv, CL),
+    Entry::MemReg(&Entry::Group(&GROUP15_MEM), &Entry::Group(&GROUP15_REG)), i!(Imul: Gv, Ev),
+    // 0xB0
+    i!(Cmpxchg: Eb, Gb), i!(Cmpxchg: Ev, Gv), i!(Lss: Gv, Mp), i!(Btr: Ev, Gv),
+    i!(Lfs: Gv, Mp), i!(Lgs: Gv, Mp), i!(Movzx: Gv, Eb), i!(Movzx: Gv, Ew),
+    Entry::Prefixed(&[x!(), x!(), i!(Popcnt: Gv, Ev), x!()]), i!(Ud1: Gv, Ev),
+    Entry::Group(&GROUP8), i!(Btc: Ev, Gv),
+    Entry::Prefixed(&[i!(Bsf: Gv, Ev), i!(Bsf: Gv, Ev), i!(Tzcnt: Gv, Ev), i!(Bsf: Gv, Ev)]),
+    Entry::Prefixed(&[i!(Bsr: Gv, Ev), i!(Bsr: Gv, Ev), i!(Lzcnt: Gv, Ev), i!(Bsr: Gv, Ev)]),
+    i!(Movsx: Gv, Eb), i!(Movsx: Gv, Ew),
+    // 0xC0
+    i!(Xadd: Eb, Gb), i!(Xadd: Ev, Gv), x!(), x!(),
+    x!(), x!(), x!(), Entry::Group(&GROUP9),
+    i!(Bswap: Zv), i!(Bswap: Zv), i!(Bswap: Zv), i!(Bswap: Zv),
+    i!(Bswap: Zv), i!(Bswap: Zv), i!(Bswap: Zv), i!(Bswap: Zv),
+    // 0xD0
+    x!(), x!(), x!(), x!(), x!(), x!(),
+    Entry::Prefixed(&[x!(), i!(Movq: Wq, Vx; VEX), x!(), x!()]),
+    x!(), x!(), x!(), x!(), x!(), x!(), x!(), x!(), x!(),
+    // 0xE0
+    x!(), x!(), x!(), x!(), x!(), x!(), x!(), x!(),
+    x!(), x!(), x!(), x!(), x!(), x!(), x!(),
+    Entry::Prefixed(&[i!(Pxor: Pq, Qq), i!(Pxor: Vx, Hx, Wx; VEX), x!(), x!()]),
+    // 0xF0
+    x!(), x!(), x!(), x!(), x!(), x!(), x!(), x!(),
+    x!(), x!(), x!(), x!(), x!(), x!(), x!(), i!(Ud0: Gv, Ev),
+];
+
+const GROUP6: [Entry; 8] = [
+    i!(Sldt: MwRv), i!(Str: MwRv), i!(Lldt: Ew), i!(Ltr: Ew),
+    i!(Verr: Ew), i!(Verw: Ew), x!(), x!(),
+];
+
+const GROUP7_MEM: [Entry; 8] = [
+    i!(Sgdt: Ms), i!(Sidt: Ms), i!(Lgdt: Ms), i!(Lidt: Ms),
+    i!(Smsw: MwRv), x!(), i!(Lmsw: Ew), i!(Invlpg: Mb),
+];
+
+const GROUP7_REG: [[Entry; 8]; 8] = [
+    [x!(), i!(Vmcall), i!(Vmlaunch), i!(Vmresume), i!(Vmxoff), x!(), x!(), x!()],
+    [i!(Monitor), i!(Mwait), i!(Clac), i!(Stac), x!(), x!(), x!(), x!()],
+    [i!(Xgetbv), i!(Xsetbv), x!(), x!(), x!(), x!(), x!(), x!()],
+    [x!(), x!(), x!(), x!(), x!(), x!(), x!(), x!()],
+    [i!(Smsw: MwRv), i!(Smsw: MwRv), i!(Smsw: MwRv), i!(Smsw: MwRv),
+     i!(Smsw: MwRv), i!(Smsw: MwRv), i!(Smsw: MwRv), i!(Smsw: MwRv)],
+    [x!(), x!(), x!(), x!(), x!(), x!(), x!(), x!()],
+    [i!(Lmsw: Ew), i!(Lmsw: Ew), i!(Lmsw: Ew), i!(Lmsw: Ew),
+     i!(Lmsw: Ew), i!(Lmsw: Ew), i!(Lmsw: Ew), i!(Lmsw: Ew)],
+    [i!(Swapgs; O64), i!(Rdtscp), x!(), x!(), x!(), x!(), x!(), x!()],
+];
+
+const GROUP_ENDBR: [[Entry; 8]; 8] = [
+    [x!(), x!(), x!(), x!(), x!(), x!(), x!(), x!()],
+    [x!(), x!(), x!(), x!(), x!(), x!(), x!(), x!()],
+    [x!(), x!(), x!(), x!(), x!(), x!(), x!(), x!()],
+    [x!(), x!(), x!(), x!(), x!(), x!(), x!(), x!()],
+    [x!(), x!(), x!(), x!(), x!(), x!(), x!(), x!()],
+    [x!(), x!(), x!(), x!(), x!(), x!(), x!(), x!()],
+    [x!(), x!(), x!(), x!(), x!(), x!(), x!(), x!()],
+    [x!(), x!(), i!(Endbr64), i!(Endbr32), x!(), x!(), x!(), x!()],
+];
+
+const GROUP_PREFETCH: [Entry; 8] = [
+    i!(Prefetch: Mb), i!(Prefetchw: Mb), i!(Prefetch: Mb), i!(Prefetch: Mb),
+    i!(Prefetch: Mb), i!(Prefetch: Mb), i!(Prefetch: Mb), i!(Prefetch: Mb),
+];
+
+const GROUP8: [Entry; 8] = [
+    x!(), x!(), x!(), x!(),
+    i!(Bt: Ev, Ib), i!(Bts: Ev, Ib), i!(Btr: Ev, Ib), i!(Btc: Ev, Ib),
+];
+
+const GROUP9: [Entry; 8] = [
+    x!(), Entry::MemReg(&i!(Cmpxchg8b: Mdq), &Entry::Invalid), x!(), x!(),
+    x!(), x!(), Entry::MemReg(&Entry::Invalid, &i!(Rdrand: Rv)), Entry::MemReg(&Entry::Invalid, &i!(Rdseed: Rv)),
+];
+
+const GROUP15_MEM: [Entry; 8] = [
+    i!(Fxsave: M), i!(Fxrstor: M), i!(Ldmxcsr: Md), i!(Stmxcsr: Md),
+    i!(Xsave: M), i!(Xrstor: M), i!(Xsaveopt: M), i!(Clflush: Mb),
+];
+
+const GROUP15_REG: [Entry; 8] = [
+    x!(), x!(), x!(), x!(),
+    x!(), i!(Lfence), i!(Mfence), i!(Sfence),
+];
+
+const GROUP16: [Entry; 8] = [
+    i!(Prefetchnta: Mb), i!(Prefetcht0: Mb), i!(Prefetcht1: Mb), i!(Prefetcht2: Mb),
+    i!(Nop: Ev), i!(Nop: Ev), i!(Nop: Ev), i!(Nop: Ev),
+];
+
+/// Entry for a VEX-only instruction of the three-byte maps, which requires a `66` prefix.
+macro_rules! vex_66 {
+    ($m:ident: $($s:ident),+ $(; $a:ident)*) => {
+        Entry::Prefixed(&[x!(), i!($m: $($s),+; VEX_ONLY $(, $a)*), x!(), x!()])
+    };
+}
+
+/// Looks up an entry in the three-byte opcode map, prefixed by `0F 38`.
+///
+/// Only a few instructions are listed; see the module documentation of `decode`.
+pub fn three_byte_38(opcode: u8) -> &'static Entry {
+    const PSHUFB: Entry = Entry::Prefixed(&[i!(Pshufb: Pq, Qq), i!(Pshufb: Vx, Hx, Wx; VEX), x!(), x!()]);
+    const MOVBE_LOAD: Entry = Entry::Prefixed(&[i!(Movbe: Gv, Mv), i!(Movbe: Gv, Mv), x!(), x!()]);
+    const MOVBE_STORE: Entry = Entry::Prefixed(&[i!(Movbe: Mv, Gv), i!(Movbe: Mv, Gv), x!(), x!()]);
+
+    const VPERMILPS: Entry = vex_66!(Vpermilps: Vx, Hx, Wx);
+    const VPERMILPD: Entry = vex_66!(Vpermilpd: Vx, Hx, Wx);
+    const VPERMPS: Entry = vex_66!(Vpermps: Vx, Hx, Wx; VEX_256);
+    const VBROADCASTSS: Entry = vex_66!(Vbroadcastss: Vx, Wd);
+    const VBROADCASTSD: Entry = vex_66!(Vbroadcastsd: Vx, Wq; VEX_256);
+    const VPERMD: Entry = vex_66!(Vpermd: Vx, Hx, Wx; VEX_256);
+    const VPSRLVD: Entry = vex_66!(Vpsrlvd: Vx, Hx, Wx);
+    const VPSRAVD: Entry = vex_66!(Vpsravd: Vx, Hx, Wx);
+    const VPSLLVD: Entry = vex_66!(Vpsllvd: Vx, Hx, Wx);
+    const VPBROADCASTD: Entry = vex_66!(Vpbroadcastd: Vx, Wd);
+    const VPBROADCASTQ: Entry = vex_66!(Vpbroadcastq: Vx, Wq);
+
+    match opcode {
+        0x00 => &PSHUFB,
+        0x0C => &VPERMILPS,
+        0x0D => &VPERMILPD,
+        0x16 => &VPERMPS,
+        0x18 => &VBROADCASTSS,
+        0x19 => &VBROADCASTSD,
+        0x36 => &VPERMD,
+        0x45 => &VPSRLVD,
+        0x46 => &VPSRAVD,
+        0x47 => &VPSLLVD,
+        0x58 => &VPBROADCASTD,
+        0x59 => &VPBROADCASTQ,
+        0xF0 => &MOVBE_LOAD,
+        0xF1 => &MOVBE_STORE,
+        _ => &Entry::Invalid,
+    }
+}
+
+/// Looks up an entry in the three-byte opcode map, prefixed by `0F 3A`.
+///
+/// Only a few instructions are listed; see the module documentation of `decode`.
+pub fn three_byte_3a(opcode: u8) -> &'static Entry {
+    const PALIGNR: Entry = Entry::Prefixed(&[i!(Palignr: Pq, Qq, Ib), i!(Palignr: Vx, Hx, Wx, Ib; VEX), x!(), x!()]);
+
+    const VPERMQ: Entry = vex_66!(Vpermq: Vx, Wx, Ib; VEX_256);
+    const VPERMPD: Entry = vex_66!(Vpermpd: Vx, Wx, Ib; VEX_256);
+    const VPBLENDD: Entry = vex_66!(Vpblendd: Vx, Hx, Wx, Ib);
+    const VPERMILPS: Entry = vex_66!(Vpermilps: Vx, Wx, Ib);
+    const VPERMILPD: Entry = vex_66!(Vpermilpd: Vx, Wx, Ib);
+    const VPERM2F128: Entry = vex_66!(Vperm2f128: Vx, Hx, Wx, Ib; VEX_256);
+    const VINSERTF128: Entry = vex_66!(Vinsertf128: Vx, Hx, Wdq, Ib; VEX_256);
+    const VEXTRACTF128: Entry = vex_66!(Vextractf128: Wdq, Vx, Ib; VEX_256);
+    const VINSERTI128: Entry = vex_66!(Vinserti128: Vx, Hx, Wdq, Ib; VEX_256);
+    const VEXTRACTI128: Entry = vex_66!(Vextracti128: Wdq, Vx, Ib; VEX_256);
+    const VPERM2I128: Entry = vex_66!(Vperm2i128: Vx, Hx, Wx, Ib; VEX_256);
+
+    match opcode {
+        0x00 => &VPERMQ,
+        0x01 => &VPERMPD,
+        0x02 => &VPBLENDD,
+        0x04 => &VPERMILPS,
+        0x05 => &VPERMILPD,
+        0x06 => &VPERM2F128,
+        0x0F => &PALIGNR,
+        0x18 => &VINSERTF128,
+        0x19 => &VEXTRACTF128,
+        0x38 => &VINSERTI128,
+        0x39 => &VEXTRACTI128,
+        0x46 => &VPERM2I128,
+        _ => &Entry::Invalid,
+    }
+}
+
+/// Memory forms of the x87 instructions, indexed by opcode and ModRM reg.
+pub const X87_MEM: [[Entry; 8]; 8] = [
+    // D8
+    [i!(Fadd: Md), i!(Fmul: Md), i!(Fcom: Md), i!(Fcomp: Md),
+     i!(Fsub: Md), i!(Fsubr: Md), i!(Fdiv: Md), i!(Fdivr: Md)],
+    // D9
+    [i!(Fld: Md), x!(), i!(Fst: Md), i!(Fstp: Md),
+     i!(Fldenv: M), i!(Fldcw: Mw), i!(Fnstenv: M), i!(Fnstcw: Mw)],
+    // DA
+    [i!(Fiadd: Md), i!(Fimul: Md), i!(Ficom: Md), i!(Ficomp: Md),
+     i!(Fisub: Md), i!(Fisubr: Md), i!(Fidiv: Md), i!(Fidivr: Md)],
+    // DB
+    [i!(Fild: Md), i!(Fisttp: Md), i!(Fist: Md), i!(Fistp: Md),
+     x!(), i!(Fld: Mt), x!(), i!(Fstp: Mt)],
+    // DC
+    [i!(Fadd: Mq), i!(Fmul: Mq), i!(Fcom: Mq), i!(Fcomp: Mq),
+     i!(Fsub: Mq), i!(Fsubr: Mq), i!(Fdiv: Mq), i!(Fdivr: Mq)],
+    // DD
+    [i!(Fld: Mq), i!(Fisttp: Mq), i!(Fst: Mq), i!(Fstp: Mq),
+     i!(Frstor: M), x!(), i!(Fnsave: M), i!(Fnstsw: Mw)],
+    // DE
+    [i!(Fiadd: Mw), i!(Fimul: Mw), i!(Ficom: Mw), i!(Ficomp: Mw),
+     i!(Fisub: Mw), i!(Fisubr: Mw), i!(Fidiv: Mw), i!(Fidivr: Mw)],
+    // DF
+    [i!(Fild: Mw), i!(Fisttp: Mw), i!(Fist: Mw), i!(Fistp: Mw),
+     i!(Fbld: Mt), i!(Fild: Mq), i!(Fbstp: Mt), i!(Fistp: Mq)],
+];
+
+/// Register forms of the x87 instructions which operate on `ST(i)`,
+/// indexed by opcode and ModRM reg.
+///
+/// Instructions with no operands are looked up with `x87_reg_fixed`.
+pub const X87_REG: [[Entry; 8]; 8] = [
+    // D8
+    [i!(Fadd: St0, Sti), i!(Fmul: St0, Sti), i!(Fcom: St0, Sti), i!(Fcomp: St0, Sti),
+     i!(Fsub: St0, Sti), i!(Fsubr: St0, Sti), i!(Fdiv: St0, Sti), i!(Fdivr: St0, Sti)],
+    // D9
+    [i!(Fld: Sti), i!(Fxch: Sti), x!(), x!(), x!(), x!(), x!(), x!()],
+    // DA
+    [i!(Fcmovb: St0, Sti), i!(Fcmove: St0, Sti), i!(Fcmovbe: St0, Sti), i!(Fcmovu: St0, Sti),
+     x!(), x!(), x!(), x!()],
+    // DB
+    [i!(Fcmovnb: St0, Sti), i!(Fcmovne: St0, Sti), i!(Fcmovnbe: St0, Sti), i!(Fcmovnu: St0, Sti),
+     x!(), i!(Fucomi: St0, Sti), i!(Fcomi: St0, Sti), x!()],
+    // DC
+    [i!(Fadd: Sti, St0), i!(Fmul: Sti, St0), x!(), x!(),
+     i!(Fsubr: Sti, St0), i!(Fsub: Sti, St0), i!(Fdivr: Sti, St0), i!(Fdiv: Sti, St0)],
+    // DD
+    [i!(Ffree: Sti), x!(), i!(Fst: Sti), i!(Fstp: Sti),
+     i!(Fucom: Sti), i!(Fucomp: Sti), x!(), x!()],
+    // DE
+    [i!(Faddp: Sti, St0), i!(Fmulp: Sti, St0), x!(), x!(),
+     i!(Fsubrp: Sti, St0), i!(Fsubp: Sti, St0), i!(Fdivrp: Sti, St0), i!(Fdivp: Sti, St0)],
+    // DF
+    [x!(), x!(), x!(), x!(),
+     x!(), i!(Fucomip: St0, Sti), i!(Fcomip: St0, Sti), x!()],
+];
+
+/// Looks up the register forms of x87 instructions which have no `ST(i)` operand.
+pub fn x87_reg_fixed(opcode: u8, modrm: u8) -> &'static Entry {
+    const FNOP: Entry = i!(Fnop);
+    const FCHS: Entry = i!(Fchs);
+    const FABS: Entry = i!(Fabs);
+    const FTST: Entry = i!(Ftst);
+    const FXAM: Entry = i!(Fxam);
+    const FLD1: Entry = i!(Fld1);
+    const FLDL2T: Entry = i!(Fldl2t);
+    const FLDL2E: Entry = i!(Fldl2e);
+    const FLDPI: Entry = i!(Fldpi);
+    const FLDLG2: Entry = i!(Fldlg2);
+    const FLDLN2: Entry = i!(Fldln2);
+    const FLDZ: Entry = i!(Fldz);
+    const F2XM1: Entry = i!(F2xm1);
+    const FYL2X: Entry = i!(Fyl2x);
+    const FPTAN: Entry = i!(Fptan);
+    const FPATAN: Entry = i!(Fpatan);
+    const FXTRACT: Entry = i!(Fxtract);
+    const FPREM1: Entry = i!(Fprem1);
+    const FDECSTP: Entry = i!(Fdecstp);
+    const FINCSTP: Entry = i!(Fincstp);
+    const FPREM: Entry = i!(Fprem);
+    const FYL2XP1: Entry = i!(Fyl2xp1);
+    const FSQRT: Entry = i!(Fsqrt);
+    const FSINCOS: Entry = i!(Fsincos);
+    const FRNDINT: Entry = i!(Frndint);
+    const FSCALE: Entry = i!(Fscale);
+    const FSIN: Entry = i!(Fsin);
+    const FCOS: Entry = i!(Fcos);
+    const FUCOMPP: Entry = i!(Fucompp);
+    const FNCLEX: Entry = i!(Fnclex);
+    const FNINIT: Entry = i!(Fninit);
+    const FCOMPP: Entry = i!(Fcompp);
+    const FNSTSW: Entry = i!(Fnstsw: AX);
+
+    match (opcode, modrm) {
+        (0xD9, 0xD0) => &FNOP,
+        (0xD9, 0xE0) => &FCHS,
+        (0xD9, 0xE1) => &FABS,
+        (0xD9, 0xE4) => &FTST,
+        (0xD9, 0xE5) => &FXAM,
+        (0xD9, 0xE8) => &FLD1,
+        (0xD9, 0xE9) => &FLDL2T,
+        (0xD9, 0xEA) => &FLDL2E,
+        (0xD9, 0xEB) => &FLDPI,
+        (0xD9, 0xEC) => &FLDLG2,
+        (0xD9, 0xED) => &FLDLN2,
+        (0xD9, 0xEE) => &FLDZ,
+        (0xD9, 0xF0) => &F2XM1,
+        (0xD9, 0xF1) => &FYL2X,
+        (0xD9, 0xF2) => &FPTAN,
+        (0xD9, 0xF3) => &FPATAN,
+        (0xD9, 0xF4) => &FXTRACT,
+        (0xD9, 0xF5) => &FPREM1,
+        (0xD9, 0xF6) => &FDECSTP,
+        (0xD9, 0xF7) => &FINCSTP,
+        (0xD9, 0xF8) => &FPREM,
+        (0xD9, 0xF9) => &FYL2XP1,
+        (0xD9, 0xFA) => &FSQRT,
+        (0xD9, 0xFB) => &FSINCOS,
+        (0xD9, 0xFC) => &FRNDINT,
+        (0xD9, 0xFD) => &FSCALE,
+        (0xD9, 0xFE) => &FSIN,
+        (0xD9, 0xFF) => &FCOS,
+        (0xDA, 0xE9) => &FUCOMPP,
+        (0xDB, 0xE2) => &FNCLEX,
+        (0xDB, 0xE3) => &FNINIT,
+        (0xDE, 0xD9) => &FCOMPP,
+        (0xDF, 0xE0) => &FNSTSW,
+        _ => &Entry::Invalid,
+    }
+}
@@ -1,21 +1,77 @@
 //! Structures representing the floating-point unit's state.
 
+pub mod xsave;
+
 /// Contains the x87 FPU state.
-#[derive(Debug, Default, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct X87State {
+    /// The eight ST registers, in physical register order.
+    pub st: [Float80; 8],
     /// The control word.
     pub control: ControlWord,
     /// The status word.
     pub status: StatusWord,
+    /// The abridged tag word, as saved by `FXSAVE`.
+    ///
+    /// Each bit is set if the corresponding register is in use.
+    pub tag: u8,
+    /// The opcode of the last non-control instruction executed.
+    pub last_opcode: u16,
+    /// Address of the last non-control instruction executed.
+    pub last_ip: u64,
+    /// Address of the memory operand of the last non-control instruction.
+    pub last_dp: u64,
+    /// The SSE control / status register.
+    ///
+    /// It is saved and restored together with the x87 state.
+    pub mxcsr: u32,
+}
+
+impl Default for X87State {
+    fn default() -> Self {
+        X87State {
+            st: [Float80::default(); 8],
+            control: ControlWord::default(),
+            status: StatusWord::default(),
+            tag: 0,
+            last_opcode: 0,
+            last_ip: 0,
+            last_dp: 0,
+            // All SSE exceptions are masked after reset.
+            mxcsr: 0x1F80,
+        }
+    }
+}
+
+/// An 80-bit extended precision floating-point number.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct Float80 {
+    /// The 64-bit significand, including the explicit integer bit.
+    pub significand: u64,
+    /// The 15-bit biased exponent, with the sign in the top bit.
+    pub sign_exponent: u16,
 }
 
 /// Contains the SSE state.
-#[derive(Debug, Default, Copy, Clone)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
 pub struct SseState {
     /// The 16 XMM/YMM registers.
     pub r: [[u64; 4]; 16],
 }
 
+/// Contains the AVX-512 state.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct Avx512State {
+    /// The opmask registers, K0 to K7.
+    pub k: [u64; 8],
+    /// The upper 256 bits of ZMM0 to ZMM15.
+    ///
+    /// The lower 256 bits are stored in `SseState`.
+    pub zmm_hi256: [[u64; 4]; 16],
+    /// The registers ZMM16 to ZMM31.
+    pub hi16_zmm: [[u64; 8]; 16],
+}
+
 bitflags! {
     /// The control word for the FPU.
     ///
@@ -0,0 +1,377 @@
+//! The area used by the `XSAVE` family of instructions.
+//!
+//! The area starts with the legacy `FXSAVE` region and a header, followed by
+//! the extended state components. This module only handles the standard
+//! (non-compacted) format, in which the location of every component is
+//! enumerated by CPUID leaf 0xD.
+
+use fpu::{ControlWord, Float80, StatusWord, X87State};
+use state::{State, Xcr0};
+
+/// Offset of the `XSTATE_BV` field of the header.
+const XSTATE_BV: usize = 512;
+
+/// Number of state components which can be described by a `Layout`.
+const COMPONENTS: usize = 10;
+
+/// The location of a state component in the `XSAVE` area.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct Component {
+    /// Offset from the start of the area, in bytes.
+    pub offset: usize,
+    /// Size of the component, in bytes.
+    pub size: usize,
+}
+
+/// Describes the state components supported by a processor,
+/// and where they are stored in the `XSAVE` area.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Layout {
+    supported: Xcr0,
+    size: usize,
+    components: [Component; COMPONENTS],
+}
+
+impl Layout {
+    /// Builds the layout from CPUID leaf 0xD.
+    ///
+    /// `cpuid` is called with the leaf and subleaf, and must return
+    /// the `[EAX, EBX, ECX, EDX]` registers.
+    pub fn from_cpuid<F: FnMut(u32, u32) -> [u32; 4]>(mut cpuid: F) -> Self {
+        let [eax, _, _, edx] = cpuid(0xD, 0);
+
+        let supported = Xcr0::from_bits_truncate(u64::from(eax) | u64::from(edx) << 32);
+
+        let mut layout = Layout::legacy(supported);
+
+        for index in 2..COMPONENTS {
+            if supported.bits() & (1 << index) != 0 {
+                let [size, offset, _, _] = cpuid(0xD, index as u32);
+
+                layout.set_component(index, offset as usize, size as usize);
+            }
+        }
+
+        layout
+    }
+
+    /// The layout used by processors which support all of the known
+    /// state components.
+    pub fn standard() -> Self {
+        let mut layout = Layout::legacy(Xcr0::all());
+
+        let components = [
+            (Xcr0::AVX, 576, 256),
+            (Xcr0::OPMASK, 1088, 64),
+            (Xcr0::ZMM_HI256, 1152, 512),
+            (Xcr0::HI16_ZMM, 1664, 1024),
+            (Xcr0::PKRU, 2688, 8),
+        ];
+
+        for &(component, offset, size) in &components {
+            layout.set_component(component.bits().trailing_zeros() as usize, offset, size);
+        }
+
+        layout
+    }
+
+    /// Creates a layout with only the legacy region described.
+    fn legacy(supported: Xcr0) -> Self {
+        let mut components = [Component::default(); COMPONENTS];
+
+        components[0] = Component { offset: 0, size: 160 };
+        components[1] = Component { offset: 160, size: 256 };
+
+        Layout {
+            supported,
+            // The legacy region and the header.
+            size: 576,
+            components,
+        }
+    }
+
+    /// Describes the location of an extended component.
+    fn set_component(&mut self, index: usize, offset: usize, size: usize) {
+        self.components[index] = Component { offset, size };
+        self.size = self.size.max(offset + size);
+    }
+
+    /// The state components supported by the processor.
+    pub fn supported(&self) -> Xcr0 {
+        self.supported
+    }
+
+    /// Size of the area needed to save the supported components.
+    ///
+    /// Components which are not known to this module are not included,
+    /// since their state is not unpacked.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the location of a single state component,
+    /// or `None` if it is not supported.
+    pub fn component(&self, component: Xcr0) -> Option<Component> {
+        let index = component.bits().trailing_zeros() as usize;
+
+        if component.bits().count_ones() != 1 || !self.supported.contains(component) || index >= COMPONENTS {
+            return None;
+        }
+
+        Some(self.components[index])
+    }
+
+    /// Returns the offset of a component, if it is in `present`.
+    fn offset(&self, present: Xcr0, component: Xcr0) -> Option<usize> {
+        if present.contains(component) {
+            self.component(component).map(|c| c.offset)
+        } else {
+            None
+        }
+    }
+
+    /// Unpacks an `XSAVE` area into the x87, SSE, AVX, AVX-512 and PKRU
+    /// registers of `state`.
+    ///
+    /// Components which are marked as being in their initial state
+    /// in the header are reset, instead of being read from the area.
+    pub fn unpack(&self, area: &[u8], state: &mut State) {
+        assert!(area.len() >= self.size, "XSAVE area is too small");
+
+        let present = Xcr0::from_bits_truncate(read_u64(area, XSTATE_BV)) & self.supported;
+
+        // MXCSR is always saved, regardless of the state of the x87 and SSE components.
+        let mxcsr = read_u32(area, 24);
+
+        if present.contains(Xcr0::X87) {
+            let x87 = &mut state.fpu;
+
+            for (i, st) in x87.st.iter_mut().enumerate() {
+                *st = Float80 {
+                    significand: read_u64(area, 32 + 16 * i),
+                    sign_exponent: read_u16(area, 32 + 16 * i + 8),
+                };
+            }
+
+            // The control word has reserved bits which are usually set.
+            unsafe {
+                x87.control = ControlWord::from_bits_unchecked(read_u16(area, 0));
+            }
+
+            x87.status = StatusWord::from_bits_truncate(read_u16(area, 2));
+            x87.tag = area[4];
+            x87.last_opcode = read_u16(area, 6);
+            x87.last_ip = read_u64(area, 8);
+            x87.last_dp = read_u64(area, 16);
+        } else {
+            state.fpu = X87State {
+                control: ControlWord::from_bits_truncate(0x37F),
+                ..X87State::default()
+            };
+        }
+
+        state.fpu.mxcsr = mxcsr;
+
+        for (i, r) in state.sse.r.iter_mut().enumerate() {
+            match self.offset(present, Xcr0::SSE) {
+                Some(offset) => read_words(area, offset + 16 * i, &mut r[..2]),
+                None => r[..2].copy_from_slice(&[0; 2]),
+            }
+
+            match self.offset(present, Xcr0::AVX) {
+                Some(offset) => read_words(area, offset + 16 * i, &mut r[2..]),
+                None => r[2..].copy_from_slice(&[0; 2]),
+            }
+        }
+
+        let avx512 = &mut state.avx512;
+
+        match self.offset(present, Xcr0::OPMASK) {
+            Some(offset) => read_words(area, offset, &mut avx512.k),
+            None => avx512.k = [0; 8],
+        }
+
+        for (i, zmm) in avx512.zmm_hi256.iter_mut().enumerate() {
+            match self.offset(present, Xcr0::ZMM_HI256) {
+                Some(offset) => read_words(area, offset + 32 * i, zmm),
+                None => *zmm = [0; 4],
+            }
+        }
+
+        for (i, zmm) in avx512.hi16_zmm.iter_mut().enumerate() {
+            match self.offset(present, Xcr0::HI16_ZMM) {
+                Some(offset) => read_words(area, offset + 64 * i, zmm),
+                None => *zmm = [0; 8],
+            }
+        }
+
+        state.pkru = match self.offset(present, Xcr0::PKRU) {
+            Some(offset) => read_u32(area, offset),
+            None => 0,
+        };
+    }
+
+    /// Packs the x87, SSE, AVX, AVX-512 and PKRU registers of `state`
+    /// into an `XSAVE` area.
+    ///
+    /// Only the components enabled in `state.xcr0` are written, the other
+    /// known components are marked as being in their initial state.
+    /// Unknown components are left unchanged, so the area should be one
+    /// that was previously saved.
+    pub fn pack(&self, state: &State, area: &mut [u8]) {
+        assert!(area.len() >= self.size, "XSAVE area is too small");
+
+        let present = (state.xcr0 | Xcr0::X87 | Xcr0::SSE) & self.supported;
+
+        let x87 = &state.fpu;
+
+        for (i, st) in x87.st.iter().enumerate() {
+            write_u64(area, 32 + 16 * i, st.significand);
+            write_u16(area, 32 + 16 * i + 8, st.sign_exponent);
+        }
+
+        write_u16(area, 0, x87.control.bits());
+        write_u16(area, 2, x87.status.bits());
+        area[4] = x87.tag;
+        write_u16(area, 6, x87.last_opcode);
+        write_u64(area, 8, x87.last_ip);
+        write_u64(area, 16, x87.last_dp);
+        write_u32(area, 24, x87.mxcsr);
+
+        for (i, r) in state.sse.r.iter().enumerate() {
+            write_words(area, 160 + 16 * i, &r[..2]);
+
+            if let Some(offset) = self.offset(present, Xcr0::AVX) {
+                write_words(area, offset + 16 * i, &r[2..]);
+            }
+        }
+
+        let avx512 = &state.avx512;
+
+        if let Some(offset) = self.offset(present, Xcr0::OPMASK) {
+            write_words(area, offset, &avx512.k);
+        }
+
+        if let Some(offset) = self.offset(present, Xcr0::ZMM_HI256) {
+            for (i, zmm) in avx512.zmm_hi256.iter().enumerate() {
+                write_words(area, offset + 32 * i, zmm);
+            }
+        }
+
+        if let Some(offset) = self.offset(present, Xcr0::HI16_ZMM) {
+            for (i, zmm) in avx512.hi16_zmm.iter().enumerate() {
+                write_words(area, offset + 64 * i, zmm);
+            }
+        }
+
+        if let Some(offset) = self.offset(present, Xcr0::PKRU) {
+            write_u32(area, offset, state.pkru);
+        }
+
+        let xstate_bv = read_u64(area, XSTATE_BV) & !Xcr0::all().bits() | present.bits();
+        write_u64(area, XSTATE_BV, xstate_bv);
+    }
+}
+
+fn read_u16(area: &[u8], offset: usize) -> u16 {
+    let mut bytes = [0; 2];
+    bytes.copy_from_slice(&area[offset..offset + 2]);
+    u16::from_le_bytes(bytes)
+}
+
+fn read_u32(area: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&area[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(area: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&area[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+fn read_words(area: &[u8], offset: usize, words: &mut [u64]) {
+    for (i, word) in words.iter_mut().enumerate() {
+        *word = read_u64(area, offset + 8 * i);
+    }
+}
+
+fn write_u16(area: &mut [u8], offset: usize, value: u16) {
+    area[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u32(area: &mut [u8], offset: usize, value: u32) {
+    area[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_u64(area: &mut [u8], offset: usize, value: u64) {
+    area[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+fn write_words(area: &mut [u8], offset: usize, words: &[u64]) {
+    for (i, &word) in words.iter().enumerate() {
+        write_u64(area, offset + 8 * i, word);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layout_and_round_trip() {
+        // A processor with AVX-512 and protection keys.
+        let layout = Layout::from_cpuid(|leaf, subleaf| {
+            assert_eq!(leaf, 0xD);
+            match subleaf {
+                0 => [0x2E7, 2696, 2696, 0],
+                2 => [256, 576, 0, 0],
+                5 => [64, 1088, 0, 0],
+                6 => [512, 1152, 0, 0],
+                7 => [1024, 1664, 0, 0],
+                9 => [8, 2688, 0, 0],
+                _ => panic!("unexpected subleaf {}", subleaf),
+            }
+        });
+
+        assert_eq!(layout, Layout::standard());
+        assert_eq!(layout.component(Xcr0::PKRU), Some(Component { offset: 2688, size: 8 }));
+        assert_eq!(layout.component(Xcr0::AVX | Xcr0::SSE), None);
+
+        let mut state = State::default();
+        state.xcr0 = Xcr0::all();
+        state.fpu.st[7] = Float80 { significand: 1 << 63, sign_exponent: 0x3FFF };
+        state.fpu.control = ControlWord::from_bits_truncate(0x37F);
+        state.sse.r[1] = [1, 2, 3, 4];
+        state.avx512.k[7] = 0xFFFF;
+        state.avx512.zmm_hi256[15] = [5, 6, 7, 8];
+        state.avx512.hi16_zmm[0] = [9; 8];
+        state.pkru = 0x5555_5554;
+
+        let mut area = vec![0; layout.size()];
+        area[XSTATE_BV + 7] = 0x80;
+        layout.pack(&state, &mut area);
+
+        assert_eq!(read_u64(&area, XSTATE_BV), 1 << 63 | 0x2E7);
+        assert_eq!(read_u64(&area, 576 + 16), 3);
+        assert_eq!(read_u32(&area, 2688), 0x5555_5554);
+
+        let mut unpacked = State::default();
+        layout.unpack(&area, &mut unpacked);
+
+        assert_eq!(unpacked.fpu, state.fpu);
+        assert_eq!(unpacked.sse, state.sse);
+        assert_eq!(unpacked.avx512, state.avx512);
+        assert_eq!(unpacked.pkru, state.pkru);
+
+        // Components which are not enabled are in their initial state.
+        state.xcr0 = Xcr0::X87 | Xcr0::SSE | Xcr0::AVX;
+        layout.pack(&state, &mut area);
+        layout.unpack(&area, &mut unpacked);
+
+        assert_eq!(unpacked.sse, state.sse);
+        assert_eq!(unpacked.avx512, Default::default());
+        assert_eq!(unpacked.pkru, 0);
+    }
+}
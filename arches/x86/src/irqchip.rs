@@ -0,0 +1,252 @@
+//! State of the legacy interrupt controllers: the 8259 PICs and the I/O APIC.
+
+/// Number of pins of the I/O APIC.
+pub const IOAPIC_PINS: usize = 24;
+
+/// The state of an 8259 Programmable Interrupt Controller.
+///
+/// A PC has two of them: the slave is connected to pin 2 of the master.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct PicState {
+    /// Level of the input lines at the last edge detection.
+    pub last_irr: u8,
+    /// Interrupt request register.
+    pub irr: u8,
+    /// Interrupt mask register.
+    pub imr: u8,
+    /// In-service register.
+    pub isr: u8,
+    /// The line with the lowest priority is `priority_add - 1`,
+    /// changed by the rotation commands.
+    pub priority_add: u8,
+    /// The vector of line 0. The vector of each line is added to it.
+    pub irq_base: u8,
+    /// Whether reading the command port returns the ISR instead of the IRR.
+    pub read_isr: bool,
+    /// The next read of the command port returns the pending interrupt.
+    pub poll: bool,
+    /// Special mask mode, where masked lines do not block
+    /// lower priority interrupts.
+    pub special_mask: bool,
+    /// The initialization word expected next, 0 if the PIC is initialized.
+    pub init_state: u8,
+    /// Automatic end-of-interrupt when the interrupt is acknowledged.
+    pub auto_eoi: bool,
+    /// Rotate the priorities on automatic end-of-interrupt.
+    pub rotate_on_auto_eoi: bool,
+    /// Special fully nested mode, used by the master of cascaded PICs.
+    pub special_fully_nested: bool,
+    /// The guest initialized the PIC with 4 initialization words.
+    pub init4: bool,
+    /// Edge/level control register: level-triggered lines have their bit set.
+    pub elcr: u8,
+    /// The lines whose trigger mode can be changed through the ELCR.
+    pub elcr_mask: u8,
+}
+
+/// The state of an I/O APIC.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct IoApicState {
+    /// Physical address of the registers.
+    pub base_address: u64,
+    /// The register selected by the `IOREGSEL` register.
+    pub register_select: u32,
+    /// The APIC ID of the I/O APIC.
+    pub id: u32,
+    /// Interrupt request register, with a bit for each pin.
+    pub irr: u32,
+    /// The redirection table, which configures each pin.
+    pub redirection: [RedirectionEntry; IOAPIC_PINS],
+}
+
+/// How an interrupt is delivered to its destination.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum DeliveryMode {
+    /// Delivers the vector to all of the destination processors.
+    #[default]
+    Fixed,
+    /// Delivers the vector to the destination processor
+    /// with the lowest priority.
+    LowestPriority,
+    /// System management interrupt.
+    Smi,
+    /// Non-maskable interrupt.
+    Nmi,
+    /// Resets the processor.
+    Init,
+    /// Starts an application processor, at the page given by the vector.
+    StartUp,
+    /// Delivers an interrupt requested by an external 8259 PIC.
+    ExtInt,
+    /// A reserved mode.
+    Other(u8),
+}
+
+impl From<u8> for DeliveryMode {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => DeliveryMode::Fixed,
+            1 => DeliveryMode::LowestPriority,
+            2 => DeliveryMode::Smi,
+            4 => DeliveryMode::Nmi,
+            5 => DeliveryMode::Init,
+            6 => DeliveryMode::StartUp,
+            7 => DeliveryMode::ExtInt,
+            _ => DeliveryMode::Other(value),
+        }
+    }
+}
+
+impl From<DeliveryMode> for u8 {
+    fn from(mode: DeliveryMode) -> Self {
+        match mode {
+            DeliveryMode::Fixed => 0,
+            DeliveryMode::LowestPriority => 1,
+            DeliveryMode::Smi => 2,
+            DeliveryMode::Nmi => 4,
+            DeliveryMode::Init => 5,
+            DeliveryMode::StartUp => 6,
+            DeliveryMode::ExtInt => 7,
+            DeliveryMode::Other(value) => value,
+        }
+    }
+}
+
+/// How the destination of an interrupt is interpreted.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum DestinationMode {
+    /// The destination is an APIC ID.
+    #[default]
+    Physical,
+    /// The destination is matched against the logical
+    /// destination registers of the local APICs.
+    Logical,
+}
+
+/// The level which asserts an interrupt line.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum Polarity {
+    /// The line is asserted when high.
+    #[default]
+    ActiveHigh,
+    /// The line is asserted when low.
+    ActiveLow,
+}
+
+/// When an interrupt line raises an interrupt.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum TriggerMode {
+    /// When the line is asserted.
+    #[default]
+    Edge,
+    /// For as long as the line is asserted.
+    Level,
+}
+
+/// An entry of the I/O APIC's redirection table, which configures a pin.
+///
+/// It converts to and from the 64-bit value of the entry's registers.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct RedirectionEntry {
+    /// The interrupt vector.
+    pub vector: u8,
+    /// How the interrupt is delivered.
+    pub delivery_mode: DeliveryMode,
+    /// How `destination` is interpreted.
+    pub destination_mode: DestinationMode,
+    /// The interrupt is waiting to be delivered. Read-only.
+    pub delivery_pending: bool,
+    /// The level which asserts the pin.
+    pub polarity: Polarity,
+    /// A level-triggered interrupt was accepted by a local APIC,
+    /// and is waiting for its end-of-interrupt. Read-only.
+    pub remote_irr: bool,
+    /// When the pin raises an interrupt.
+    pub trigger_mode: TriggerMode,
+    /// The pin is masked, and does not raise interrupts.
+    pub masked: bool,
+    /// The APIC ID, or set of logical processors, which receive the interrupt.
+    pub destination: u8,
+}
+
+impl RedirectionEntry {
+    /// The address of the message signaled interrupt which delivers this entry's
+    /// interrupt to the local APICs.
+    pub fn msi_address(&self) -> u64 {
+        let logical = self.destination_mode == DestinationMode::Logical;
+        0xFEE0_0000 | u64::from(self.destination) << 12 | (logical as u64) << 2
+    }
+
+    /// The data of the message signaled interrupt which delivers this entry's interrupt.
+    pub fn msi_data(&self) -> u32 {
+        let level = self.trigger_mode == TriggerMode::Level;
+        u32::from(self.vector) | u32::from(u8::from(self.delivery_mode) & 0b111) << 8 | (level as u32) << 15
+    }
+}
+
+impl From<u64> for RedirectionEntry {
+    fn from(value: u64) -> Self {
+        let bit = |n: u32| value & (1 << n) != 0;
+
+        RedirectionEntry {
+            vector: value as u8,
+            delivery_mode: DeliveryMode::from((value >> 8) as u8 & 0b111),
+            destination_mode: if bit(11) {
+                DestinationMode::Logical
+            } else {
+                DestinationMode::Physical
+            },
+            delivery_pending: bit(12),
+            polarity: if bit(13) { Polarity::ActiveLow } else { Polarity::ActiveHigh },
+            remote_irr: bit(14),
+            trigger_mode: if bit(15) { TriggerMode::Level } else { TriggerMode::Edge },
+            masked: bit(16),
+            destination: (value >> 56) as u8,
+        }
+    }
+}
+
+impl From<RedirectionEntry> for u64 {
+    fn from(entry: RedirectionEntry) -> Self {
+        let bit = |set: bool, n: u32| if set { 1 << n } else { 0 };
+
+        u64::from(entry.vector)
+            | u64::from(u8::from(entry.delivery_mode) & 0b111) << 8
+            | bit(entry.destination_mode == DestinationMode::Logical, 11)
+            | bit(entry.delivery_pending, 12)
+            | bit(entry.polarity == Polarity::ActiveLow, 13)
+            | bit(entry.remote_irr, 14)
+            | bit(entry.trigger_mode == TriggerMode::Level, 15)
+            | bit(entry.masked, 16)
+            | u64::from(entry.destination) << 56
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redirection_entry() {
+        // Level-triggered, active-low vector 0x31 to logical destination 0x0F.
+        let value = 0x0F00_0000_0000_A931;
+        let entry = RedirectionEntry::from(value);
+
+        assert_eq!(entry.vector, 0x31);
+        assert_eq!(entry.delivery_mode, DeliveryMode::LowestPriority);
+        assert_eq!(entry.destination_mode, DestinationMode::Logical);
+        assert_eq!(entry.polarity, Polarity::ActiveLow);
+        assert_eq!(entry.trigger_mode, TriggerMode::Level);
+        assert!(!entry.masked && !entry.remote_irr);
+        assert_eq!(entry.destination, 0x0F);
+
+        assert_eq!(u64::from(entry), value);
+
+        assert_eq!(entry.msi_address(), 0xFEE0_F004);
+        assert_eq!(entry.msi_data(), 0x8131);
+
+        // The reset value only has the mask bit set.
+        let masked = RedirectionEntry { masked: true, ..Default::default() };
+        assert_eq!(u64::from(masked), 0x1_0000);
+    }
+}
@@ -12,4 +12,14 @@ pub mod fpu;
 
 pub mod msr;
 
+pub mod cpuid;
+
 pub mod vmx;
+
+pub mod decode;
+
+pub mod irqchip;
+
+pub mod apic;
+
+pub mod acpi;
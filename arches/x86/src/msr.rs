@@ -3,6 +3,12 @@
 /// Time-stamp counter.
 pub const TSC: u32 = 0x10;
 
+/// Local APIC base address and enable bits.
+pub const APIC_BASE: u32 = 0x1B;
+
+/// MTRR capabilities.
+pub const MTRR_CAP: u32 = 0xFE;
+
 /// SYSENTER code segment.
 pub const SYSENTER_CS: u32 = 0x174;
 /// SYSENTER stack pointer.
@@ -10,11 +16,272 @@ pub const SYSENTER_SP: u32 = 0x175;
 /// SYSENTER instruction pointer.
 pub const SYSENTER_IP: u32 = 0x176;
 
+/// Enables miscellaneous processor features.
+pub const MISC_ENABLE: u32 = 0x1A0;
+
+/// Base of the first variable-range MTRR.
+///
+/// The base and mask registers of each range alternate.
+pub const MTRR_PHYS_BASE0: u32 = 0x200;
+/// Mask of the first variable-range MTRR.
+pub const MTRR_PHYS_MASK0: u32 = 0x201;
+
+/// Fixed-range MTRR for 0x00000 - 0x7FFFF.
+pub const MTRR_FIX_64K_00000: u32 = 0x250;
+/// Fixed-range MTRR for 0x80000 - 0x9FFFF.
+pub const MTRR_FIX_16K_80000: u32 = 0x258;
+/// Fixed-range MTRR for 0xA0000 - 0xBFFFF.
+pub const MTRR_FIX_16K_A0000: u32 = 0x259;
+/// First fixed-range MTRR for 0xC0000 - 0xFFFFF.
+///
+/// The other seven 4 KiB ranges follow, up to 0xF8000.
+pub const MTRR_FIX_4K_C0000: u32 = 0x268;
+
+/// Page attribute table.
+pub const PAT: u32 = 0x277;
+
+/// Default memory type, and MTRR enable bits.
+pub const MTRR_DEF_TYPE: u32 = 0x2FF;
+
+/// The TSC value at which the local APIC timer fires, in TSC-deadline mode.
+pub const TSC_DEADLINE: u32 = 0x6E0;
+
+/// Extended feature enable register.
+pub const EFER: u32 = 0xC000_0080;
+/// SYSCALL segment selectors.
+pub const STAR: u32 = 0xC000_0081;
+/// SYSCALL entry point in 64-bit mode.
+pub const LSTAR: u32 = 0xC000_0082;
+/// SYSCALL entry point in compatibility mode.
+pub const CSTAR: u32 = 0xC000_0083;
+/// RFLAGS mask for SYSCALL.
+pub const FMASK: u32 = 0xC000_0084;
+/// Base of the FS segment.
+pub const FS_BASE: u32 = 0xC000_0100;
+/// Base of the GS segment.
+pub const GS_BASE: u32 = 0xC000_0101;
+/// Value swapped with the GS base by `SWAPGS`.
+pub const KERNEL_GS_BASE: u32 = 0xC000_0102;
+/// Auxiliary value returned by `RDTSCP`.
+pub const TSC_AUX: u32 = 0xC000_0103;
+
+/// Number of variable-range MTRRs in `MtrrState`.
+pub const VARIABLE_MTRRS: usize = 8;
+
+/// Returns the index of the MSR of fixed-range MTRR `n`,
+/// in the order used by `MtrrState::fixed`.
+fn fixed_mtrr(n: usize) -> u32 {
+    match n {
+        0 => MTRR_FIX_64K_00000,
+        1 => MTRR_FIX_16K_80000,
+        2 => MTRR_FIX_16K_A0000,
+        _ => MTRR_FIX_4K_C0000 + n as u32 - 3,
+    }
+}
+
+/// The value of a model-specific register.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct Msr {
+    /// The index of the register, used by `RDMSR` / `WRMSR`.
+    pub index: u32,
+    /// The value of the register.
+    pub value: u64,
+}
+
+impl Msr {
+    /// Creates an MSR with the given value.
+    pub fn new(index: u32, value: u64) -> Self {
+        Msr { index, value }
+    }
+}
+
+/// Contains the memory type range registers.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct MtrrState {
+    /// The default memory type, and the enable bits.
+    pub def_type: u64,
+    /// The fixed-range MTRRs, in order of their address ranges.
+    pub fixed: [u64; 11],
+    /// The base and mask registers of the variable-range MTRRs.
+    pub variable: [(u64, u64); VARIABLE_MTRRS],
+}
+
 /// Contains all the architectural MSRs.
-#[derive(Debug, Copy, Clone)]
+///
+/// MSRs which are part of `State`, such as EFER, APIC_BASE
+/// or the FS / GS bases, are not included.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct MSRState {
     /// The time-stamp counter.
     pub tsc: u64,
     /// The SYSENTER code segment.
     pub sysenter_cs: u64,
+    /// The SYSENTER stack pointer.
+    pub sysenter_sp: u64,
+    /// The SYSENTER instruction pointer.
+    pub sysenter_ip: u64,
+    /// The SYSCALL segment selectors.
+    pub star: u64,
+    /// The 64-bit mode SYSCALL entry point.
+    pub lstar: u64,
+    /// The compatibility mode SYSCALL entry point.
+    pub cstar: u64,
+    /// The RFLAGS mask for SYSCALL.
+    pub fmask: u64,
+    /// The GS base swapped in by `SWAPGS`.
+    pub kernel_gs_base: u64,
+    /// The auxiliary value returned by `RDTSCP`.
+    pub tsc_aux: u64,
+    /// The page attribute table.
+    pub pat: u64,
+    /// Miscellaneous feature enables.
+    pub misc_enable: u64,
+    /// The memory type range registers.
+    pub mtrr: MtrrState,
+}
+
+impl Default for MSRState {
+    fn default() -> Self {
+        MSRState {
+            tsc: 0,
+            sysenter_cs: 0,
+            sysenter_sp: 0,
+            sysenter_ip: 0,
+            star: 0,
+            lstar: 0,
+            cstar: 0,
+            fmask: 0,
+            kernel_gs_base: 0,
+            tsc_aux: 0,
+            // Write-back, write-through, uncached and uncacheable,
+            // repeated twice.
+            pat: 0x0007_0406_0007_0406,
+            // Fast string operations are enabled.
+            misc_enable: 1,
+            mtrr: MtrrState::default(),
+        }
+    }
+}
+
+impl MSRState {
+    /// Returns the index and value of every MSR in this state.
+    pub fn to_msrs(&self) -> Vec<Msr> {
+        let mut state = *self;
+
+        MSRState::indices()
+            .into_iter()
+            .map(|index| Msr::new(index, *state.field(index).unwrap()))
+            .collect()
+    }
+
+    /// Updates the state from a list of MSRs.
+    ///
+    /// Returns an error with the index of the first MSR which
+    /// is not part of this state.
+    pub fn update(&mut self, msrs: &[Msr]) -> Result<(), u32> {
+        for msr in msrs {
+            match self.field(msr.index) {
+                Some(field) => *field = msr.value,
+                None => return Err(msr.index),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads the value of an MSR, if it is part of this state.
+    pub fn get(&self, index: u32) -> Option<u64> {
+        let mut state = *self;
+        state.field(index).map(|field| *field)
+    }
+
+    /// The indices of all of the MSRs in this state.
+    pub fn indices() -> Vec<u32> {
+        let mut indices = vec![
+            TSC,
+            SYSENTER_CS,
+            SYSENTER_SP,
+            SYSENTER_IP,
+            STAR,
+            LSTAR,
+            CSTAR,
+            FMASK,
+            KERNEL_GS_BASE,
+            TSC_AUX,
+            PAT,
+            MISC_ENABLE,
+            MTRR_DEF_TYPE,
+        ];
+
+        indices.extend((0..11).map(fixed_mtrr));
+        indices.extend((0..2 * VARIABLE_MTRRS as u32).map(|n| MTRR_PHYS_BASE0 + n));
+
+        indices
+    }
+
+    /// Returns the field storing an MSR.
+    fn field(&mut self, index: u32) -> Option<&mut u64> {
+        let field = match index {
+            TSC => &mut self.tsc,
+            SYSENTER_CS => &mut self.sysenter_cs,
+            SYSENTER_SP => &mut self.sysenter_sp,
+            SYSENTER_IP => &mut self.sysenter_ip,
+            STAR => &mut self.star,
+            LSTAR => &mut self.lstar,
+            CSTAR => &mut self.cstar,
+            FMASK => &mut self.fmask,
+            KERNEL_GS_BASE => &mut self.kernel_gs_base,
+            TSC_AUX => &mut self.tsc_aux,
+            PAT => &mut self.pat,
+            MISC_ENABLE => &mut self.misc_enable,
+            MTRR_DEF_TYPE => &mut self.mtrr.def_type,
+            _ => {
+                if let Some(n) = (0..11).position(|n| fixed_mtrr(n) == index) {
+                    return Some(&mut self.mtrr.fixed[n]);
+                }
+
+                let n = index.wrapping_sub(MTRR_PHYS_BASE0) as usize;
+
+                if n >= 2 * VARIABLE_MTRRS {
+                    return None;
+                }
+
+                let (ref mut base, ref mut mask) = self.mtrr.variable[n / 2];
+
+                if n.is_multiple_of(2) {
+                    base
+                } else {
+                    mask
+                }
+            }
+        };
+
+        Some(field)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn msr_state_round_trip() {
+        let mut state = MSRState::default();
+        state.lstar = 0xFFFF_8000_0000_1000;
+        state.mtrr.fixed[10] = 0x0606_0606_0606_0606;
+        state.mtrr.variable[7] = (0x8000_0006, 0xFF_8000_0800);
+
+        let msrs = state.to_msrs();
+        assert_eq!(msrs.len(), 13 + 11 + 16);
+        assert!(msrs.contains(&Msr::new(MTRR_FIX_4K_C0000 + 7, 0x0606_0606_0606_0606)));
+        assert!(msrs.contains(&Msr::new(MTRR_PHYS_MASK0 + 14, 0xFF_8000_0800)));
+
+        let mut copy = MSRState::default();
+        copy.update(&msrs).unwrap();
+        assert_eq!(copy, state);
+
+        assert_eq!(copy.get(LSTAR), Some(0xFFFF_8000_0000_1000));
+        assert_eq!(copy.get(EFER), None);
+        assert_eq!(copy.update(&[Msr::new(MTRR_PHYS_BASE0 + 16, 0)]), Err(0x210));
+    }
 }
@@ -3,7 +3,7 @@
 use fpu;
 
 /// Stores information about a memory segment.
-#[derive(Debug, Default, Copy, Clone)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
 pub struct Segment {
     /// The starting physical address of this segment,
     pub base: u64,
@@ -35,6 +35,18 @@ pub struct Segment {
     pub accessed: bool,
     /// Available for OS to use.
     pub available: bool,
+    /// Set if the segment register was loaded with a null selector,
+    /// or the segment is otherwise unusable.
+    pub unusable: bool,
+}
+
+/// The location of a descriptor table, such as the GDT or the IDT.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct DescriptorTable {
+    /// Linear address of the table.
+    pub base: u64,
+    /// Size of the table in bytes, minus one.
+    pub limit: u16,
 }
 
 /// Stores the state for an `x86_64` CPU.
@@ -70,6 +82,14 @@ pub struct State {
     pub fs: Segment,
     /// Extra segment #3.
     pub gs: Segment,
+    /// The task register, pointing to the current task state segment.
+    pub tr: Segment,
+    /// The local descriptor table register.
+    pub ldt: Segment,
+    /// The global descriptor table register.
+    pub gdt: DescriptorTable,
+    /// The interrupt descriptor table register.
+    pub idt: DescriptorTable,
     /// Control register 0.
     pub cr0: Cr0,
     /// Contains the linear address of the last page-fault.
@@ -82,11 +102,19 @@ pub struct State {
     pub cr8: u64,
     /// This register is architectural on AMD64.
     pub efer: Efer,
+    /// The value of the local APIC's base address MSR.
+    pub apic_base: u64,
+    /// Extended control register 0.
+    pub xcr0: Xcr0,
 
     /// The x87 floating-point unit's state.
     pub fpu: fpu::X87State,
     /// The SSE / AVX registers.
     pub sse: fpu::SseState,
+    /// The AVX-512 registers.
+    pub avx512: fpu::Avx512State,
+    /// The protection key rights register for user pages.
+    pub pkru: u32,
 }
 
 impl Default for State {
@@ -121,6 +149,25 @@ impl Default for State {
 
         let ds = common;
 
+        // The task register and the LDT are system segments.
+        let system = Segment {
+            user_system: false,
+            code_data: false,
+            write_read: true,
+            ..common
+        };
+
+        // Busy 16-bit TSS.
+        let tr = Segment {
+            accessed: true,
+            ..system
+        };
+
+        let table = DescriptorTable {
+            base: 0,
+            limit: 0xFFFF,
+        };
+
         State {
             r,
             ip,
@@ -130,6 +177,10 @@ impl Default for State {
             es: ds,
             fs: ds,
             gs: ds,
+            tr,
+            ldt: system,
+            gdt: table,
+            idt: table,
             flags: Flags::default(),
             cr0: Cr0::default(),
             cr2: 0,
@@ -137,8 +188,13 @@ impl Default for State {
             cr4: Cr4::default(),
             cr8: 0,
             efer: Efer::default(),
+            // The APIC is enabled, at its default address, on the bootstrap processor.
+            apic_base: 0xFEE0_0900,
+            xcr0: Xcr0::default(),
             fpu: fpu::X87State::default(),
             sse: fpu::SseState::default(),
+            avx512: fpu::Avx512State::default(),
+            pkru: 0,
         }
     }
 }
@@ -330,3 +386,33 @@ impl Default for Efer {
         Efer::empty()
     }
 }
+
+bitflags! {
+    /// The extended control register 0, which enables the state components
+    /// managed by `XSAVE`.
+    ///
+    /// The same bits are used in the header of the `XSAVE` area, to indicate
+    /// which components are saved.
+    pub struct Xcr0: u64 {
+        /// x87 FPU state. Must always be set.
+        const X87 = 1 << 0;
+        /// SSE state: the XMM registers and MXCSR.
+        const SSE = 1 << 1;
+        /// The upper halves of the YMM registers.
+        const AVX = 1 << 2;
+        /// The AVX-512 opmask registers.
+        const OPMASK = 1 << 5;
+        /// The upper 256 bits of ZMM0 to ZMM15.
+        const ZMM_HI256 = 1 << 6;
+        /// The registers ZMM16 to ZMM31.
+        const HI16_ZMM = 1 << 7;
+        /// The protection key rights register for user pages.
+        const PKRU = 1 << 9;
+    }
+}
+
+impl Default for Xcr0 {
+    fn default() -> Self {
+        Xcr0::X87
+    }
+}
@@ -0,0 +1,329 @@
+//! The I/O APIC, which turns interrupt lines into messages to the local APICs.
+
+use accel::errors::Result;
+use x86::irqchip::{IoApicState, RedirectionEntry, TriggerMode, IOAPIC_PINS};
+
+/// Physical address of the registers, after reset.
+pub const DEFAULT_BASE: u64 = 0xFEC0_0000;
+
+/// Size of the MMIO region of the registers.
+pub const MMIO_SIZE: u64 = 0x1000;
+
+/// Selects the register accessed through `IOWIN`.
+const IOREGSEL: u64 = 0x00;
+/// Window to the selected register.
+const IOWIN: u64 = 0x10;
+/// End-of-interrupt register, which clears the remote IRR of the entries with a vector.
+const EOI: u64 = 0x40;
+
+/// I/O APIC ID.
+const REG_ID: u32 = 0x00;
+/// Version, and number of redirection entries minus one.
+const REG_VERSION: u32 = 0x01;
+/// Arbitration ID.
+const REG_ARBITRATION: u32 = 0x02;
+/// Low half of the first redirection entry. The entries follow, two registers each.
+const REG_REDIRECTION: u32 = 0x10;
+
+/// Version of the I/O APIC, which has an EOI register.
+const VERSION: u32 = 0x20;
+
+/// Bits of a redirection entry which the guest cannot write.
+const READ_ONLY_BITS: u64 = (1 << 12) | (1 << 14);
+
+/// Delivers the interrupts of an I/O APIC to the local APICs.
+pub trait MsiSink {
+    /// Sends the message signaled interrupt for a pin.
+    ///
+    /// The message is the same each time, until the guest reprograms the pin.
+    fn send_msi(&self, pin: u32, address: u64, data: u32) -> Result<()>;
+}
+
+/// A model of an I/O APIC with 24 pins.
+///
+/// The level of a pin is its logical level: the polarity of the
+/// redirection entries is not used.
+#[derive(Debug, Clone)]
+pub struct IoApic {
+    state: IoApicState,
+    /// The level of each pin.
+    levels: u32,
+}
+
+impl IoApic {
+    /// Creates an I/O APIC in its reset state, with all of the pins masked.
+    pub fn new() -> Self {
+        let mut state = IoApicState::default();
+
+        state.base_address = DEFAULT_BASE;
+
+        for entry in state.redirection.iter_mut() {
+            entry.masked = true;
+        }
+
+        IoApic { state, levels: 0 }
+    }
+
+    /// Retrieves the state of the I/O APIC.
+    pub fn state(&self) -> IoApicState {
+        self.state
+    }
+
+    /// Replaces the state of the I/O APIC.
+    ///
+    /// Level-triggered pins whose bit is set in the IRR are considered asserted.
+    pub fn set_state(&mut self, state: &IoApicState) {
+        self.state = *state;
+        self.levels = state.irr;
+    }
+
+    /// Checks if a physical address is in the MMIO region of the registers.
+    pub fn contains(&self, address: u64) -> bool {
+        address >= self.state.base_address && address - self.state.base_address < MMIO_SIZE
+    }
+
+    /// Reads a register, at an offset in the MMIO region.
+    pub fn read(&self, offset: u64) -> u32 {
+        match offset {
+            IOREGSEL => self.state.register_select,
+            IOWIN => self.read_register(self.state.register_select),
+            _ => 0,
+        }
+    }
+
+    /// Writes a register, at an offset in the MMIO region.
+    pub fn write(&mut self, offset: u64, value: u32, sink: &MsiSink) -> Result<()> {
+        match offset {
+            IOREGSEL => self.state.register_select = value & 0xFF,
+            IOWIN => {
+                let register = self.state.register_select;
+                self.write_register(register, value, sink)?;
+            }
+            EOI => self.eoi(value as u8, sink)?,
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    /// Reads an indirect register.
+    fn read_register(&self, register: u32) -> u32 {
+        match register {
+            REG_ID | REG_ARBITRATION => (self.state.id & 0xF) << 24,
+            REG_VERSION => VERSION | (IOAPIC_PINS as u32 - 1) << 16,
+            _ => match Self::redirection_register(register) {
+                Some((pin, high)) => {
+                    let entry = u64::from(self.state.redirection[pin]);
+                    (if high { entry >> 32 } else { entry }) as u32
+                }
+                None => 0,
+            },
+        }
+    }
+
+    /// Writes an indirect register.
+    fn write_register(&mut self, register: u32, value: u32, sink: &MsiSink) -> Result<()> {
+        if register == REG_ID {
+            self.state.id = (value >> 24) & 0xF;
+            return Ok(());
+        }
+
+        let (pin, high) = match Self::redirection_register(register) {
+            Some(location) => location,
+            None => return Ok(()),
+        };
+
+        let old = u64::from(self.state.redirection[pin]);
+
+        let new = if high {
+            old & 0xFFFF_FFFF | u64::from(value) << 32
+        } else {
+            old & !0xFFFF_FFFF | u64::from(value)
+        };
+
+        let mut entry = RedirectionEntry::from(new & !READ_ONLY_BITS | old & READ_ONLY_BITS);
+
+        let bit = 1 << pin;
+
+        match entry.trigger_mode {
+            TriggerMode::Edge => entry.remote_irr = false,
+            // The IRR follows the level of the pin.
+            TriggerMode::Level => self.state.irr = self.state.irr & !bit | self.levels & bit,
+        }
+
+        self.state.redirection[pin] = entry;
+
+        // Unmasking the pin delivers a pending interrupt.
+        self.service(pin, sink)
+    }
+
+    /// Returns the pin configured by a register, and whether it is the high half of the entry.
+    fn redirection_register(register: u32) -> Option<(usize, bool)> {
+        let index = register.checked_sub(REG_REDIRECTION)? as usize;
+
+        if index < IOAPIC_PINS * 2 {
+            Some((index / 2, index % 2 == 1))
+        } else {
+            None
+        }
+    }
+
+    /// Sets the level of a pin.
+    ///
+    /// Edge-triggered pins raise an interrupt when they become asserted,
+    /// while level-triggered pins raise one when they are asserted and the
+    /// previous interrupt got its end-of-interrupt.
+    pub fn set_irq(&mut self, pin: u32, level: bool, sink: &MsiSink) -> Result<()> {
+        let pin = pin as usize;
+
+        if pin >= IOAPIC_PINS {
+            bail!("the I/O APIC has no pin {}", pin);
+        }
+
+        let bit = 1 << pin;
+        let rising = level && self.levels & bit == 0;
+
+        if level {
+            self.levels |= bit;
+        } else {
+            self.levels &= !bit;
+        }
+
+        match self.state.redirection[pin].trigger_mode {
+            TriggerMode::Edge if rising => self.state.irr |= bit,
+            TriggerMode::Edge => (),
+            TriggerMode::Level => self.state.irr = self.state.irr & !bit | self.levels & bit,
+        }
+
+        self.service(pin, sink)
+    }
+
+    /// Signals the end of a level-triggered interrupt with the given vector.
+    ///
+    /// Pins which are still asserted raise another interrupt.
+    pub fn eoi(&mut self, vector: u8, sink: &MsiSink) -> Result<()> {
+        for pin in 0..IOAPIC_PINS {
+            let entry = &mut self.state.redirection[pin];
+
+            if entry.vector == vector && entry.trigger_mode == TriggerMode::Level && entry.remote_irr {
+                entry.remote_irr = false;
+                self.service(pin, sink)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Delivers the interrupt of a pin, if it is pending and can be delivered.
+    fn service(&mut self, pin: usize, sink: &MsiSink) -> Result<()> {
+        let bit = 1 << pin;
+        let entry = &mut self.state.redirection[pin];
+
+        if self.state.irr & bit == 0 || entry.masked {
+            return Ok(());
+        }
+
+        match entry.trigger_mode {
+            // Waits for the end of the previous interrupt.
+            TriggerMode::Level if entry.remote_irr => return Ok(()),
+            TriggerMode::Level => entry.remote_irr = true,
+            TriggerMode::Edge => self.state.irr &= !bit,
+        }
+
+        sink.send_msi(pin as u32, entry.msi_address(), entry.msi_data())
+    }
+}
+
+impl Default for IoApic {
+    fn default() -> Self {
+        IoApic::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    #[derive(Default)]
+    struct Messages(RefCell<Vec<(u32, u64, u32)>>);
+
+    impl MsiSink for Messages {
+        fn send_msi(&self, pin: u32, address: u64, data: u32) -> Result<()> {
+            self.0.borrow_mut().push((pin, address, data));
+            Ok(())
+        }
+    }
+
+    impl Messages {
+        fn take(&self) -> Vec<(u32, u64, u32)> {
+            self.0.borrow_mut().drain(..).collect()
+        }
+    }
+
+    /// Writes an indirect register, as the guest does.
+    fn write(ioapic: &mut IoApic, register: u32, value: u32, sink: &Messages) {
+        ioapic.write(IOREGSEL, register, sink).unwrap();
+        ioapic.write(IOWIN, value, sink).unwrap();
+    }
+
+    #[test]
+    fn registers() {
+        let sink = Messages::default();
+        let mut ioapic = IoApic::new();
+
+        write(&mut ioapic, REG_ID, 0x0200_0000, &sink);
+        assert_eq!(ioapic.state().id, 2);
+
+        ioapic.write(IOREGSEL, REG_VERSION, &sink).unwrap();
+        assert_eq!(ioapic.read(IOWIN), 0x17_0020);
+
+        // Vector 0x31, level-triggered, to APIC ID 1.
+        write(&mut ioapic, REG_REDIRECTION + 7, 0x0100_0000, &sink);
+        write(&mut ioapic, REG_REDIRECTION + 6, 0x1_8031, &sink);
+
+        let entry = ioapic.state().redirection[3];
+        assert_eq!((entry.vector, entry.destination), (0x31, 1));
+        assert!(entry.masked);
+
+        ioapic.write(IOREGSEL, REG_REDIRECTION + 6, &sink).unwrap();
+        assert_eq!(ioapic.read(IOWIN), 0x1_8031);
+
+        assert!(ioapic.contains(0xFEC0_0010));
+        assert!(!ioapic.contains(0xFEC0_1000));
+    }
+
+    #[test]
+    fn edge_and_level_interrupts() {
+        let sink = Messages::default();
+        let mut ioapic = IoApic::new();
+
+        // Pin 1: edge-triggered vector 0x21.
+        write(&mut ioapic, REG_REDIRECTION + 2, 0x21, &sink);
+
+        ioapic.set_irq(1, true, &sink).unwrap();
+        ioapic.set_irq(1, true, &sink).unwrap();
+        ioapic.set_irq(1, false, &sink).unwrap();
+        assert_eq!(sink.take(), [(1, 0xFEE0_0000, 0x21)]);
+
+        // Pin 9: masked level-triggered vector 0x29, asserted before being unmasked.
+        write(&mut ioapic, REG_REDIRECTION + 18, 0x1_8029, &sink);
+        ioapic.set_irq(9, true, &sink).unwrap();
+        assert!(sink.take().is_empty());
+
+        write(&mut ioapic, REG_REDIRECTION + 18, 0x8029, &sink);
+        assert_eq!(sink.take(), [(9, 0xFEE0_0000, 0x8029)]);
+        assert!(ioapic.state().redirection[9].remote_irr);
+
+        // The pin is still asserted after the end-of-interrupt.
+        ioapic.eoi(0x29, &sink).unwrap();
+        assert_eq!(sink.take().len(), 1);
+
+        ioapic.set_irq(9, false, &sink).unwrap();
+        ioapic.write(EOI, 0x29, &sink).unwrap();
+        assert!(sink.take().is_empty());
+        assert!(!ioapic.state().redirection[9].remote_irr);
+
+        assert!(ioapic.set_irq(24, true, &sink).is_err());
+    }
+}
@@ -0,0 +1,896 @@
+//! The local APIC, which delivers interrupts to a processor.
+//!
+//! Its registers are accessed through MMIO in xAPIC mode, and through
+//! MSRs in x2APIC mode. Time is measured in TSC cycles: the timer
+//! counts down once every `divisor` cycles.
+
+use accel::errors::Result;
+use x86::apic::{self, LapicState, Lvt, LvtEntry, TimerMode};
+use x86::irqchip::{DeliveryMode, DestinationMode, TriggerMode};
+use x86::msr;
+
+/// Bit of the `APIC_BASE` MSR set on the bootstrap processor.
+const BASE_BSP: u64 = 1 << 8;
+/// Bit of the `APIC_BASE` MSR which enables x2APIC mode.
+const BASE_X2APIC: u64 = 1 << 10;
+/// Bit of the `APIC_BASE` MSR which enables the APIC.
+const BASE_ENABLE: u64 = 1 << 11;
+/// The address bits of the `APIC_BASE` MSR.
+const BASE_ADDRESS: u64 = 0xF_FFFF_F000;
+
+/// Size of the MMIO region of the registers.
+pub const MMIO_SIZE: u64 = 0x1000;
+
+/// An integrated APIC, with 7 LVT entries.
+const VERSION: u32 = 0x14 | (apic::Lvt::ALL.len() as u32 - 1) << 16;
+
+/// Self IPI register, only available in x2APIC mode.
+const SELF_IPI: usize = 0x3F0;
+
+/// Destination of an interrupt sent to all processors.
+pub const BROADCAST: u32 = 0xFFFF_FFFF;
+
+/// Selects the processors which receive an IPI, instead of its destination.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Shorthand {
+    /// The interrupt is sent to its destination.
+    Destination,
+    /// The interrupt is sent to the sending processor.
+    ToSelf,
+    /// The interrupt is sent to all processors.
+    AllIncludingSelf,
+    /// The interrupt is sent to all processors, except the sending one.
+    AllExcludingSelf,
+}
+
+/// An interrupt sent between APICs: an inter-processor interrupt,
+/// a message signaled interrupt, or an interrupt of the I/O APIC.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Ipi {
+    /// The interrupt vector.
+    pub vector: u8,
+    /// How the interrupt is delivered.
+    pub delivery_mode: DeliveryMode,
+    /// How `destination` is interpreted.
+    pub destination_mode: DestinationMode,
+    /// Whether the interrupt is asserted. Only de-asserted INITs are ignored.
+    pub level: bool,
+    /// When the interrupt is raised.
+    pub trigger_mode: TriggerMode,
+    /// The shorthand used instead of the destination.
+    pub shorthand: Shorthand,
+    /// The APIC ID, or set of logical processors, which receive the interrupt.
+    ///
+    /// `BROADCAST` is sent to all processors.
+    pub destination: u32,
+}
+
+impl Ipi {
+    /// Decodes the value of the interrupt command register.
+    ///
+    /// The destination is the high 8 bits in xAPIC mode, and the high 32 bits in x2APIC mode.
+    pub fn from_icr(icr: u64, x2apic: bool) -> Self {
+        let destination = if x2apic {
+            (icr >> 32) as u32
+        } else {
+            xapic_destination((icr >> 56) as u8)
+        };
+
+        let bit = |n: u32| icr & (1 << n) != 0;
+
+        Ipi {
+            vector: icr as u8,
+            delivery_mode: DeliveryMode::from((icr >> 8) as u8 & 0b111),
+            destination_mode: if bit(11) {
+                DestinationMode::Logical
+            } else {
+                DestinationMode::Physical
+            },
+            level: bit(14),
+            trigger_mode: if bit(15) { TriggerMode::Level } else { TriggerMode::Edge },
+            shorthand: match (icr >> 18) & 0b11 {
+                0 => Shorthand::Destination,
+                1 => Shorthand::ToSelf,
+                2 => Shorthand::AllIncludingSelf,
+                _ => Shorthand::AllExcludingSelf,
+            },
+            destination,
+        }
+    }
+
+    /// Decodes a message signaled interrupt.
+    pub fn from_msi(address: u64, data: u32) -> Self {
+        let level = data & (1 << 15) != 0;
+
+        Ipi {
+            vector: data as u8,
+            delivery_mode: DeliveryMode::from((data >> 8) as u8 & 0b111),
+            destination_mode: if address & (1 << 2) != 0 {
+                DestinationMode::Logical
+            } else {
+                DestinationMode::Physical
+            },
+            level: true,
+            trigger_mode: if level { TriggerMode::Level } else { TriggerMode::Edge },
+            shorthand: Shorthand::Destination,
+            destination: xapic_destination((address >> 12) as u8),
+        }
+    }
+}
+
+/// Converts an 8-bit destination, where 0xFF is a broadcast.
+fn xapic_destination(destination: u8) -> u32 {
+    match destination {
+        0xFF => BROADCAST,
+        destination => u32::from(destination),
+    }
+}
+
+/// Something the processor's APIC asks of the rest of the system.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Event {
+    /// An IPI, to be delivered with `deliver`.
+    Ipi(Ipi),
+    /// The end of a level-triggered interrupt, to be forwarded to the I/O APIC.
+    Eoi(u8),
+}
+
+/// A model of a local APIC.
+#[derive(Debug, Clone)]
+pub struct Lapic {
+    state: LapicState,
+    /// Value of the `APIC_BASE` MSR.
+    apic_base: u64,
+    /// The initial APIC ID, which is the ID in x2APIC mode.
+    id: u32,
+    /// The TSC value at which the timer fires next.
+    timer_deadline: Option<u64>,
+    /// Value of the `TSC_DEADLINE` MSR.
+    tsc_deadline: u64,
+    nmi_pending: bool,
+    init_pending: bool,
+    /// Set after an INIT, until a start-up IPI is received.
+    waiting_for_startup: bool,
+    startup_vector: Option<u8>,
+}
+
+impl Lapic {
+    /// Creates an enabled APIC in its reset state. The processor with ID 0 is the bootstrap processor.
+    pub fn new(id: u32) -> Self {
+        let bsp = if id == 0 { BASE_BSP } else { 0 };
+
+        let mut lapic = Lapic {
+            state: LapicState::default(),
+            apic_base: apic::DEFAULT_BASE | BASE_ENABLE | bsp,
+            id,
+            timer_deadline: None,
+            tsc_deadline: 0,
+            nmi_pending: false,
+            init_pending: false,
+            waiting_for_startup: false,
+            startup_vector: None,
+        };
+
+        lapic.reset();
+        lapic
+    }
+
+    /// Resets the registers, as done by an INIT. The `APIC_BASE` MSR does not change.
+    fn reset(&mut self) {
+        self.state = LapicState::default();
+
+        if self.x2apic_enabled() {
+            self.state.write(apic::ID, self.id);
+            self.state.write(apic::LDR, x2apic_ldr(self.id));
+        } else {
+            self.state.set_id(self.id as u8);
+        }
+
+        self.state.write(apic::VERSION, VERSION);
+        self.state.write(apic::DFR, 0xFFFF_FFFF);
+        self.state.write(apic::SVR, 0xFF);
+
+        for &lvt in Lvt::ALL.iter() {
+            self.state.set_lvt(lvt, LvtEntry { masked: true, ..Default::default() });
+        }
+
+        self.timer_deadline = None;
+        self.tsc_deadline = 0;
+    }
+
+    /// Retrieves the registers.
+    pub fn state(&self) -> LapicState {
+        self.state
+    }
+
+    /// Replaces the registers. The timer restarts from its initial count.
+    pub fn set_state(&mut self, state: &LapicState, now: u64) {
+        self.state = *state;
+        self.update_ppr();
+        self.start_timer(now);
+    }
+
+    /// Value of the `APIC_BASE` MSR.
+    pub fn apic_base(&self) -> u64 {
+        self.apic_base
+    }
+
+    /// Sets the `APIC_BASE` MSR, which enables the APIC and x2APIC mode.
+    ///
+    /// x2APIC mode can only be left by disabling the APIC.
+    pub fn set_apic_base(&mut self, value: u64) -> Result<()> {
+        if value & !(BASE_ADDRESS | BASE_BSP | BASE_X2APIC | BASE_ENABLE) != 0 {
+            bail!("reserved bits set in APIC base {:#x}", value);
+        }
+
+        let enable = value & BASE_ENABLE != 0;
+        let x2apic = value & BASE_X2APIC != 0;
+
+        if x2apic && !enable {
+            bail!("x2APIC mode requires the APIC to be enabled");
+        }
+
+        if self.x2apic_enabled() && enable && !x2apic {
+            bail!("the APIC must be disabled before leaving x2APIC mode");
+        }
+
+        let entering_x2apic = x2apic && !self.x2apic_enabled();
+
+        self.apic_base = value;
+
+        if !enable {
+            // A disabled APIC is software-disabled once it is enabled again.
+            self.reset();
+        } else if entering_x2apic {
+            self.state.write(apic::ID, self.id);
+            self.state.write(apic::LDR, x2apic_ldr(self.id));
+        }
+
+        Ok(())
+    }
+
+    /// Whether the APIC is enabled in the `APIC_BASE` MSR.
+    pub fn enabled(&self) -> bool {
+        self.apic_base & BASE_ENABLE != 0
+    }
+
+    /// Whether the registers are accessed through MSRs.
+    pub fn x2apic_enabled(&self) -> bool {
+        self.apic_base & (BASE_ENABLE | BASE_X2APIC) == BASE_ENABLE | BASE_X2APIC
+    }
+
+    /// Whether the APIC is both enabled and software-enabled, and accepts interrupts.
+    fn accepts_interrupts(&self) -> bool {
+        self.enabled() && self.state.software_enabled()
+    }
+
+    /// Checks if a physical address is in the MMIO region of the registers.
+    ///
+    /// The registers are not mapped in x2APIC mode.
+    pub fn contains(&self, address: u64) -> bool {
+        let base = self.apic_base & BASE_ADDRESS;
+        self.enabled() && !self.x2apic_enabled() && address >= base && address - base < MMIO_SIZE
+    }
+
+    /// Reads a register, at an offset in the MMIO region.
+    pub fn read(&self, offset: u64, now: u64) -> u32 {
+        match offset as usize & !0xF {
+            offset if offset < apic::PAGE_SIZE => self.read_register(offset, now),
+            _ => 0,
+        }
+    }
+
+    /// Writes a register, at an offset in the MMIO region.
+    pub fn write(&mut self, offset: u64, value: u32, now: u64) -> Option<Event> {
+        match offset as usize & !0xF {
+            apic::ICR_LOW => {
+                self.state.write(apic::ICR_LOW, value & !(1 << 12));
+
+                let icr = u64::from(self.state.read(apic::ICR_HIGH)) << 32 | u64::from(value);
+                Some(Event::Ipi(Ipi::from_icr(icr, false)))
+            }
+            apic::ICR_HIGH => {
+                self.state.write(apic::ICR_HIGH, value & 0xFF00_0000);
+                None
+            }
+            apic::ID => {
+                self.state.write(apic::ID, value & 0xFF00_0000);
+                None
+            }
+            apic::LDR => {
+                self.state.write(apic::LDR, value & 0xFF00_0000);
+                None
+            }
+            apic::DFR => {
+                self.state.write(apic::DFR, value | 0x0FFF_FFFF);
+                None
+            }
+            offset => self.write_register(offset, value, now),
+        }
+    }
+
+    /// Checks if an MSR is emulated by the APIC.
+    pub fn handles_msr(index: u32) -> bool {
+        match index {
+            msr::APIC_BASE | msr::TSC_DEADLINE => true,
+            index => (apic::X2APIC_MSR_BASE..apic::X2APIC_MSR_BASE + 0x100).contains(&index),
+        }
+    }
+
+    /// Reads one of the APIC's MSRs.
+    pub fn read_msr(&self, index: u32, now: u64) -> Result<u64> {
+        match index {
+            msr::APIC_BASE => return Ok(self.apic_base),
+            msr::TSC_DEADLINE => return Ok(self.tsc_deadline),
+            _ => (),
+        }
+
+        let offset = self.x2apic_offset(index)?;
+
+        match offset {
+            apic::ICR_LOW => {
+                let high = u64::from(self.state.read(apic::ICR_HIGH));
+                Ok(high << 32 | u64::from(self.state.read(apic::ICR_LOW)))
+            }
+            apic::ID | apic::VERSION | apic::TPR | apic::PPR | apic::LDR | apic::SVR | apic::ESR => {
+                Ok(u64::from(self.read_register(offset, now)))
+            }
+            apic::ISR..=0x270 | apic::TIMER_INITIAL_COUNT | apic::TIMER_CURRENT_COUNT | apic::TIMER_DIVIDE => {
+                Ok(u64::from(self.read_register(offset, now)))
+            }
+            offset if Lvt::ALL.iter().any(|lvt| lvt.offset() == offset) => {
+                Ok(u64::from(self.read_register(offset, now)))
+            }
+            _ => bail!("cannot read x2APIC MSR {:#x}", index),
+        }
+    }
+
+    /// Writes one of the APIC's MSRs.
+    pub fn write_msr(&mut self, index: u32, value: u64, now: u64) -> Result<Option<Event>> {
+        match index {
+            msr::APIC_BASE => {
+                self.set_apic_base(value)?;
+                return Ok(None);
+            }
+            msr::TSC_DEADLINE => {
+                if self.state.lvt(Lvt::Timer).timer_mode == TimerMode::TscDeadline {
+                    self.tsc_deadline = value;
+                    self.timer_deadline = if value == 0 { None } else { Some(value) };
+                }
+                return Ok(None);
+            }
+            _ => (),
+        }
+
+        let offset = self.x2apic_offset(index)?;
+
+        if offset != apic::ICR_LOW && value >> 32 != 0 {
+            bail!("cannot write {:#x} to x2APIC MSR {:#x}", value, index);
+        }
+
+        let event = match offset {
+            apic::ICR_LOW => {
+                self.state.write(apic::ICR_LOW, value as u32 & !(1 << 12));
+                self.state.write(apic::ICR_HIGH, (value >> 32) as u32);
+
+                Some(Event::Ipi(Ipi::from_icr(value, true)))
+            }
+            SELF_IPI => Some(Event::Ipi(Ipi {
+                vector: value as u8,
+                delivery_mode: DeliveryMode::Fixed,
+                destination_mode: DestinationMode::Physical,
+                level: true,
+                trigger_mode: TriggerMode::Edge,
+                shorthand: Shorthand::ToSelf,
+                destination: 0,
+            })),
+            apic::EOI if value != 0 => bail!("cannot write {:#x} to the x2APIC EOI register", value),
+            apic::TPR | apic::EOI | apic::SVR | apic::ESR | apic::TIMER_INITIAL_COUNT | apic::TIMER_DIVIDE => {
+                self.write_register(offset, value as u32, now)
+            }
+            offset if Lvt::ALL.iter().any(|lvt| lvt.offset() == offset) => {
+                self.write_register(offset, value as u32, now)
+            }
+            _ => bail!("cannot write x2APIC MSR {:#x}", index),
+        };
+
+        Ok(event)
+    }
+
+    /// Returns the register accessed through an x2APIC MSR.
+    fn x2apic_offset(&self, index: u32) -> Result<usize> {
+        if !Self::handles_msr(index) {
+            bail!("MSR {:#x} is not an x2APIC register", index);
+        }
+
+        if !self.x2apic_enabled() {
+            bail!("cannot access MSR {:#x}, x2APIC mode is disabled", index);
+        }
+
+        Ok((index - apic::X2APIC_MSR_BASE) as usize * 0x10)
+    }
+
+    /// Reads a register, in either mode.
+    fn read_register(&self, offset: usize, now: u64) -> u32 {
+        match offset {
+            apic::TIMER_CURRENT_COUNT => self.timer_current_count(now),
+            apic::APR | apic::EOI | SELF_IPI => 0,
+            offset => self.state.read(offset),
+        }
+    }
+
+    /// Writes a register which behaves the same in both modes.
+    fn write_register(&mut self, offset: usize, value: u32, now: u64) -> Option<Event> {
+        match offset {
+            apic::TPR => {
+                self.state.set_tpr(value as u8);
+                self.update_ppr();
+            }
+            apic::EOI => return self.eoi(),
+            apic::SVR => {
+                self.state.write(apic::SVR, value & 0x3FF);
+
+                // Software-disabling the APIC masks all of the LVT entries.
+                if !self.state.software_enabled() {
+                    for &lvt in Lvt::ALL.iter() {
+                        let entry = self.state.lvt(lvt);
+                        self.state.set_lvt(lvt, LvtEntry { masked: true, ..entry });
+                    }
+                }
+            }
+            // Writing the error status register latches the errors, of which there are none.
+            apic::ESR => self.state.write(apic::ESR, 0),
+            apic::TIMER_INITIAL_COUNT => {
+                if self.state.lvt(Lvt::Timer).timer_mode != TimerMode::TscDeadline {
+                    self.state.set_timer_initial_count(value);
+                    self.start_timer(now);
+                }
+            }
+            apic::TIMER_DIVIDE => self.state.write(apic::TIMER_DIVIDE, value & 0b1011),
+            offset => {
+                if let Some(&lvt) = Lvt::ALL.iter().find(|lvt| lvt.offset() == offset) {
+                    self.write_lvt(lvt, value);
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Writes an entry of the local vector table.
+    fn write_lvt(&mut self, lvt: Lvt, value: u32) {
+        let old = self.state.lvt(lvt);
+        let mut entry = LvtEntry::from(value);
+
+        // The status bits are read-only.
+        entry.delivery_pending = old.delivery_pending;
+        entry.remote_irr = old.remote_irr;
+
+        if !self.state.software_enabled() {
+            entry.masked = true;
+        }
+
+        if lvt != Lvt::Timer {
+            entry.timer_mode = TimerMode::OneShot;
+        } else if entry.timer_mode != old.timer_mode {
+            // Changing the mode stops the timer.
+            self.timer_deadline = None;
+            self.tsc_deadline = 0;
+            self.state.set_timer_initial_count(0);
+        }
+
+        self.state.set_lvt(lvt, entry);
+    }
+
+    /// The value of CR8, which is the priority class of the TPR.
+    pub fn cr8(&self) -> u64 {
+        u64::from(self.state.tpr() >> 4)
+    }
+
+    /// Sets the TPR from the value of CR8.
+    pub fn set_cr8(&mut self, cr8: u64) {
+        self.state.set_tpr((cr8 as u8 & 0xF) << 4);
+        self.update_ppr();
+    }
+
+    /// Computes the processor priority, from the TPR and the highest in-service interrupt.
+    fn update_ppr(&mut self) {
+        let tpr = self.state.tpr();
+        let isrv = self.state.isr().highest().unwrap_or(0);
+
+        let ppr = if tpr & 0xF0 >= isrv & 0xF0 { tpr } else { isrv & 0xF0 };
+
+        self.state.write(apic::PPR, u32::from(ppr));
+    }
+
+    /// Checks if the APIC is the destination of an interrupt, ignoring shorthands.
+    pub fn matches(&self, destination: u32, mode: DestinationMode) -> bool {
+        if destination == BROADCAST {
+            return true;
+        }
+
+        let ldr = self.state.read(apic::LDR);
+
+        match mode {
+            DestinationMode::Physical if self.x2apic_enabled() => destination == self.id,
+            DestinationMode::Physical => destination == u32::from(self.state.id()),
+            // In x2APIC mode, the high 16 bits select a cluster of up to 16 processors.
+            DestinationMode::Logical if self.x2apic_enabled() => {
+                destination >> 16 == ldr >> 16 && destination & ldr & 0xFFFF != 0
+            }
+            DestinationMode::Logical => {
+                let logical = (ldr >> 24) as u8;
+                let destination = destination as u8;
+
+                if self.state.read(apic::DFR) >> 28 == 0xF {
+                    // Flat model: each bit is a processor.
+                    logical & destination != 0
+                } else {
+                    // Cluster model: the high 4 bits are the cluster.
+                    logical >> 4 == destination >> 4 && logical & destination & 0xF != 0
+                }
+            }
+        }
+    }
+
+    /// Accepts an interrupt sent to this APIC.
+    pub fn accept(&mut self, ipi: &Ipi) {
+        match ipi.delivery_mode {
+            DeliveryMode::Fixed | DeliveryMode::LowestPriority => {
+                self.raise(ipi.vector, ipi.trigger_mode);
+            }
+            DeliveryMode::Nmi => self.nmi_pending = true,
+            // Only the de-assertion of a level-triggered INIT does nothing.
+            DeliveryMode::Init if ipi.trigger_mode == TriggerMode::Level && !ipi.level => (),
+            DeliveryMode::Init => {
+                self.reset();
+                self.init_pending = true;
+                self.waiting_for_startup = true;
+            }
+            DeliveryMode::StartUp if self.waiting_for_startup => {
+                self.waiting_for_startup = false;
+                self.startup_vector = Some(ipi.vector);
+            }
+            // ExtINT interrupts are acknowledged from the PIC.
+            _ => (),
+        }
+    }
+
+    /// Requests an interrupt with a vector.
+    fn raise(&mut self, vector: u8, trigger_mode: TriggerMode) {
+        if !self.accepts_interrupts() {
+            return;
+        }
+
+        let mut irr = self.state.irr();
+        irr.set(vector, true);
+        self.state.set_irr(&irr);
+
+        let mut tmr = self.state.tmr();
+        tmr.set(vector, trigger_mode == TriggerMode::Level);
+        self.state.set_tmr(&tmr);
+    }
+
+    /// Returns the highest priority interrupt which can be delivered to the processor.
+    pub fn pending_interrupt(&self) -> Option<u8> {
+        if !self.accepts_interrupts() {
+            return None;
+        }
+
+        let vector = self.state.irr().highest()?;
+
+        if vector & 0xF0 > self.state.ppr() & 0xF0 {
+            Some(vector)
+        } else {
+            None
+        }
+    }
+
+    /// Moves the pending interrupt to the in-service register, and returns its vector.
+    ///
+    /// It must then be delivered to the processor.
+    pub fn acknowledge(&mut self) -> Option<u8> {
+        let vector = self.pending_interrupt()?;
+
+        let mut irr = self.state.irr();
+        irr.set(vector, false);
+        self.state.set_irr(&irr);
+
+        let mut isr = self.state.isr();
+        isr.set(vector, true);
+        self.state.set_isr(&isr);
+
+        self.update_ppr();
+
+        Some(vector)
+    }
+
+    /// Ends the highest priority in-service interrupt.
+    fn eoi(&mut self) -> Option<Event> {
+        let mut isr = self.state.isr();
+        let vector = isr.highest()?;
+
+        isr.set(vector, false);
+        self.state.set_isr(&isr);
+
+        self.update_ppr();
+
+        if self.state.tmr().get(vector) {
+            Some(Event::Eoi(vector))
+        } else {
+            None
+        }
+    }
+
+    /// Checks if the processor accepts the interrupts of the PIC, through LINT0.
+    ///
+    /// This is also the case when the APIC is disabled.
+    pub fn accepts_pic_interrupts(&self) -> bool {
+        let lint0 = self.state.lvt(Lvt::Lint0);
+        !self.enabled() || (!lint0.masked && lint0.delivery_mode == DeliveryMode::ExtInt)
+    }
+
+    /// Returns true once after an NMI was received.
+    pub fn take_nmi(&mut self) -> bool {
+        ::std::mem::replace(&mut self.nmi_pending, false)
+    }
+
+    /// Returns true once after an INIT was received. The processor must then
+    /// be reset, and wait for a start-up IPI.
+    pub fn take_init(&mut self) -> bool {
+        ::std::mem::replace(&mut self.init_pending, false)
+    }
+
+    /// Returns the vector of a start-up IPI received after an INIT, once.
+    ///
+    /// The processor starts executing at address `vector << 12`.
+    pub fn take_startup(&mut self) -> Option<u8> {
+        self.startup_vector.take()
+    }
+
+    /// The TSC value at which `tick` must be called next, if the timer is running.
+    pub fn timer_deadline(&self) -> Option<u64> {
+        self.timer_deadline
+    }
+
+    /// Starts counting down from the initial count.
+    fn start_timer(&mut self, now: u64) {
+        let initial = u64::from(self.state.timer_initial_count());
+
+        self.timer_deadline = match self.state.lvt(Lvt::Timer).timer_mode {
+            TimerMode::TscDeadline if self.tsc_deadline != 0 => Some(self.tsc_deadline),
+            TimerMode::TscDeadline => None,
+            _ if initial == 0 => None,
+            _ => Some(now + initial * u64::from(self.state.timer_divisor())),
+        };
+    }
+
+    /// The current count of the timer.
+    fn timer_current_count(&self, now: u64) -> u32 {
+        match (self.state.lvt(Lvt::Timer).timer_mode, self.timer_deadline) {
+            (TimerMode::TscDeadline, _) => 0,
+            (_, Some(deadline)) if deadline > now => {
+                ((deadline - now) / u64::from(self.state.timer_divisor())) as u32
+            }
+            _ => 0,
+        }
+    }
+
+    /// Raises the timer interrupt if the timer expired.
+    ///
+    /// A periodic timer which expired several times only raises one interrupt.
+    pub fn tick(&mut self, now: u64) {
+        let deadline = match self.timer_deadline {
+            Some(deadline) if deadline <= now => deadline,
+            _ => return,
+        };
+
+        let entry = self.state.lvt(Lvt::Timer);
+
+        self.timer_deadline = match entry.timer_mode {
+            TimerMode::Periodic => {
+                let initial = u64::from(self.state.timer_initial_count());
+                let period = initial * u64::from(self.state.timer_divisor());
+
+                Some(deadline + period * ((now - deadline) / period + 1))
+            }
+            TimerMode::TscDeadline => {
+                self.tsc_deadline = 0;
+                None
+            }
+            _ => None,
+        };
+
+        if !entry.masked {
+            self.raise(entry.vector, TriggerMode::Edge);
+        }
+    }
+}
+
+/// The logical destination of an x2APIC, derived from its ID.
+fn x2apic_ldr(id: u32) -> u32 {
+    (id >> 4) << 16 | 1 << (id & 0xF)
+}
+
+/// Delivers an interrupt to the APICs which are its destination.
+///
+/// `source` is the index of the APIC which sent the IPI, if any.
+/// Lowest priority interrupts go to the destination with the lowest task priority.
+pub fn deliver(lapics: &mut [Lapic], source: Option<usize>, ipi: &Ipi) {
+    let targets: Vec<usize> = (0..lapics.len())
+        .filter(|&i| match ipi.shorthand {
+            Shorthand::Destination => lapics[i].matches(ipi.destination, ipi.destination_mode),
+            Shorthand::ToSelf => Some(i) == source,
+            Shorthand::AllIncludingSelf => true,
+            Shorthand::AllExcludingSelf => Some(i) != source,
+        })
+        .collect();
+
+    if ipi.delivery_mode == DeliveryMode::LowestPriority {
+        if let Some(&i) = targets.iter().min_by_key(|&&i| lapics[i].state.tpr()) {
+            lapics[i].accept(ipi);
+        }
+    } else {
+        for i in targets {
+            lapics[i].accept(ipi);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Software-enables an APIC in xAPIC mode.
+    fn enable(lapic: &mut Lapic) {
+        assert_eq!(lapic.write(apic::SVR as u64, 0x1FF, 0), None);
+        assert!(lapic.state().software_enabled());
+    }
+
+    fn fixed(vector: u8, trigger_mode: TriggerMode) -> Ipi {
+        Ipi::from_msi(0xFEE0_0000, u32::from(vector) | ((trigger_mode == TriggerMode::Level) as u32 * 0x8000))
+    }
+
+    #[test]
+    fn priorities() {
+        let mut lapic = Lapic::new(0);
+        assert!(lapic.contains(0xFEE0_0020));
+        assert_eq!(lapic.read(apic::ID as u64, 0), 0);
+        assert_eq!(lapic.read(apic::VERSION as u64, 0), 0x6_0014);
+
+        // Interrupts are ignored until the APIC is software-enabled.
+        lapic.accept(&fixed(0x31, TriggerMode::Edge));
+        assert_eq!(lapic.pending_interrupt(), None);
+
+        enable(&mut lapic);
+        lapic.write(apic::TPR as u64, 0x40, 0);
+        assert_eq!(lapic.cr8(), 4);
+
+        lapic.accept(&fixed(0x31, TriggerMode::Edge));
+        lapic.accept(&fixed(0x45, TriggerMode::Level));
+        assert_eq!(lapic.pending_interrupt(), None);
+
+        lapic.set_cr8(3);
+        assert_eq!(lapic.read(apic::TPR as u64, 0), 0x30);
+        assert_eq!(lapic.acknowledge(), Some(0x45));
+        assert_eq!(lapic.read(apic::PPR as u64, 0), 0x40);
+        assert_eq!(lapic.pending_interrupt(), None);
+
+        // The end of a level-triggered interrupt is forwarded to the I/O APIC.
+        assert_eq!(lapic.write(apic::EOI as u64, 0, 0), Some(Event::Eoi(0x45)));
+        assert_eq!(lapic.read(apic::PPR as u64, 0), 0x30);
+
+        lapic.set_cr8(0);
+        assert_eq!(lapic.acknowledge(), Some(0x31));
+        assert_eq!(lapic.write(apic::EOI as u64, 0, 0), None);
+        assert_eq!(lapic.acknowledge(), None);
+
+        // The PIC is connected through LINT0 in virtual wire mode.
+        assert!(!lapic.accepts_pic_interrupts());
+        lapic.write(Lvt::Lint0.offset() as u64, 0x700, 0);
+        assert!(lapic.accepts_pic_interrupts());
+    }
+
+    #[test]
+    fn ipis() {
+        let mut lapics: Vec<_> = (0..3).map(Lapic::new).collect();
+
+        for lapic in &mut lapics {
+            enable(lapic);
+        }
+
+        // The bootstrap processor switches to x2APIC mode.
+        let bsp = &mut lapics[0];
+        assert!(bsp.read_msr(0x802, 0).is_err());
+        assert!(bsp.write_msr(msr::APIC_BASE, 0xFEE0_0D00, 0).is_ok());
+        assert!(!bsp.contains(0xFEE0_0000));
+        assert_eq!(bsp.read_msr(0x80D, 0).unwrap(), 1);
+        assert!(bsp.write_msr(msr::APIC_BASE, 0xFEE0_0900, 0).is_err());
+
+        // INIT and start-up IPIs to processor 2.
+        for &icr in &[0x2_0000_4500, 0x2_0000_4608] {
+            match lapics[0].write_msr(0x830, icr, 0).unwrap() {
+                Some(Event::Ipi(ipi)) => deliver(&mut lapics, Some(0), &ipi),
+                event => panic!("unexpected event: {:?}", event),
+            }
+        }
+
+        assert!(!lapics[1].take_init());
+        assert!(lapics[2].take_init());
+        assert_eq!(lapics[2].take_startup(), Some(0x08));
+        assert!(!lapics[2].state().software_enabled());
+
+        // A fixed IPI to all other processors, from processor 1.
+        let ipi = match lapics[1].write(apic::ICR_LOW as u64, 0xC_0050, 0) {
+            Some(Event::Ipi(ipi)) => ipi,
+            event => panic!("unexpected event: {:?}", event),
+        };
+        deliver(&mut lapics, Some(1), &ipi);
+
+        assert_eq!(lapics[0].pending_interrupt(), Some(0x50));
+        assert_eq!(lapics[1].pending_interrupt(), None);
+
+        // An NMI and a self IPI.
+        let nmi = Ipi { delivery_mode: DeliveryMode::Nmi, destination: 1, ..ipi };
+        deliver(&mut lapics, None, &Ipi { shorthand: Shorthand::Destination, ..nmi });
+        assert!(lapics[1].take_nmi());
+        assert!(!lapics[1].take_nmi());
+
+        match lapics[0].write_msr(0x83F, 0x60, 0).unwrap() {
+            Some(Event::Ipi(ipi)) => deliver(&mut lapics, Some(0), &ipi),
+            event => panic!("unexpected event: {:?}", event),
+        }
+        assert_eq!(lapics[0].pending_interrupt(), Some(0x60));
+
+        // Logical flat destinations.
+        lapics[1].write(apic::LDR as u64, 0x0200_0000, 0);
+        assert!(lapics[1].matches(0x06, DestinationMode::Logical));
+        assert!(!lapics[1].matches(0x01, DestinationMode::Logical));
+        assert!(lapics[1].matches(BROADCAST, DestinationMode::Physical));
+    }
+
+    #[test]
+    fn timer() {
+        let mut lapic = Lapic::new(0);
+        enable(&mut lapic);
+
+        // One-shot, dividing by 2.
+        lapic.write(Lvt::Timer.offset() as u64, 0xEC, 0);
+        lapic.write(apic::TIMER_DIVIDE as u64, 0, 0);
+        lapic.write(apic::TIMER_INITIAL_COUNT as u64, 100, 1000);
+
+        assert_eq!(lapic.timer_deadline(), Some(1200));
+        assert_eq!(lapic.read(apic::TIMER_CURRENT_COUNT as u64, 1100), 50);
+
+        lapic.tick(1199);
+        assert_eq!(lapic.pending_interrupt(), None);
+        lapic.tick(1200);
+        assert_eq!(lapic.acknowledge(), Some(0xEC));
+        assert_eq!(lapic.timer_deadline(), None);
+        lapic.write(apic::EOI as u64, 0, 0);
+
+        // Periodic, dividing by 1.
+        lapic.write(Lvt::Timer.offset() as u64, 0x2_00EC, 0);
+        lapic.write(apic::TIMER_DIVIDE as u64, 0b1011, 0);
+        lapic.write(apic::TIMER_INITIAL_COUNT as u64, 10, 0);
+
+        lapic.tick(25);
+        assert_eq!(lapic.timer_deadline(), Some(30));
+        assert_eq!(lapic.acknowledge(), Some(0xEC));
+        lapic.write(apic::EOI as u64, 0, 0);
+
+        // TSC deadline, which is only accessible through the MSR.
+        lapic.write(Lvt::Timer.offset() as u64, 0x4_00EC, 0);
+        assert_eq!(lapic.timer_deadline(), None);
+
+        lapic.write(apic::TIMER_INITIAL_COUNT as u64, 10, 0);
+        assert_eq!(lapic.timer_deadline(), None);
+
+        lapic.write_msr(msr::TSC_DEADLINE, 500, 0).unwrap();
+        lapic.tick(499);
+        assert_eq!(lapic.pending_interrupt(), None);
+        lapic.tick(500);
+        assert_eq!(lapic.pending_interrupt(), Some(0xEC));
+        assert_eq!(lapic.read_msr(msr::TSC_DEADLINE, 500).unwrap(), 0);
+    }
+}
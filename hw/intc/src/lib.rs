@@ -0,0 +1,24 @@
+//! Interrupt controllers, emulated in user space.
+//!
+//! These models are used when the accelerator does not emulate some of
+//! the interrupt controllers itself. Their state uses the same types as
+//! the accelerators', so that it can be moved between the two.
+
+#![warn(missing_docs, missing_debug_implementations)]
+#![cfg_attr(feature = "cargo-clippy", warn(clippy))]
+
+#[macro_use]
+extern crate error_chain;
+
+extern crate accel;
+
+extern crate vm_x86 as x86;
+
+pub mod ioapic;
+pub use ioapic::{IoApic, MsiSink};
+
+pub mod lapic;
+pub use lapic::Lapic;
+
+pub mod pic;
+pub use pic::Pic;
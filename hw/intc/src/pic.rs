@@ -0,0 +1,534 @@
+//! A pair of cascaded 8259A Programmable Interrupt Controllers.
+//!
+//! The slave is connected to line 2 of the master, and the master's
+//! output is connected to the processor's interrupt pin.
+
+use accel::errors::Result;
+use x86::irqchip::PicState;
+
+/// Command port of the master PIC.
+pub const MASTER_COMMAND: u16 = 0x20;
+/// Data port of the master PIC.
+pub const MASTER_DATA: u16 = 0x21;
+/// Command port of the slave PIC.
+pub const SLAVE_COMMAND: u16 = 0xA0;
+/// Data port of the slave PIC.
+pub const SLAVE_DATA: u16 = 0xA1;
+/// Edge/level control register of the master PIC.
+pub const MASTER_ELCR: u16 = 0x4D0;
+/// Edge/level control register of the slave PIC.
+pub const SLAVE_ELCR: u16 = 0x4D1;
+
+/// The line of the master which is connected to the slave.
+const CASCADE_LINE: u8 = 2;
+
+/// Lines whose trigger mode can be changed. The timer, keyboard,
+/// cascade and RTC lines are always edge-triggered.
+const MASTER_ELCR_MASK: u8 = 0xF8;
+const SLAVE_ELCR_MASK: u8 = 0xDE;
+
+/// Emulates both PICs of a PC.
+///
+/// The levels of the lines are their logical levels, as seen by the PICs.
+///
+/// The state has the same meaning as the accelerators' in-kernel PICs,
+/// so it can be moved with `get_pic_state` and `set_pic_state`.
+#[derive(Debug, Clone)]
+pub struct Pic {
+    /// The master is first, the slave second.
+    chips: [PicState; 2],
+}
+
+impl Pic {
+    /// Creates the PICs in their reset state.
+    pub fn new() -> Self {
+        let mut chips = [PicState::default(); 2];
+
+        chips[0].elcr_mask = MASTER_ELCR_MASK;
+        chips[1].elcr_mask = SLAVE_ELCR_MASK;
+
+        Pic { chips }
+    }
+
+    /// Retrieves the state of the master and of the slave.
+    pub fn state(&self) -> [PicState; 2] {
+        self.chips
+    }
+
+    /// Replaces the state of the master and of the slave.
+    pub fn set_state(&mut self, state: &[PicState; 2]) {
+        self.chips = *state;
+        self.update();
+    }
+
+    /// Checks if the PICs handle an I/O port.
+    pub fn contains(&self, port: u16) -> bool {
+        const PORTS: &[u16] = &[
+            MASTER_COMMAND,
+            MASTER_DATA,
+            SLAVE_COMMAND,
+            SLAVE_DATA,
+            MASTER_ELCR,
+            SLAVE_ELCR,
+        ];
+
+        PORTS.contains(&port)
+    }
+
+    /// Reads from one of the PICs' I/O ports.
+    pub fn read(&mut self, port: u16) -> u8 {
+        let (index, data) = match port {
+            MASTER_COMMAND => (0, false),
+            MASTER_DATA => (0, true),
+            SLAVE_COMMAND => (1, false),
+            SLAVE_DATA => (1, true),
+            MASTER_ELCR => return self.chips[0].elcr,
+            SLAVE_ELCR => return self.chips[1].elcr,
+            _ => return 0xFF,
+        };
+
+        if self.chips[index].poll {
+            self.chips[index].poll = false;
+            return self.poll(index);
+        }
+
+        let chip = &self.chips[index];
+
+        if data {
+            chip.imr
+        } else if chip.read_isr {
+            chip.isr
+        } else {
+            chip.irr
+        }
+    }
+
+    /// Writes to one of the PICs' I/O ports.
+    pub fn write(&mut self, port: u16, value: u8) {
+        match port {
+            MASTER_COMMAND => self.write_command(0, value),
+            MASTER_DATA => self.write_data(0, value),
+            SLAVE_COMMAND => self.write_command(1, value),
+            SLAVE_DATA => self.write_data(1, value),
+            MASTER_ELCR => self.chips[0].elcr = value & self.chips[0].elcr_mask,
+            SLAVE_ELCR => self.chips[1].elcr = value & self.chips[1].elcr_mask,
+            _ => (),
+        }
+    }
+
+    /// Handles an initialization command word 1, or an operation command word 2 or 3.
+    fn write_command(&mut self, index: usize, value: u8) {
+        let chip = &mut self.chips[index];
+
+        if value & 0x10 != 0 {
+            // ICW1 resets the PIC, except for the ELCR.
+            *chip = PicState {
+                // A level-triggered line stays requested while it is asserted.
+                irr: chip.irr & chip.elcr,
+                elcr: chip.elcr,
+                elcr_mask: chip.elcr_mask,
+                init4: value & 0x01 != 0,
+                init_state: 1,
+                ..Default::default()
+            };
+        } else if value & 0x08 != 0 {
+            // OCW3
+            if value & 0x04 != 0 {
+                chip.poll = true;
+            }
+
+            if value & 0x02 != 0 {
+                chip.read_isr = value & 0x01 != 0;
+            }
+
+            if value & 0x40 != 0 {
+                chip.special_mask = value & 0x20 != 0;
+            }
+        } else {
+            // OCW2
+            let line = value & 0x07;
+
+            match value >> 5 {
+                // Rotate in automatic EOI mode (clear / set).
+                0 => chip.rotate_on_auto_eoi = false,
+                4 => chip.rotate_on_auto_eoi = true,
+                // Non-specific EOI, and rotate on non-specific EOI.
+                command @ 1 | command @ 5 => {
+                    if let Some(priority) = priority(chip, chip.isr) {
+                        let line = (priority + chip.priority_add) & 7;
+
+                        if command == 5 {
+                            chip.priority_add = (line + 1) & 7;
+                        }
+
+                        chip.isr &= !(1 << line);
+                    }
+                }
+                // Specific EOI.
+                3 => chip.isr &= !(1 << line),
+                // Set priority, making the line the one with the lowest priority.
+                6 => chip.priority_add = (line + 1) & 7,
+                // Rotate on specific EOI.
+                7 => {
+                    chip.priority_add = (line + 1) & 7;
+                    chip.isr &= !(1 << line);
+                }
+                // No operation.
+                _ => (),
+            }
+        }
+
+        self.update();
+    }
+
+    /// Handles an initialization command word 2, 3 or 4, or an operation command word 1.
+    fn write_data(&mut self, index: usize, value: u8) {
+        let chip = &mut self.chips[index];
+
+        match chip.init_state {
+            // OCW1
+            0 => chip.imr = value,
+            // ICW2
+            1 => {
+                chip.irq_base = value & 0xF8;
+                chip.init_state = 2;
+            }
+            // ICW3, which does not matter since the PICs are always cascaded.
+            2 => chip.init_state = if chip.init4 { 3 } else { 0 },
+            // ICW4
+            _ => {
+                chip.special_fully_nested = value & 0x10 != 0;
+                chip.auto_eoi = value & 0x02 != 0;
+                chip.init_state = 0;
+            }
+        }
+
+        self.update();
+    }
+
+    /// Sets the level of an interrupt line, from 0 to 7 for the master,
+    /// and from 8 to 15 for the slave.
+    pub fn set_irq(&mut self, irq: u32, level: bool) -> Result<()> {
+        if irq >= 16 || irq == u32::from(CASCADE_LINE) {
+            bail!("the PICs have no line {}", irq);
+        }
+
+        set_line(&mut self.chips[irq as usize / 8], irq as u8 % 8, level);
+
+        self.update();
+
+        Ok(())
+    }
+
+    /// Checks if the master requests an interrupt from the processor.
+    pub fn output(&self) -> bool {
+        pending_line(&self.chips[0], true).is_some()
+    }
+
+    /// Acknowledges the pending interrupt, and returns its vector.
+    ///
+    /// If no interrupt is pending, the vector of line 7 is returned,
+    /// as a spurious interrupt.
+    pub fn acknowledge(&mut self) -> u8 {
+        let vector = match pending_line(&self.chips[0], true) {
+            Some(CASCADE_LINE) => {
+                acknowledge(&mut self.chips[0], CASCADE_LINE);
+
+                let line = match pending_line(&self.chips[1], false) {
+                    Some(line) => {
+                        acknowledge(&mut self.chips[1], line);
+                        line
+                    }
+                    None => 7,
+                };
+
+                self.chips[1].irq_base + line
+            }
+            Some(line) => {
+                acknowledge(&mut self.chips[0], line);
+                self.chips[0].irq_base + line
+            }
+            None => self.chips[0].irq_base + 7,
+        };
+
+        self.update();
+
+        vector
+    }
+
+    /// Acknowledges the pending interrupt of a PIC in poll mode,
+    /// and returns the line with bit 7 set, or 0 if none is pending.
+    fn poll(&mut self, index: usize) -> u8 {
+        let result = match pending_line(&self.chips[index], index == 0) {
+            Some(line) => {
+                acknowledge(&mut self.chips[index], line);
+                0x80 | line
+            }
+            None => 0,
+        };
+
+        self.update();
+
+        result
+    }
+
+    /// Connects the output of the slave to the master.
+    fn update(&mut self) {
+        let level = pending_line(&self.chips[1], false).is_some();
+        set_line(&mut self.chips[0], CASCADE_LINE, level);
+    }
+}
+
+impl Default for Pic {
+    fn default() -> Self {
+        Pic::new()
+    }
+}
+
+/// Sets the level of one of the lines of a PIC.
+fn set_line(chip: &mut PicState, line: u8, level: bool) {
+    let bit = 1 << line;
+
+    if level {
+        // Edge-triggered lines are requested on the rising edge.
+        if chip.elcr & bit != 0 || chip.last_irr & bit == 0 {
+            chip.irr |= bit;
+        }
+
+        chip.last_irr |= bit;
+    } else {
+        if chip.elcr & bit != 0 {
+            chip.irr &= !bit;
+        }
+
+        chip.last_irr &= !bit;
+    }
+}
+
+/// Returns the priority of the highest priority line in a mask,
+/// where 0 is the highest priority.
+fn priority(chip: &PicState, mask: u8) -> Option<u8> {
+    (0..8).find(|&priority| mask & (1 << ((priority + chip.priority_add) & 7)) != 0)
+}
+
+/// Returns the line whose interrupt should be delivered, if any.
+fn pending_line(chip: &PicState, master: bool) -> Option<u8> {
+    let requested = priority(chip, chip.irr & !chip.imr)?;
+
+    let mut in_service = chip.isr;
+
+    // Masked lines do not block lower priority interrupts.
+    if chip.special_mask {
+        in_service &= !chip.imr;
+    }
+
+    // More interrupts from the slave are allowed while one of them is in service.
+    if chip.special_fully_nested && master {
+        in_service &= !(1 << CASCADE_LINE);
+    }
+
+    match priority(chip, in_service) {
+        Some(current) if current <= requested => None,
+        _ => Some((requested + chip.priority_add) & 7),
+    }
+}
+
+/// Moves a line's interrupt from the IRR to the ISR.
+fn acknowledge(chip: &mut PicState, line: u8) {
+    let bit = 1 << line;
+
+    chip.isr |= bit;
+
+    // A level-triggered line stays requested while it is asserted.
+    if chip.elcr & bit == 0 {
+        chip.irr &= !bit;
+    }
+
+    if chip.auto_eoi {
+        if chip.rotate_on_auto_eoi {
+            chip.priority_add = (line + 1) & 7;
+        }
+
+        chip.isr &= !bit;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Replays the initialization of SeaBIOS, with vectors 0x08 and 0x70.
+    fn seabios_init(pic: &mut Pic) {
+        for &(port, value) in &[
+            (MASTER_COMMAND, 0x11),
+            (SLAVE_COMMAND, 0x11),
+            (MASTER_DATA, 0x08),
+            (SLAVE_DATA, 0x70),
+            (MASTER_DATA, 0x04),
+            (SLAVE_DATA, 0x02),
+            (MASTER_DATA, 0x01),
+            (SLAVE_DATA, 0x01),
+            // Mask all lines, except for the cascade.
+            (MASTER_DATA, 0xFB),
+            (SLAVE_DATA, 0xFF),
+        ] {
+            pic.write(port, value);
+        }
+    }
+
+    #[test]
+    fn seabios() {
+        let mut pic = Pic::new();
+        seabios_init(&mut pic);
+
+        let state = pic.state();
+        assert_eq!((state[0].irq_base, state[1].irq_base), (0x08, 0x70));
+        assert_eq!((state[0].init_state, state[1].init_state), (0, 0));
+        assert!(state[0].init4 && !state[0].auto_eoi);
+
+        // Masked lines are requested, but not delivered.
+        pic.set_irq(0, true).unwrap();
+        pic.set_irq(0, false).unwrap();
+        assert!(!pic.output());
+        assert_eq!(pic.read(MASTER_COMMAND), 0x01);
+
+        // Unmasking the timer delivers its interrupt.
+        pic.write(MASTER_DATA, 0xFA);
+        assert!(pic.output());
+        assert_eq!(pic.acknowledge(), 0x08);
+        assert!(!pic.output());
+
+        // The RTC, on the slave.
+        pic.write(SLAVE_DATA, 0xFE);
+        pic.set_irq(8, true).unwrap();
+        assert!(!pic.output());
+
+        // Ends the timer's interrupt.
+        pic.write(MASTER_COMMAND, 0x20);
+        assert!(pic.output());
+        assert_eq!(pic.acknowledge(), 0x70);
+
+        pic.write(MASTER_COMMAND, 0x0B);
+        pic.write(SLAVE_COMMAND, 0x0B);
+        assert_eq!((pic.read(MASTER_COMMAND), pic.read(SLAVE_COMMAND)), (0x04, 0x01));
+
+        pic.write(SLAVE_COMMAND, 0x20);
+        pic.write(MASTER_COMMAND, 0x20);
+        assert_eq!((pic.read(MASTER_COMMAND), pic.read(SLAVE_COMMAND)), (0, 0));
+
+        // Without an interrupt, the acknowledgement is spurious.
+        assert_eq!(pic.acknowledge(), 0x0F);
+        assert!(pic.set_irq(2, true).is_err());
+    }
+
+    #[test]
+    fn linux() {
+        let mut pic = Pic::new();
+        seabios_init(&mut pic);
+
+        // Linux probes the PIC through the mask register.
+        pic.write(SLAVE_DATA, 0xFF);
+        pic.write(MASTER_DATA, 0xA5);
+        assert_eq!(pic.read(MASTER_DATA), 0xA5);
+
+        // Reinitializes the PICs, with vectors 0x30 and 0x38.
+        for &(port, value) in &[
+            (MASTER_DATA, 0xFF),
+            (MASTER_COMMAND, 0x11),
+            (MASTER_DATA, 0x30),
+            (MASTER_DATA, 0x04),
+            (MASTER_DATA, 0x01),
+            (SLAVE_COMMAND, 0x11),
+            (SLAVE_DATA, 0x38),
+            (SLAVE_DATA, 0x02),
+            (SLAVE_DATA, 0x01),
+            (MASTER_DATA, 0xFB),
+            (SLAVE_DATA, 0xFD),
+        ] {
+            pic.write(port, value);
+        }
+
+        assert_eq!((pic.state()[0].irq_base, pic.state()[1].irq_base), (0x30, 0x38));
+
+        // The ACPI SCI, which is level-triggered, on line 9.
+        pic.write(SLAVE_ELCR, 0xFF);
+        assert_eq!(pic.read(SLAVE_ELCR), SLAVE_ELCR_MASK);
+
+        pic.set_irq(9, true).unwrap();
+        assert_eq!(pic.acknowledge(), 0x39);
+
+        // Linux masks the line and sends specific EOIs to both PICs.
+        pic.write(SLAVE_DATA, 0xFF);
+        pic.write(SLAVE_COMMAND, 0x61);
+        pic.write(MASTER_COMMAND, 0x62);
+        assert!(!pic.output());
+
+        // The line is still asserted once it is unmasked.
+        pic.write(SLAVE_DATA, 0xFD);
+        assert!(pic.output());
+        assert_eq!(pic.acknowledge(), 0x39);
+
+        // The handler deasserts the line before the EOIs.
+        pic.set_irq(9, false).unwrap();
+        pic.write(SLAVE_COMMAND, 0x61);
+        pic.write(MASTER_COMMAND, 0x62);
+        assert!(!pic.output());
+
+        // The state can be restored.
+        let state = pic.state();
+        let mut restored = Pic::new();
+        restored.set_state(&state);
+        assert_eq!(restored.state(), state);
+    }
+
+    #[test]
+    fn priorities() {
+        let mut pic = Pic::new();
+
+        // Automatic EOI, with rotation.
+        for &value in &[0x20, 0x04, 0x03, 0x00] {
+            pic.write(MASTER_DATA, value);
+        }
+        pic.write(MASTER_COMMAND, 0x11);
+        for &value in &[0x20, 0x04, 0x03, 0x00] {
+            pic.write(MASTER_DATA, value);
+        }
+        pic.write(MASTER_COMMAND, 0x80);
+
+        pic.set_irq(1, true).unwrap();
+        pic.set_irq(3, true).unwrap();
+        assert_eq!(pic.acknowledge(), 0x21);
+        assert_eq!(pic.state()[0].isr, 0);
+
+        // Line 1 now has the lowest priority.
+        pic.set_irq(1, false).unwrap();
+        pic.set_irq(1, true).unwrap();
+        assert_eq!(pic.acknowledge(), 0x23);
+        assert_eq!(pic.acknowledge(), 0x21);
+
+        // Without automatic EOI, line 5 blocks line 6 while it is in service.
+        pic.write(MASTER_COMMAND, 0x11);
+        for &value in &[0x20, 0x04, 0x01, 0x00] {
+            pic.write(MASTER_DATA, value);
+        }
+
+        pic.set_irq(5, true).unwrap();
+        assert_eq!(pic.acknowledge(), 0x25);
+
+        pic.set_irq(6, true).unwrap();
+        assert!(!pic.output());
+
+        // In special mask mode, masking line 5 lets line 6 through.
+        pic.write(MASTER_DATA, 0x20);
+        pic.write(MASTER_COMMAND, 0x68);
+        assert!(pic.output());
+
+        // Polling acknowledges the interrupt.
+        pic.write(MASTER_COMMAND, 0x0C);
+        assert_eq!(pic.read(MASTER_COMMAND), 0x86);
+        assert_eq!(pic.state()[0].isr, 0x60);
+
+        pic.write(MASTER_COMMAND, 0x0C);
+        assert_eq!(pic.read(MASTER_COMMAND), 0);
+    }
+}
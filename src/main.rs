@@ -4,6 +4,7 @@
 #![cfg_attr(feature = "cargo-clippy", warn(clippy))]
 
 extern crate accel;
+extern crate interp;
 #[cfg(target_os = "linux")]
 extern crate kvm;
 #[cfg(target_os = "windows")]
@@ -11,51 +12,50 @@ extern crate hax;
 extern crate vm_x86 as x86;
 
 #[cfg(target_os = "linux")]
-fn create_accelerator() -> Box<accel::Accelerator> {
-    kvm::create().expect("Failed to create KVM accelerator")
+fn create_hardware_accelerator() -> accel::errors::Result<Box<accel::Accelerator>> {
+    kvm::create()
 }
 
 #[cfg(target_os = "windows")]
-fn create_accelerator() -> Box<accel::Accelerator> {
-    hax::create().expect("Failed to create HAX accelerator")
+fn create_hardware_accelerator() -> accel::errors::Result<Box<accel::Accelerator>> {
+    hax::create()
 }
 
-fn main() {
-    let acc = create_accelerator();
-
-    let vm = acc.create_vm().expect("Failed to create VM");
+/// Creates an accelerator, and chooses which interrupt controllers it emulates.
+fn create_accelerator() -> (Box<accel::Accelerator>, accel::IrqChipMode) {
+    match create_hardware_accelerator() {
+        Ok(acc) => (acc, accel::IrqChipMode::Kernel),
+        Err(err) => {
+            println!("Hardware acceleration unavailable ({}), using the interpreter", err);
 
-    let memory = {
-        let mut memory = Box::new([0u8; 4096]);
+            // The interpreter does not emulate any interrupt controller.
+            let acc = interp::create().expect("Failed to create interpreter");
+            (acc, accel::IrqChipMode::User)
+        }
+    }
+}
 
-        // Write some instructions to that memory.
-        {
-            let reset_vector = 4096 - 16;
-            let mem = &mut memory[reset_vector..];
+fn main() {
+    let (acc, irqchip) = create_accelerator();
 
-            // Move imm8 to AL.
-            mem[0] = 0xB0;
-            mem[1] = 127;
+    let vm = acc.create_vm(irqchip).expect("Failed to create VM");
 
-            // Output to port.
-            mem[2] = 0xE6;
-            // The actual port.
-            // TODO: come up with a port for debugging.
-            mem[3] = 0;
-        }
+    let base = 4 * 1024 * 1024 * 1024 - 4096;
+    let memory = accel::GuestMemory::new(&[(base, 4096)]).expect("Failed to allocate memory");
 
-        memory
-    };
+    // Write some instructions to that memory.
+    let code = [
+        // Move imm8 to AL.
+        0xB0, 127,
+        // Output to port.
+        // TODO: come up with a port for debugging.
+        0xE6, 0,
+    ];
 
-    let region = accel::MemoryRegion {
-        slot: 0,
-        host: memory.as_ref(),
-        guest: 4 * 1024 * 1024 * 1024 - 4096,
-    };
+    let reset_vector = base + 4096 - 16;
+    memory.write(reset_vector, &code).expect("Failed to write code");
 
-    vm.allocate_memory(region).expect(
-        "Failed to allocate memory",
-    );
+    memory.register(&*vm).expect("Failed to map memory");
 
     let max_recommended_vcpus = vm.max_recommended_vcpus().unwrap();
     println!("Max recommended vCPUs: {}", max_recommended_vcpus);
@@ -79,11 +79,21 @@ fn main() {
 
             Ok(())
         }
+
+        fn mmio(&self, addr: u64, is_write: bool, data: &mut [u8]) -> accel::errors::Result<()> {
+            println!("MMIO at address {:X}", addr);
+
+            if is_write {
+                println!("Data: {:?}", data);
+            }
+
+            Ok(())
+        }
     }
 
     let cbs = CpuCallbacks;
 
-    let vcpu = vm.create_vcpu(0, &cbs).expect("Failed to create vCPU");
+    let vcpu = vm.create_vcpu(0, None, &cbs).expect("Failed to create vCPU");
 
     let _exit_state = vcpu.run().expect("Failed to run vCPU");
 }
@@ -7,6 +7,45 @@ use x86;
 /// or other data.
 pub type CpuState = x86::state::State;
 
+/// The table of values returned by the `CPUID` instruction.
+pub type Cpuid = x86::cpuid::Cpuid;
+
+/// The index and value of a model-specific register.
+pub type Msr = x86::msr::Msr;
+
+/// An interrupt controller of the VM, whose pins GSIs can be routed to.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum IrqChip {
+    /// The master 8259 PIC, with pins 0-7.
+    PicMaster,
+    /// The slave 8259 PIC, with pins 0-7.
+    PicSlave,
+    /// The I/O APIC, with pins 0-23.
+    IoApic,
+}
+
+/// The state of an 8259 PIC.
+pub type PicState = x86::irqchip::PicState;
+
+/// The state of an I/O APIC.
+pub type IoApicState = x86::irqchip::IoApicState;
+
+/// The registers of a vCPU's local APIC.
+pub type LapicState = x86::apic::LapicState;
+
 /// An architecture-specific number representing the reason why
 /// the virtual CPU stopped execution.
 pub type ExitReason = x86::vmx::ExitReason;
+
+/// The state of the virtual CPU when a debug event occured.
+#[derive(Debug, Copy, Clone)]
+pub struct DebugExit {
+    /// The exception vector which caused the exit.
+    pub exception: u32,
+    /// The instruction pointer.
+    pub pc: u64,
+    /// The debug status register.
+    pub dr6: u64,
+    /// The debug control register.
+    pub dr7: u64,
+}
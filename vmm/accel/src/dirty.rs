@@ -0,0 +1,155 @@
+//! Tracking of the guest pages written to by the guest.
+
+use memory::PAGE_SIZE;
+
+/// Bitmap of the pages of a memory slot which were written to by the guest.
+///
+/// Each bit corresponds to a page, starting from the lowest bit of the first word.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DirtyBitmap {
+    slot: u8,
+    guest: u64,
+    pages: usize,
+    bits: Vec<u64>,
+}
+
+impl DirtyBitmap {
+    /// Creates a bitmap with all pages clean for a slot of `pages` pages,
+    /// starting at the `guest` physical address.
+    pub fn new(slot: u8, guest: u64, pages: usize) -> Self {
+        DirtyBitmap {
+            slot,
+            guest,
+            pages,
+            bits: vec![0; pages.div_ceil(64)],
+        }
+    }
+
+    /// Memory slot this bitmap belongs to.
+    pub fn slot(&self) -> u8 {
+        self.slot
+    }
+
+    /// Guest physical address of the first page.
+    pub fn guest(&self) -> u64 {
+        self.guest
+    }
+
+    /// Number of pages covered by this bitmap.
+    pub fn pages(&self) -> usize {
+        self.pages
+    }
+
+    /// Checks if a page, given by its index in the slot, is dirty.
+    pub fn is_dirty(&self, page: usize) -> bool {
+        page < self.pages && self.bits[page / 64] & (1 << (page % 64)) != 0
+    }
+
+    /// Marks a page as dirty.
+    pub fn set_dirty(&mut self, page: usize) {
+        assert!(page < self.pages, "page {} is outside of the slot", page);
+        self.bits[page / 64] |= 1 << (page % 64);
+    }
+
+    /// Merges the dirty pages of another bitmap of the same slot into this one.
+    pub fn merge(&mut self, other: &DirtyBitmap) {
+        assert_eq!(self.slot, other.slot);
+
+        for (word, other) in self.bits.iter_mut().zip(&other.bits) {
+            *word |= *other;
+        }
+    }
+
+    /// Number of dirty pages.
+    pub fn dirty_pages(&self) -> usize {
+        self.bits.iter().map(|word| word.count_ones() as usize).sum()
+    }
+
+    /// Returns the raw bitmap, in the format used by most accelerators.
+    pub fn as_raw(&self) -> &[u64] {
+        &self.bits
+    }
+
+    /// Returns the raw bitmap, to be filled in by an accelerator.
+    pub fn as_raw_mut(&mut self) -> &mut [u64] {
+        &mut self.bits
+    }
+
+    /// Iterates over the ranges of contiguous dirty pages.
+    pub fn ranges(&self) -> DirtyRanges<'_> {
+        DirtyRanges {
+            bitmap: self,
+            page: 0,
+        }
+    }
+}
+
+/// A range of contiguous dirty pages.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct DirtyRange {
+    /// Guest physical address of the first page.
+    pub guest: u64,
+    /// Size of the range, in bytes.
+    pub size: usize,
+}
+
+/// Iterator over the dirty ranges of a `DirtyBitmap`.
+pub struct DirtyRanges<'a> {
+    bitmap: &'a DirtyBitmap,
+    page: usize,
+}
+
+impl<'a> Iterator for DirtyRanges<'a> {
+    type Item = DirtyRange;
+
+    fn next(&mut self) -> Option<DirtyRange> {
+        let bitmap = self.bitmap;
+
+        while self.page < bitmap.pages && !bitmap.is_dirty(self.page) {
+            // Skip over clean words quickly.
+            if self.page.is_multiple_of(64) && bitmap.bits[self.page / 64] == 0 {
+                self.page += 64;
+            } else {
+                self.page += 1;
+            }
+        }
+
+        if self.page >= bitmap.pages {
+            return None;
+        }
+
+        let start = self.page;
+
+        while self.page < bitmap.pages && bitmap.is_dirty(self.page) {
+            self.page += 1;
+        }
+
+        Some(DirtyRange {
+            guest: bitmap.guest + (start * PAGE_SIZE) as u64,
+            size: (self.page - start) * PAGE_SIZE,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dirty_ranges() {
+        let mut bitmap = DirtyBitmap::new(1, 0x10000, 200);
+
+        assert_eq!(bitmap.ranges().next(), None);
+
+        for &page in &[0, 1, 2, 63, 64, 130, 199] {
+            bitmap.set_dirty(page);
+        }
+
+        assert_eq!(bitmap.dirty_pages(), 7);
+        assert!(bitmap.is_dirty(64));
+        assert!(!bitmap.is_dirty(65));
+
+        let ranges: Vec<_> = bitmap.ranges().map(|r| (r.guest, r.size / PAGE_SIZE)).collect();
+        assert_eq!(ranges, [(0x10000, 3), (0x4F000, 2), (0x92000, 1), (0xD7000, 1)]);
+    }
+}
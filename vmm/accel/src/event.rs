@@ -0,0 +1,89 @@
+//! Counters used to signal events between device threads and the accelerator.
+
+use errors::Result;
+use nix::sys::eventfd;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+/// An event counter, backed by a Linux `eventfd`.
+///
+/// Writing to the counter adds to its value, and reading waits until the
+/// value is not zero, then resets it. The accelerator can signal an event
+/// when the guest writes to an I/O address, or inject an interrupt when
+/// a device thread signals one.
+#[derive(Debug)]
+pub struct EventFd {
+    file: File,
+}
+
+impl EventFd {
+    /// Creates a counter with a value of 0.
+    pub fn new() -> Result<Self> {
+        let fd = eventfd::eventfd(0, eventfd::EFD_CLOEXEC).map_err(|_| io::Error::last_os_error())?;
+        let file = unsafe { File::from_raw_fd(fd) };
+
+        Ok(EventFd { file })
+    }
+
+    /// Adds `value` to the counter, waking up the readers.
+    pub fn write(&self, value: u64) -> Result<()> {
+        (&self.file).write_all(&value.to_ne_bytes())?;
+        Ok(())
+    }
+
+    /// Waits until the counter is not zero, then returns its value and resets it.
+    pub fn read(&self) -> Result<u64> {
+        let mut buffer = [0; 8];
+        (&self.file).read_exact(&mut buffer)?;
+        Ok(u64::from_ne_bytes(buffer))
+    }
+
+    /// Creates another handle to the same counter, e.g. for another thread.
+    pub fn try_clone(&self) -> Result<Self> {
+        let file = self.file.try_clone()?;
+        Ok(EventFd { file })
+    }
+}
+
+impl AsRawFd for EventFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+/// The address of an I/O access.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum IoAddress {
+    /// An I/O port.
+    Port(u16),
+    /// A guest physical address, which is not mapped to a memory slot.
+    Memory(u64),
+}
+
+/// Describes the guest writes which signal an `EventFd`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct IoEvent {
+    /// The address written to.
+    pub address: IoAddress,
+    /// The size of the write, in bytes: 1, 2, 4 or 8.
+    pub len: u32,
+    /// If set, only writes of this value signal the event.
+    pub datamatch: Option<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_counter() {
+        let event = EventFd::new().unwrap();
+        let copy = event.try_clone().unwrap();
+
+        event.write(2).unwrap();
+        copy.write(3).unwrap();
+
+        assert_eq!(copy.read().unwrap(), 5);
+    }
+}
@@ -3,9 +3,14 @@
 #![warn(missing_docs)]
 #![cfg_attr(feature = "cargo-clippy", warn(clippy))]
 
+#[macro_use]
+extern crate bitflags;
 #[macro_use]
 extern crate error_chain;
 
+extern crate memmap;
+#[cfg(target_os = "linux")]
+extern crate nix;
 extern crate vm_x86 as x86;
 
 pub mod errors;
@@ -13,11 +18,51 @@ use errors::Result;
 
 pub mod arch;
 
+mod memory;
+pub use memory::{ByteValued, GuestMemory, PAGE_SIZE};
+
+mod dirty;
+pub use dirty::{DirtyBitmap, DirtyRange, DirtyRanges};
+
+#[cfg(target_os = "linux")]
+mod event;
+#[cfg(target_os = "linux")]
+pub use event::{EventFd, IoAddress, IoEvent};
+
+#[cfg(feature = "test-util")]
+pub mod test_util;
+
 /// An accelerator takes advantage of hardware features to enable
 /// fast virtualization.
 pub trait Accelerator {
     /// Create a virtual machine.
-    fn create_vm<'a>(&'a self) -> Result<Box<VirtualMachine<'a> + 'a>>;
+    ///
+    /// The `irqchip` mode chooses which interrupt controllers are emulated
+    /// by the accelerator. Fails if the accelerator does not support it.
+    fn create_vm<'a>(&'a self, irqchip: IrqChipMode) -> Result<Box<VirtualMachine<'a> + 'a>>;
+}
+
+/// Which interrupt controllers are emulated by the accelerator,
+/// as opposed to device models in user space.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum IrqChipMode {
+    /// The accelerator emulates all of the interrupt controllers.
+    ///
+    /// On x86, these are the PICs, the I/O APIC and the local APICs.
+    #[default]
+    Kernel,
+    /// The accelerator only emulates the local interrupt controllers.
+    ///
+    /// On x86, the local APICs are emulated by the accelerator, while the
+    /// PICs and the I/O APIC are emulated in user space. The accelerator's
+    /// own model of the I/O APIC is used for `set_irq_line` on its pins, and
+    /// routes interrupts to the local APICs through MSI routes.
+    Split,
+    /// The accelerator does not emulate any interrupt controller.
+    ///
+    /// Interrupts are injected into the vCPUs with `inject_interrupt`,
+    /// and the methods which use the interrupt controllers fail.
+    User,
 }
 
 /// A virtual machine is a group of resources such as virtual CPUs,
@@ -35,16 +80,128 @@ pub trait VirtualMachine<'a> {
     /// Maximum value for a virtual CPU's ID.
     fn max_vcpu_ids(&self) -> Result<usize>;
 
-    /// Allocates a block of host memory to the VM.
+    /// Maximum number of memory slots.
+    fn max_memory_slots(&self) -> Result<usize>;
+
+    /// Maps a block of host memory into the VM's physical address space.
+    ///
+    /// If the slot is already in use, it is modified instead: it can be moved
+    /// to a different guest address, or have dirty logging turned on or off.
+    /// The size and the `READ_ONLY` flag of an existing slot cannot change.
+    ///
+    /// Usually called through `GuestMemory`.
     fn allocate_memory(&self, memory: MemoryRegion) -> Result<()>;
 
+    /// Unmaps a memory slot from the VM.
+    fn free_memory(&self, slot: u8) -> Result<()>;
+
+    /// Tracks dirty pages with per-vCPU rings of `entries` entries,
+    /// instead of scanning a bitmap for each slot.
+    ///
+    /// Must be called before creating any vCPU. The way dirty pages are
+    /// retrieved does not change. Accelerators which track dirty pages
+    /// directly ignore this.
+    fn enable_dirty_ring(&self, entries: u32) -> Result<()>;
+
+    /// Retrieves the pages of a slot written to by the guest since
+    /// its log was last cleared.
+    ///
+    /// The slot must have the `LOG_DIRTY_PAGES` flag set.
+    fn get_dirty_log(&self, slot: u8) -> Result<DirtyBitmap>;
+
+    /// Clears the dirty pages in `bitmap` from the slot's log,
+    /// so that further writes to them are logged again.
+    ///
+    /// This must be done before reading the contents of the pages,
+    /// otherwise writes done in between might be missed.
+    fn clear_dirty_log(&self, bitmap: &DirtyBitmap) -> Result<()>;
+
+    /// Sets the level of an interrupt line of the VM's interrupt controllers.
+    ///
+    /// The `gsi` is the Global System Interrupt number of the line. On x86,
+    /// GSIs 0-15 are connected to the PICs and the I/O APIC, and higher GSIs
+    /// only to the I/O APIC.
+    ///
+    /// How the level is interpreted depends on the trigger mode the guest
+    /// programmed for the line:
+    /// - For level-triggered lines, the interrupt is raised as long as the
+    ///   line is asserted, and again after each end-of-interrupt if the line
+    ///   is still asserted. The device must deassert the line once the guest
+    ///   has serviced it.
+    /// - For edge-triggered lines, the interrupt is raised when the line goes
+    ///   from deasserted to asserted. A device signals an edge by asserting
+    ///   and then deasserting the line.
+    fn set_irq_line(&self, gsi: u32, level: bool) -> Result<()>;
+
+    /// Sends a message signaled interrupt.
+    ///
+    /// MSIs are always edge-triggered: the `address` and `data` describe the
+    /// destination, the vector and the delivery mode of a single interrupt.
+    /// On x86, the address is in the `0xFEE0_0000` range of the local APICs.
+    fn send_msi(&self, address: u64, data: u32) -> Result<()>;
+
+    /// Adds a route for a GSI, in addition to its existing routes.
+    ///
+    /// By default, GSIs 0-15 are routed to the PICs and to the I/O APIC,
+    /// and GSIs 16-23 to the I/O APIC. Other GSIs can be used for MSIs:
+    /// asserting the line of a GSI routed to an MSI sends the message.
+    fn add_irq_route(&self, gsi: u32, route: IrqRoute) -> Result<()>;
+
+    /// Removes all of the routes of a GSI.
+    fn remove_irq_routes(&self, gsi: u32) -> Result<()>;
+
+    /// Retrieves the state of the master or slave PIC.
+    ///
+    /// Fails if `chip` is not one of the PICs.
+    fn get_pic_state(&self, chip: arch::IrqChip) -> Result<arch::PicState>;
+
+    /// Replaces the state of the master or slave PIC, e.g. to restore a snapshot.
+    fn set_pic_state(&self, chip: arch::IrqChip, state: &arch::PicState) -> Result<()>;
+
+    /// Retrieves the state of the I/O APIC, including its redirection table.
+    fn get_ioapic_state(&self) -> Result<arch::IoApicState>;
+
+    /// Replaces the state of the I/O APIC.
+    fn set_ioapic_state(&self, state: &arch::IoApicState) -> Result<()>;
+
+    /// Signals `event` when the guest does a write described by `io`.
+    ///
+    /// The write completes without exiting to the `CpuCallbacks`, so that
+    /// a device thread waiting on the event can handle it, e.g. as a doorbell.
+    #[cfg(target_os = "linux")]
+    fn register_ioevent(&self, io: IoEvent, event: &EventFd) -> Result<()>;
+
+    /// Stops signaling `event` for the writes described by `io`.
+    #[cfg(target_os = "linux")]
+    fn unregister_ioevent(&self, io: IoEvent, event: &EventFd) -> Result<()>;
+
+    /// Injects an interrupt on a GSI each time `event` is signaled.
+    ///
+    /// This lets device threads raise edge-triggered interrupts
+    /// without calling into the VM.
+    #[cfg(target_os = "linux")]
+    fn register_irqfd(&self, event: &EventFd, gsi: u32) -> Result<()>;
+
+    /// Stops injecting interrupts when `event` is signaled.
+    #[cfg(target_os = "linux")]
+    fn unregister_irqfd(&self, event: &EventFd, gsi: u32) -> Result<()>;
+
+    /// Retrieves the CPUID leaves which can be given to the vCPUs.
+    ///
+    /// This is the starting point for building a vCPU's `Cpuid` table.
+    fn supported_cpuid(&self) -> Result<arch::Cpuid>;
+
     /// Create a new virtual CPU.
     ///
     /// The `id` is a unique number identifying this CPU.
     /// On x86, this will become the APIC ID of the vCPU.
+    ///
+    /// The vCPU reports the leaves in `cpuid` to the guest. If it is `None`,
+    /// the supported leaves are used, adjusted for a single-threaded package.
     fn create_vcpu<'b>(
         &'b self,
         id: usize,
+        cpuid: Option<&arch::Cpuid>,
         callbacks: &'b CpuCallbacks,
     ) -> Result<Box<VirtualCPU<'b> + 'b>>;
 }
@@ -58,9 +215,53 @@ pub trait VirtualCPU<'a> {
     /// and the user mode structure.
     fn sync(&self, state: &mut arch::CpuState, set: bool) -> Result<()>;
 
+    /// Reads the values of a list of model-specific registers.
+    ///
+    /// Fails if any of the registers is not supported.
+    fn get_msrs(&self, msrs: &mut [arch::Msr]) -> Result<()>;
+
+    /// Writes the values of a list of model-specific registers.
+    ///
+    /// Fails if any of the registers is not supported,
+    /// or cannot be set to the given value.
+    fn set_msrs(&self, msrs: &[arch::Msr]) -> Result<()>;
+
+    /// Retrieves the registers of the vCPU's local APIC,
+    /// including its pending interrupts and its timer.
+    fn get_lapic(&self) -> Result<arch::LapicState>;
+
+    /// Replaces the registers of the vCPU's local APIC, e.g. to restore a snapshot.
+    ///
+    /// The registers are set without the side effects of guest writes,
+    /// except that the timer is restarted with the current count.
+    fn set_lapic(&self, state: &arch::LapicState) -> Result<()>;
+
+    /// Checks if the guest can accept an interrupt injected with `inject_interrupt`,
+    /// which is when interrupts are enabled and not blocked by the last instruction.
+    ///
+    /// This is only known after the vCPU ran, and stopped.
+    fn can_inject_interrupt(&self) -> bool;
+
+    /// Injects an external interrupt with the given vector,
+    /// when the interrupt controllers are emulated in user space.
+    ///
+    /// It is delivered when the vCPU runs again. If the guest cannot accept it
+    /// right now, request an interrupt window and inject it once it opens.
+    fn inject_interrupt(&self, vector: u8) -> Result<()>;
+
+    /// Requests that `run` returns `ExitState::InterruptWindow` as soon as
+    /// the guest can accept an injected interrupt.
+    ///
+    /// The request stays active until it is cleared.
+    fn request_interrupt_window(&self, request: bool);
+
     /// Runs the virtual CPU on the current thread.
-    // TODO: instead of an exit state structure,
-    // we should move everything to callbacks (eventually).
+    ///
+    /// Exits which can be handled through the `CpuCallbacks`, such as
+    /// port I/O or MMIO, do not return: the guest is re-entered
+    /// after the callback completes.
+    ///
+    /// This function only returns when the caller must act on an event.
     fn run(&self) -> Result<ExitState>;
 }
 
@@ -70,14 +271,53 @@ pub trait VirtualCPU<'a> {
 ///
 /// Memory should be aligned on page / huge page
 /// boundaries for best performance.
+///
+/// The guest writes to this memory, so it must remain mapped for as long
+/// as it is in use by the VM. `GuestMemory` takes care of this.
 #[derive(Debug, Copy, Clone)]
-pub struct MemoryRegion<'a> {
+pub struct MemoryRegion {
     /// The memory slot to operate on.
     pub slot: u8,
-    /// Host virtual memory block.
-    pub host: &'a [u8],
     /// Guest physical address.
-    pub guest: usize,
+    pub guest: u64,
+    /// Start of the host virtual memory block.
+    pub host: *mut u8,
+    /// Size of the memory block, in bytes.
+    pub size: usize,
+    /// Properties of this memory slot.
+    pub flags: MemoryFlags,
+}
+
+bitflags! {
+    /// Properties of a memory slot.
+    #[derive(Default)]
+    pub struct MemoryFlags: u8 {
+        /// The guest cannot write to this slot, writes cause MMIO exits instead.
+        ///
+        /// This is used for ROM regions.
+        const READ_ONLY = 1 << 0;
+        /// The accelerator keeps track of the pages written to by the guest.
+        const LOG_DIRTY_PAGES = 1 << 1;
+    }
+}
+
+/// The destination of the interrupts signaled on a GSI.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum IrqRoute {
+    /// An input pin of an interrupt controller.
+    IrqChip {
+        /// The interrupt controller.
+        chip: arch::IrqChip,
+        /// The pin of the controller.
+        pin: u32,
+    },
+    /// A message signaled interrupt, as sent by `send_msi`.
+    Msi {
+        /// The address of the message.
+        address: u64,
+        /// The data of the message.
+        data: u32,
+    },
 }
 
 /// Trait containing callbacks which control the vCPU's execution.
@@ -97,13 +337,64 @@ pub trait CpuCallbacks {
         buffer: &mut [u8],
         element_size: usize,
     ) -> Result<()>;
+
+    /// Function called to emulate an access to memory-mapped I/O.
+    ///
+    /// The parameters are:
+    /// - `addr` is the guest physical address being accessed.
+    /// - `is_write` is true if the guest writes to memory, false if it reads.
+    /// - `data` contains the written value, or must be filled with
+    ///   the value to be read. Its length is the size of the access.
+    fn mmio(&self, addr: u64, is_write: bool, data: &mut [u8]) -> Result<()>;
 }
 
 /// Structure providing additional data on the vCPU's exit.
 #[derive(Debug, Copy, Clone)]
 pub enum ExitState {
+    /// The vCPU is halted, waiting for an interrupt.
+    Halt,
+    /// The guest can accept an injected interrupt,
+    /// as requested by `request_interrupt_window`.
+    InterruptWindow,
     /// The virtual machine gracefully shut down.
+    ///
+    /// On x86, this is also returned when the guest triple faults.
     Shutdown,
+    /// The guest requested a reset.
+    Reset,
+    /// A debug exception was triggered, e.g. by a breakpoint.
+    Debug(arch::DebugExit),
+    /// The guest triggered a platform-level event.
+    SystemEvent(SystemEvent),
+    /// The vCPU could not enter guest mode, most likely due to invalid state.
+    ///
+    /// Contains the hardware-specific reason for the failure.
+    FailEntry(u64),
+    /// Execution was interrupted by a signal sent to this thread.
+    Interrupted,
     /// An unknown / unhandled error occured.
     Unknown(arch::ExitReason),
 }
+
+/// A platform-level event triggered by the guest, which is not
+/// a shutdown or reset request.
+#[derive(Debug, Copy, Clone)]
+pub struct SystemEvent {
+    /// The type of the event.
+    pub kind: SystemEventKind,
+    /// Accelerator-specific flags.
+    pub flags: u64,
+}
+
+/// Types of system events.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SystemEventKind {
+    /// The guest crashed (e.g. a kernel panic notification).
+    Crash,
+    /// A halted vCPU should be woken up.
+    Wakeup,
+    /// The guest requested to be suspended.
+    Suspend,
+    /// An event which is specific to the accelerator.
+    Unknown(u32),
+}
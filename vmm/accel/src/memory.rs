@@ -0,0 +1,388 @@
+//! Guest physical memory management.
+
+use errors::Result;
+use memmap as mm;
+use std::{mem, ptr, slice};
+use {DirtyBitmap, MemoryFlags, MemoryRegion, VirtualMachine};
+
+/// Maximum number of memory slots which can be described by a `MemoryRegion`.
+const MAX_SLOTS: usize = 256;
+
+/// Size of a guest page. Memory regions must be aligned to this size.
+pub const PAGE_SIZE: usize = 4096;
+
+/// Types which can be read from or written to guest memory as raw bytes.
+///
+/// # Safety
+///
+/// Implementors must be plain-old-data: every bit pattern of the type's size
+/// must be a valid value, and the type must not contain any padding bytes.
+/// This holds for integers and for `#[repr(C)]` structures made of them,
+/// without gaps between the fields.
+pub unsafe trait ByteValued: Copy + Default {}
+
+macro_rules! byte_valued {
+    ($($ty:ty),*) => {
+        $(unsafe impl ByteValued for $ty {})*
+    };
+}
+
+byte_valued!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
+
+/// A block of anonymous host memory, mapped at a guest physical address.
+struct Region {
+    slot: u8,
+    guest: u64,
+    flags: MemoryFlags,
+    host: *mut u8,
+    // Keeps the host memory mapped for as long as the region exists.
+    mapping: mm::Mmap,
+}
+
+impl Region {
+    /// Allocates the host memory for a new region.
+    fn new(slot: u8, guest: u64, size: usize, flags: MemoryFlags) -> Result<Self> {
+        let mut mapping = mm::Mmap::anonymous(size, mm::Protection::ReadWrite)?;
+
+        Ok(Region {
+            slot,
+            guest,
+            flags,
+            host: mapping.mut_ptr(),
+            mapping,
+        })
+    }
+
+    /// Returns the guest physical address right after the end of this region.
+    fn end(&self) -> u64 {
+        self.guest + self.mapping.len() as u64
+    }
+
+    /// Checks if a guest physical address falls into this region.
+    fn contains(&self, addr: u64) -> bool {
+        addr >= self.guest && addr < self.end()
+    }
+
+    /// Describes this region to the accelerator.
+    fn memory_region(&self) -> MemoryRegion {
+        MemoryRegion {
+            slot: self.slot,
+            guest: self.guest,
+            host: self.host,
+            size: self.mapping.len(),
+            flags: self.flags,
+        }
+    }
+}
+
+/// The guest's physical memory.
+///
+/// Owns the host memory backing each slot, and translates guest physical
+/// addresses to host memory. Accesses may cross from one slot into the next,
+/// as long as there is no hole between them.
+pub struct GuestMemory {
+    regions: Vec<Region>,
+}
+
+// Guest memory is shared with the vCPUs anyway, and is only ever
+// accessed through copies.
+unsafe impl Send for GuestMemory {}
+unsafe impl Sync for GuestMemory {}
+
+impl GuestMemory {
+    /// Allocates guest RAM for the given list of `(address, size)` ranges.
+    ///
+    /// Each range becomes a memory slot, numbered in the order they are given.
+    /// The ranges must be page-aligned and must not overlap.
+    pub fn new(ranges: &[(u64, usize)]) -> Result<Self> {
+        let mut memory = GuestMemory { regions: Vec::new() };
+
+        for &(guest, size) in ranges {
+            memory.add_region(guest, size, MemoryFlags::empty())?;
+        }
+
+        Ok(memory)
+    }
+
+    /// Allocates a new region of guest memory, returning its slot.
+    ///
+    /// The region is only visible to the guest after calling `register`.
+    /// Regions with the `READ_ONLY` flag can still be written from the host,
+    /// e.g. to load firmware.
+    pub fn add_region(&mut self, guest: u64, size: usize, flags: MemoryFlags) -> Result<u8> {
+        self.check_range(None, guest, size)?;
+
+        let slot = match (0..MAX_SLOTS).find(|&slot| self.position(slot as u8).is_none()) {
+            Some(slot) => slot as u8,
+            None => bail!("no free memory slots"),
+        };
+
+        self.regions.push(Region::new(slot, guest, size, flags)?);
+
+        Ok(slot)
+    }
+
+    /// Unmaps a region from the VM, and frees its host memory.
+    pub fn remove_region(&mut self, vm: &VirtualMachine, slot: u8) -> Result<()> {
+        let index = self.index(slot)?;
+
+        vm.free_memory(slot)?;
+        self.regions.remove(index);
+
+        Ok(())
+    }
+
+    /// Moves a region to a different guest physical address.
+    ///
+    /// The contents of the region are preserved.
+    pub fn move_region(&mut self, vm: &VirtualMachine, slot: u8, guest: u64) -> Result<()> {
+        let index = self.index(slot)?;
+        let size = self.regions[index].mapping.len();
+
+        self.check_range(Some(slot), guest, size)?;
+
+        let region = &mut self.regions[index];
+        let old = region.guest;
+
+        region.guest = guest;
+
+        if let Err(error) = vm.allocate_memory(region.memory_region()) {
+            region.guest = old;
+            return Err(error);
+        }
+
+        Ok(())
+    }
+
+    /// Changes the size of a region.
+    ///
+    /// Accelerators cannot resize slots in place, so the slot is unmapped and
+    /// then remapped with new host memory. The contents are preserved,
+    /// up to the new size. If the new memory cannot be mapped, the old memory
+    /// is mapped again.
+    pub fn resize_region(&mut self, vm: &VirtualMachine, slot: u8, size: usize) -> Result<()> {
+        let index = self.index(slot)?;
+        let guest = self.regions[index].guest;
+
+        self.check_range(Some(slot), guest, size)?;
+
+        let region = Region::new(slot, guest, size, self.regions[index].flags)?;
+
+        {
+            let old = &self.regions[index];
+            let len = old.mapping.len().min(size);
+            unsafe { ptr::copy_nonoverlapping(old.host, region.host, len) };
+        }
+
+        vm.free_memory(slot)?;
+
+        if let Err(error) = vm.allocate_memory(region.memory_region()) {
+            vm.allocate_memory(self.regions[index].memory_region())?;
+            return Err(error);
+        }
+
+        self.regions[index] = region;
+
+        Ok(())
+    }
+
+    /// Turns dirty page logging on or off for a region.
+    pub fn set_dirty_logging(&mut self, vm: &VirtualMachine, slot: u8, enabled: bool) -> Result<()> {
+        let index = self.index(slot)?;
+        let region = &mut self.regions[index];
+        let old = region.flags;
+
+        region.flags.set(MemoryFlags::LOG_DIRTY_PAGES, enabled);
+
+        if let Err(error) = vm.allocate_memory(region.memory_region()) {
+            region.flags = old;
+            return Err(error);
+        }
+
+        Ok(())
+    }
+
+    /// Retrieves the pages written to by the guest in every slot which has
+    /// dirty logging enabled, and resets their logs.
+    pub fn dirty_log(&self, vm: &VirtualMachine) -> Result<Vec<DirtyBitmap>> {
+        let mut bitmaps = Vec::new();
+
+        for region in &self.regions {
+            if region.flags.contains(MemoryFlags::LOG_DIRTY_PAGES) {
+                let bitmap = vm.get_dirty_log(region.slot)?;
+                vm.clear_dirty_log(&bitmap)?;
+                bitmaps.push(bitmap);
+            }
+        }
+
+        Ok(bitmaps)
+    }
+
+    /// Total size of guest memory, in bytes.
+    pub fn size(&self) -> usize {
+        self.regions.iter().map(|r| r.mapping.len()).sum()
+    }
+
+    /// Maps all of the memory slots into a virtual machine.
+    ///
+    /// Slots which are already mapped are left unchanged.
+    /// The memory must not be dropped while the VM is still using it.
+    pub fn register(&self, vm: &VirtualMachine) -> Result<()> {
+        let max_slots = vm.max_memory_slots()?;
+
+        for region in &self.regions {
+            if region.slot as usize >= max_slots {
+                bail!("memory slot {} is over the limit of {} slots", region.slot, max_slots);
+            }
+
+            vm.allocate_memory(region.memory_region())?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the index of a slot in the list of regions.
+    fn position(&self, slot: u8) -> Option<usize> {
+        self.regions.iter().position(|r| r.slot == slot)
+    }
+
+    /// Returns the index of a slot, or an error if it does not exist.
+    fn index(&self, slot: u8) -> Result<usize> {
+        match self.position(slot) {
+            Some(index) => Ok(index),
+            None => bail!("memory slot {} does not exist", slot),
+        }
+    }
+
+    /// Checks if a range can be used for the memory slot `slot`.
+    ///
+    /// The range must be page-aligned and must not overlap any other slot.
+    fn check_range(&self, slot: Option<u8>, guest: u64, size: usize) -> Result<()> {
+        if size == 0 || !guest.is_multiple_of(PAGE_SIZE as u64) || !size.is_multiple_of(PAGE_SIZE) {
+            bail!("memory region at {:#x} with size {:#x} is not page-aligned", guest, size);
+        }
+
+        let end = match guest.checked_add(size as u64) {
+            Some(end) => end,
+            None => bail!("memory region at {:#x} overflows the address space", guest),
+        };
+
+        let overlaps = self
+            .regions
+            .iter()
+            .filter(|r| Some(r.slot) != slot)
+            .any(|r| guest < r.end() && r.guest < end);
+
+        if overlaps {
+            bail!("memory region at {:#x} overlaps another region", guest);
+        }
+
+        Ok(())
+    }
+
+    /// Calls `f` for each contiguous host memory block backing
+    /// the `[addr, addr + len)` guest physical range.
+    ///
+    /// The arguments of `f` are the host pointer, the offset in the range,
+    /// and the size of the block.
+    fn for_each_block<F>(&self, addr: u64, len: usize, mut f: F) -> Result<()>
+    where
+        F: FnMut(*mut u8, usize, usize),
+    {
+        let mut offset = 0;
+
+        while offset < len {
+            let current = match addr.checked_add(offset as u64) {
+                Some(current) => current,
+                None => bail!("guest memory access at {:#x} overflows", addr),
+            };
+
+            let region = match self.regions.iter().find(|r| r.contains(current)) {
+                Some(region) => region,
+                None => bail!("guest address {:#x} is not backed by memory", current),
+            };
+
+            let start = (current - region.guest) as usize;
+            let size = ((region.end() - current) as usize).min(len - offset);
+
+            f(unsafe { region.host.add(start) }, offset, size);
+
+            offset += size;
+        }
+
+        Ok(())
+    }
+
+    /// Reads guest memory starting at a guest physical address.
+    pub fn read(&self, addr: u64, buffer: &mut [u8]) -> Result<()> {
+        let dst = buffer.as_mut_ptr();
+
+        self.for_each_block(addr, buffer.len(), |host, offset, size| unsafe {
+            ptr::copy_nonoverlapping(host, dst.add(offset), size)
+        })
+    }
+
+    /// Writes to guest memory starting at a guest physical address.
+    pub fn write(&self, addr: u64, buffer: &[u8]) -> Result<()> {
+        let src = buffer.as_ptr();
+
+        self.for_each_block(addr, buffer.len(), |host, offset, size| unsafe {
+            ptr::copy_nonoverlapping(src.add(offset), host, size)
+        })
+    }
+
+    /// Reads an object from guest memory.
+    pub fn read_obj<T: ByteValued>(&self, addr: u64) -> Result<T> {
+        let mut value = T::default();
+
+        {
+            let bytes = unsafe {
+                slice::from_raw_parts_mut(&mut value as *mut T as *mut u8, mem::size_of::<T>())
+            };
+            self.read(addr, bytes)?;
+        }
+
+        Ok(value)
+    }
+
+    /// Writes an object to guest memory.
+    pub fn write_obj<T: ByteValued>(&self, addr: u64, value: T) -> Result<()> {
+        let bytes = unsafe { slice::from_raw_parts(&value as *const T as *const u8, mem::size_of::<T>()) };
+        self.write(addr, bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn access_across_slots() {
+        let memory = GuestMemory::new(&[(0x2000, 0x1000), (0x1000, 0x1000), (0x10000, 0x1000)]).unwrap();
+
+        assert_eq!(memory.size(), 0x3000);
+
+        // Write across the boundary of two adjacent slots.
+        memory.write_obj(0x1FFE, 0x1122_3344u32).unwrap();
+        assert_eq!(memory.read_obj::<u32>(0x1FFE).unwrap(), 0x1122_3344);
+        assert_eq!(memory.read_obj::<u16>(0x2000).unwrap(), 0x1122);
+
+        let mut buffer = [0; 4];
+        memory.read(0x1FFE, &mut buffer).unwrap();
+        assert_eq!(buffer, [0x44, 0x33, 0x22, 0x11]);
+
+        // Accesses must not go into unmapped memory.
+        assert!(memory.read_obj::<u32>(0x2FFE).is_err());
+        assert!(memory.write(0x8000, &[0]).is_err());
+
+        // Slots are numbered in order, reusing free slot numbers.
+        let mut memory = memory;
+        assert_eq!(memory.add_region(0x20000, 0x1000, MemoryFlags::READ_ONLY).unwrap(), 3);
+        memory.regions.retain(|r| r.slot != 1);
+        assert_eq!(memory.add_region(0x30000, 0x1000, MemoryFlags::empty()).unwrap(), 1);
+        assert!(memory.add_region(0x20000, 0x2000, MemoryFlags::empty()).is_err());
+
+        assert!(GuestMemory::new(&[(0x1000, 0x2000), (0x2000, 0x1000)]).is_err());
+        assert!(GuestMemory::new(&[(0x1000, 0x100)]).is_err());
+    }
+}
@@ -0,0 +1,70 @@
+//! Fixtures for the tests of the accelerators which run guest code.
+//!
+//! Only built with the `test-util` feature.
+
+use super::{Accelerator, CpuCallbacks, GuestMemory, IrqChipMode, VirtualCPU, VirtualMachine};
+use std::ops::Deref;
+
+/// Guest physical address of the code run by the tests.
+pub const CODE: u64 = 0x1000;
+
+/// Size of the RAM at the start of the guest physical address space.
+pub const RAM_SIZE: usize = 0xC000;
+
+/// A test VM, along with its memory and the callbacks of its vCPUs.
+///
+/// The VM is leaked, so that the vCPUs can borrow it for the whole test.
+pub struct TestVm<C: 'static> {
+    /// The guest's memory, registered with the VM.
+    pub memory: GuestMemory,
+    /// The callbacks given to the vCPUs.
+    pub cb: &'static C,
+    /// The virtual machine.
+    pub inner: &'static VirtualMachine<'static>,
+}
+
+impl<C> Deref for TestVm<C> {
+    type Target = VirtualMachine<'static>;
+
+    fn deref(&self) -> &Self::Target {
+        self.inner
+    }
+}
+
+/// A vCPU of a test VM.
+pub type TestVcpu = Box<VirtualCPU<'static>>;
+
+/// Creates a VM with RAM at the start of memory, whose vCPUs jump to `code`
+/// after reset. No vCPU is created.
+pub fn test_guest<C>(accel: &'static Accelerator, irqchip: IrqChipMode, code: &[u8]) -> TestVm<C>
+where
+    C: CpuCallbacks + Default,
+{
+    let base = 4 * 1024 * 1024 * 1024 - 4096;
+    let memory = GuestMemory::new(&[(0, RAM_SIZE), (base, 4096)]).unwrap();
+
+    memory.write(CODE, code).unwrap();
+
+    // jmp 0x0000:0x1000
+    memory.write(base + 4096 - 16, &[0xEA, 0x00, 0x10, 0x00, 0x00]).unwrap();
+
+    let inner: &'static VirtualMachine = Box::leak(accel.create_vm(irqchip).unwrap());
+
+    memory.register(inner).unwrap();
+
+    TestVm {
+        memory,
+        cb: Box::leak(Box::new(C::default())),
+        inner,
+    }
+}
+
+/// Creates a VM which runs `code`, and its first vCPU.
+pub fn test_vm<C>(accel: &'static Accelerator, irqchip: IrqChipMode, code: &[u8]) -> (TestVm<C>, TestVcpu)
+where
+    C: CpuCallbacks + Default,
+{
+    let vm: TestVm<C> = test_guest(accel, irqchip, code);
+    let vcpu = vm.inner.create_vcpu(0, None, vm.cb).unwrap();
+    (vm, vcpu)
+}
@@ -0,0 +1,1140 @@
+//! Fetching and executing x86 instructions.
+//!
+//! The interpreter supports real mode and flat protected mode (with segments
+//! set up through `sync`). Paging is not supported.
+
+use accel::errors::Result;
+use accel::{CpuCallbacks, ExitState};
+use std::{cmp, ptr};
+use vm::VirtualMachine;
+use x86::cpuid::Cpuid;
+use x86::decode::{self, Instruction, Memory, Mnemonic, Mode, Operand, Prefixes, Register};
+use x86::decode::SegmentRegister as Seg;
+use x86::msr;
+use x86::state::{Cr0, Cr4, DescriptorTable, Flags, Segment, State};
+
+/// Maximum number of bytes transferred by a single string I/O callback.
+const MAX_STRING_IO: usize = 4096;
+
+/// Returns a mask covering an operand of `size` bytes.
+fn mask(size: usize) -> u64 {
+    if size >= 8 {
+        !0
+    } else {
+        (1 << (size * 8)) - 1
+    }
+}
+
+/// Returns the sign bit of an operand of `size` bytes.
+fn sign_bit(size: usize) -> u64 {
+    1 << (size * 8 - 1)
+}
+
+/// Sign-extends an operand of `size` bytes to 64 bits.
+fn sign_extend(value: u64, size: usize) -> u64 {
+    let shift = 64 - size * 8;
+    (((value << shift) as i64) >> shift) as u64
+}
+
+/// Sign-extends a value of `bits` bits, at most 128, to 128 bits.
+fn sign_extend_wide(value: u128, bits: usize) -> i128 {
+    let shift = 128 - bits;
+    ((value << shift) as i128) >> shift
+}
+
+/// Returns the size of an operand, in bytes.
+fn operand_size(operand: &Operand) -> usize {
+    match *operand {
+        Operand::Register(register) => register.size() as usize,
+        Operand::Memory(memory) => memory.size as usize,
+        Operand::Immediate { size, .. } => size as usize,
+        Operand::Relative(_) | Operand::FarPointer { .. } => 0,
+    }
+}
+
+/// Returns the encoding of an arithmetic or logic instruction, as used by `alu`.
+fn alu_op(mnemonic: Mnemonic) -> Option<u8> {
+    let op = match mnemonic {
+        Mnemonic::Add => 0,
+        Mnemonic::Or => 1,
+        Mnemonic::Adc => 2,
+        Mnemonic::Sbb => 3,
+        Mnemonic::And => 4,
+        Mnemonic::Sub => 5,
+        Mnemonic::Xor => 6,
+        Mnemonic::Cmp => 7,
+        _ => return None,
+    };
+
+    Some(op)
+}
+
+/// The registers of a virtual CPU which are not held in its `State`,
+/// because the local APIC may own them.
+pub trait SystemRegisters {
+    /// Reads a model-specific register.
+    fn read_msr(&self, state: &State, index: u32) -> Result<u64>;
+
+    /// Writes a model-specific register.
+    fn write_msr(&self, state: &mut State, index: u32, value: u64) -> Result<()>;
+
+    /// Reads CR8, the task priority register.
+    fn cr8(&self, state: &State) -> u64;
+
+    /// Writes CR8.
+    fn set_cr8(&self, state: &mut State, value: u64);
+}
+
+/// Interprets instructions on behalf of a virtual CPU.
+pub struct Cpu<'a> {
+    vm: &'a VirtualMachine,
+    cb: &'a CpuCallbacks,
+    regs: &'a SystemRegisters,
+    cpuid: &'a Cpuid,
+    state: &'a mut State,
+    /// Address of the instruction being executed, to which faults return.
+    fault_ip: u64,
+    /// Set after an `STI` which enabled interrupts,
+    /// since they are only accepted after the next instruction.
+    interrupt_shadow: bool,
+}
+
+impl<'a> Cpu<'a> {
+    /// Creates a new interpreter for the given CPU state.
+    pub fn new(
+        vm: &'a VirtualMachine,
+        cb: &'a CpuCallbacks,
+        regs: &'a SystemRegisters,
+        cpuid: &'a Cpuid,
+        state: &'a mut State,
+    ) -> Self {
+        Cpu {
+            vm,
+            cb,
+            regs,
+            cpuid,
+            state,
+            fault_ip: 0,
+            interrupt_shadow: false,
+        }
+    }
+
+    /// Checks if an external interrupt can be delivered before the next instruction.
+    pub fn interrupts_enabled(&self) -> bool {
+        self.state.flags.contains(Flags::INTERRUPT) && !self.interrupt_shadow
+    }
+
+    /// Delivers an external interrupt.
+    pub fn external_interrupt(&mut self, vector: u8) -> Result<()> {
+        self.interrupt(vector)
+    }
+
+    /// Delivers a non-maskable interrupt.
+    pub fn nmi(&mut self) -> Result<()> {
+        self.interrupt(2)
+    }
+
+    /// Resets the processor, as done by an INIT. The `APIC_BASE` MSR does not change.
+    pub fn init(&mut self) {
+        let apic_base = self.state.apic_base;
+
+        *self.state = State::default();
+        self.state.apic_base = apic_base;
+        self.interrupt_shadow = false;
+    }
+
+    /// Starts executing in real mode, at the page given by a start-up IPI's vector.
+    pub fn startup(&mut self, vector: u8) -> Result<()> {
+        self.load_segment(Seg::Cs, u16::from(vector) << 8)?;
+        self.state.ip = 0;
+
+        Ok(())
+    }
+
+    /// Executes a single instruction.
+    ///
+    /// Returns `Some` if the vCPU must stop running.
+    pub fn step(&mut self) -> Result<Option<ExitState>> {
+        self.interrupt_shadow = false;
+
+        let start = self.state.ip;
+        self.fault_ip = start;
+        let mode = Mode::from_state(self.state);
+
+        let mut bytes = [0; decode::MAX_LENGTH];
+        let len = self.fetch(&mut bytes)?;
+
+        let insn = match decode::decode(&bytes[..len], mode) {
+            Ok(insn) => insn,
+            Err(error) => bail!("failed to decode instruction at {:#x}: {}", start, error),
+        };
+
+        let ip_size = if mode == Mode::Bits16 { 2 } else { 4 };
+        self.state.ip = start.wrapping_add(insn.len as u64) & mask(ip_size);
+
+        let result = self.execute(&insn);
+
+        if result.is_err() {
+            // Leave the instruction pointer at the faulting instruction.
+            self.state.ip = start;
+        }
+
+        result
+    }
+
+    /// Reads the bytes of the current instruction, stopping at the end of guest RAM.
+    ///
+    /// Returns the number of bytes read.
+    fn fetch(&self, bytes: &mut [u8]) -> Result<usize> {
+        let mut len = 0;
+
+        for byte in bytes.iter_mut() {
+            let addr = self.linear(Seg::Cs, self.state.ip.wrapping_add(len as u64))?;
+
+            match self.vm.host_address(addr, 1, false) {
+                Some(host) => *byte = unsafe { *host },
+                None => break,
+            }
+
+            len += 1;
+        }
+
+        if len == 0 {
+            bail!("executing code from MMIO at {:#x} is not supported", self.state.ip);
+        }
+
+        Ok(len)
+    }
+
+    /// Retrieves a segment register.
+    fn segment(&self, seg: Seg) -> &Segment {
+        match seg {
+            Seg::Es => &self.state.es,
+            Seg::Cs => &self.state.cs,
+            Seg::Ss => &self.state.ss,
+            Seg::Ds => &self.state.ds,
+            Seg::Fs => &self.state.fs,
+            Seg::Gs => &self.state.gs,
+        }
+    }
+
+    /// Retrieves a mutable reference to a segment register.
+    fn segment_mut(&mut self, seg: Seg) -> &mut Segment {
+        match seg {
+            Seg::Es => &mut self.state.es,
+            Seg::Cs => &mut self.state.cs,
+            Seg::Ss => &mut self.state.ss,
+            Seg::Ds => &mut self.state.ds,
+            Seg::Fs => &mut self.state.fs,
+            Seg::Gs => &mut self.state.gs,
+        }
+    }
+
+    /// Loads a segment register with a new selector.
+    fn load_segment(&mut self, seg: Seg, selector: u16) -> Result<()> {
+        if self.state.cr0.contains(Cr0::PROTECTED_MODE) {
+            bail!("loading segments in protected mode is not supported");
+        }
+
+        let segment = self.segment_mut(seg);
+        segment.selector = selector;
+        segment.base = u64::from(selector) << 4;
+
+        Ok(())
+    }
+
+    /// Reads from guest physical memory, falling back to MMIO.
+    fn read_phys(&self, addr: u64, data: &mut [u8]) -> Result<()> {
+        match self.vm.host_address(addr, data.len(), false) {
+            Some(host) => unsafe { ptr::copy_nonoverlapping(host, data.as_mut_ptr(), data.len()) },
+            None => self.cb.mmio(addr, false, data)?,
+        }
+
+        Ok(())
+    }
+
+    /// Writes to guest physical memory, falling back to MMIO.
+    fn write_phys(&self, addr: u64, data: &mut [u8]) -> Result<()> {
+        match self.vm.host_address(addr, data.len(), true) {
+            Some(host) => unsafe { ptr::copy_nonoverlapping(data.as_ptr(), host, data.len()) },
+            None => self.cb.mmio(addr, true, data)?,
+        }
+
+        Ok(())
+    }
+
+    /// Translates a segment-relative address to a physical address.
+    fn linear(&self, seg: Seg, offset: u64) -> Result<u64> {
+        if self.state.cr0.contains(Cr0::PAGING) {
+            bail!("paging is not supported by the interpreter");
+        }
+
+        Ok(self.segment(seg).base.wrapping_add(offset) & 0xFFFF_FFFF)
+    }
+
+    /// Reads a little-endian value of `size` bytes from memory.
+    fn read_mem(&self, seg: Seg, offset: u64, size: usize) -> Result<u64> {
+        let mut data = [0; 8];
+        let addr = self.linear(seg, offset)?;
+        self.read_phys(addr, &mut data[..size])?;
+        Ok(u64::from_le_bytes(data))
+    }
+
+    /// Writes a little-endian value of `size` bytes to memory.
+    fn write_mem(&self, seg: Seg, offset: u64, size: usize, value: u64) -> Result<()> {
+        let mut data = value.to_le_bytes();
+        let addr = self.linear(seg, offset)?;
+        self.write_phys(addr, &mut data[..size])
+    }
+
+    /// Reads a register.
+    fn reg(&self, register: Register) -> Result<u64> {
+        let value = match register {
+            Register::Gpr8High(index) => (self.state.r[index as usize] >> 8) & 0xFF,
+            Register::Gpr8(index) | Register::Gpr16(index) | Register::Gpr32(index) | Register::Gpr64(index) => {
+                self.state.r[index as usize] & mask(register.size() as usize)
+            }
+            Register::Segment(seg) => u64::from(self.segment(seg).selector),
+            Register::Ip(size) => self.state.ip & mask(size as usize),
+            Register::Control(0) => self.state.cr0.bits(),
+            Register::Control(2) => self.state.cr2,
+            Register::Control(3) => self.state.cr3,
+            Register::Control(4) => self.state.cr4.bits(),
+            Register::Control(8) => self.regs.cr8(self.state),
+            _ => bail!("register {} is not supported", register),
+        };
+
+        Ok(value)
+    }
+
+    /// Writes a register.
+    fn set_reg(&mut self, register: Register, value: u64) -> Result<()> {
+        match register {
+            Register::Gpr8High(index) => {
+                let r = &mut self.state.r[index as usize];
+                *r = (*r & !0xFF00) | ((value & 0xFF) << 8);
+            }
+            // 32-bit writes clear the upper half of the register.
+            Register::Gpr32(index) => self.state.r[index as usize] = value & mask(4),
+            Register::Gpr8(index) | Register::Gpr16(index) | Register::Gpr64(index) => {
+                let size = register.size() as usize;
+                let r = &mut self.state.r[index as usize];
+                *r = (*r & !mask(size)) | (value & mask(size));
+            }
+            Register::Segment(Seg::Cs) => bail!("CS cannot be loaded directly"),
+            Register::Segment(seg) => self.load_segment(seg, value as u16)?,
+            Register::Control(0) => self.state.cr0 = Cr0::from_bits_truncate(value),
+            Register::Control(2) => self.state.cr2 = value,
+            Register::Control(3) => self.state.cr3 = value,
+            Register::Control(4) => self.state.cr4 = Cr4::from_bits_truncate(value),
+            Register::Control(8) => self.regs.set_cr8(self.state, value),
+            _ => bail!("register {} is not supported", register),
+        }
+
+        Ok(())
+    }
+
+    /// Reads the low `size` bytes of a general-purpose register.
+    fn gpr(&self, size: usize, index: u8) -> u64 {
+        self.state.r[index as usize] & mask(size)
+    }
+
+    /// Writes the low `size` bytes of a general-purpose register.
+    fn set_gpr(&mut self, size: usize, index: u8, value: u64) {
+        // This cannot fail for general-purpose registers.
+        let _ = self.set_reg(Register::gpr(index, size as u8), value);
+    }
+
+    /// Writes a 64-bit value to EDX:EAX.
+    fn set_edx_eax(&mut self, value: u64) {
+        self.set_gpr(4, 0, value & mask(4));
+        self.set_gpr(4, 2, value >> 32);
+    }
+
+    /// Computes the offset of a memory operand in its segment.
+    fn address(&self, memory: &Memory, address_size: u8) -> Result<u64> {
+        let mut offset = memory.displacement as u64;
+
+        if let Some(base) = memory.base {
+            offset = offset.wrapping_add(self.reg(base)?);
+        }
+
+        if let Some(index) = memory.index {
+            offset = offset.wrapping_add(self.reg(index)? * u64::from(memory.scale));
+        }
+
+        Ok(offset & mask(address_size as usize))
+    }
+
+    /// Reads the value of an operand.
+    fn read(&self, insn: &Instruction, operand: &Operand) -> Result<u64> {
+        match *operand {
+            Operand::Register(register) => self.reg(register),
+            Operand::Memory(ref memory) => {
+                let offset = self.address(memory, insn.address_size)?;
+                self.read_mem(memory.segment, offset, memory.size as usize)
+            }
+            Operand::Immediate { value, .. } => Ok(value),
+            _ => bail!("invalid operand for instruction {}", insn),
+        }
+    }
+
+    /// Writes the value of an operand.
+    fn write(&mut self, insn: &Instruction, operand: &Operand, value: u64) -> Result<()> {
+        match *operand {
+            Operand::Register(register) => self.set_reg(register, value),
+            Operand::Memory(ref memory) => {
+                let offset = self.address(memory, insn.address_size)?;
+                self.write_mem(memory.segment, offset, memory.size as usize, value)
+            }
+            _ => bail!("invalid operand for instruction {}", insn),
+        }
+    }
+
+    /// Reads the limit and base of a descriptor table from memory, for LGDT and LIDT.
+    fn descriptor_table(&self, insn: &Instruction, operand: &Operand) -> Result<DescriptorTable> {
+        let memory = match *operand {
+            Operand::Memory(ref memory) => memory,
+            _ => bail!("invalid operand for instruction {}", insn),
+        };
+
+        let offset = self.address(memory, insn.address_size)?;
+        let limit = self.read_mem(memory.segment, offset, 2)? as u16;
+
+        let base_size = if insn.mode == Mode::Bits64 { 8 } else { 4 };
+        let mut base = self.read_mem(memory.segment, offset.wrapping_add(2), base_size)?;
+
+        // With a 16-bit operand size, only 24 bits of the base are loaded.
+        if insn.operand_size == 2 && insn.mode != Mode::Bits64 {
+            base &= 0xFF_FFFF;
+        }
+
+        Ok(DescriptorTable { base, limit })
+    }
+
+    /// Updates the zero, sign and parity flags based on a result.
+    fn set_result_flags(&mut self, result: u64, size: usize) {
+        let result = result & mask(size);
+        let flags = &mut self.state.flags;
+
+        flags.set(Flags::ZERO, result == 0);
+        flags.set(Flags::SIGN, result & sign_bit(size) != 0);
+        flags.set(Flags::PARITY, (result as u8).count_ones() & 1 == 0);
+    }
+
+    /// Performs an arithmetic or logic operation, updating the flags.
+    ///
+    /// The operation is encoded as in the ALU instructions' opcodes:
+    /// ADD, OR, ADC, SBB, AND, SUB, XOR, CMP.
+    fn alu(&mut self, op: u8, a: u64, b: u64, size: usize) -> u64 {
+        let carry = self.state.flags.contains(Flags::CARRY) as u64;
+        let sign = sign_bit(size);
+
+        let (result, cf, of) = match op {
+            0 | 2 => {
+                let carry = if op == 2 { carry } else { 0 };
+                let result = a.wrapping_add(b).wrapping_add(carry) & mask(size);
+                let cf = result < a || (carry != 0 && result == a);
+                let of = (a ^ result) & (b ^ result) & sign != 0;
+                (result, cf, of)
+            }
+            3 | 5 | 7 => {
+                let carry = if op == 3 { carry } else { 0 };
+                let result = a.wrapping_sub(b).wrapping_sub(carry) & mask(size);
+                let cf = a < b || (carry != 0 && a == b);
+                let of = (a ^ b) & (a ^ result) & sign != 0;
+                (result, cf, of)
+            }
+            1 => (a | b, false, false),
+            4 => (a & b, false, false),
+            _ => (a ^ b, false, false),
+        };
+
+        self.state.flags.set(Flags::CARRY, cf);
+        self.state.flags.set(Flags::OVERFLOW, of);
+        self.state.flags.set(Flags::ADJUST, (a ^ b ^ result) & 0x10 != 0);
+        self.set_result_flags(result, size);
+
+        result
+    }
+
+    /// Increments or decrements a value, preserving the carry flag.
+    fn inc_dec(&mut self, value: u64, size: usize, decrement: bool) -> u64 {
+        let carry = self.state.flags.contains(Flags::CARRY);
+        let result = self.alu(if decrement { 5 } else { 0 }, value, 1, size);
+        self.state.flags.set(Flags::CARRY, carry);
+        result
+    }
+
+    /// Returns the size of the stack pointer.
+    fn stack_size(&self) -> usize {
+        if self.state.ss.op_size {
+            4
+        } else {
+            2
+        }
+    }
+
+    /// Pushes a value on the stack.
+    fn push(&mut self, value: u64, size: usize) -> Result<()> {
+        let sp_mask = mask(self.stack_size());
+        let sp = self.state.r[4].wrapping_sub(size as u64) & sp_mask;
+
+        self.write_mem(Seg::Ss, sp, size, value)?;
+        self.state.r[4] = (self.state.r[4] & !sp_mask) | sp;
+
+        Ok(())
+    }
+
+    /// Pops a value from the stack.
+    fn pop(&mut self, size: usize) -> Result<u64> {
+        let sp_mask = mask(self.stack_size());
+        let sp = self.state.r[4] & sp_mask;
+
+        let value = self.read_mem(Seg::Ss, sp, size)?;
+        self.state.r[4] = (self.state.r[4] & !sp_mask) | (sp.wrapping_add(size as u64) & sp_mask);
+
+        Ok(value)
+    }
+
+    /// Releases `bytes` bytes from the stack.
+    fn release_stack(&mut self, bytes: u64) {
+        let sp_mask = mask(self.stack_size());
+        let sp = self.state.r[4].wrapping_add(bytes) & sp_mask;
+        self.state.r[4] = (self.state.r[4] & !sp_mask) | sp;
+    }
+
+    /// Jumps to an absolute offset in the current code segment.
+    fn jump(&mut self, ip: u64, size: usize) {
+        self.state.ip = ip & mask(size);
+    }
+
+    /// Returns the target of a near branch instruction.
+    fn branch_target(&self, insn: &Instruction, operand: &Operand) -> Result<u64> {
+        match *operand {
+            Operand::Relative(offset) => Ok(self.state.ip.wrapping_add(offset as u64)),
+            _ => self.read(insn, operand),
+        }
+    }
+
+    /// Reads the target of a far branch instruction, as a selector and an offset.
+    fn far_target(&self, insn: &Instruction, operand: &Operand) -> Result<(u16, u64)> {
+        match *operand {
+            Operand::FarPointer { selector, offset } => Ok((selector, u64::from(offset))),
+            Operand::Memory(ref memory) => {
+                let size = insn.operand_size as usize;
+                let address = self.address(memory, insn.address_size)?;
+                let offset = self.read_mem(memory.segment, address, size)?;
+                let selector = self.read_mem(memory.segment, address.wrapping_add(size as u64), 2)?;
+                Ok((selector as u16, offset))
+            }
+            _ => bail!("invalid operand for instruction {}", insn),
+        }
+    }
+
+    /// Evaluates the condition of a conditional instruction.
+    fn condition(&self, cc: u8) -> bool {
+        let flags = self.state.flags;
+        let of = flags.contains(Flags::OVERFLOW);
+        let cf = flags.contains(Flags::CARRY);
+        let zf = flags.contains(Flags::ZERO);
+        let sf = flags.contains(Flags::SIGN);
+        let pf = flags.contains(Flags::PARITY);
+
+        let result = match cc >> 1 {
+            0 => of,
+            1 => cf,
+            2 => zf,
+            3 => cf || zf,
+            4 => sf,
+            5 => pf,
+            6 => sf != of,
+            _ => zf || sf != of,
+        };
+
+        // Odd condition codes are negated.
+        result != (cc & 1 != 0)
+    }
+
+    /// Delivers an interrupt through the real mode interrupt vector table.
+    fn interrupt(&mut self, vector: u8) -> Result<()> {
+        if self.state.cr0.contains(Cr0::PROTECTED_MODE) {
+            bail!("interrupts are only supported in real mode");
+        }
+
+        let flags = self.state.flags.bits();
+        let cs = u64::from(self.state.cs.selector);
+        let ip = self.state.ip;
+
+        self.push(flags, 2)?;
+        self.push(cs, 2)?;
+        self.push(ip, 2)?;
+
+        self.state.flags.remove(Flags::INTERRUPT | Flags::TRAP);
+
+        let mut entry = [0; 4];
+        self.read_phys(u64::from(vector) * 4, &mut entry)?;
+        let entry = u32::from_le_bytes(entry);
+
+        self.load_segment(Seg::Cs, (entry >> 16) as u16)?;
+        self.state.ip = u64::from(entry & 0xFFFF);
+
+        Ok(())
+    }
+
+    /// Performs a port I/O operation on the accumulator.
+    fn port_io(&mut self, port: u16, output: bool, size: usize) -> Result<()> {
+        let mut buffer = (self.gpr(size, 0) as u32).to_le_bytes();
+
+        self.cb.port_io(port, output, &mut buffer[..size], size)?;
+
+        if !output {
+            let value = u32::from_le_bytes(buffer);
+            self.set_gpr(size, 0, u64::from(value));
+        }
+
+        Ok(())
+    }
+
+    /// Checks if a string instruction has a repeat prefix.
+    fn is_repeated(insn: &Instruction) -> bool {
+        insn.prefixes.intersects(Prefixes::REP | Prefixes::REPNE)
+    }
+
+    /// Number of iterations of a string instruction.
+    fn string_count(&self, insn: &Instruction) -> u64 {
+        if Self::is_repeated(insn) {
+            self.gpr(insn.address_size as usize, 1)
+        } else {
+            1
+        }
+    }
+
+    /// Returns the offset of the `index`th element of a string operand.
+    fn string_offset(&self, insn: &Instruction, reg: usize, index: usize, size: usize) -> u64 {
+        let base = self.state.r[reg];
+        let delta = (index * size) as u64;
+
+        let offset = if self.state.flags.contains(Flags::DIRECTION) {
+            base.wrapping_sub(delta)
+        } else {
+            base.wrapping_add(delta)
+        };
+
+        offset & mask(insn.address_size as usize)
+    }
+
+    /// Advances a string instruction's index register past `count` elements.
+    fn string_advance(&mut self, insn: &Instruction, reg: usize, count: usize, size: usize) {
+        let offset = self.string_offset(insn, reg, count, size);
+        let addr_mask = mask(insn.address_size as usize);
+        self.state.r[reg] = (self.state.r[reg] & !addr_mask) | offset;
+    }
+
+    /// Decrements the counter register of a repeated string instruction.
+    fn string_decrement(&mut self, insn: &Instruction, count: usize) {
+        if Self::is_repeated(insn) {
+            let addr_mask = mask(insn.address_size as usize);
+            let cx = self.state.r[1].wrapping_sub(count as u64) & addr_mask;
+            self.state.r[1] = (self.state.r[1] & !addr_mask) | cx;
+        }
+    }
+
+    /// Executes the INS / OUTS instructions.
+    ///
+    /// Repeated instructions are batched, to reduce the number of callbacks.
+    fn string_io(&mut self, insn: &Instruction, output: bool) -> Result<()> {
+        let port = self.state.r[2] as u16;
+        let memory = match insn.operands[if output { 1 } else { 0 }] {
+            Operand::Memory(memory) => memory,
+            _ => bail!("invalid operand for instruction {}", insn),
+        };
+        let seg = memory.segment;
+        let size = memory.size as usize;
+        let reg = if output { 6 } else { 7 };
+
+        let mut remaining = self.string_count(insn) as usize;
+
+        while remaining > 0 {
+            let count = cmp::min(remaining, MAX_STRING_IO / size);
+            let mut buffer = vec![0; count * size];
+
+            if output {
+                for (i, element) in buffer.chunks_mut(size).enumerate() {
+                    let offset = self.string_offset(insn, reg, i, size);
+                    let value = self.read_mem(seg, offset, size)?;
+                    element.copy_from_slice(&value.to_le_bytes()[..size]);
+                }
+            }
+
+            self.cb.port_io(port, output, &mut buffer, size)?;
+
+            if !output {
+                for (i, element) in buffer.chunks(size).enumerate() {
+                    let offset = self.string_offset(insn, reg, i, size);
+                    let mut value = [0; 8];
+                    value[..size].copy_from_slice(element);
+                    self.write_mem(seg, offset, size, u64::from_le_bytes(value))?;
+                }
+            }
+
+            self.string_advance(insn, reg, count, size);
+            self.string_decrement(insn, count);
+            remaining -= count;
+        }
+
+        Ok(())
+    }
+
+    /// Executes the MOVS, STOS and LODS instructions.
+    fn string_move(&mut self, insn: &Instruction) -> Result<()> {
+        let (dst, src) = (&insn.operands[0], &insn.operands[1]);
+        let size = operand_size(dst);
+        let count = self.string_count(insn);
+
+        for _ in 0..count {
+            let value = self.read(insn, src)?;
+            self.write(insn, dst, value)?;
+
+            // Advance the index registers used by the memory operands.
+            for operand in &insn.operands {
+                if let Operand::Memory(Memory { base: Some(base), .. }) = *operand {
+                    let reg = match base {
+                        Register::Gpr16(index) | Register::Gpr32(index) | Register::Gpr64(index) => index,
+                        _ => continue,
+                    };
+                    self.string_advance(insn, reg as usize, 1, size);
+                }
+            }
+
+            self.string_decrement(insn, 1);
+        }
+
+        Ok(())
+    }
+
+    /// Executes a shift or rotate instruction.
+    fn shift(&mut self, mnemonic: Mnemonic, value: u64, count: u64, size: usize) -> Result<u64> {
+        let bits = (size * 8) as u64;
+        let count = count & if size == 8 { 0x3F } else { 0x1F };
+
+        if count == 0 {
+            return Ok(value);
+        }
+
+        let msb = |value: u64| value & sign_bit(size) != 0;
+
+        let result = match mnemonic {
+            Mnemonic::Rol => {
+                let c = count % bits;
+                let result = ((value << c) | (value >> (bits - c))) & mask(size);
+                let cf = result & 1 != 0;
+                self.state.flags.set(Flags::CARRY, cf);
+                self.state.flags.set(Flags::OVERFLOW, msb(result) != cf);
+                return Ok(result);
+            }
+            Mnemonic::Ror => {
+                let c = count % bits;
+                let result = ((value >> c) | (value << (bits - c))) & mask(size);
+                self.state.flags.set(Flags::CARRY, msb(result));
+                self.state.flags.set(Flags::OVERFLOW, msb(result) != msb(result << 1));
+                return Ok(result);
+            }
+            Mnemonic::Shl => {
+                let result = value.checked_shl(count as u32).unwrap_or(0) & mask(size);
+                let cf = count <= bits && (value >> (bits - count)) & 1 != 0;
+                self.state.flags.set(Flags::CARRY, cf);
+                self.state.flags.set(Flags::OVERFLOW, msb(result) != cf);
+                result
+            }
+            Mnemonic::Shr => {
+                let result = value.checked_shr(count as u32).unwrap_or(0);
+                let cf = (value >> (count - 1)) & 1 != 0;
+                self.state.flags.set(Flags::CARRY, cf);
+                self.state.flags.set(Flags::OVERFLOW, msb(value));
+                result
+            }
+            Mnemonic::Sar => {
+                let value = sign_extend(value, size) as i64;
+                let result = (value >> count) as u64 & mask(size);
+                let cf = (value >> (count - 1)) & 1 != 0;
+                self.state.flags.set(Flags::CARRY, cf);
+                self.state.flags.set(Flags::OVERFLOW, false);
+                result
+            }
+            _ => bail!("rotate through carry is not supported"),
+        };
+
+        self.set_result_flags(result, size);
+
+        Ok(result)
+    }
+
+    /// Executes the one-operand forms of MUL, IMUL, DIV and IDIV,
+    /// which operate on the accumulator.
+    fn multiply_divide(&mut self, mnemonic: Mnemonic, value: u64, size: usize) -> Result<()> {
+        // The results have twice the size of the operands, so they are computed in 128 bits.
+        let bits = size * 8;
+
+        match mnemonic {
+            Mnemonic::Mul | Mnemonic::Imul => {
+                let a = self.gpr(size, 0);
+
+                let (product, overflow) = if mnemonic == Mnemonic::Mul {
+                    let product = u128::from(a) * u128::from(value);
+                    (product, product >> bits != 0)
+                } else {
+                    let product = i128::from(sign_extend(a, size) as i64) * i128::from(sign_extend(value, size) as i64);
+                    let low = sign_extend(product as u64 & mask(size), size) as i64;
+                    (product as u128, i128::from(low) != product)
+                };
+
+                if size == 1 {
+                    self.set_gpr(2, 0, product as u64);
+                } else {
+                    self.set_gpr(size, 0, product as u64);
+                    self.set_gpr(size, 2, (product >> bits) as u64);
+                }
+
+                self.state.flags.set(Flags::CARRY, overflow);
+                self.state.flags.set(Flags::OVERFLOW, overflow);
+            }
+            _ => {
+                if value == 0 {
+                    return self.divide_error();
+                }
+
+                let dividend = if size == 1 {
+                    u128::from(self.gpr(2, 0))
+                } else {
+                    u128::from(self.gpr(size, 2)) << bits | u128::from(self.gpr(size, 0))
+                };
+
+                let (quotient, remainder) = if mnemonic == Mnemonic::Div {
+                    let divisor = u128::from(value);
+                    let quotient = dividend / divisor;
+
+                    if quotient > u128::from(mask(size)) {
+                        return self.divide_error();
+                    }
+
+                    (quotient as u64, (dividend % divisor) as u64)
+                } else {
+                    let dividend = sign_extend_wide(dividend, bits * 2);
+                    let divisor = i128::from(sign_extend(value, size) as i64);
+
+                    // Only the largest negative dividend divided by -1 overflows 128 bits.
+                    let (quotient, remainder) = match (dividend.checked_div(divisor), dividend.checked_rem(divisor)) {
+                        (Some(quotient), Some(remainder)) => (quotient, remainder),
+                        _ => return self.divide_error(),
+                    };
+
+                    if i128::from(sign_extend(quotient as u64 & mask(size), size) as i64) != quotient {
+                        return self.divide_error();
+                    }
+
+                    (quotient as u64 & mask(size), remainder as u64 & mask(size))
+                };
+
+                if size == 1 {
+                    self.set_gpr(1, 0, quotient);
+                    self.set_reg(Register::Gpr8High(0), remainder)?;
+                } else {
+                    self.set_gpr(size, 0, quotient);
+                    self.set_gpr(size, 2, remainder);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Raises a divide error. Since it is a fault, the handler returns to the division.
+    fn divide_error(&mut self) -> Result<()> {
+        self.state.ip = self.fault_ip;
+        self.interrupt(0)
+    }
+
+    /// Executes a decoded instruction.
+    fn execute(&mut self, insn: &Instruction) -> Result<Option<ExitState>> {
+        let ops = &insn.operands[..];
+        let size = insn.operand_size as usize;
+        let mnemonic = insn.mnemonic;
+
+        if let Some(op) = alu_op(mnemonic) {
+            let a = self.read(insn, &ops[0])?;
+            let b = self.read(insn, &ops[1])?;
+            let result = self.alu(op, a, b, operand_size(&ops[0]));
+
+            if mnemonic != Mnemonic::Cmp {
+                self.write(insn, &ops[0], result)?;
+            }
+
+            return Ok(None);
+        }
+
+        if let Some(cc) = mnemonic.condition() {
+            let taken = self.condition(cc);
+
+            match ops {
+                // Jcc
+                [target @ Operand::Relative(_)] => {
+                    if taken {
+                        let target = self.branch_target(insn, target)?;
+                        self.jump(target, size);
+                    }
+                }
+                // SETcc
+                [dst] => self.write(insn, dst, taken as u64)?,
+                // CMOVcc
+                _ => {
+                    let value = if taken { &ops[1] } else { &ops[0] };
+                    let value = self.read(insn, value)?;
+                    self.write(insn, &ops[0], value)?;
+                }
+            }
+
+            return Ok(None);
+        }
+
+        match mnemonic {
+            Mnemonic::Test => {
+                let a = self.read(insn, &ops[0])?;
+                let b = self.read(insn, &ops[1])?;
+                self.alu(4, a, b, operand_size(&ops[0]));
+            }
+            Mnemonic::Inc | Mnemonic::Dec => {
+                let value = self.read(insn, &ops[0])?;
+                let result = self.inc_dec(value, operand_size(&ops[0]), mnemonic == Mnemonic::Dec);
+                self.write(insn, &ops[0], result)?;
+            }
+            Mnemonic::Not => {
+                let value = self.read(insn, &ops[0])?;
+                self.write(insn, &ops[0], !value)?;
+            }
+            Mnemonic::Neg => {
+                let value = self.read(insn, &ops[0])?;
+                let result = self.alu(5, 0, value, operand_size(&ops[0]));
+                self.write(insn, &ops[0], result)?;
+            }
+            Mnemonic::Mul | Mnemonic::Div | Mnemonic::Idiv => {
+                let value = self.read(insn, &ops[0])?;
+                self.multiply_divide(mnemonic, value, operand_size(&ops[0]))?;
+            }
+            Mnemonic::Imul if ops.len() == 1 => {
+                let value = self.read(insn, &ops[0])?;
+                self.multiply_divide(mnemonic, value, operand_size(&ops[0]))?;
+            }
+            Mnemonic::Imul => {
+                // The two and three operand forms truncate the result.
+                let a = sign_extend(self.read(insn, &ops[ops.len() - 2])?, size) as i64;
+                let b = sign_extend(self.read(insn, &ops[ops.len() - 1])?, size) as i64;
+                let product = a.wrapping_mul(b) as u64;
+                let overflow = sign_extend(product & mask(size), size) != product;
+
+                self.write(insn, &ops[0], product)?;
+                self.state.flags.set(Flags::CARRY, overflow);
+                self.state.flags.set(Flags::OVERFLOW, overflow);
+            }
+            Mnemonic::Mov | Mnemonic::Movzx => {
+                let value = self.read(insn, &ops[1])?;
+                self.write(insn, &ops[0], value)?;
+            }
+            Mnemonic::Movsx => {
+                let value = sign_extend(self.read(insn, &ops[1])?, operand_size(&ops[1]));
+                self.write(insn, &ops[0], value)?;
+            }
+            Mnemonic::Lea => match ops[1] {
+                Operand::Memory(ref memory) => {
+                    let offset = self.address(memory, insn.address_size)?;
+                    self.write(insn, &ops[0], offset)?;
+                }
+                _ => bail!("LEA requires a memory operand"),
+            },
+            Mnemonic::Xchg => {
+                let a = self.read(insn, &ops[0])?;
+                let b = self.read(insn, &ops[1])?;
+                self.write(insn, &ops[0], b)?;
+                self.write(insn, &ops[1], a)?;
+            }
+            Mnemonic::Push => {
+                let value = self.read(insn, &ops[0])?;
+                self.push(value, size)?;
+            }
+            Mnemonic::Pop => {
+                let value = self.pop(size)?;
+                self.write(insn, &ops[0], value)?;
+            }
+            Mnemonic::Cbw => {
+                let half = size / 2;
+                let value = sign_extend(self.gpr(half, 0), half);
+                self.set_gpr(size, 0, value);
+            }
+            Mnemonic::Cwd => {
+                let value = if self.gpr(size, 0) & sign_bit(size) != 0 { mask(size) } else { 0 };
+                self.set_gpr(size, 2, value);
+            }
+            Mnemonic::Pushf => {
+                let flags = self.state.flags.bits();
+                self.push(flags, size)?;
+            }
+            Mnemonic::Popf => {
+                let value = self.pop(size)?;
+                let flags = (self.state.flags.bits() & !mask(size)) | value;
+                self.state.flags = Flags::from_bits_truncate(flags) | Flags::RESERVED_ONE;
+            }
+            Mnemonic::Sahf => {
+                let ah = self.reg(Register::Gpr8High(0))?;
+                let flags = (self.state.flags.bits() & !0xFF) | ah;
+                self.state.flags = Flags::from_bits_truncate(flags) | Flags::RESERVED_ONE;
+            }
+            Mnemonic::Lahf => {
+                let flags = self.state.flags.bits() & 0xFF;
+                self.set_reg(Register::Gpr8High(0), flags)?;
+            }
+            Mnemonic::Movs | Mnemonic::Stos | Mnemonic::Lods => self.string_move(insn)?,
+            Mnemonic::Ins => self.string_io(insn, false)?,
+            Mnemonic::Outs => self.string_io(insn, true)?,
+            Mnemonic::Rol | Mnemonic::Ror | Mnemonic::Rcl | Mnemonic::Rcr | Mnemonic::Shl | Mnemonic::Shr
+            | Mnemonic::Sar => {
+                let value = self.read(insn, &ops[0])?;
+                let count = self.read(insn, &ops[1])?;
+                let result = self.shift(mnemonic, value, count, operand_size(&ops[0]))?;
+                self.write(insn, &ops[0], result)?;
+            }
+            Mnemonic::Ret | Mnemonic::Retf => {
+                let ip = self.pop(size)?;
+
+                if mnemonic == Mnemonic::Retf {
+                    let cs = self.pop(size)? as u16;
+                    self.load_segment(Seg::Cs, cs)?;
+                }
+
+                self.jump(ip, size);
+
+                if let Some(operand) = ops.first() {
+                    let bytes = self.read(insn, operand)?;
+                    self.release_stack(bytes);
+                }
+            }
+            Mnemonic::Int3 => self.interrupt(3)?,
+            Mnemonic::Int => {
+                let vector = self.read(insn, &ops[0])? as u8;
+                self.interrupt(vector)?;
+            }
+            Mnemonic::Into => {
+                if self.state.flags.contains(Flags::OVERFLOW) {
+                    self.interrupt(4)?;
+                }
+            }
+            Mnemonic::Iret => {
+                if self.state.cr0.contains(Cr0::PROTECTED_MODE) {
+                    bail!("IRET is only supported in real mode");
+                }
+                let ip = self.pop(size)?;
+                let cs = self.pop(size)? as u16;
+                let flags = self.pop(size)?;
+                self.load_segment(Seg::Cs, cs)?;
+                self.jump(ip, size);
+                let flags = (self.state.flags.bits() & !mask(size)) | flags;
+                self.state.flags = Flags::from_bits_truncate(flags) | Flags::RESERVED_ONE;
+            }
+            Mnemonic::Loop | Mnemonic::Loope | Mnemonic::Loopne => {
+                let addr_size = insn.address_size as usize;
+                let cx = self.gpr(addr_size, 1).wrapping_sub(1) & mask(addr_size);
+                self.set_gpr(addr_size, 1, cx);
+
+                let zf = self.state.flags.contains(Flags::ZERO);
+                let taken = cx != 0 && match mnemonic {
+                    Mnemonic::Loopne => !zf,
+                    Mnemonic::Loope => zf,
+                    _ => true,
+                };
+
+                if taken {
+                    let target = self.branch_target(insn, &ops[0])?;
+                    self.jump(target, size);
+                }
+            }
+            Mnemonic::Jcxz => {
+                if self.gpr(insn.address_size as usize, 1) == 0 {
+                    let target = self.branch_target(insn, &ops[0])?;
+                    self.jump(target, size);
+                }
+            }
+            Mnemonic::In => {
+                let port = self.read(insn, &ops[1])? as u16;
+                self.port_io(port, false, operand_size(&ops[0]))?;
+            }
+            Mnemonic::Out => {
+                let port = self.read(insn, &ops[0])? as u16;
+                self.port_io(port, true, operand_size(&ops[1]))?;
+            }
+            Mnemonic::Call => {
+                let target = self.branch_target(insn, &ops[0])?;
+                let ip = self.state.ip;
+                self.push(ip, size)?;
+                self.jump(target, size);
+            }
+            Mnemonic::Jmp => {
+                let target = self.branch_target(insn, &ops[0])?;
+                self.jump(target, size);
+            }
+            Mnemonic::Callf | Mnemonic::Jmpf => {
+                let (cs, ip) = self.far_target(insn, &ops[0])?;
+
+                if mnemonic == Mnemonic::Callf {
+                    let (old_cs, old_ip) = (u64::from(self.state.cs.selector), self.state.ip);
+                    self.push(old_cs, size)?;
+                    self.push(old_ip, size)?;
+                }
+
+                self.load_segment(Seg::Cs, cs)?;
+                self.jump(ip, size);
+            }
+            Mnemonic::Cpuid => {
+                let function = self.state.r[0] as u32;
+                let index = self.state.r[1] as u32;
+
+                // Leaves which are not in the table return zeros.
+                let r = self.cpuid.get(function, index).map_or([0; 4], |entry| entry.r);
+
+                self.state.r[0] = u64::from(r[0]);
+                self.state.r[3] = u64::from(r[1]);
+                self.state.r[1] = u64::from(r[2]);
+                self.state.r[2] = u64::from(r[3]);
+            }
+            Mnemonic::Lgdt => self.state.gdt = self.descriptor_table(insn, &ops[0])?,
+            Mnemonic::Lidt => self.state.idt = self.descriptor_table(insn, &ops[0])?,
+            Mnemonic::Rdtsc => {
+                let tsc = self.regs.read_msr(self.state, msr::TSC)?;
+                self.set_edx_eax(tsc);
+            }
+            Mnemonic::Rdmsr => {
+                let index = self.state.r[1] as u32;
+                let value = self.regs.read_msr(self.state, index)?;
+                self.set_edx_eax(value);
+            }
+            Mnemonic::Wrmsr => {
+                let index = self.state.r[1] as u32;
+                let value = self.gpr(4, 2) << 32 | self.gpr(4, 0);
+                self.regs.write_msr(self.state, index, value)?;
+            }
+            Mnemonic::Hlt => return Ok(Some(ExitState::Halt)),
+            Mnemonic::Cmc => self.state.flags.toggle(Flags::CARRY),
+            Mnemonic::Clc => self.state.flags.remove(Flags::CARRY),
+            Mnemonic::Stc => self.state.flags.insert(Flags::CARRY),
+            Mnemonic::Cli => self.state.flags.remove(Flags::INTERRUPT),
+            Mnemonic::Sti => {
+                self.interrupt_shadow = !self.state.flags.contains(Flags::INTERRUPT);
+                self.state.flags.insert(Flags::INTERRUPT);
+            }
+            Mnemonic::Cld => self.state.flags.remove(Flags::DIRECTION),
+            Mnemonic::Std => self.state.flags.insert(Flags::DIRECTION),
+            Mnemonic::Nop | Mnemonic::Pause | Mnemonic::Fwait => (),
+            _ => bail!("unsupported instruction: {}", insn),
+        }
+
+        Ok(None)
+    }
+}
@@ -0,0 +1,21 @@
+//! The interpreter's global object, used to create virtual machines.
+
+use accel;
+use accel::errors::Result;
+use vm::VirtualMachine;
+
+/// The software accelerator.
+///
+/// It has no state of its own, since it does not depend on any host resources.
+#[derive(Debug, Copy, Clone)]
+pub struct Interpreter;
+
+impl accel::Accelerator for Interpreter {
+    fn create_vm<'a>(&'a self, irqchip: accel::IrqChipMode) -> Result<Box<accel::VirtualMachine + 'a>> {
+        if irqchip == accel::IrqChipMode::Kernel {
+            bail!("the interpreter only emulates the local APICs, the PICs must be emulated in user space");
+        }
+
+        Ok(Box::new(VirtualMachine::new(irqchip)))
+    }
+}
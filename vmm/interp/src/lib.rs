@@ -0,0 +1,30 @@
+//! Software virtualization, by interpreting the guest's instructions.
+//!
+//! # Usage
+//! Use the [`create`](fn.create.html) function to create an `Accelerator` object.
+//!
+//! The interpreter delivers the same callbacks as the hardware accelerators,
+//! which makes it useful for testing device models on hosts without
+//! hardware virtualization support.
+
+#![warn(missing_docs, missing_copy_implementations, missing_debug_implementations)]
+#![cfg_attr(feature = "cargo-clippy", warn(clippy))]
+
+#[macro_use]
+extern crate error_chain;
+
+extern crate accel;
+
+extern crate intc;
+
+extern crate vm_x86 as x86;
+
+mod global;
+mod vm;
+mod vcpu;
+mod exec;
+
+/// Creates an object which implements the `Accelerator` trait.
+pub fn create() -> accel::errors::Result<Box<accel::Accelerator>> {
+    Ok(Box::new(global::Interpreter))
+}
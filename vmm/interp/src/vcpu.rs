@@ -0,0 +1,825 @@
+use accel;
+use accel::errors::Result;
+use accel::CpuCallbacks;
+use exec::{Cpu, SystemRegisters};
+use intc::Lapic;
+use std::cell::{Cell, RefCell};
+use vm::VirtualMachine;
+use x86::cpuid::Cpuid;
+use x86::msr::{self, MSRState};
+use x86::state::{Efer, State};
+
+pub struct VirtualCPU<'a> {
+    vm: &'a VirtualMachine,
+    id: usize,
+    state: RefCell<State>,
+    msrs: RefCell<MSRState>,
+    cpuid: Cpuid,
+    cb: &'a CpuCallbacks,
+    /// An injected interrupt, delivered once the guest can accept it.
+    pending_interrupt: Cell<Option<u8>>,
+    /// Set if `run` must stop when the guest can accept an interrupt.
+    interrupt_window: Cell<bool>,
+    /// Set after an INIT, until the local APIC receives a start-up IPI.
+    waiting_for_startup: Cell<bool>,
+}
+
+impl<'a> VirtualCPU<'a> {
+    /// Initializes the virtual CPU, in its reset state.
+    pub fn new(vm: &'a VirtualMachine, id: usize, cpuid: Cpuid, cb: &'a CpuCallbacks) -> Self {
+        VirtualCPU {
+            vm,
+            id,
+            state: RefCell::new(State::default()),
+            msrs: RefCell::new(MSRState::default()),
+            cpuid,
+            cb,
+            pending_interrupt: Cell::new(None),
+            interrupt_window: Cell::new(false),
+            waiting_for_startup: Cell::new(false),
+        }
+    }
+
+    /// Accepts the highest priority interrupt of the local APIC, if there is one.
+    fn acknowledge_lapic_interrupt(&self) -> Option<u8> {
+        if !self.vm.has_lapics() {
+            return None;
+        }
+
+        self.with_lapic(|lapic| lapic.acknowledge())
+    }
+
+    /// Fails unless the vCPU has a local APIC.
+    fn require_lapic(&self) -> Result<()> {
+        if !self.vm.has_lapics() {
+            bail!("the local APIC is only emulated in split interrupt controller mode");
+        }
+
+        Ok(())
+    }
+
+    /// Runs a function on the vCPU's local APIC.
+    fn with_lapic<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut Lapic) -> R,
+    {
+        self.vm.with_lapic(self.id, f)
+    }
+
+    /// Handles the INITs, start-up IPIs and NMIs received by the local APIC,
+    /// and raises the timer interrupt.
+    ///
+    /// Returns `Some` if the vCPU must stop running.
+    fn lapic_events(&self, cpu: &mut Cpu) -> Result<Option<accel::ExitState>> {
+        let now = self.msrs.borrow().tsc;
+
+        let (init, startup, nmi) = self.with_lapic(|lapic| {
+            lapic.tick(now);
+            (lapic.take_init(), lapic.take_startup(), lapic.take_nmi())
+        });
+
+        if init {
+            cpu.init();
+            self.waiting_for_startup.set(true);
+        }
+
+        if let Some(vector) = startup {
+            cpu.startup(vector)?;
+            self.waiting_for_startup.set(false);
+        }
+
+        if self.waiting_for_startup.get() {
+            return Ok(Some(accel::ExitState::Halt));
+        }
+
+        if nmi {
+            cpu.nmi()?;
+        }
+
+        Ok(None)
+    }
+
+    /// Skips the time spent halted, until the local APIC's timer fires.
+    ///
+    /// Returns `false` if no interrupt of the local APIC will wake up the vCPU.
+    fn wake_up(&self, interrupts_enabled: bool) -> bool {
+        if !interrupts_enabled {
+            return false;
+        }
+
+        let mut msrs = self.msrs.borrow_mut();
+
+        self.with_lapic(|lapic| {
+            if let Some(deadline) = lapic.timer_deadline() {
+                msrs.tsc = msrs.tsc.max(deadline);
+                lapic.tick(msrs.tsc);
+            }
+
+            lapic.pending_interrupt().is_some()
+        })
+    }
+}
+
+/// Keeps the MSRs which are not part of `State`, and sends the accesses
+/// to the registers of the local APIC, including CR8, to it.
+impl<'a> SystemRegisters for VirtualCPU<'a> {
+    fn read_msr(&self, state: &State, index: u32) -> Result<u64> {
+        let msr_state = self.msrs.borrow();
+
+        if self.vm.has_lapics() && Lapic::handles_msr(index) {
+            return self.with_lapic(|lapic| lapic.read_msr(index, msr_state.tsc));
+        }
+
+        let value = match index {
+            msr::EFER => state.efer.bits(),
+            msr::APIC_BASE => state.apic_base,
+            msr::FS_BASE => state.fs.base,
+            msr::GS_BASE => state.gs.base,
+            index => match msr_state.get(index) {
+                Some(value) => value,
+                None => bail!("failed to read MSR {:#x}", index),
+            },
+        };
+
+        Ok(value)
+    }
+
+    fn write_msr(&self, state: &mut State, index: u32, value: u64) -> Result<()> {
+        let mut msr_state = self.msrs.borrow_mut();
+
+        if self.vm.has_lapics() && Lapic::handles_msr(index) {
+            let event = self.with_lapic(|lapic| lapic.write_msr(index, value, msr_state.tsc))?;
+            return self.vm.lapic_event(self.id, event);
+        }
+
+        match index {
+            msr::EFER => state.efer = Efer::from_bits_truncate(value),
+            msr::APIC_BASE => state.apic_base = value,
+            msr::FS_BASE => state.fs.base = value,
+            msr::GS_BASE => state.gs.base = value,
+            _ => {
+                if msr_state.update(&[accel::arch::Msr { index, value }]).is_err() {
+                    bail!("failed to write {:#x} to MSR {:#x}", value, index);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn cr8(&self, state: &State) -> u64 {
+        if self.vm.has_lapics() {
+            self.with_lapic(|lapic| lapic.cr8())
+        } else {
+            state.cr8
+        }
+    }
+
+    fn set_cr8(&self, state: &mut State, value: u64) {
+        if self.vm.has_lapics() {
+            self.with_lapic(|lapic| lapic.set_cr8(value));
+        } else {
+            state.cr8 = value;
+        }
+    }
+}
+
+/// Emulates the registers of the local APIC and of the I/O APIC,
+/// and forwards the other accesses to the VMM.
+struct ApicCallbacks<'a, 'b: 'a> {
+    vcpu: &'a VirtualCPU<'b>,
+}
+
+impl<'a, 'b> CpuCallbacks for ApicCallbacks<'a, 'b> {
+    fn port_io(&self, port: u16, output: bool, buffer: &mut [u8], element_size: usize) -> Result<()> {
+        self.vcpu.cb.port_io(port, output, buffer, element_size)
+    }
+
+    fn mmio(&self, addr: u64, is_write: bool, data: &mut [u8]) -> Result<()> {
+        let vcpu = self.vcpu;
+        let now = vcpu.msrs.borrow().tsc;
+
+        if vcpu.vm.lapic_mmio(vcpu.id, addr, is_write, data, now)? || vcpu.vm.ioapic_mmio(addr, is_write, data)? {
+            return Ok(());
+        }
+
+        vcpu.cb.mmio(addr, is_write, data)
+    }
+}
+
+impl<'a> accel::VirtualCPU<'a> for VirtualCPU<'a> {
+    fn sync(&self, state: &mut State, set: bool) -> Result<()> {
+        if set {
+            *self.state.borrow_mut() = *state;
+        } else {
+            *state = *self.state.borrow();
+        }
+
+        // The local APIC holds the TPR, which is accessed as CR8.
+        if self.vm.has_lapics() {
+            self.with_lapic(|lapic| -> Result<()> {
+                if set {
+                    if lapic.apic_base() != state.apic_base {
+                        lapic.set_apic_base(state.apic_base)?;
+                    }
+
+                    lapic.set_cr8(state.cr8);
+                } else {
+                    state.apic_base = lapic.apic_base();
+                    state.cr8 = lapic.cr8();
+                }
+
+                Ok(())
+            })?;
+        }
+
+        Ok(())
+    }
+
+    fn get_msrs(&self, msrs: &mut [accel::arch::Msr]) -> Result<()> {
+        let state = self.state.borrow();
+
+        for msr in msrs {
+            msr.value = self.read_msr(&state, msr.index)?;
+        }
+
+        Ok(())
+    }
+
+    fn set_msrs(&self, msrs: &[accel::arch::Msr]) -> Result<()> {
+        let mut state = self.state.borrow_mut();
+
+        for msr in msrs {
+            self.write_msr(&mut state, msr.index, msr.value)?;
+        }
+
+        Ok(())
+    }
+
+    fn get_lapic(&self) -> Result<accel::arch::LapicState> {
+        self.require_lapic()?;
+        Ok(self.with_lapic(|lapic| lapic.state()))
+    }
+
+    fn set_lapic(&self, state: &accel::arch::LapicState) -> Result<()> {
+        self.require_lapic()?;
+
+        let now = self.msrs.borrow().tsc;
+        self.with_lapic(|lapic| lapic.set_state(state, now));
+
+        Ok(())
+    }
+
+    fn can_inject_interrupt(&self) -> bool {
+        use x86::state::Flags;
+
+        self.pending_interrupt.get().is_none() && self.state.borrow().flags.contains(Flags::INTERRUPT)
+    }
+
+    fn inject_interrupt(&self, vector: u8) -> Result<()> {
+        if let Some(pending) = self.pending_interrupt.get() {
+            bail!("cannot inject vector {:#x}, vector {:#x} is still pending", vector, pending);
+        }
+
+        self.pending_interrupt.set(Some(vector));
+
+        Ok(())
+    }
+
+    fn request_interrupt_window(&self, request: bool) {
+        self.interrupt_window.set(request);
+    }
+
+    fn run(&self) -> Result<accel::ExitState> {
+        let lapics = self.vm.has_lapics();
+
+        let apic_callbacks = ApicCallbacks { vcpu: self };
+        let cb: &CpuCallbacks = if lapics { &apic_callbacks } else { self.cb };
+
+        let mut state = self.state.borrow_mut();
+        let mut cpu = Cpu::new(self.vm, cb, self, &self.cpuid, &mut state);
+
+        loop {
+            if lapics {
+                if let Some(exit) = self.lapic_events(&mut cpu)? {
+                    return Ok(exit);
+                }
+            }
+
+            if cpu.interrupts_enabled() {
+                if let Some(vector) = self.pending_interrupt.take() {
+                    cpu.external_interrupt(vector)?;
+                } else if let Some(vector) = self.acknowledge_lapic_interrupt() {
+                    cpu.external_interrupt(vector)?;
+                } else if self.interrupt_window.get() {
+                    return Ok(accel::ExitState::InterruptWindow);
+                }
+            }
+
+            // The TSC counts the instructions executed.
+            let exit = cpu.step()?;
+            self.msrs.borrow_mut().tsc += 1;
+
+            match exit {
+                Some(accel::ExitState::Halt) if lapics && self.wake_up(cpu.interrupts_enabled()) => (),
+                Some(exit) => return Ok(exit),
+                None => (),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use accel::{Accelerator, IrqChipMode, MemoryFlags};
+    use accel::errors::Result;
+    use accel::test_util::{self, TestVcpu, TestVm, CODE};
+    use global::Interpreter;
+    use std::cell::{Cell, RefCell};
+    use x86::state::State;
+
+    #[derive(Default)]
+    struct Callbacks {
+        output: RefCell<Vec<(u16, Vec<u8>)>>,
+        mmio: Cell<(u64, u8)>,
+    }
+
+    impl accel::CpuCallbacks for Callbacks {
+        fn port_io(&self, port: u16, output: bool, buffer: &mut [u8], _size: usize) -> Result<()> {
+            if output {
+                self.output.borrow_mut().push((port, buffer.to_vec()));
+            } else {
+                buffer[0] = 5;
+            }
+            Ok(())
+        }
+
+        fn mmio(&self, addr: u64, _is_write: bool, data: &mut [u8]) -> Result<()> {
+            self.mmio.set((addr, data[0]));
+            Ok(())
+        }
+    }
+
+    /// Creates an interpreted VM which runs `code`, and its first vCPU.
+    fn test_vm(irqchip: IrqChipMode, code: &[u8]) -> (TestVm<Callbacks>, TestVcpu) {
+        test_util::test_vm(&Interpreter, irqchip, code)
+    }
+
+    /// Switches a vCPU to 64-bit mode with a flat code segment, at the start of the code,
+    /// returning its new state.
+    fn enter_long_mode(vcpu: &accel::VirtualCPU) -> State {
+        use x86::state::Efer;
+
+        let mut state = State::default();
+        vcpu.sync(&mut state, false).unwrap();
+        state.efer |= Efer::LM_ENABLE | Efer::LM_ACTIVE;
+        state.cs.long = true;
+        state.cs.base = 0;
+        state.ip = CODE;
+        vcpu.sync(&mut state, true).unwrap();
+        state
+    }
+
+    /// Runs a vCPU until it halts, returning its final state.
+    fn run(vcpu: &accel::VirtualCPU) -> State {
+        match vcpu.run().unwrap() {
+            accel::ExitState::Halt => (),
+            state => panic!("Unexpected exit state: {:?}", state),
+        }
+
+        let mut state = State::default();
+        vcpu.sync(&mut state, false).unwrap();
+        state
+    }
+
+    #[test]
+    fn port_io_and_mmio() {
+        let code = [
+            // mov al, 0x7F
+            0xB0, 0x7F,
+            // out 0x10, al
+            0xE6, 0x10,
+            // in al, 0x11
+            0xE4, 0x11,
+            // add al, 3
+            0x04, 0x03,
+            // out 0x12, al
+            0xE6, 0x12,
+            // mov byte [0xF010], 0x42
+            0xC6, 0x06, 0x10, 0xF0, 0x42,
+            // hlt
+            0xF4,
+        ];
+
+        let (vm, vcpu) = test_vm(IrqChipMode::User, &code);
+        run(&*vcpu);
+
+        let output = vm.cb.output.borrow();
+        assert_eq!(*output, [(0x10, vec![0x7F]), (0x12, vec![8])]);
+        assert_eq!(vm.cb.mmio.get(), (0xF010, 0x42));
+    }
+
+    #[test]
+    fn interrupt_injection() {
+        let code = [
+            // mov sp, 0x1000
+            0xBC, 0x00, 0x10,
+            // hlt
+            0xF4,
+            // sti; nop; hlt
+            0xFB, 0x90, 0xF4,
+        ];
+
+        let handler = [
+            // mov al, 0x30; out 0x10, al; iret
+            0xB0, 0x30, 0xE6, 0x10, 0xCF,
+        ];
+
+        let (vm, vcpu) = test_vm(IrqChipMode::User, &code);
+        vm.memory.write(0x800, &handler).unwrap();
+
+        // Vector 0x30 goes to 0000:0800.
+        vm.memory.write(0x30 * 4, &[0x00, 0x08, 0x00, 0x00]).unwrap();
+
+        vcpu.request_interrupt_window(true);
+
+        // Interrupts are disabled.
+        run(&*vcpu);
+
+        assert!(!vcpu.can_inject_interrupt());
+
+        // The window only opens after the instruction following `sti`.
+        match vcpu.run().unwrap() {
+            accel::ExitState::InterruptWindow => (),
+            state => panic!("Unexpected exit state: {:?}", state),
+        }
+
+        let mut state = State::default();
+        vcpu.sync(&mut state, false).unwrap();
+        assert_eq!(state.ip, CODE + 6);
+
+        assert!(vcpu.can_inject_interrupt());
+        vcpu.request_interrupt_window(false);
+        vcpu.inject_interrupt(0x30).unwrap();
+
+        run(&*vcpu);
+        assert_eq!(*vm.cb.output.borrow(), [(0x10, vec![0x30])]);
+
+        // The interpreter does not emulate the PICs.
+        assert!(Interpreter.create_vm(IrqChipMode::Kernel).is_err());
+    }
+
+    #[test]
+    fn split_irqchip() {
+        use x86::msr;
+
+        let code = [
+            // mov sp, 0x1000
+            0xBC, 0x00, 0x10,
+            // The local APIC is moved to 0xFE000, in the first megabyte.
+            // mov ax, 0xFE00; mov ds, ax
+            0xB8, 0x00, 0xFE, 0x8E, 0xD8,
+            // Software-enable the APIC.
+            // mov word [0xF0], 0x1FF
+            0xC7, 0x06, 0xF0, 0x00, 0xFF, 0x01,
+            // One-shot timer with vector 0x40, counting 100 cycles.
+            // mov word [0x320], 0x40
+            0xC7, 0x06, 0x20, 0x03, 0x40, 0x00,
+            // mov word [0x3E0], 0x0B
+            0xC7, 0x06, 0xE0, 0x03, 0x0B, 0x00,
+            // mov word [0x380], 100
+            0xC7, 0x06, 0x80, 0x03, 0x64, 0x00,
+            // sti; hlt
+            0xFB, 0xF4,
+            // out 0x11, al; hlt; hlt
+            0xE6, 0x11, 0xF4, 0xF4,
+            // INIT and start-up IPIs to the APIC with ID 1, at page 9.
+            // mov dword [0x310], 0x01000000
+            0x66, 0xC7, 0x06, 0x10, 0x03, 0x00, 0x00, 0x00, 0x01,
+            // mov dword [0x300], 0x4500
+            0x66, 0xC7, 0x06, 0x00, 0x03, 0x00, 0x45, 0x00, 0x00,
+            // mov dword [0x300], 0x4609
+            0x66, 0xC7, 0x06, 0x00, 0x03, 0x09, 0x46, 0x00, 0x00,
+            // cli; hlt
+            0xFA, 0xF4,
+        ];
+
+        let handler = |vector| {
+            [
+                // mov al, vector; out 0x10, al
+                0xB0, vector, 0xE6, 0x10,
+                // End of interrupt.
+                // mov word [0xB0], 0
+                0xC7, 0x06, 0xB0, 0x00, 0x00, 0x00,
+                // iret
+                0xCF,
+            ]
+        };
+
+        let ap_code = [
+            // mov al, 0x99; out 0x12, al; hlt
+            0xB0, 0x99, 0xE6, 0x12, 0xF4,
+        ];
+
+        let (vm, bsp) = test_vm(IrqChipMode::Split, &code);
+        vm.memory.write(0x800, &handler(0x40)).unwrap();
+        vm.memory.write(0x900, &handler(0x41)).unwrap();
+        vm.memory.write(0x9000, &ap_code).unwrap();
+
+        vm.memory.write(0x40 * 4, &[0x00, 0x08, 0x00, 0x00]).unwrap();
+        vm.memory.write(0x41 * 4, &[0x00, 0x09, 0x00, 0x00]).unwrap();
+
+        let ap = vm.create_vcpu(1, None, vm.cb).unwrap();
+
+        bsp.set_msrs(&[accel::arch::Msr { index: msr::APIC_BASE, value: 0xFE900 }]).unwrap();
+
+        // The timer wakes up the halted vCPU.
+        run(&*bsp);
+        assert_eq!(*vm.cb.output.borrow(), [(0x10, vec![0x40]), (0x11, vec![0x40])]);
+
+        // CR8 blocks the interrupts whose priority class is not above it.
+        let mut state = State::default();
+        bsp.sync(&mut state, false).unwrap();
+        state.cr8 = 5;
+        bsp.sync(&mut state, true).unwrap();
+
+        vm.send_msi(0xFEE0_0000, 0x41).unwrap();
+
+        run(&*bsp);
+        assert_eq!(vm.cb.output.borrow().len(), 2);
+        assert_eq!(bsp.get_lapic().unwrap().tpr(), 0x50);
+        assert!(bsp.get_lapic().unwrap().irr().get(0x41));
+
+        bsp.sync(&mut state, false).unwrap();
+        assert_eq!(state.cr8, 5);
+        state.cr8 = 0;
+        bsp.sync(&mut state, true).unwrap();
+
+        run(&*bsp);
+        assert_eq!(vm.cb.output.borrow()[2], (0x10, vec![0x41]));
+        assert_eq!(bsp.get_lapic().unwrap().isr().highest(), None);
+
+        // The application processor starts at the page of the start-up IPI.
+        run(&*ap);
+        assert_eq!(vm.cb.output.borrow()[3], (0x12, vec![0x99]));
+
+        // The I/O APIC and the PICs are emulated in user space.
+        assert!(vm.get_pic_state(accel::arch::IrqChip::PicMaster).is_err());
+        assert!(vm.get_ioapic_state().is_ok());
+    }
+
+    #[test]
+    fn multiply_divide() {
+        use x86::state::Efer;
+
+        let code = [
+            // mul rcx
+            0x48, 0xF7, 0xE1,
+            // mov rbx, rdx
+            0x48, 0x89, 0xD3,
+            // div rcx
+            0x48, 0xF7, 0xF1,
+            // hlt
+            0xF4,
+            // idiv ecx
+            0x66, 0xF7, 0xF9,
+        ];
+
+        let handler = [
+            // mov al, 0xDE; out 0x10, al; hlt
+            0xB0, 0xDE, 0xE6, 0x10, 0xF4,
+        ];
+
+        let (vm, vcpu) = test_vm(IrqChipMode::User, &code);
+        vm.memory.write(0x800, &handler).unwrap();
+
+        // The divide error goes to 0000:0800.
+        vm.memory.write(0, &[0x00, 0x08, 0x00, 0x00]).unwrap();
+
+        // The 64-bit forms work on RDX:RAX.
+        let mut state = enter_long_mode(&*vcpu);
+        state.r[0] = !0;
+        state.r[1] = 0x10;
+        vcpu.sync(&mut state, true).unwrap();
+
+        let mut state = run(&*vcpu);
+        assert_eq!(state.r[3], 0xF);
+        assert_eq!(state.r[0], !0);
+        assert_eq!(state.r[2], 0);
+
+        // The most negative dividend divided by -1 does not fit in the quotient.
+        state.efer = Efer::empty();
+        state.cs.long = false;
+        state.ip = CODE + 10;
+        state.r[0] = 0;
+        state.r[1] = 0xFFFF_FFFF;
+        state.r[2] = 0x8000_0000;
+        state.r[4] = 0x1000;
+        vcpu.sync(&mut state, true).unwrap();
+
+        run(&*vcpu);
+        assert_eq!(*vm.cb.output.borrow(), [(0x10, vec![0xDE])]);
+
+        // The divide error is a fault, which returns to the division.
+        assert_eq!(vm.memory.read_obj::<u16>(0xFFA).unwrap(), CODE as u16 + 10);
+    }
+
+    #[test]
+    fn sign_extension_and_shifts() {
+        let code = [
+            // mov rax, -2
+            0x48, 0xC7, 0xC0, 0xFE, 0xFF, 0xFF, 0xFF,
+            // cqo
+            0x48, 0x99,
+            // mov ebx, 1
+            0xBB, 0x01, 0x00, 0x00, 0x00,
+            // shl rbx, 40
+            0x48, 0xC1, 0xE3, 0x28,
+            // mov rsi, rbx
+            0x48, 0x89, 0xDE,
+            // mov cl, 33
+            0xB1, 0x21,
+            // shr rbx, cl
+            0x48, 0xD3, 0xEB,
+            // hlt
+            0xF4,
+        ];
+
+        let (_vm, vcpu) = test_vm(IrqChipMode::User, &code);
+        enter_long_mode(&*vcpu);
+
+        let state = run(&*vcpu);
+        assert_eq!(state.r[2], !0);
+
+        // 64-bit shifts use six bits of the count.
+        assert_eq!(state.r[6], 1 << 40);
+        assert_eq!(state.r[3], 1 << 7);
+    }
+
+    #[test]
+    fn system_registers() {
+        use x86::msr;
+        use x86::state::DescriptorTable;
+
+        let code = [
+            // mov eax, 5; mov cr8, rax
+            0xB8, 0x05, 0x00, 0x00, 0x00, 0x44, 0x0F, 0x22, 0xC0,
+            // mov ecx, 0xC0000103; mov eax, 0x1234; xor edx, edx; wrmsr
+            0xB9, 0x03, 0x01, 0x00, 0xC0, 0xB8, 0x34, 0x12, 0x00, 0x00, 0x31, 0xD2, 0x0F, 0x30,
+            // xor eax, eax; rdmsr; mov ebx, eax
+            0x31, 0xC0, 0x0F, 0x32, 0x89, 0xC3,
+            // rdtsc
+            0x0F, 0x31,
+            // lgdt [0x800]; lidt [0x810]
+            0x0F, 0x01, 0x14, 0x25, 0x00, 0x08, 0x00, 0x00,
+            0x0F, 0x01, 0x1C, 0x25, 0x10, 0x08, 0x00, 0x00,
+            // mov rsi, cr8; mov rdi, cr0
+            0x44, 0x0F, 0x20, 0xC6, 0x0F, 0x20, 0xC7,
+            // hlt
+            0xF4,
+        ];
+
+        let (vm, vcpu) = test_vm(IrqChipMode::Split, &code);
+        vm.memory.write(0x800, &[0x27, 0x00, 0x00, 0x20, 0, 0, 0, 0, 0, 0]).unwrap();
+        vm.memory.write(0x810, &[0xFF, 0x0F, 0x00, 0x30, 0, 0, 0, 0, 0, 0]).unwrap();
+
+        enter_long_mode(&*vcpu);
+        let state = run(&*vcpu);
+
+        assert_eq!(state.r[3], 0x1234);
+        let mut msrs = [accel::arch::Msr { index: msr::TSC_AUX, value: 0 }];
+        vcpu.get_msrs(&mut msrs).unwrap();
+        assert_eq!(msrs[0].value, 0x1234);
+
+        // The TSC counts the instructions executed before RDTSC.
+        assert_eq!(state.r[0], 9);
+
+        assert_eq!(state.gdt, DescriptorTable { base: 0x2000, limit: 0x27 });
+        assert_eq!(state.idt, DescriptorTable { base: 0x3000, limit: 0xFFF });
+
+        // CR8 is the task priority register of the local APIC.
+        assert_eq!(state.r[6], 5);
+        assert_eq!(state.cr8, 5);
+        assert_eq!(vcpu.get_lapic().unwrap().tpr(), 0x50);
+
+        assert_eq!(state.r[7], state.cr0.bits());
+    }
+
+    #[test]
+    fn string_io_and_loops() {
+        let code = [
+            // mov si, 0x500
+            0xBE, 0x00, 0x05,
+            // mov cx, 5
+            0xB9, 0x05, 0x00,
+            // mov dx, 0xE9
+            0xBA, 0xE9, 0x00,
+            // rep outsb
+            0xF3, 0x6E,
+            // mov bl, 3
+            0xB3, 0x03,
+            // dec bl
+            0xFE, 0xCB,
+            // jnz -4
+            0x75, 0xFC,
+            // hlt
+            0xF4,
+        ];
+
+        let (vm, vcpu) = test_vm(IrqChipMode::User, &code);
+        vm.memory.write(0x500, b"hello").unwrap();
+
+        let state = run(&*vcpu);
+
+        assert_eq!(*vm.cb.output.borrow(), [(0xE9, b"hello".to_vec())]);
+        assert_eq!(state.r[1] & 0xFFFF, 0);
+        assert_eq!(state.r[3] & 0xFF, 0);
+        assert_eq!(state.r[6] & 0xFFFF, 0x505);
+    }
+
+    #[test]
+    fn read_only_memory() {
+        let code = [
+            // mov al, [0xC010]
+            0xA0, 0x10, 0xC0,
+            // out 0x12, al
+            0xE6, 0x12,
+            // mov byte [0xC010], 0x42
+            0xC6, 0x06, 0x10, 0xC0, 0x42,
+            // hlt
+            0xF4,
+        ];
+
+        let (mut vm, vcpu) = test_vm(IrqChipMode::User, &code);
+
+        let rom = vm.memory.add_region(0xC000, 4096, MemoryFlags::READ_ONLY).unwrap();
+        vm.memory.write(0xC010, &[0x5A]).unwrap();
+        vm.memory.register(vm.inner).unwrap();
+
+        run(&*vcpu);
+
+        // Writes to ROM are sent to the MMIO handler.
+        assert_eq!(*vm.cb.output.borrow(), [(0x12, vec![0x5A])]);
+        assert_eq!(vm.cb.mmio.get(), (0xC010, 0x42));
+        assert_eq!(vm.memory.read_obj::<u8>(0xC010).unwrap(), 0x5A);
+
+        // Once the ROM is moved away, its old address becomes MMIO.
+        vm.memory.move_region(vm.inner, rom, 0x10000).unwrap();
+        assert_eq!(vm.memory.read_obj::<u8>(0x10010).unwrap(), 0x5A);
+
+        let vcpu = vm.inner.create_vcpu(1, None, vm.cb).unwrap();
+        run(&*vcpu);
+
+        assert_eq!(vm.cb.output.borrow()[1], (0x12, vec![0]));
+
+        vm.memory.remove_region(vm.inner, rom).unwrap();
+        assert!(vm.memory.read_obj::<u8>(0x10010).is_err());
+        assert!(vm.memory.remove_region(vm.inner, rom).is_err());
+    }
+
+    #[test]
+    fn dirty_log() {
+        let code = [
+            // mov word [0x0FFF], 0x1234
+            0xC7, 0x06, 0xFF, 0x0F, 0x34, 0x12,
+            // mov byte [0x3010], 1
+            0xC6, 0x06, 0x10, 0x30, 0x01,
+            // hlt
+            0xF4,
+        ];
+
+        let (mut vm, vcpu) = test_vm(IrqChipMode::User, &code);
+        vm.memory.set_dirty_logging(vm.inner, 0, true).unwrap();
+
+        run(&*vcpu);
+
+        let bitmaps = vm.memory.dirty_log(&*vm).unwrap();
+        assert_eq!(bitmaps.len(), 1);
+
+        let ranges: Vec<_> = bitmaps[0].ranges().map(|r| (r.guest, r.size)).collect();
+        assert_eq!(ranges, [(0, 0x2000), (0x3000, 0x1000)]);
+
+        // Retrieving the log clears it.
+        assert_eq!(vm.memory.dirty_log(&*vm).unwrap()[0].dirty_pages(), 0);
+    }
+
+    #[test]
+    fn cpuid() {
+        let code = [
+            // xor eax, eax
+            0x66, 0x31, 0xC0,
+            // cpuid
+            0x0F, 0xA2,
+            // hlt
+            0xF4,
+        ];
+
+        let (_vm, vcpu) = test_vm(IrqChipMode::User, &code);
+        let state = run(&*vcpu);
+
+        let registers = [state.r[3], state.r[2], state.r[1]];
+        let bytes: Vec<u8> = registers.iter().flat_map(|&r| (r as u32).to_le_bytes().to_vec()).collect();
+        assert_eq!(bytes, b"vm-rs interp");
+        assert_eq!(state.r[0], 1);
+    }
+}
@@ -0,0 +1,405 @@
+use accel;
+use accel::errors::Result;
+use intc::{self, IoApic, Lapic};
+use intc::lapic::{self, Event, Ipi};
+use std::cell::{RefCell, RefMut};
+use vcpu::VirtualCPU;
+use x86::irqchip::IOAPIC_PINS;
+use x86::cpuid::{Cpuid, CpuidEntry, Topology};
+
+/// Maximum number of virtual CPUs, limited by the 8-bit APIC ID.
+const MAX_VCPUS: usize = 255;
+
+/// A block of host memory, mapped into the guest's physical address space.
+#[derive(Debug, Clone)]
+struct Region {
+    slot: u8,
+    guest: u64,
+    host: *mut u8,
+    size: usize,
+    flags: accel::MemoryFlags,
+    /// Pages written to since the log was last cleared, if logging is enabled.
+    dirty: Option<accel::DirtyBitmap>,
+}
+
+impl Region {
+    /// Checks if the `[addr, addr + len)` range is contained in this region.
+    fn contains(&self, addr: u64, len: usize) -> bool {
+        addr >= self.guest && addr.checked_add(len as u64).is_some_and(|end| end <= self.guest + self.size as u64)
+    }
+}
+
+pub struct VirtualMachine {
+    regions: RefCell<Vec<Region>>,
+    irqchip: accel::IrqChipMode,
+    /// The local APICs in `Split` mode, indexed by vCPU ID.
+    lapics: RefCell<Vec<Lapic>>,
+    /// The I/O APIC in `Split` mode.
+    ioapic: RefCell<IoApic>,
+}
+
+impl VirtualMachine {
+    /// Initializes a new virtual machine, with no memory.
+    ///
+    /// The local APICs and the I/O APIC are only emulated in `Split` mode.
+    pub fn new(irqchip: accel::IrqChipMode) -> Self {
+        VirtualMachine {
+            regions: RefCell::new(Vec::new()),
+            irqchip,
+            lapics: RefCell::new(Vec::new()),
+            ioapic: RefCell::new(IoApic::new()),
+        }
+    }
+
+    /// Checks if the local APICs are emulated by the interpreter.
+    pub fn has_lapics(&self) -> bool {
+        self.irqchip == accel::IrqChipMode::Split
+    }
+
+    /// Fails unless the local APICs are emulated by the interpreter.
+    fn require_lapic(&self) -> Result<()> {
+        if !self.has_lapics() {
+            bail!("the local APICs are only emulated in split interrupt controller mode");
+        }
+
+        Ok(())
+    }
+
+    /// Runs a function on the local APIC of a vCPU.
+    pub fn with_lapic<F, R>(&self, id: usize, f: F) -> R
+    where
+        F: FnOnce(&mut Lapic) -> R,
+    {
+        f(&mut self.lapics.borrow_mut()[id])
+    }
+
+    /// Handles an access to the registers of a vCPU's local APIC.
+    ///
+    /// Returns `false` if the address does not belong to the local APIC.
+    pub fn lapic_mmio(&self, id: usize, addr: u64, is_write: bool, data: &mut [u8], now: u64) -> Result<bool> {
+        let mut lapics = self.lapics.borrow_mut();
+        let lapic = &mut lapics[id];
+
+        if !lapic.contains(addr) {
+            return Ok(false);
+        }
+
+        let offset = addr - (lapic.apic_base() & !0xFFF);
+
+        // The registers are 32-bit and little-endian.
+        if is_write {
+            let value = data.iter().rev().fold(0, |value, &byte| value << 8 | u32::from(byte));
+            let event = lapic.write(offset, value, now);
+
+            drop(lapics);
+            self.lapic_event(id, event)?;
+        } else {
+            let value = lapic.read(offset, now);
+
+            for (i, byte) in data.iter_mut().enumerate() {
+                *byte = value.checked_shr(i as u32 * 8).unwrap_or(0) as u8;
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Handles a request of a vCPU's local APIC.
+    pub fn lapic_event(&self, id: usize, event: Option<Event>) -> Result<()> {
+        match event {
+            Some(Event::Ipi(ipi)) => lapic::deliver(&mut self.lapics.borrow_mut(), Some(id), &ipi),
+            Some(Event::Eoi(vector)) => self.ioapic.borrow_mut().eoi(vector, self)?,
+            None => (),
+        }
+
+        Ok(())
+    }
+
+    /// Handles an access to the registers of the I/O APIC.
+    ///
+    /// Returns `false` if the address does not belong to the I/O APIC.
+    pub fn ioapic_mmio(&self, addr: u64, is_write: bool, data: &mut [u8]) -> Result<bool> {
+        if !self.has_lapics() || !self.ioapic.borrow().contains(addr) {
+            return Ok(false);
+        }
+
+        let mut ioapic = self.ioapic.borrow_mut();
+        let offset = addr - ioapic.state().base_address;
+
+        if is_write {
+            let value = data.iter().rev().fold(0, |value, &byte| value << 8 | u32::from(byte));
+            ioapic.write(offset, value, self)?;
+        } else {
+            let value = ioapic.read(offset);
+
+            for (i, byte) in data.iter_mut().enumerate() {
+                *byte = value.checked_shr(i as u32 * 8).unwrap_or(0) as u8;
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Delivers a message signaled interrupt to the local APICs.
+    fn deliver_msi(&self, address: u64, data: u32) -> Result<()> {
+        self.require_lapic()?;
+
+        let ipi = Ipi::from_msi(address, data);
+        lapic::deliver(&mut self.lapics.borrow_mut(), None, &ipi);
+
+        Ok(())
+    }
+
+    /// Translates a guest physical memory range to a host pointer.
+    ///
+    /// Returns `None` if the range is not completely contained in a single
+    /// memory region, or if it is written to and the region is read-only.
+    /// In that case, the access must be emulated as MMIO.
+    ///
+    /// Pages which are written to are marked as dirty.
+    pub fn host_address(&self, addr: u64, len: usize, write: bool) -> Option<*mut u8> {
+        let mut regions = self.regions.borrow_mut();
+
+        let region = regions
+            .iter_mut()
+            .find(|region| region.contains(addr, len))
+            .filter(|region| !write || !region.flags.contains(accel::MemoryFlags::READ_ONLY))?;
+
+        let offset = (addr - region.guest) as usize;
+
+        if let (true, Some(dirty)) = (write, region.dirty.as_mut()) {
+            let last = (offset + len.max(1) - 1) / accel::PAGE_SIZE;
+
+            for page in offset / accel::PAGE_SIZE..=last {
+                dirty.set_dirty(page);
+            }
+        }
+
+        Some(unsafe { region.host.add(offset) })
+    }
+
+    /// Retrieves a memory slot which has dirty logging enabled.
+    fn dirty_region(&self, slot: u8) -> Result<RefMut<'_, Region>> {
+        let regions = self.regions.borrow_mut();
+
+        match regions.iter().position(|r| r.slot == slot) {
+            Some(index) if regions[index].dirty.is_some() => Ok(RefMut::map(regions, |r| &mut r[index])),
+            Some(_) => bail!("memory slot {} does not have dirty logging enabled", slot),
+            None => bail!("memory slot {} does not exist", slot),
+        }
+    }
+}
+
+impl<'a> accel::VirtualMachine<'a> for VirtualMachine {
+    fn max_recommended_vcpus(&self) -> Result<usize> {
+        // The vCPUs are interpreted in software, so only the 8-bit APIC IDs limit their number.
+        Ok(MAX_VCPUS)
+    }
+
+    fn max_vcpus(&self) -> Result<usize> {
+        Ok(MAX_VCPUS)
+    }
+
+    fn max_vcpu_ids(&self) -> Result<usize> {
+        Ok(MAX_VCPUS)
+    }
+
+    fn max_memory_slots(&self) -> Result<usize> {
+        Ok(256)
+    }
+
+    fn allocate_memory(&self, memory: accel::MemoryRegion) -> Result<()> {
+        let mut region = Region {
+            slot: memory.slot,
+            guest: memory.guest,
+            host: memory.host,
+            size: memory.size,
+            flags: memory.flags,
+            dirty: None,
+        };
+
+        let mut regions = self.regions.borrow_mut();
+
+        // Changing an existing slot replaces it, but only some properties can change.
+        if let Some(old) = regions.iter().find(|r| r.slot == region.slot) {
+            let read_only = accel::MemoryFlags::READ_ONLY;
+
+            if old.size != region.size || (old.flags ^ region.flags).contains(read_only) {
+                bail!("memory slot {} cannot be resized or made read-only", region.slot);
+            }
+
+            region.dirty = old.dirty.clone();
+        }
+
+        if region.flags.contains(accel::MemoryFlags::LOG_DIRTY_PAGES) {
+            // Keep the pages logged so far, even if the slot has moved.
+            let pages = region.size / accel::PAGE_SIZE;
+            let mut dirty = accel::DirtyBitmap::new(region.slot, region.guest, pages);
+
+            if let Some(ref old) = region.dirty {
+                dirty.merge(old);
+            }
+
+            region.dirty = Some(dirty);
+        } else {
+            region.dirty = None;
+        }
+
+        let overlaps = regions.iter().filter(|r| r.slot != region.slot).any(|r| {
+            region.guest < r.guest + r.size as u64 && r.guest < region.guest + region.size as u64
+        });
+
+        if overlaps {
+            bail!("memory slot {} overlaps another slot", region.slot);
+        }
+
+        regions.retain(|r| r.slot != region.slot);
+        regions.push(region);
+
+        Ok(())
+    }
+
+    fn free_memory(&self, slot: u8) -> Result<()> {
+        let mut regions = self.regions.borrow_mut();
+
+        match regions.iter().position(|r| r.slot == slot) {
+            Some(index) => regions.remove(index),
+            None => bail!("memory slot {} does not exist", slot),
+        };
+
+        Ok(())
+    }
+
+    fn enable_dirty_ring(&self, _entries: u32) -> Result<()> {
+        // Written pages are marked directly in the slot's bitmap.
+        Ok(())
+    }
+
+    fn get_dirty_log(&self, slot: u8) -> Result<accel::DirtyBitmap> {
+        let region = self.dirty_region(slot)?;
+        Ok(region.dirty.clone().unwrap())
+    }
+
+    fn clear_dirty_log(&self, bitmap: &accel::DirtyBitmap) -> Result<()> {
+        let mut region = self.dirty_region(bitmap.slot())?;
+        let dirty = region.dirty.as_mut().unwrap();
+
+        for (word, cleared) in dirty.as_raw_mut().iter_mut().zip(bitmap.as_raw()) {
+            *word &= !cleared;
+        }
+
+        Ok(())
+    }
+
+    fn set_irq_line(&self, gsi: u32, level: bool) -> Result<()> {
+        if !self.has_lapics() || gsi as usize >= IOAPIC_PINS {
+            bail!("the interpreter has no interrupt controller, cannot set GSI {}", gsi);
+        }
+
+        self.ioapic.borrow_mut().set_irq(gsi, level, self)
+    }
+
+    fn send_msi(&self, address: u64, data: u32) -> Result<()> {
+        self.deliver_msi(address, data)
+    }
+
+    fn add_irq_route(&self, gsi: u32, _route: accel::IrqRoute) -> Result<()> {
+        bail!("the interpreter has no interrupt controller, cannot route GSI {}", gsi)
+    }
+
+    fn remove_irq_routes(&self, gsi: u32) -> Result<()> {
+        bail!("the interpreter has no interrupt controller, cannot route GSI {}", gsi)
+    }
+
+    fn get_pic_state(&self, chip: accel::arch::IrqChip) -> Result<accel::arch::PicState> {
+        bail!("the interpreter has no interrupt controller, cannot get {:?}", chip)
+    }
+
+    fn set_pic_state(&self, chip: accel::arch::IrqChip, _state: &accel::arch::PicState) -> Result<()> {
+        bail!("the interpreter has no interrupt controller, cannot set {:?}", chip)
+    }
+
+    fn get_ioapic_state(&self) -> Result<accel::arch::IoApicState> {
+        self.require_lapic()?;
+        Ok(self.ioapic.borrow().state())
+    }
+
+    fn set_ioapic_state(&self, state: &accel::arch::IoApicState) -> Result<()> {
+        self.require_lapic()?;
+        self.ioapic.borrow_mut().set_state(state);
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn register_ioevent(&self, io: accel::IoEvent, _event: &accel::EventFd) -> Result<()> {
+        bail!("the interpreter does not support I/O events, for {:?}", io.address)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn unregister_ioevent(&self, io: accel::IoEvent, _event: &accel::EventFd) -> Result<()> {
+        bail!("the interpreter does not support I/O events, for {:?}", io.address)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn register_irqfd(&self, _event: &accel::EventFd, gsi: u32) -> Result<()> {
+        bail!("the interpreter has no interrupt controller, cannot inject GSI {}", gsi)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn unregister_irqfd(&self, _event: &accel::EventFd, gsi: u32) -> Result<()> {
+        bail!("the interpreter has no interrupt controller, cannot inject GSI {}", gsi)
+    }
+
+    fn supported_cpuid(&self) -> Result<Cpuid> {
+        // The local APIC is only present in `Split` mode.
+        let apic = if self.has_lapics() { 1 << 9 } else { 0 };
+
+        let mut cpuid = Cpuid::new(vec![
+            // A 486-class processor, without an FPU.
+            CpuidEntry::new(0, None, [1, 0, 0, 0]),
+            CpuidEntry::new(1, None, [0x400, 0, 0, apic]),
+            CpuidEntry::new(0x8000_0000, None, [0x8000_0000, 0, 0, 0]),
+        ]);
+
+        cpuid.set_vendor("vm-rs interp");
+        cpuid.set_brand("vm-rs software interpreter");
+
+        Ok(cpuid)
+    }
+
+    fn create_vcpu<'b>(
+        &'b self,
+        id: usize,
+        cpuid: Option<&Cpuid>,
+        cb: &'b accel::CpuCallbacks,
+    ) -> Result<Box<accel::VirtualCPU<'b> + 'b>> {
+        if id >= MAX_VCPUS {
+            bail!("vCPU ID {} is too large", id);
+        }
+
+        let cpuid = match cpuid {
+            Some(cpuid) => cpuid.clone(),
+            None => self.supported_cpuid()?.for_vcpu(id as u32, &Topology::default()),
+        };
+
+        if self.has_lapics() {
+            let mut lapics = self.lapics.borrow_mut();
+
+            while lapics.len() <= id {
+                let id = lapics.len();
+                lapics.push(Lapic::new(id as u32));
+            }
+        }
+
+        let vcpu = VirtualCPU::new(self, id, cpuid, cb);
+
+        Ok(Box::new(vcpu))
+    }
+}
+
+impl intc::MsiSink for VirtualMachine {
+    /// Delivers an interrupt of the I/O APIC to the local APICs.
+    fn send_msi(&self, _pin: u32, address: u64, data: u32) -> Result<()> {
+        self.deliver_msi(address, data)
+    }
+}
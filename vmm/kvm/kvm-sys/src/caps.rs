@@ -15,9 +15,25 @@ pub enum Capability {
     MaxRecommendedVCpus = 9,
     /// Maximum number of memory slots per VM.
     MaxMemSlots = 10,
+    /// Support for setting the GSI routing table.
+    ///
+    /// Returned value is the maximum number of routing entries.
+    IrqRouting = 25,
+    /// Support for injecting interrupts through eventfds.
+    IrqFd = 32,
+    /// Support for signaling eventfds on guest writes.
+    IoEventFd = 36,
     SetIdentityMapAddress = 37,
+    /// Support for getting and setting the `XSAVE` area of vCPUs.
+    Xsave = 55,
+    /// Support for getting and setting the extended control registers.
+    Xcrs = 56,
     /// Hard vCPU limit.
     MaxVCpus = 66,
+    /// Support for the TSC deadline mode of the in-kernel local APIC timer.
+    TscDeadlineTimer = 72,
+    /// Support for injecting MSIs directly.
+    SignalMsi = 77,
     /// Support for ROM regions.
     ReadOnlyMemory = 81,
     EmulateCpuid = 95,
@@ -28,6 +44,22 @@ pub enum Capability {
     ///
     /// Returned value is maximum number of address spaces.
     MultiAddressSpace = 118,
+    /// The PICs and the I/O APIC can be emulated in user space,
+    /// while the local APICs are emulated by KVM.
+    SplitIrqChip = 121,
     /// Maximum ID for virtual CPUs.
     MaxVCpuId = 128,
+    /// 32-bit x2APIC IDs, in MSI routes and in the state of the local APICs.
+    ///
+    /// Returned value is the set of supported flags.
+    X2ApicApi = 129,
+    /// Dirty pages are only write-protected again when they are
+    /// explicitly cleared, instead of when the log is retrieved.
+    ///
+    /// Returned value is the set of supported flags.
+    ManualDirtyLogProtect = 168,
+    /// Per-vCPU rings of dirty pages, as an alternative to dirty bitmaps.
+    ///
+    /// Returned value is the maximum size of a ring, in bytes.
+    DirtyLogRing = 192,
 }
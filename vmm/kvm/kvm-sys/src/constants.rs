@@ -1,2 +1,8 @@
 /// The version of the stable KVM API.
 pub const API_VERSION: u32 = 12;
+
+/// Offset in pages of a vCPU's dirty ring, when `mmap`ing its file descriptor.
+pub const DIRTY_LOG_PAGE_OFFSET: u64 = 64;
+
+/// Maximum number of entries in a CPUID table.
+pub const MAX_CPUID_ENTRIES: usize = 256;
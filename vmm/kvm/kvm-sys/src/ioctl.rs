@@ -59,15 +59,28 @@ macro_rules! kvm_ioctl {
 kvm_ioctl!(none_arg get_api_version with 0x00);
 kvm_ioctl!(none_arg check_extension with 0x03);
 kvm_ioctl!(none_arg create_vm with 0x01);
+// The argument must be 0, otherwise KVM returns `EINVAL`.
+kvm_ioctl!(none_arg get_vcpu_mmap_size with 0x04);
 
-kvm_ioctl!(none get_vcpu_mmap_size with 0x04);
-
+kvm_ioctl!(readwrite get_msr_index_list with 0x02; structs::msr::MsrListHeader);
+kvm_ioctl!(readwrite get_supported_cpuid with 0x05; structs::cpuid::CpuidHeader);
 kvm_ioctl!(readwrite get_emulated_cpuid with 0x09; structs::cpuid::CpuidHeader);
 
 kvm_ioctl!(none create_irq_chip with 0x60);
+kvm_ioctl!(write_ptr irq_line with 0x61; structs::irq::IrqLevel);
 kvm_ioctl!(readwrite get_irq_chip with 0x62; structs::irq::IrqChip);
+kvm_ioctl!(read set_irq_chip with 0x63; structs::irq::IrqChip);
+kvm_ioctl!(write_ptr signal_msi with 0xA5; structs::irq::Msi);
+kvm_ioctl!(write_ptr set_gsi_routing with 0x6A; structs::irq::IrqRoutingHeader);
+kvm_ioctl!(write_ptr irqfd with 0x76; structs::event::IrqFd);
+kvm_ioctl!(write_ptr ioeventfd with 0x79; structs::event::IoEventFd);
 
 kvm_ioctl!(write_ptr set_memory_region with 0x46; structs::mem::MemoryRegion);
+kvm_ioctl!(write_ptr get_dirty_log with 0x42; structs::mem::DirtyLog);
+kvm_ioctl!(readwrite clear_dirty_log with 0xC0; structs::mem::ClearDirtyLog);
+kvm_ioctl!(none reset_dirty_rings with 0xC7);
+
+kvm_ioctl!(write_ptr enable_cap with 0xA3; structs::caps::EnableCap);
 
 kvm_ioctl!(none_arg create_vcpu with 0x41);
 
@@ -81,5 +94,21 @@ kvm_ioctl!(write_ptr set_regs with 0x82; structs::state::Registers);
 kvm_ioctl!(read get_sregs with 0x83; structs::state::SpecialRegisters);
 kvm_ioctl!(write_ptr set_sregs with 0x84; structs::state::SpecialRegisters);
 
+kvm_ioctl!(write_ptr interrupt with 0x86; structs::irq::Interrupt);
+
+kvm_ioctl!(readwrite get_msrs with 0x88; structs::msr::MsrsHeader);
+kvm_ioctl!(write_ptr set_msrs with 0x89; structs::msr::MsrsHeader);
+
+kvm_ioctl!(write_ptr set_cpuid2 with 0x90; structs::cpuid::CpuidHeader);
+kvm_ioctl!(readwrite get_cpuid2 with 0x91; structs::cpuid::CpuidHeader);
+
 kvm_ioctl!(read get_fpu with 0x8C; structs::fpu::FpuState);
 kvm_ioctl!(write_ptr set_fpu with 0x8D; structs::fpu::FpuState);
+
+kvm_ioctl!(read get_lapic with 0x8E; structs::irq::LapicState);
+kvm_ioctl!(write_ptr set_lapic with 0x8F; structs::irq::LapicState);
+
+kvm_ioctl!(read get_xsave with 0xA4; structs::fpu::Xsave);
+kvm_ioctl!(write_ptr set_xsave with 0xA5; structs::fpu::Xsave);
+kvm_ioctl!(read get_xcrs with 0xA6; structs::fpu::Xcrs);
+kvm_ioctl!(write_ptr set_xcrs with 0xA7; structs::fpu::Xcrs);
@@ -0,0 +1,26 @@
+//! Structures related to capabilities.
+
+/// Used to enable a capability which changes the behaviour of a VM or vCPU.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct EnableCap {
+    /// The capability to enable.
+    pub cap: u32,
+    /// Must be 0.
+    pub flags: u32,
+    /// Capability-specific arguments.
+    pub args: [u64; 4],
+    _padding: [u8; 64],
+}
+
+impl EnableCap {
+    /// Creates a request to enable `cap` with the given arguments.
+    pub fn new(cap: u32, args: [u64; 4]) -> Self {
+        EnableCap {
+            cap,
+            flags: 0,
+            args,
+            _padding: [0; 64],
+        }
+    }
+}
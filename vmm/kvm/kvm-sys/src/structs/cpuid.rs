@@ -1,11 +1,11 @@
 //! Representation of CPU ID data.
 
-use std::fmt;
+use std::{fmt, mem, slice};
 
 /// Header of CPUID entries array.
 ///
 /// You must allocate a contiguous array of `CpuidEntry`,
-/// with this structure as a header.
+/// with this structure as a header. `CpuidEntries` does this.
 #[derive(Debug, Default, Copy, Clone)]
 #[repr(C)]
 pub struct CpuidHeader {
@@ -14,6 +14,62 @@ pub struct CpuidHeader {
     _padding: u32,
 }
 
+/// A list of CPUID entries, with a `CpuidHeader`.
+pub struct CpuidEntries {
+    // The header and the entries are all made of 32-bit words.
+    buffer: Vec<u32>,
+}
+
+impl CpuidEntries {
+    /// Number of words in the header.
+    const HEADER: usize = mem::size_of::<CpuidHeader>() / mem::size_of::<u32>();
+
+    /// Number of words in each entry.
+    const ENTRY: usize = mem::size_of::<CpuidEntry>() / mem::size_of::<u32>();
+
+    /// Allocates a list with room for `capacity` entries, to be filled in by KVM.
+    pub fn with_capacity(capacity: usize) -> Self {
+        let mut buffer = vec![0; Self::HEADER + capacity * Self::ENTRY];
+        buffer[0] = capacity as u32;
+        CpuidEntries { buffer }
+    }
+
+    /// Allocates a list with the given entries.
+    pub fn new(entries: &[CpuidEntry]) -> Self {
+        let mut list = Self::with_capacity(entries.len());
+        list.entries_mut().copy_from_slice(entries);
+        list
+    }
+
+    /// Number of entries in the list.
+    pub fn len(&self) -> usize {
+        (self.buffer[0] as usize).min((self.buffer.len() - Self::HEADER) / Self::ENTRY)
+    }
+
+    /// Checks if the list has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The entries in the list.
+    pub fn entries(&self) -> &[CpuidEntry] {
+        let ptr = self.buffer[Self::HEADER..].as_ptr() as *const CpuidEntry;
+        unsafe { slice::from_raw_parts(ptr, self.len()) }
+    }
+
+    /// The entries in the list, which can be modified.
+    pub fn entries_mut(&mut self) -> &mut [CpuidEntry] {
+        let len = self.len();
+        let ptr = self.buffer[Self::HEADER..].as_mut_ptr() as *mut CpuidEntry;
+        unsafe { slice::from_raw_parts_mut(ptr, len) }
+    }
+
+    /// Returns a pointer to the list, to be passed to KVM.
+    pub fn as_mut_ptr(&mut self) -> *mut CpuidHeader {
+        self.buffer.as_mut_ptr() as *mut CpuidHeader
+    }
+}
+
 #[derive(Default, Copy, Clone)]
 #[repr(C)]
 pub struct CpuidEntry {
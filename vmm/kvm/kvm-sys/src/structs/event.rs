@@ -0,0 +1,65 @@
+//! Structures used to connect eventfds to the guest.
+
+/// Injects an interrupt when an eventfd is signaled.
+#[derive(Debug, Default, Copy, Clone)]
+#[repr(C)]
+pub struct IrqFd {
+    pub fd: u32,
+    pub gsi: u32,
+    pub flags: IrqFdFlags,
+    /// Signaled when a level-triggered interrupt is acknowledged,
+    /// if the `RESAMPLE` flag is set.
+    pub resample_fd: u32,
+    _padding: [u8; 16],
+}
+
+bitflags! {
+    /// Flags for an `IrqFd`.
+    #[derive(Default)]
+    pub struct IrqFdFlags: u32 {
+        /// Removes the irqfd instead of adding it.
+        const DEASSIGN = 1 << 0;
+        /// The interrupt is level-triggered.
+        const RESAMPLE = 1 << 1;
+    }
+}
+
+/// Signals an eventfd when the guest writes to an address.
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct IoEventFd {
+    /// Value which must be written, if the `DATAMATCH` flag is set.
+    pub datamatch: u64,
+    pub addr: u64,
+    /// Size of the write, in bytes.
+    pub len: u32,
+    pub fd: i32,
+    pub flags: IoEventFdFlags,
+    _padding: [u8; 36],
+}
+
+impl Default for IoEventFd {
+    fn default() -> Self {
+        IoEventFd {
+            datamatch: 0,
+            addr: 0,
+            len: 0,
+            fd: 0,
+            flags: IoEventFdFlags::empty(),
+            _padding: [0; 36],
+        }
+    }
+}
+
+bitflags! {
+    /// Flags for an `IoEventFd`.
+    #[derive(Default)]
+    pub struct IoEventFdFlags: u32 {
+        /// Only writes of `datamatch` signal the eventfd.
+        const DATAMATCH = 1 << 0;
+        /// The address is an I/O port.
+        const PIO = 1 << 1;
+        /// Removes the ioeventfd instead of adding it.
+        const DEASSIGN = 1 << 2;
+    }
+}
@@ -1,8 +1,11 @@
 //! Structures representing the x87 FPU and SSE / AVX state.
 
-#[derive(Debug, Copy, Clone)]
+use std::{mem, slice};
+
+#[derive(Debug, Default, Copy, Clone)]
 #[repr(C)]
 pub struct FpuState {
+    /// The x87 registers. Each 80-bit value is stored in 16 bytes.
     pub regs: [[u64; 2]; 8],
     pub control_word: u16,
     pub status_word: u16,
@@ -20,3 +23,63 @@ pub struct FpuState {
     pub mxcsr: u32,
     _padding2: u32,
 }
+
+/// The area used by the `XSAVE` instruction, in standard (non-compacted) format.
+///
+/// The offsets of the state components are given by CPUID leaf 0xD.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct Xsave {
+    pub region: [u32; 1024],
+}
+
+impl Xsave {
+    /// Returns the area as bytes.
+    pub fn bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.region.as_ptr() as *const u8, mem::size_of::<Self>()) }
+    }
+
+    /// Returns the area as mutable bytes.
+    pub fn bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.region.as_mut_ptr() as *mut u8, mem::size_of::<Self>()) }
+    }
+}
+
+impl Default for Xsave {
+    fn default() -> Self {
+        Xsave { region: [0; 1024] }
+    }
+}
+
+/// An extended control register.
+#[derive(Debug, Default, Copy, Clone)]
+#[repr(C)]
+pub struct Xcr {
+    /// The index of the register. XCR0 has index 0.
+    pub xcr: u32,
+    _reserved: u32,
+    pub value: u64,
+}
+
+impl Xcr {
+    /// Creates an entry for the register with the given index.
+    pub fn new(xcr: u32, value: u64) -> Self {
+        Xcr {
+            xcr,
+            _reserved: 0,
+            value,
+        }
+    }
+}
+
+/// The extended control registers of a vCPU.
+#[derive(Debug, Default, Copy, Clone)]
+#[repr(C)]
+pub struct Xcrs {
+    /// Number of valid entries in `xcrs`.
+    pub count: u32,
+    /// Must be 0.
+    pub flags: u32,
+    pub xcrs: [Xcr; 16],
+    _padding: [u64; 16],
+}
@@ -1,5 +1,181 @@
 //! Structures representing interrupt controllers' state.
 
+use std::{mem, slice};
+use x86::irqchip;
+
+/// Sets the level of an interrupt line.
+#[derive(Debug, Default, Copy, Clone)]
+#[repr(C)]
+pub struct IrqLevel {
+    /// The GSI of the line.
+    pub irq: u32,
+    /// 1 to assert the line, 0 to deassert it.
+    pub level: u32,
+}
+
+/// An interrupt injected into a vCPU, without an in-kernel IRQ chip.
+#[derive(Debug, Default, Copy, Clone)]
+#[repr(C)]
+pub struct Interrupt {
+    /// The interrupt vector.
+    pub irq: u32,
+}
+
+/// A message signaled interrupt.
+#[derive(Debug, Default, Copy, Clone)]
+#[repr(C)]
+pub struct Msi {
+    pub address_lo: u32,
+    pub address_hi: u32,
+    pub data: u32,
+    pub flags: u32,
+    /// ID of the device sending the message, not used on x86.
+    pub devid: u32,
+    _padding: [u8; 12],
+}
+
+/// Header of the GSI routing table, followed by `len` entries.
+///
+/// Use `IrqRouting` to allocate one.
+#[derive(Debug, Default, Copy, Clone)]
+#[repr(C)]
+pub struct IrqRoutingHeader {
+    /// Number of entries in the table.
+    pub len: u32,
+    pub flags: u32,
+}
+
+/// The type of a routing entry.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[repr(u32)]
+pub enum RoutingKind {
+    IrqChip = 1,
+    Msi = 2,
+}
+
+/// Routes a GSI to a pin of an IRQ chip.
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct RoutingIrqChip {
+    pub chip: ChipId,
+    pub pin: u32,
+}
+
+/// Routes a GSI to an MSI.
+#[derive(Debug, Default, Copy, Clone)]
+#[repr(C)]
+pub struct RoutingMsi {
+    pub address_lo: u32,
+    pub address_hi: u32,
+    pub data: u32,
+    _padding: u32,
+}
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub union RoutingData {
+    pub irqchip: RoutingIrqChip,
+    pub msi: RoutingMsi,
+    _padding: [u32; 8],
+}
+
+/// An entry of the GSI routing table.
+///
+/// A GSI can have several entries, one for each destination.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct IrqRoutingEntry {
+    pub gsi: u32,
+    pub kind: RoutingKind,
+    pub flags: u32,
+    _padding: u32,
+    pub data: RoutingData,
+}
+
+impl IrqRoutingEntry {
+    /// Routes a GSI to a pin of an IRQ chip.
+    pub fn irqchip(gsi: u32, chip: ChipId, pin: u32) -> Self {
+        let mut data = RoutingData { _padding: [0; 8] };
+        data.irqchip = RoutingIrqChip { chip, pin };
+
+        IrqRoutingEntry {
+            gsi,
+            kind: RoutingKind::IrqChip,
+            flags: 0,
+            _padding: 0,
+            data,
+        }
+    }
+
+    /// Routes a GSI to an MSI with the given address and data.
+    pub fn msi(gsi: u32, address: u64, data: u32) -> Self {
+        let mut msi = RoutingMsi::default();
+
+        msi.address_lo = address as u32;
+        msi.address_hi = (address >> 32) as u32;
+        msi.data = data;
+
+        let mut data = RoutingData { _padding: [0; 8] };
+        data.msi = msi;
+
+        IrqRoutingEntry {
+            gsi,
+            kind: RoutingKind::Msi,
+            flags: 0,
+            _padding: 0,
+            data,
+        }
+    }
+}
+
+/// A GSI routing table, with a `IrqRoutingHeader`.
+pub struct IrqRouting {
+    // The header and the entries are all made of 32-bit words.
+    buffer: Vec<u32>,
+}
+
+impl IrqRouting {
+    /// Number of words in the header.
+    const HEADER: usize = mem::size_of::<IrqRoutingHeader>() / mem::size_of::<u32>();
+
+    /// Number of words in each entry.
+    const ENTRY: usize = mem::size_of::<IrqRoutingEntry>() / mem::size_of::<u32>();
+
+    /// Allocates a table with the given entries.
+    pub fn new(entries: &[IrqRoutingEntry]) -> Self {
+        let mut buffer = vec![0; Self::HEADER + entries.len() * Self::ENTRY];
+        buffer[0] = entries.len() as u32;
+
+        let mut table = IrqRouting { buffer };
+
+        let ptr = table.buffer[Self::HEADER..].as_mut_ptr() as *mut IrqRoutingEntry;
+        unsafe { slice::from_raw_parts_mut(ptr, entries.len()) }.copy_from_slice(entries);
+
+        table
+    }
+
+    /// Number of entries in the table.
+    pub fn len(&self) -> usize {
+        self.buffer[0] as usize
+    }
+
+    /// Checks if the table has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The entries in the table.
+    pub fn entries(&self) -> &[IrqRoutingEntry] {
+        let ptr = self.buffer[Self::HEADER..].as_ptr() as *const IrqRoutingEntry;
+        unsafe { slice::from_raw_parts(ptr, self.len()) }
+    }
+
+    /// Returns a pointer to the table, to be passed to KVM.
+    pub fn as_mut_ptr(&mut self) -> *mut IrqRoutingHeader {
+        self.buffer.as_mut_ptr() as *mut IrqRoutingHeader
+    }
+}
+
 /// Structure containing the data of an IRQ chip.
 ///
 /// You must fill the `ChipId` field with
@@ -12,6 +188,17 @@ pub struct IrqChip {
     pub state: IrqChipState,
 }
 
+impl IrqChip {
+    /// Creates a zeroed structure for the given chip.
+    pub fn new(id: ChipId) -> Self {
+        IrqChip {
+            id,
+            _padding: 0,
+            state: IrqChipState { _padding: [0; 512] },
+        }
+    }
+}
+
 /// The type of the IRQ chip.
 #[derive(Debug, Copy, Clone)]
 #[repr(u32)]
@@ -30,7 +217,7 @@ pub union IrqChipState {
 }
 
 /// The state of an emulated Programmable Interrupt Controller.
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Default, Copy, Clone)]
 #[repr(C)]
 pub struct PicState {
     /// Last IRR value for edge detection.
@@ -58,7 +245,8 @@ pub struct PicState {
     pub elcr_mask: u8,
 }
 
-#[derive(Debug, Copy, Clone)]
+/// The state of an emulated I/O APIC.
+#[derive(Debug, Default, Copy, Clone)]
 #[repr(C)]
 pub struct IoApicState {
     /// The APIC base physical address.
@@ -71,6 +259,58 @@ pub struct IoApicState {
     pub irr: u32,
     _padding: u32,
     /// Each IRQ is configured by one 64-bit register.
-    // TODO: replace this with a bitfield.
-    pub redir_tbl: [u64; 24],
+    pub redir_tbl: [RedirectionEntry; 24],
+}
+
+/// An entry of the I/O APIC's redirection table, in its register layout.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+#[repr(transparent)]
+pub struct RedirectionEntry(pub u64);
+
+impl From<irqchip::RedirectionEntry> for RedirectionEntry {
+    fn from(entry: irqchip::RedirectionEntry) -> Self {
+        RedirectionEntry(entry.into())
+    }
+}
+
+impl From<RedirectionEntry> for irqchip::RedirectionEntry {
+    fn from(entry: RedirectionEntry) -> Self {
+        entry.0.into()
+    }
+}
+
+/// The registers of a local APIC.
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct LapicState {
+    /// The first 1 KiB of the register page.
+    pub regs: [u8; 1024],
+}
+
+impl Default for LapicState {
+    fn default() -> Self {
+        LapicState { regs: [0; 1024] }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn irq_routing_layout() {
+        assert_eq!(mem::size_of::<IrqRoutingEntry>(), 48);
+
+        let table = IrqRouting::new(&[
+            IrqRoutingEntry::irqchip(3, ChipId::IOAPIC, 3),
+            IrqRoutingEntry::msi(24, 0xFEE0_1000, 0x4041),
+        ]);
+
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.entries()[1].gsi, 24);
+
+        // Entries start right after the 8-byte header.
+        assert_eq!(table.buffer[..8], [2, 0, 3, 1, 0, 0, 2, 3]);
+        assert_eq!(table.buffer[14..21], [24, 2, 0, 0, 0xFEE0_1000, 0, 0x4041]);
+    }
 }
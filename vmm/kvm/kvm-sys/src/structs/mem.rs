@@ -1,5 +1,7 @@
 //! Structures related to memory management.
 
+use std::sync::atomic::AtomicU32;
+
 /// A guest physical memory slot.
 ///
 /// Memory regions in the same address space must not overlap.
@@ -30,7 +32,7 @@ pub struct MemoryRegion {
     pub guest_phys_addr: u64,
     /// Size in bytes of this memory region.
     ///
-    /// If `Flags::READ_ONLY` is set, this must be 0.
+    /// Setting the size of an existing slot to 0 deletes it.
     pub size: u64,
     /// Starting address of the host virtual memory region.
     pub host_virt_addr: u64,
@@ -46,3 +48,65 @@ bitflags! {
         const READ_ONLY = 1 << 1;
     }
 }
+
+/// Used to retrieve the dirty page bitmap of a memory slot.
+///
+/// The slot must have the `LOG_DIRTY_PAGES` flag set.
+#[derive(Debug, Default, Copy, Clone)]
+#[repr(C)]
+pub struct DirtyLog {
+    /// The memory slot to retrieve the log of.
+    pub slot: u16,
+    /// The address space of the slot.
+    pub address_space: u16,
+
+    _padding: u32,
+
+    /// Pointer to a bitmap with one bit for each page in the slot,
+    /// rounded up to a multiple of 64 bits.
+    pub dirty_bitmap: u64,
+}
+
+/// Used to clear the dirty state of a range of pages in a memory slot.
+///
+/// Requires the `ManualDirtyLogProtect` capability to be enabled.
+#[derive(Debug, Default, Copy, Clone)]
+#[repr(C)]
+pub struct ClearDirtyLog {
+    /// The memory slot to clear the log of.
+    pub slot: u16,
+    /// The address space of the slot.
+    pub address_space: u16,
+    /// Number of pages to clear.
+    ///
+    /// Must be a multiple of 64, unless the range ends at the end of the slot.
+    pub num_pages: u32,
+    /// First page to clear. Must be a multiple of 64.
+    pub first_page: u64,
+    /// Pointer to a bitmap of the pages to clear, starting with `first_page`.
+    pub dirty_bitmap: u64,
+}
+
+/// An entry in a vCPU's dirty ring, describing a page written to by the guest.
+#[repr(C)]
+pub struct DirtyGfn {
+    /// The state of this entry, described by `DirtyGfnFlags`.
+    ///
+    /// KVM publishes an entry by setting `DIRTY`, and the application
+    /// hands it back by setting `RESET`.
+    pub flags: AtomicU32,
+    /// The memory slot of the page, with the address space in the upper 16 bits.
+    pub slot: u32,
+    /// Index of the page in the memory slot.
+    pub offset: u64,
+}
+
+bitflags! {
+    pub struct DirtyGfnFlags: u32 {
+        /// The entry contains a dirty page which was not yet harvested.
+        const DIRTY = 1 << 0;
+        /// The entry was harvested, and can be reused by KVM once
+        /// the dirty rings are reset.
+        const RESET = 1 << 1;
+    }
+}
@@ -1,11 +1,17 @@
 //! Structures used by the KVM interfaces.
 
+pub mod caps;
+
 pub mod cpuid;
 
+pub mod event;
+
 pub mod irq;
 
 pub mod mem;
 
+pub mod msr;
+
 pub mod state;
 
 pub mod fpu;
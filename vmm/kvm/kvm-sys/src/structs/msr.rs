@@ -0,0 +1,142 @@
+//! Structures used to access model-specific registers.
+
+use std::{mem, slice};
+
+/// Header of a list of MSRs, followed by `len` entries.
+///
+/// Use `Msrs` to allocate one.
+#[derive(Debug, Default, Copy, Clone)]
+#[repr(C)]
+pub struct MsrsHeader {
+    /// Number of entries in the list.
+    pub len: u32,
+    _padding: u32,
+}
+
+/// The index and value of an MSR.
+#[derive(Debug, Default, Copy, Clone)]
+#[repr(C)]
+pub struct MsrEntry {
+    pub index: u32,
+    _reserved: u32,
+    pub data: u64,
+}
+
+impl MsrEntry {
+    /// Creates an entry for an MSR with the given value.
+    pub fn new(index: u32, data: u64) -> Self {
+        MsrEntry {
+            index,
+            _reserved: 0,
+            data,
+        }
+    }
+}
+
+/// A list of MSR entries, with a `MsrsHeader`.
+pub struct Msrs {
+    // The header and the entries are all made of 64-bit words.
+    buffer: Vec<u64>,
+}
+
+impl Msrs {
+    /// Allocates a list with the given entries.
+    pub fn new(entries: &[MsrEntry]) -> Self {
+        let words = mem::size_of::<MsrEntry>() / mem::size_of::<u64>();
+
+        let mut msrs = Msrs {
+            buffer: vec![0; 1 + entries.len() * words],
+        };
+
+        msrs.buffer[0] = entries.len() as u64;
+        msrs.entries_mut().copy_from_slice(entries);
+
+        msrs
+    }
+
+    /// Number of entries in the list.
+    pub fn len(&self) -> usize {
+        self.buffer[0] as u32 as usize
+    }
+
+    /// Checks if the list has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The entries in the list.
+    pub fn entries(&self) -> &[MsrEntry] {
+        unsafe { slice::from_raw_parts(self.buffer[1..].as_ptr() as *const MsrEntry, self.len()) }
+    }
+
+    /// The entries in the list, which can be modified.
+    pub fn entries_mut(&mut self) -> &mut [MsrEntry] {
+        let len = self.len();
+        unsafe { slice::from_raw_parts_mut(self.buffer[1..].as_mut_ptr() as *mut MsrEntry, len) }
+    }
+
+    /// Returns a pointer to the list, to be passed to KVM.
+    pub fn as_mut_ptr(&mut self) -> *mut MsrsHeader {
+        self.buffer.as_mut_ptr() as *mut MsrsHeader
+    }
+}
+
+/// Header of a list of MSR indices, followed by `len` indices.
+///
+/// Use `MsrList` to allocate one.
+#[derive(Debug, Default, Copy, Clone)]
+#[repr(C)]
+pub struct MsrListHeader {
+    /// On input, the number of indices which fit in the list.
+    /// On output, the number of indices in the list.
+    pub len: u32,
+}
+
+/// A list of MSR indices, with a `MsrListHeader`.
+pub struct MsrList {
+    buffer: Vec<u32>,
+}
+
+impl MsrList {
+    /// Allocates a list with room for `capacity` indices.
+    pub fn new(capacity: usize) -> Self {
+        let mut buffer = vec![0; 1 + capacity];
+        buffer[0] = capacity as u32;
+        MsrList { buffer }
+    }
+
+    /// The indices in the list.
+    pub fn indices(&self) -> &[u32] {
+        let len = (self.buffer[0] as usize).min(self.buffer.len() - 1);
+        &self.buffer[1..=len]
+    }
+
+    /// Returns a pointer to the list, to be passed to KVM.
+    pub fn as_mut_ptr(&mut self) -> *mut MsrListHeader {
+        self.buffer.as_mut_ptr() as *mut MsrListHeader
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn msrs_layout() {
+        let mut msrs = Msrs::new(&[MsrEntry::new(0x10, 1), MsrEntry::new(0xC000_0082, !0)]);
+
+        assert_eq!(msrs.len(), 2);
+        assert_eq!(msrs.entries()[1].index, 0xC000_0082);
+
+        let header = unsafe { &*msrs.as_mut_ptr() };
+        assert_eq!(header.len, 2);
+
+        // Entries start right after the 8-byte header.
+        assert_eq!(msrs.buffer[1..], [0x10, 1, 0xC000_0082, !0]);
+
+        let mut list = MsrList::new(2);
+        list.buffer[0] = 1;
+        list.buffer[1] = 0x174;
+        assert_eq!(list.indices(), [0x174]);
+    }
+}
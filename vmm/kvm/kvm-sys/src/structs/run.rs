@@ -63,12 +63,15 @@ pub enum ExitReason {
     Unknown = 0,
     /// Port I/O emulation.
     Io = 2,
+    /// A debug exception was triggered.
     Debug = 4,
     Hlt = 5,
     Mmio = 6,
     IrqWindowOpen = 7,
+    /// The guest triple faulted.
     Shutdown = 8,
     FailEntry = 9,
+    /// A signal is pending.
     Interrupt = 10,
     // TODO: is this actually used anywhere?
     SetTpr = 11,
@@ -76,6 +79,14 @@ pub enum ExitReason {
     Nmi = 16,
     /// An internal error occured in KVM.
     InternalError = 17,
+    /// The guest triggered a platform-level event, such as a reset.
+    SystemEvent = 24,
+    /// The guest signaled the end of a level-triggered interrupt
+    /// from an I/O APIC emulated in user space.
+    IoapicEoi = 26,
+    /// The vCPU's dirty ring is full, and must be harvested before
+    /// the vCPU can run again.
+    DirtyRingFull = 31,
 }
 
 /// Architecture-specific exit reason.
@@ -86,11 +97,19 @@ pub union ExitData {
     /// The vCPU stopped running due to an unknown reason.
     pub unknown: HardwareExitReason,
     /// The vCPU failed to run.
-    pub fail_entry: HardwareExitReason,
+    pub fail_entry: FailEntryState,
     /// The guest attempted to do port I/O.
     pub io: IoState,
+    /// The guest accessed memory which is not backed by a memory slot.
+    pub mmio: MmioState,
+    /// A debug exception was triggered.
+    pub debug: DebugState,
     /// An internal kernel module error occured.
     pub internal: InternalError,
+    /// The guest triggered a system event.
+    pub system_event: SystemEventState,
+    /// The guest signaled an end-of-interrupt for the I/O APIC.
+    pub eoi: EoiState,
     _padding: [u8; 256],
 }
 
@@ -114,6 +133,110 @@ pub struct IoState {
     pub data_offset: u64,
 }
 
+/// Describes a memory access which must be emulated by user space.
+///
+/// For reads, the application must fill `data` with the value to be read,
+/// which KVM copies into the guest on the next `run`.
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct MmioState {
+    /// The guest physical address that was accessed.
+    pub phys_addr: u64,
+    /// The data written by the guest, or to be read by the guest.
+    pub data: [u8; 8],
+    /// Size in bytes of the access.
+    pub len: u32,
+    /// Non-zero if the guest wrote to memory, zero if it read from memory.
+    ///
+    /// Stored as an integer since KVM could use any value.
+    pub is_write: u8,
+}
+
+impl MmioState {
+    /// Checks if the guest wrote to memory.
+    pub fn is_write(&self) -> bool {
+        self.is_write != 0
+    }
+}
+
+/// Information on why the vCPU failed to enter guest mode.
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct FailEntryState {
+    /// Hardware-specific reason for the failure.
+    ///
+    /// On Intel, this is the VM-entry failure exit reason.
+    pub hardware_entry_failure_reason: u64,
+    /// The host CPU on which the entry was attempted.
+    pub cpu: u32,
+}
+
+/// The state of the vCPU when a debug exception occured.
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct DebugState {
+    /// The exception vector.
+    pub exception: u32,
+    _padding: u32,
+    /// The instruction pointer.
+    pub pc: u64,
+    /// Value of the debug status register.
+    pub dr6: u64,
+    /// Value of the debug control register.
+    pub dr7: u64,
+}
+
+/// Describes a system event triggered by the guest.
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct SystemEventState {
+    /// The type of the event, as a raw value.
+    ///
+    /// Use `kind()` to interpret it, since newer kernels might add other types.
+    pub kind: u32,
+    _padding: u32,
+    /// Architecture-specific flags.
+    pub flags: u64,
+}
+
+impl SystemEventState {
+    /// The type of the event.
+    pub fn kind(&self) -> SystemEventType {
+        match self.kind {
+            1 => SystemEventType::Shutdown,
+            2 => SystemEventType::Reset,
+            3 => SystemEventType::Crash,
+            4 => SystemEventType::Wakeup,
+            5 => SystemEventType::Suspend,
+            kind => SystemEventType::Unknown(kind),
+        }
+    }
+}
+
+/// The end of a level-triggered interrupt.
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct EoiState {
+    /// The vector of the interrupt.
+    pub vector: u8,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SystemEventType {
+    /// The guest requested a shutdown.
+    Shutdown,
+    /// The guest requested a reset.
+    Reset,
+    /// The guest crashed.
+    Crash,
+    /// A halted vCPU should be woken up.
+    Wakeup,
+    /// The guest requested to be suspended.
+    Suspend,
+    /// An event type this crate does not know about.
+    Unknown(u32),
+}
+
 #[derive(Debug, Copy, Clone)]
 #[repr(u32)]
 pub enum InternalError {
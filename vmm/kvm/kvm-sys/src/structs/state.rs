@@ -57,14 +57,17 @@ pub struct Segment {
     pub limit: u32,
     /// Index in the GDT.
     pub selector: u16,
-    /// Type of selector.
-    pub code_data: bool,
+    /// Type of segment, as found in the descriptor.
+    ///
+    /// For code / data segments, the bits are: accessed, writable / readable,
+    /// direction / conforming and code / data.
+    pub kind: u8,
     /// Whether it is present or not.
     pub present: bool,
     /// Descriptor Privilege Level.
     pub priv_level: u8,
-    /// Direction / conforming.
-    pub direction_conforming: bool,
+    /// Default operation size (16 bit / 32 bit).
+    pub default_big: bool,
     /// User / system.
     pub user_system: bool,
     /// Long mode.
@@ -73,7 +76,7 @@ pub struct Segment {
     pub gran: bool,
     /// Bit available for use by CPU.
     pub avl: bool,
-    /// Bit reserved for the CPU's use.
+    /// Set if the segment cannot be used.
     pub unusable: bool,
     padding: u8,
 }
@@ -0,0 +1,63 @@
+//! Per-vCPU rings of dirty pages.
+
+use accel;
+use accel::errors::Result;
+use kvm;
+use kvm::structs::mem::{DirtyGfn, DirtyGfnFlags};
+use memmap as mm;
+use std::cell::Cell;
+use std::fs::File;
+use std::mem;
+use std::sync::atomic::Ordering;
+
+/// A ring of pages written to by the guest on a vCPU.
+///
+/// KVM pushes entries as the guest writes to memory, and we harvest them.
+pub struct DirtyRing {
+    ring: mm::Mmap,
+    entries: u32,
+    /// Index of the next entry to harvest.
+    next: Cell<u32>,
+}
+
+impl DirtyRing {
+    /// Maps the dirty ring of a vCPU, which has room for `entries` entries.
+    pub fn new(vcpu: &File, entries: u32) -> Result<Self> {
+        let prot = mm::Protection::ReadWrite;
+        let offset = kvm::DIRTY_LOG_PAGE_OFFSET as usize * accel::PAGE_SIZE;
+        let len = entries as usize * mem::size_of::<DirtyGfn>();
+
+        let ring = mm::Mmap::open_with_offset(vcpu, prot, offset, len)?;
+
+        Ok(DirtyRing {
+            ring,
+            entries,
+            next: Cell::new(0),
+        })
+    }
+
+    /// Calls `f` with the slot and page index of every new entry in the ring,
+    /// and marks the entries as harvested.
+    ///
+    /// The ring must be reset afterwards, to allow KVM to reuse the entries.
+    pub fn harvest<F: FnMut(u16, u64)>(&self, mut f: F) {
+        let ring = self.ring.ptr() as *const DirtyGfn;
+
+        loop {
+            let index = self.next.get();
+            let entry = unsafe { &*ring.add((index % self.entries) as usize) };
+
+            let flags = DirtyGfnFlags::from_bits_truncate(entry.flags.load(Ordering::Acquire));
+
+            if !flags.contains(DirtyGfnFlags::DIRTY) {
+                break;
+            }
+
+            // The upper 16 bits contain the address space, which is always 0.
+            f(entry.slot as u16, entry.offset);
+
+            entry.flags.store(DirtyGfnFlags::RESET.bits(), Ordering::Release);
+            self.next.set(index.wrapping_add(1));
+        }
+    }
+}
@@ -2,11 +2,13 @@
 //! query supported capabilities, and set process-wide settings.
 
 use std::fs::File;
+use std::io;
 use accel;
 use accel::errors::Result;
 use kvm;
 use kvm::Capability;
 use vm::VirtualMachine;
+use x86::cpuid::{Cpuid, CpuidEntry, Register};
 
 #[derive(Debug)]
 pub struct Global {
@@ -80,16 +82,68 @@ impl Global {
         }
     }
 
+    /// Retrieves the list of MSRs which can be accessed through the vCPUs.
+    pub fn msr_index_list(&self) -> Result<Vec<u32>> {
+        use kvm::structs::msr::MsrList;
+
+        // The first call fails, but tells us how many MSRs there are.
+        let mut list = MsrList::new(0);
+
+        if let Err(error) = unsafe { kvm::ioctl::get_msr_index_list(self.fd(), list.as_mut_ptr()) } {
+            if error.kind() != io::ErrorKind::ArgumentListTooLong {
+                return Err(error.into());
+            }
+        }
+
+        let mut list = MsrList::new(unsafe { (*list.as_mut_ptr()).len } as usize);
+
+        unsafe { kvm::ioctl::get_msr_index_list(self.fd(), list.as_mut_ptr())? };
+
+        Ok(list.indices().to_vec())
+    }
+
+    /// Retrieves the CPUID leaves supported by KVM and the host processor.
+    pub fn supported_cpuid(&self) -> Result<Cpuid> {
+        use kvm::structs::cpuid::{CpuidEntries, CpuidFlag};
+
+        let mut list = CpuidEntries::with_capacity(kvm::MAX_CPUID_ENTRIES);
+
+        unsafe { kvm::ioctl::get_supported_cpuid(self.fd(), list.as_mut_ptr())? };
+
+        let entries: Vec<_> = list
+            .entries()
+            .iter()
+            .map(|entry| {
+                let index = if entry.flags.contains(CpuidFlag::SIGNIFICANT_INDEX) {
+                    Some(entry.index)
+                } else {
+                    None
+                };
+
+                CpuidEntry::new(entry.function, index, entry.r)
+            })
+            .collect();
+
+        let mut cpuid = Cpuid::new(entries);
+
+        // KVM does not report the features which depend on the in-kernel irqchip.
+        if self.check_capability(Capability::TscDeadlineTimer)? != 0 {
+            cpuid.set_bits(1, None, Register::Ecx, 1 << 24);
+        }
+
+        Ok(cpuid)
+    }
+
     /// The size of the vCPU run state structure, in bytes.
     pub fn vcpu_mmap_size(&self) -> Result<usize> {
-        let size = unsafe { kvm::ioctl::get_vcpu_mmap_size(self.fd())? };
+        let size = unsafe { kvm::ioctl::get_vcpu_mmap_size(self.fd(), 0)? };
 
         Ok(size as usize)
     }
 }
 
 impl accel::Accelerator for Global {
-    fn create_vm<'a>(&'a self) -> Result<Box<accel::VirtualMachine + 'a>> {
+    fn create_vm<'a>(&'a self, irqchip: accel::IrqChipMode) -> Result<Box<accel::VirtualMachine + 'a>> {
         // This is only relevant for non-x86.
         let machine_type = 0;
 
@@ -98,7 +152,7 @@ impl accel::Accelerator for Global {
         use std::os::unix::io::FromRawFd;
         let file = unsafe { File::from_raw_fd(fd as i32) };
 
-        let vm = VirtualMachine::new(self, file)?;
+        let vm = VirtualMachine::new(self, file, irqchip)?;
 
         Ok(Box::new(vm))
     }
@@ -119,6 +173,7 @@ mod tests {
 
         let g = Global::new().unwrap();
 
-        g.create_vm().expect("Failed to create virtual machine");
+        g.create_vm(accel::IrqChipMode::Kernel).expect("Failed to create virtual machine");
+        g.create_vm(accel::IrqChipMode::User).expect("Failed to create virtual machine");
     }
 }
@@ -22,16 +22,23 @@ extern crate error_chain;
 
 extern crate accel;
 
+extern crate intc;
+
 extern crate kvm_sys as kvm;
 
 extern crate vm_x86 as x86;
 
 extern crate memmap;
 
+mod dirty;
 mod global;
+mod routing;
 mod vm;
 mod vcpu;
 
+#[cfg(test)]
+mod test_util;
+
 /// Creates an object which implements the `Accelerator` trait.
 pub fn create() -> accel::errors::Result<Box<accel::Accelerator>> {
     let global = global::Global::new()?;
@@ -0,0 +1,107 @@
+//! The table which routes GSIs to interrupt controllers and MSIs.
+
+use accel::IrqRoute;
+use accel::arch::IrqChip;
+use kvm::structs::irq::{ChipId, IrqRouting, IrqRoutingEntry};
+
+/// Number of pins of the I/O APIC.
+const IOAPIC_PINS: u32 = 24;
+
+/// Number of GSIs connected to the PICs.
+const PIC_PINS: u32 = 16;
+
+/// The routes of all GSIs.
+///
+/// Setting the routing table replaces all of KVM's routes,
+/// so the table always contains the default routes which were not removed.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct GsiRouting {
+    routes: Vec<(u32, IrqRoute)>,
+}
+
+impl Default for GsiRouting {
+    /// The routes set up by KVM when the IRQ chip is created.
+    fn default() -> Self {
+        let mut routing = GsiRouting::empty();
+
+        for gsi in 0..IOAPIC_PINS {
+            if gsi < PIC_PINS {
+                let chip = if gsi < 8 { IrqChip::PicMaster } else { IrqChip::PicSlave };
+                routing.add(gsi, IrqRoute::IrqChip { chip, pin: gsi % 8 });
+            }
+
+            routing.add(gsi, IrqRoute::IrqChip { chip: IrqChip::IoApic, pin: gsi });
+        }
+
+        routing
+    }
+}
+
+impl GsiRouting {
+    /// Creates a table without any route.
+    ///
+    /// This is the initial table when the PICs and the I/O APIC are not emulated by KVM.
+    pub fn empty() -> Self {
+        GsiRouting { routes: Vec::new() }
+    }
+
+    /// Returns the routes of a GSI.
+    pub fn routes(&self, gsi: u32) -> Vec<IrqRoute> {
+        self.routes.iter().filter(|&&(g, _)| g == gsi).map(|&(_, route)| route).collect()
+    }
+
+    /// Adds a route for a GSI, unless it already exists.
+    pub fn add(&mut self, gsi: u32, route: IrqRoute) {
+        if !self.routes.contains(&(gsi, route)) {
+            self.routes.push((gsi, route));
+        }
+    }
+
+    /// Removes all of the routes of a GSI.
+    pub fn remove(&mut self, gsi: u32) {
+        self.routes.retain(|&(g, _)| g != gsi);
+    }
+
+    /// Builds the table to be passed to KVM.
+    pub fn to_kvm(&self) -> IrqRouting {
+        let entries: Vec<_> = self
+            .routes
+            .iter()
+            .map(|&(gsi, route)| match route {
+                IrqRoute::IrqChip { chip, pin } => {
+                    let chip = match chip {
+                        IrqChip::PicMaster => ChipId::PIC1,
+                        IrqChip::PicSlave => ChipId::PIC2,
+                        IrqChip::IoApic => ChipId::IOAPIC,
+                    };
+
+                    IrqRoutingEntry::irqchip(gsi, chip, pin)
+                }
+                IrqRoute::Msi { address, data } => IrqRoutingEntry::msi(gsi, address, data),
+            })
+            .collect();
+
+        IrqRouting::new(&entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_routes() {
+        let mut routing = GsiRouting::default();
+        assert_eq!(routing.routes.len(), 16 * 2 + 8);
+
+        let msi = IrqRoute::Msi { address: 0xFEE0_0000, data: 0x41 };
+        routing.add(24, msi);
+        routing.add(24, msi);
+        routing.remove(9);
+
+        let table = routing.to_kvm();
+        assert_eq!(table.len(), 16 * 2 + 8 - 2 + 1);
+        assert!(table.entries().iter().all(|e| e.gsi != 9));
+        assert_eq!(table.entries().last().unwrap().gsi, 24);
+    }
+}
@@ -0,0 +1,99 @@
+//! Fixtures shared by the tests which run guest code.
+
+use accel::{self, IrqChipMode};
+use accel::errors::Result;
+use accel::test_util::{self, TestVcpu, TestVm};
+use global::Global;
+use std::cell::Cell;
+
+/// Port used by the guest to stop the test.
+pub const EXIT_PORT: u16 = 0xF4;
+
+/// Address of the number of interrupts received by `INTERRUPT_CODE`.
+pub const INTERRUPT_COUNTER: u64 = 0x500;
+
+/// Code which counts the interrupts on IRQ 5 of the master PIC and on vector 0x40,
+/// stopping after each of them. It also stops once it is ready for interrupts.
+pub const INTERRUPT_CODE: &[u8] = &[
+    // cli
+    0xFA,
+    // xor ax, ax; mov ds, ax; mov ss, ax; mov sp, 0x8000
+    0x31, 0xC0, 0x8E, 0xD8, 0x8E, 0xD0, 0xBC, 0x00, 0x80,
+    // Install the handlers for IRQ 5 and vector 0x40 in the interrupt vector table.
+    // mov word [0x94], 0x105D; mov word [0x96], 0
+    0xC7, 0x06, 0x94, 0x00, 0x5D, 0x10, 0xC7, 0x06, 0x96, 0x00, 0x00, 0x00,
+    // mov word [0x100], 0x1068; mov word [0x102], 0
+    0xC7, 0x06, 0x00, 0x01, 0x68, 0x10, 0xC7, 0x06, 0x02, 0x01, 0x00, 0x00,
+    // The local APIC only accepts MSIs once it is software-enabled,
+    // which is done through the x2APIC MSRs in real mode.
+    // mov ecx, 0x1B; rdmsr; or ax, 0xC00; wrmsr
+    0x66, 0xB9, 0x1B, 0x00, 0x00, 0x00, 0x0F, 0x32, 0x0D, 0x00, 0x0C, 0x0F, 0x30,
+    // mov ecx, 0x80F; mov eax, 0x1FF; xor edx, edx; wrmsr
+    0x66, 0xB9, 0x0F, 0x08, 0x00, 0x00, 0x66, 0xB8, 0xFF, 0x01, 0x00, 0x00,
+    0x66, 0x31, 0xD2, 0x0F, 0x30,
+    // Initialize the master PIC, with vectors starting at 0x20,
+    // and only IRQ 5 unmasked.
+    0xB0, 0x11, 0xE6, 0x20,
+    0xB0, 0x20, 0xE6, 0x21,
+    0xB0, 0x04, 0xE6, 0x21,
+    0xB0, 0x01, 0xE6, 0x21,
+    0xB0, 0xDF, 0xE6, 0x21,
+    // out 0xF4, al
+    0xE6, 0xF4,
+    // Wait for an interrupt, then exit.
+    // sti; hlt; cli; out 0xF4, al; jmp -7
+    0xFB, 0xF4, 0xFA, 0xE6, 0xF4, 0xEB, 0xF9,
+    // Handler for IRQ 5:
+    // inc byte [0x500]
+    0xFE, 0x06, 0x00, 0x05,
+    // push ax; mov al, 0x20; out 0x20, al; pop ax; iret
+    0x50, 0xB0, 0x20, 0xE6, 0x20, 0x58, 0xCF,
+    // Handler for vector 0x40:
+    // inc byte [0x500]
+    0xFE, 0x06, 0x00, 0x05,
+    // mov ecx, 0x80B; xor eax, eax; xor edx, edx; wrmsr; iret
+    0x66, 0xB9, 0x0B, 0x08, 0x00, 0x00, 0x66, 0x31, 0xC0, 0x66, 0x31, 0xD2, 0x0F, 0x30, 0xCF,
+];
+
+#[derive(Default)]
+pub struct Callbacks {
+    pub port_value: Cell<u8>,
+    pub mmio_addr: Cell<u64>,
+    pub mmio_value: Cell<u8>,
+}
+
+impl accel::CpuCallbacks for Callbacks {
+    fn port_io(&self, port: u16, _output: bool, buffer: &mut [u8], _size: usize) -> Result<()> {
+        if port == EXIT_PORT {
+            bail!("guest exited");
+        }
+
+        self.port_value.set(buffer[0]);
+        Ok(())
+    }
+
+    fn mmio(&self, addr: u64, _is_write: bool, data: &mut [u8]) -> Result<()> {
+        self.mmio_addr.set(addr);
+        self.mmio_value.set(data[0]);
+        Ok(())
+    }
+}
+
+/// A test VM, with `Callbacks` for its vCPUs.
+pub type Vm = TestVm<Callbacks>;
+
+/// Creates a KVM VM with RAM at the start of memory, whose vCPUs jump to
+/// `code` after reset. No vCPU is created.
+pub fn test_guest(irqchip: IrqChipMode, code: &[u8]) -> Vm {
+    test_util::test_guest(kvm(), irqchip, code)
+}
+
+/// Creates a KVM VM which runs `code`, and its first vCPU.
+pub fn test_vm(irqchip: IrqChipMode, code: &[u8]) -> (Vm, TestVcpu) {
+    test_util::test_vm(kvm(), irqchip, code)
+}
+
+/// Opens KVM for the whole test.
+fn kvm() -> &'static Global {
+    Box::leak(Box::new(Global::new().unwrap()))
+}
@@ -4,29 +4,61 @@ use accel::CpuCallbacks;
 use vm::VirtualMachine;
 use std::fs::File;
 use x86;
+use x86::apic::LapicState;
+use x86::cpuid::Cpuid;
+use x86::fpu::xsave::Layout;
 use x86::state::State;
 use kvm;
 use kvm::RawFd;
+use kvm::Capability;
+use kvm::structs::msr::{MsrEntry, Msrs};
 use kvm::structs::run;
 use memmap as mm;
-use std::{mem, slice};
+use std::arch::x86_64 as arch;
+use std::{io, mem, slice};
 
 pub struct VirtualCPU<'a> {
     vm: &'a VirtualMachine<'a>,
     file: File,
     run: mm::Mmap,
     cb: &'a CpuCallbacks,
+    /// Layout of the `XSAVE` area, if it can be accessed.
+    xsave: Option<Layout>,
+    /// Whether the extended control registers can be accessed.
+    xcrs: bool,
 }
 
 impl<'a> VirtualCPU<'a> {
-    /// Initializes the virtual CPU.
-    pub fn new(vm: &'a VirtualMachine, file: File, cb: &'a CpuCallbacks) -> Result<Self> {
+    /// Initializes the virtual CPU, with the given CPUID table.
+    pub fn new(vm: &'a VirtualMachine, file: File, cpuid: &Cpuid, cb: &'a CpuCallbacks) -> Result<Self> {
         let prot = mm::Protection::ReadWrite;
         let offset = 0;
-        let len = 64;
+        // The run state is followed by other data, such as the port I/O buffers.
+        let len = vm.global().vcpu_mmap_size()?;
         let run = mm::Mmap::open_with_offset(&file, prot, offset, len)?;
 
-        let vcpu = VirtualCPU { vm, file, run, cb };
+        // KVM uses the same layout for the `XSAVE` area as the host.
+        let xsave = if vm.check_capability(Capability::Xsave)? != 0 {
+            Some(Layout::from_cpuid(|leaf, subleaf| {
+                let r = arch::__cpuid_count(leaf, subleaf);
+                [r.eax, r.ebx, r.ecx, r.edx]
+            }))
+        } else {
+            None
+        };
+
+        let xcrs = vm.check_capability(Capability::Xcrs)? != 0;
+
+        let vcpu = VirtualCPU {
+            vm,
+            file,
+            run,
+            cb,
+            xsave,
+            xcrs,
+        };
+
+        vcpu.set_cpuid(cpuid)?;
 
         Ok(vcpu)
     }
@@ -44,6 +76,37 @@ impl<'a> VirtualCPU<'a> {
         unsafe { mem::transmute(self.run.ptr()) }
     }
 
+    /// Sets the values reported by the `CPUID` instruction.
+    ///
+    /// This must be done before the vCPU runs for the first time.
+    fn set_cpuid(&self, cpuid: &Cpuid) -> Result<()> {
+        use kvm::structs::cpuid::{CpuidEntries, CpuidEntry, CpuidFlag};
+
+        let entries: Vec<_> = cpuid
+            .entries()
+            .iter()
+            .map(|entry| {
+                let mut kvm_entry = CpuidEntry::default();
+
+                kvm_entry.function = entry.function;
+                kvm_entry.r = entry.r;
+
+                if let Some(index) = entry.index {
+                    kvm_entry.index = index;
+                    kvm_entry.flags = CpuidFlag::SIGNIFICANT_INDEX;
+                }
+
+                kvm_entry
+            })
+            .collect();
+
+        let mut list = CpuidEntries::new(&entries);
+
+        unsafe { kvm::ioctl::set_cpuid2(self.fd(), list.as_mut_ptr())? };
+
+        Ok(())
+    }
+
     fn set_regs(&self, state: &State) -> Result<()> {
         let mut regs = kvm::structs::state::Registers::default();
         let r = &state.r;
@@ -102,27 +165,42 @@ impl<'a> VirtualCPU<'a> {
 
     fn set_sregs(&self, state: &State) -> Result<()> {
         use self::kvm::structs::state;
+
+        // Start from the current values, to preserve any pending interrupt.
         let mut sregs = state::SpecialRegisters::default();
+        unsafe { kvm::ioctl::get_sregs(self.fd(), &mut sregs)? };
 
         fn into(s: x86::state::Segment) -> state::Segment {
             let mut sg = state::Segment::default();
 
             sg.base = s.base;
             sg.limit = s.limit;
-            sg.present = s.present;
             sg.selector = s.selector;
+            sg.kind = (s.accessed as u8)
+                | (s.write_read as u8) << 1
+                | (s.direction_conforming as u8) << 2
+                | (s.code_data as u8) << 3;
+            sg.present = s.present;
+            sg.priv_level = s.dpl;
+            sg.default_big = s.op_size;
             sg.user_system = s.user_system;
-            sg.code_data = s.code_data;
-            sg.avl = s.available;
-            sg.direction_conforming = s.direction_conforming;
-            sg.gran = s.granularity;
             sg.long = s.long;
-            sg.priv_level = s.dpl;
-            sg.unusable = false;
+            sg.gran = s.granularity;
+            sg.avl = s.available;
+            sg.unusable = s.unusable;
 
             sg
         }
 
+        fn table(t: x86::state::DescriptorTable) -> state::DescriptorTable {
+            let mut dt = state::DescriptorTable::default();
+
+            dt.base = t.base;
+            dt.limit = t.limit;
+
+            dt
+        }
+
         sregs.cs = into(state.cs);
         sregs.ds = into(state.ds);
         sregs.ss = into(state.ss);
@@ -130,7 +208,20 @@ impl<'a> VirtualCPU<'a> {
         sregs.fs = into(state.fs);
         sregs.gs = into(state.gs);
 
-        // TODO: finish.
+        sregs.tr = into(state.tr);
+        sregs.ldt = into(state.ldt);
+
+        sregs.gdt = table(state.gdt);
+        sregs.idt = table(state.idt);
+
+        sregs.cr0 = state.cr0.bits();
+        sregs.cr2 = state.cr2;
+        sregs.cr3 = state.cr3;
+        sregs.cr4 = state.cr4.bits();
+        sregs.cr8 = state.cr8;
+
+        sregs.efer = state.efer.bits();
+        sregs.apic_base = state.apic_base;
 
         unsafe { kvm::ioctl::set_sregs(self.fd(), &mut sregs)? };
 
@@ -139,6 +230,7 @@ impl<'a> VirtualCPU<'a> {
 
     fn get_sregs(&self, state: &mut State) -> Result<()> {
         use self::kvm::structs::state;
+        use x86::state::{Cr0, Cr4, Efer};
 
         let sregs = {
             let mut sregs = state::SpecialRegisters::default();
@@ -149,21 +241,30 @@ impl<'a> VirtualCPU<'a> {
         };
 
         fn into(sg: state::Segment) -> x86::state::Segment {
-            let mut s = x86::state::Segment::default();
-
-            s.base = sg.base;
-            s.limit = sg.limit;
-            s.present = sg.present;
-            s.selector = sg.selector;
-            s.user_system = sg.user_system;
-            s.code_data = sg.code_data;
-            s.available = sg.avl;
-            s.direction_conforming = sg.direction_conforming;
-            s.granularity = sg.gran;
-            s.long = sg.long;
-            s.dpl = sg.priv_level;
-
-            s
+            x86::state::Segment {
+                base: sg.base,
+                limit: sg.limit,
+                selector: sg.selector,
+                accessed: sg.kind & (1 << 0) != 0,
+                write_read: sg.kind & (1 << 1) != 0,
+                direction_conforming: sg.kind & (1 << 2) != 0,
+                code_data: sg.kind & (1 << 3) != 0,
+                present: sg.present,
+                dpl: sg.priv_level,
+                op_size: sg.default_big,
+                user_system: sg.user_system,
+                long: sg.long,
+                granularity: sg.gran,
+                available: sg.avl,
+                unusable: sg.unusable,
+            }
+        }
+
+        fn table(dt: state::DescriptorTable) -> x86::state::DescriptorTable {
+            x86::state::DescriptorTable {
+                base: dt.base,
+                limit: dt.limit,
+            }
         }
 
         state.cs = into(sregs.cs);
@@ -173,32 +274,168 @@ impl<'a> VirtualCPU<'a> {
         state.fs = into(sregs.fs);
         state.gs = into(sregs.gs);
 
-        // TODO: finish.
+        state.tr = into(sregs.tr);
+        state.ldt = into(sregs.ldt);
+
+        state.gdt = table(sregs.gdt);
+        state.idt = table(sregs.idt);
+
+        // Keep bits which are not known to `vm-x86`, so that they can be restored.
+        unsafe {
+            state.cr0 = Cr0::from_bits_unchecked(sregs.cr0);
+            state.cr4 = Cr4::from_bits_unchecked(sregs.cr4);
+            state.efer = Efer::from_bits_unchecked(sregs.efer);
+        }
+
+        state.cr2 = sregs.cr2;
+        state.cr3 = sregs.cr3;
+        state.cr8 = sregs.cr8;
+
+        state.apic_base = sregs.apic_base;
 
         Ok(())
     }
-}
 
-impl<'a> accel::VirtualCPU<'a> for VirtualCPU<'a> {
-    fn sync(&self, state: &mut State, set: bool) -> Result<()> {
-        if set {
-            self.set_regs(state)?;
-            self.set_sregs(state)?;
-        } else {
-            self.get_regs(state)?;
-            self.get_sregs(state)?;
+    fn set_fpu(&self, state: &State) -> Result<()> {
+        let mut fpu = kvm::structs::fpu::FpuState::default();
+        let x87 = &state.fpu;
+
+        for (reg, st) in fpu.regs.iter_mut().zip(&x87.st) {
+            *reg = [st.significand, u64::from(st.sign_exponent)];
         }
 
+        fpu.control_word = x87.control.bits();
+        fpu.status_word = x87.status.bits();
+        fpu.tag_word = x87.tag;
+        fpu.last_opcode = x87.last_opcode;
+        fpu.last_ip = x87.last_ip;
+        fpu.last_dp = x87.last_dp;
+
+        // Only the lower halves of the YMM registers are transferred.
+        for (xmm, r) in fpu.xmm.iter_mut().zip(&state.sse.r) {
+            xmm.copy_from_slice(&r[..2]);
+        }
+
+        unsafe { kvm::ioctl::set_fpu(self.fd(), &mut fpu)? };
+
         Ok(())
     }
 
-    fn run(&self) -> Result<accel::ExitState> {
-        let result = unsafe { kvm::ioctl::run(self.fd(), 0)? };
+    fn get_fpu(&self, state: &mut State) -> Result<()> {
+        use x86::fpu::{ControlWord, Float80, StatusWord};
+
+        let fpu = {
+            let mut fpu = kvm::structs::fpu::FpuState::default();
+
+            unsafe { kvm::ioctl::get_fpu(self.fd(), &mut fpu)? };
+
+            fpu
+        };
+
+        let x87 = &mut state.fpu;
+
+        for (st, reg) in x87.st.iter_mut().zip(&fpu.regs) {
+            *st = Float80 {
+                significand: reg[0],
+                sign_exponent: reg[1] as u16,
+            };
+        }
+
+        // The control word has reserved bits which are usually set.
+        unsafe {
+            x87.control = ControlWord::from_bits_unchecked(fpu.control_word);
+        }
+
+        x87.status = StatusWord::from_bits_truncate(fpu.status_word);
+        x87.tag = fpu.tag_word;
+        x87.last_opcode = fpu.last_opcode;
+        x87.last_ip = fpu.last_ip;
+        x87.last_dp = fpu.last_dp;
+
+        // KVM does not transfer MXCSR through these ioctls, only the XMM registers.
+        for (r, xmm) in state.sse.r.iter_mut().zip(&fpu.xmm) {
+            r[..2].copy_from_slice(xmm);
+        }
+
+        Ok(())
+    }
+
+    fn set_xsave(&self, state: &State, layout: &Layout) -> Result<()> {
+        let mut xsave = kvm::structs::fpu::Xsave::default();
+
+        // Start from the current area, to preserve the components we do not know about.
+        unsafe { kvm::ioctl::get_xsave(self.fd(), &mut xsave)? };
+
+        layout.pack(state, xsave.bytes_mut());
+
+        unsafe { kvm::ioctl::set_xsave(self.fd(), &mut xsave)? };
+
+        Ok(())
+    }
+
+    fn get_xsave(&self, state: &mut State, layout: &Layout) -> Result<()> {
+        let mut xsave = kvm::structs::fpu::Xsave::default();
+
+        unsafe { kvm::ioctl::get_xsave(self.fd(), &mut xsave)? };
+
+        layout.unpack(xsave.bytes(), state);
+
+        Ok(())
+    }
+
+    fn set_xcrs(&self, state: &State) -> Result<()> {
+        let mut xcrs = kvm::structs::fpu::Xcrs::default();
+
+        xcrs.count = 1;
+        xcrs.xcrs[0] = kvm::structs::fpu::Xcr::new(0, state.xcr0.bits());
+
+        unsafe { kvm::ioctl::set_xcrs(self.fd(), &mut xcrs)? };
+
+        Ok(())
+    }
+
+    fn get_xcrs(&self, state: &mut State) -> Result<()> {
+        use x86::state::Xcr0;
+
+        let mut xcrs = kvm::structs::fpu::Xcrs::default();
+
+        unsafe { kvm::ioctl::get_xcrs(self.fd(), &mut xcrs)? };
+
+        let count = xcrs.count as usize;
+
+        if let Some(xcr0) = xcrs.xcrs[..count].iter().find(|xcr| xcr.xcr == 0) {
+            unsafe {
+                state.xcr0 = Xcr0::from_bits_unchecked(xcr0.value);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reports an MSR which KVM failed to access.
+    fn msr_failed(&self, msr: &accel::arch::Msr, write: bool) -> Result<()> {
+        if !self.vm.global().msr_index_list()?.contains(&msr.index) {
+            bail!("MSR {:#x} is not supported", msr.index);
+        }
+
+        if write {
+            bail!("failed to write {:#x} to MSR {:#x}", msr.value, msr.index)
+        } else {
+            bail!("failed to read MSR {:#x}", msr.index)
+        }
+    }
+
+    /// Enters the guest once, and handles the resulting exit.
+    ///
+    /// Returns `None` if the exit was handled and the guest can be re-entered.
+    fn run_once(&self) -> Result<Option<accel::ExitState>> {
+        if let Err(error) = unsafe { kvm::ioctl::run(self.fd(), 0) } {
+            // A pending signal interrupted the vCPU.
+            if error.kind() == io::ErrorKind::Interrupted {
+                return Ok(Some(accel::ExitState::Interrupted));
+            }
 
-        // Compare with EINTR.
-        if result == 4 {
-            // TODO: handle pending unmasked signal.
-            unimplemented!("Received interrupt while running");
+            return Err(error.into());
         }
 
         let run = self.run_state();
@@ -207,55 +444,784 @@ impl<'a> accel::VirtualCPU<'a> for VirtualCPU<'a> {
         use accel::ExitState as ES;
         let state = match run.exit_reason {
             ER::Io => {
-                let io = unsafe { &run.exit.io };
+                let io = unsafe { run.exit.io };
 
-                let len = io.count as usize;
                 let element_size = io.size as usize;
-                let buf_size = len * element_size;
+                let buf_size = io.count as usize * element_size;
 
                 let port = io.port;
                 let output = io.direction;
 
-                // The buffer must be `mmap`ed.
-                let mut mmap = {
-                    let prot = mm::Protection::ReadWrite;
-                    let offset = io.data_offset as usize;
-                    let len = buf_size;
-                    mm::Mmap::open_with_offset(&self.file, prot, offset, len)?
-                };
-
+                // The buffer is stored in the `mmap`ed region, after the run state.
                 let buffer = unsafe {
-                    let ptr = mmap.mut_ptr();
-                    let len = len;
-                    slice::from_raw_parts_mut(ptr, len)
+                    let ptr = self.run.ptr().offset(io.data_offset as isize) as *mut u8;
+                    slice::from_raw_parts_mut(ptr, buf_size)
                 };
 
                 self.cb.port_io(port, output, buffer, element_size)?;
 
-                ES::Shutdown
+                None
+            }
+            ER::Mmio => {
+                let mmio = unsafe { &mut run.exit.mmio };
+
+                let addr = mmio.phys_addr;
+                let is_write = mmio.is_write();
+                let len = mmio.len as usize;
+
+                let data = &mut mmio.data[..len];
+
+                if !self.vm.ioapic_mmio(addr, is_write, data)? {
+                    self.cb.mmio(addr, is_write, data)?;
+                }
+
+                None
+            }
+            ER::IoapicEoi => {
+                let eoi = unsafe { run.exit.eoi };
+                self.vm.ioapic_eoi(eoi.vector)?;
+                None
+            }
+            ER::IrqWindowOpen => Some(ES::InterruptWindow),
+            ER::DirtyRingFull => {
+                self.vm.harvest_dirty_rings()?;
+                None
+            }
+            ER::Hlt => Some(ES::Halt),
+            ER::Shutdown => Some(ES::Shutdown),
+            ER::Interrupt => Some(ES::Interrupted),
+            ER::Debug => {
+                let debug = unsafe { run.exit.debug };
+
+                Some(ES::Debug(accel::arch::DebugExit {
+                    exception: debug.exception,
+                    pc: debug.pc,
+                    dr6: debug.dr6,
+                    dr7: debug.dr7,
+                }))
+            }
+            ER::SystemEvent => {
+                let event = unsafe { run.exit.system_event };
+
+                use self::run::SystemEventType as SET;
+                use accel::SystemEventKind as SEK;
+                let kind = match event.kind() {
+                    SET::Shutdown => return Ok(Some(ES::Shutdown)),
+                    SET::Reset => return Ok(Some(ES::Reset)),
+                    SET::Crash => SEK::Crash,
+                    SET::Wakeup => SEK::Wakeup,
+                    SET::Suspend => SEK::Suspend,
+                    SET::Unknown(kind) => SEK::Unknown(kind),
+                };
+
+                Some(ES::SystemEvent(accel::SystemEvent {
+                    kind,
+                    flags: event.flags,
+                }))
             }
             ER::Unknown => {
                 let hw_exit_reason = unsafe { run.exit.unknown };
-                ES::Unknown(hw_exit_reason)
+                Some(ES::Unknown(hw_exit_reason))
             }
             ER::FailEntry => {
-                let exit_reason = unsafe { run.exit.fail_entry };
-                panic!(
-                    "vCPU failed to enter: {:?} ({})",
-                    exit_reason,
-                    exit_reason as u32
-                );
+                let fail_entry = unsafe { run.exit.fail_entry };
+                Some(ES::FailEntry(fail_entry.hardware_entry_failure_reason))
             }
             ER::InternalError => {
                 let suberror = unsafe { run.exit.internal };
-                panic!("Internal KVM error: {:?} ({})", suberror, suberror as u32)
+                bail!("Internal KVM error: {:?} ({})", suberror, suberror as u32)
             }
             _ => {
                 let er = run.exit_reason;
-                panic!("Unknown KVM exit reason: {:?} ({})", er, er as u32)
+                bail!("Unknown KVM exit reason: {:?} ({})", er, er as u32)
             }
         };
 
         Ok(state)
     }
 }
+
+impl<'a> accel::VirtualCPU<'a> for VirtualCPU<'a> {
+    fn sync(&self, state: &mut State, set: bool) -> Result<()> {
+        if set {
+            self.set_regs(state)?;
+            self.set_sregs(state)?;
+
+            // XCR0 determines which components of the XSAVE area can be set.
+            if self.xcrs {
+                self.set_xcrs(state)?;
+            }
+
+            match self.xsave {
+                Some(ref layout) => self.set_xsave(state, layout)?,
+                None => self.set_fpu(state)?,
+            }
+        } else {
+            self.get_regs(state)?;
+            self.get_sregs(state)?;
+
+            if self.xcrs {
+                self.get_xcrs(state)?;
+            }
+
+            match self.xsave {
+                Some(ref layout) => self.get_xsave(state, layout)?,
+                None => self.get_fpu(state)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn get_msrs(&self, msrs: &mut [accel::arch::Msr]) -> Result<()> {
+        let entries: Vec<_> = msrs.iter().map(|msr| MsrEntry::new(msr.index, 0)).collect();
+        let mut list = Msrs::new(&entries);
+
+        // KVM stops at the first MSR which cannot be read.
+        let count = unsafe { kvm::ioctl::get_msrs(self.fd(), list.as_mut_ptr())? } as usize;
+
+        if count < msrs.len() {
+            return self.msr_failed(&msrs[count], false);
+        }
+
+        for (msr, entry) in msrs.iter_mut().zip(list.entries()) {
+            msr.value = entry.data;
+        }
+
+        Ok(())
+    }
+
+    fn set_msrs(&self, msrs: &[accel::arch::Msr]) -> Result<()> {
+        let entries: Vec<_> = msrs.iter().map(|msr| MsrEntry::new(msr.index, msr.value)).collect();
+        let mut list = Msrs::new(&entries);
+
+        let count = unsafe { kvm::ioctl::set_msrs(self.fd(), list.as_mut_ptr())? } as usize;
+
+        if count < msrs.len() {
+            return self.msr_failed(&msrs[count], true);
+        }
+
+        Ok(())
+    }
+
+    fn get_lapic(&self) -> Result<LapicState> {
+        let mut lapic = kvm::structs::irq::LapicState::default();
+
+        unsafe { kvm::ioctl::get_lapic(self.fd(), &mut lapic)? };
+
+        Ok(LapicState::from_bytes(&lapic.regs))
+    }
+
+    fn set_lapic(&self, state: &LapicState) -> Result<()> {
+        let mut lapic = kvm::structs::irq::LapicState::default();
+        lapic.regs = *state.as_bytes();
+
+        unsafe { kvm::ioctl::set_lapic(self.fd(), &mut lapic)? };
+
+        Ok(())
+    }
+
+    fn can_inject_interrupt(&self) -> bool {
+        let run = self.run_state();
+        run.ready_for_interrupt_injection && run.if_flag != 0
+    }
+
+    fn inject_interrupt(&self, vector: u8) -> Result<()> {
+        use kvm::structs::irq::Interrupt;
+
+        if self.vm.irqchip() == accel::IrqChipMode::Kernel {
+            bail!("interrupts can only be injected if the PICs are emulated in user space");
+        }
+
+        let mut interrupt = Interrupt { irq: u32::from(vector) };
+
+        unsafe { kvm::ioctl::interrupt(self.fd(), &mut interrupt)? };
+
+        Ok(())
+    }
+
+    fn request_interrupt_window(&self, request: bool) {
+        use std::sync::atomic::Ordering;
+
+        self.run_state().request_interrupt_window.store(request, Ordering::Relaxed);
+    }
+
+    fn run(&self) -> Result<accel::ExitState> {
+        loop {
+            if let Some(state) = self.run_once()? {
+                return Ok(state);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use accel::{Accelerator, IrqChipMode};
+    use accel::arch::IrqChip;
+    use global::Global;
+    use test_util::{test_guest, test_vm, Callbacks, INTERRUPT_CODE, INTERRUPT_COUNTER};
+    use x86::cpuid::Topology;
+    use x86::fpu::{ControlWord, Float80, StatusWord};
+    use x86::irqchip::{RedirectionEntry, TriggerMode};
+    use x86::msr::{self, MSRState, Msr};
+    use x86::state::{Cr0, Cr4, DescriptorTable, Efer, Segment, State, Xcr0};
+
+    #[test]
+    fn run_handles_io_exits() {
+        let code: &[u8] = &[
+            // mov al, 0x7F
+            0xB0, 0x7F,
+            // out 0x10, al
+            0xE6, 0x10,
+            // mov byte [0xF010], 0x42
+            0xC6, 0x06, 0x10, 0xF0, 0x42,
+            // out 0xF4, al
+            0xE6, 0xF4,
+        ];
+
+        let (vm, vcpu) = test_vm(IrqChipMode::Kernel, code);
+
+        assert!(vcpu.run().is_err());
+
+        assert_eq!(vm.cb.port_value.get(), 0x7F);
+        assert_eq!(vm.cb.mmio_addr.get(), 0xF010);
+        assert_eq!(vm.cb.mmio_value.get(), 0x42);
+    }
+
+    #[test]
+    fn io_events() {
+        let code: &[u8] = &[
+            // mov al, 0x7F; out 0x10, al
+            0xB0, 0x7F, 0xE6, 0x10,
+            // mov al, 0x20; out 0x10, al
+            0xB0, 0x20, 0xE6, 0x10,
+            // mov byte [0xF010], 0x42
+            0xC6, 0x06, 0x10, 0xF0, 0x42,
+            // out 0xF4, al
+            0xE6, 0xF4,
+        ];
+
+        let (vm, vcpu) = test_vm(IrqChipMode::Kernel, code);
+
+        let port = accel::IoEvent {
+            address: accel::IoAddress::Port(0x10),
+            len: 1,
+            datamatch: Some(0x7F),
+        };
+        let port_event = accel::EventFd::new().unwrap();
+        vm.register_ioevent(port, &port_event).unwrap();
+
+        let mmio = accel::IoEvent {
+            address: accel::IoAddress::Memory(0xF010),
+            len: 1,
+            datamatch: None,
+        };
+        let mmio_event = accel::EventFd::new().unwrap();
+        vm.register_ioevent(mmio, &mmio_event).unwrap();
+
+        assert!(vcpu.run().is_err());
+
+        // Only the write which did not match went through the callbacks.
+        assert_eq!(port_event.read().unwrap(), 1);
+        assert_eq!(vm.cb.port_value.get(), 0x20);
+
+        assert_eq!(mmio_event.read().unwrap(), 1);
+        assert_eq!(vm.cb.mmio_addr.get(), 0);
+
+        vm.unregister_ioevent(port, &port_event).unwrap();
+        vm.unregister_ioevent(mmio, &mmio_event).unwrap();
+    }
+
+    #[test]
+    fn user_irqchip() {
+        // Number of interrupts received by the guest.
+        let counter = 0x500;
+
+        let code: &[u8] = &[
+            // cli
+            0xFA,
+            // xor ax, ax; mov ds, ax; mov ss, ax; mov sp, 0x8000
+            0x31, 0xC0, 0x8E, 0xD8, 0x8E, 0xD0, 0xBC, 0x00, 0x80,
+            // Install the handler for vector 0x30.
+            // mov word [0xC0], 0x101B; mov word [0xC2], 0
+            0xC7, 0x06, 0xC0, 0x00, 0x1B, 0x10, 0xC7, 0x06, 0xC2, 0x00, 0x00, 0x00,
+            // out 0xF4, al
+            0xE6, 0xF4,
+            // sti; jmp $
+            0xFB, 0xEB, 0xFE,
+            // Handler for vector 0x30:
+            // inc byte [0x500]; out 0xF4, al; iret
+            0xFE, 0x06, 0x00, 0x05, 0xE6, 0xF4, 0xCF,
+        ];
+
+        let (vm, vcpu) = test_vm(IrqChipMode::User, code);
+
+        // There is no in-kernel interrupt controller to send interrupts to.
+        assert!(vm.set_irq_line(5, true).is_err());
+        assert!(vm.get_ioapic_state().is_err());
+
+        // The guest is ready, with interrupts disabled.
+        assert!(vcpu.run().is_err());
+        assert!(!vcpu.can_inject_interrupt());
+
+        vcpu.request_interrupt_window(true);
+
+        match vcpu.run().unwrap() {
+            accel::ExitState::InterruptWindow => (),
+            state => panic!("Unexpected exit state: {:?}", state),
+        }
+
+        assert!(vcpu.can_inject_interrupt());
+        assert_eq!(vm.memory.read_obj::<u8>(counter).unwrap(), 0);
+
+        vcpu.request_interrupt_window(false);
+        vcpu.inject_interrupt(0x30).unwrap();
+
+        assert!(vcpu.run().is_err());
+        assert_eq!(vm.memory.read_obj::<u8>(counter).unwrap(), 1);
+    }
+
+    #[test]
+    fn dirty_log() {
+        let code: &[u8] = &[
+            // mov word [0x0FFF], 0x1234
+            0xC7, 0x06, 0xFF, 0x0F, 0x34, 0x12,
+            // mov byte [0x3010], 1
+            0xC6, 0x06, 0x10, 0x30, 0x01,
+            // out 0xF4, al
+            0xE6, 0xF4,
+        ];
+
+        let (mut vm, vcpu) = test_vm(IrqChipMode::Kernel, code);
+        vm.memory.set_dirty_logging(vm.inner, 0, true).unwrap();
+
+        assert!(vcpu.run().is_err());
+
+        let bitmaps = vm.memory.dirty_log(&*vm).unwrap();
+        let ranges: Vec<_> = bitmaps[0].ranges().map(|r| (r.guest, r.size)).collect();
+        assert_eq!(ranges, [(0, 0x2000), (0x3000, 0x1000)]);
+        assert_eq!(vm.memory.read_obj::<u16>(0x0FFF).unwrap(), 0x1234);
+
+        assert_eq!(vm.memory.dirty_log(&*vm).unwrap()[0].dirty_pages(), 0);
+    }
+
+    #[test]
+    fn dirty_ring() {
+        let code: &[u8] = &[
+            // mov word [0x0FFF], 0x1234
+            0xC7, 0x06, 0xFF, 0x0F, 0x34, 0x12,
+            // mov byte [0x3010], 1
+            0xC6, 0x06, 0x10, 0x30, 0x01,
+            // out 0xF4, al
+            0xE6, 0xF4,
+        ];
+
+        // The ring must be enabled before the vCPU is created.
+        let mut vm = test_guest(IrqChipMode::Kernel, code);
+
+        if vm.enable_dirty_ring(512).is_err() {
+            // Dirty rings are not supported by this host.
+            return;
+        }
+
+        vm.memory.set_dirty_logging(vm.inner, 0, true).unwrap();
+
+        let vcpu = vm.create_vcpu(0, None, vm.cb).unwrap();
+        assert!(vcpu.run().is_err());
+
+        let bitmaps = vm.memory.dirty_log(&*vm).unwrap();
+        let ranges: Vec<_> = bitmaps[0].ranges().map(|r| (r.guest, r.size)).collect();
+        assert_eq!(ranges, [(0, 0x2000), (0x3000, 0x1000)]);
+
+        assert_eq!(vm.memory.dirty_log(&*vm).unwrap()[0].dirty_pages(), 0);
+    }
+
+    /// Sets the vCPU's state, and checks that it is read back unchanged.
+    fn check_round_trip(vcpu: &accel::VirtualCPU, state: &State) {
+        vcpu.sync(&mut state.clone(), true).unwrap();
+
+        let mut synced = State::default();
+        vcpu.sync(&mut synced, false).unwrap();
+
+        assert_eq!(synced.r, state.r);
+        assert_eq!(synced.ip, state.ip);
+        assert_eq!(synced.flags, state.flags);
+
+        let segments = |s: &State| [s.cs, s.ds, s.ss, s.es, s.fs, s.gs, s.tr, s.ldt];
+        assert_eq!(segments(&synced), segments(state));
+
+        assert_eq!((synced.gdt, synced.idt), (state.gdt, state.idt));
+        assert_eq!((synced.cr0, synced.cr2, synced.cr3), (state.cr0, state.cr2, state.cr3));
+        assert_eq!((synced.cr4, synced.cr8), (state.cr4, state.cr8));
+        assert_eq!((synced.efer, synced.apic_base), (state.efer, state.apic_base));
+
+        assert_eq!(synced.fpu, state.fpu);
+        assert_eq!(synced.sse, state.sse);
+        assert_eq!(synced.avx512, state.avx512);
+        assert_eq!((synced.xcr0, synced.pkru), (state.xcr0, state.pkru));
+    }
+
+    #[test]
+    fn sync_round_trip() {
+        let (vm, vcpu) = test_vm(IrqChipMode::Kernel, &[]);
+
+        // Real mode, as after reset.
+        let mut state = State::default();
+        state.r[0] = 0x1234;
+        check_round_trip(&*vcpu, &state);
+
+        // Some floating-point state: 1.0 and -2.0 at the top of the stack.
+        state.fpu.st[0] = Float80 { significand: 1 << 63, sign_exponent: 0x3FFF };
+        state.fpu.st[1] = Float80 { significand: 1 << 63, sign_exponent: 0xC000 };
+        state.fpu.control = ControlWord::from_bits_truncate(0x37F);
+        state.fpu.status = StatusWord::C1 | StatusWord::from_bits_truncate(6 << 11);
+        state.fpu.tag = 0b1100_0000;
+        state.fpu.last_opcode = 0x1E8;
+        state.fpu.last_ip = 0x1000;
+        state.fpu.last_dp = 0x2000;
+        state.fpu.mxcsr = 0x1F80 | 1 << 15;
+        state.sse.r[3] = [0x0123_4567, 0x89AB_CDEF, 0, 0];
+        state.sse.r[15] = [!0, 1, 0, 0];
+        check_round_trip(&*vcpu, &state);
+
+        // All of the extended state components supported by the host.
+        let supported = vm.supported_cpuid().unwrap().get(0xD, 0).map_or(0, |entry| entry.r[0]);
+        state.xcr0 = Xcr0::from_bits_truncate(u64::from(supported));
+
+        if state.xcr0.contains(Xcr0::AVX) {
+            state.sse.r[3][2..].copy_from_slice(&[0xAAAA_0000, 0x5555]);
+        }
+
+        if state.xcr0.contains(Xcr0::OPMASK | Xcr0::ZMM_HI256 | Xcr0::HI16_ZMM) {
+            state.avx512.k[1] = 0xF0F0;
+            state.avx512.zmm_hi256[2] = [1, 2, 3, 4];
+            state.avx512.hi16_zmm[15] = [!0; 8];
+        }
+
+        if state.xcr0.contains(Xcr0::PKRU) {
+            state.pkru = 0x5555_5550;
+        }
+
+        check_round_trip(&*vcpu, &state);
+
+        // Protected mode, with flat segments.
+        let code = Segment {
+            base: 0,
+            limit: 0xFFFF_FFFF,
+            selector: 0x08,
+            present: true,
+            user_system: true,
+            code_data: true,
+            write_read: true,
+            accessed: true,
+            op_size: true,
+            granularity: true,
+            ..Segment::default()
+        };
+
+        let data = Segment {
+            selector: 0x10,
+            code_data: false,
+            ..code
+        };
+
+        state.cr0 = Cr0::PROTECTED_MODE | Cr0::EXTENSION_TYPE;
+        state.cs = code;
+        state.ds = data;
+        state.ss = data;
+        state.es = data;
+        state.fs = Segment {
+            base: 0x8000,
+            ..data
+        };
+        state.gs = Segment {
+            selector: 0,
+            present: false,
+            unusable: true,
+            ..data
+        };
+        // Busy 32-bit TSS.
+        state.tr = Segment {
+            base: 0x3000,
+            limit: 0x67,
+            selector: 0x18,
+            user_system: false,
+            op_size: false,
+            granularity: false,
+            ..code
+        };
+        state.gdt = DescriptorTable { base: 0x1000, limit: 0x1F };
+        state.idt = DescriptorTable { base: 0x2000, limit: 0x7FF };
+        state.ip = 0x10_0000;
+        check_round_trip(&*vcpu, &state);
+
+        // Long mode, with paging.
+        state.cs = Segment {
+            long: true,
+            op_size: false,
+            ..code
+        };
+        state.cr0 |= Cr0::PAGING;
+        state.cr3 = 0x4000;
+        state.cr4 = Cr4::PHYSICAL_ADDRESS_EXTENSION;
+        state.efer = Efer::LM_ENABLE | Efer::LM_ACTIVE;
+        state.r[8] = 0xDEAD_BEEF_0000;
+        check_round_trip(&*vcpu, &state);
+    }
+
+    #[test]
+    fn msr_access() {
+        let global = Global::new().unwrap();
+
+        let supported = global.msr_index_list().unwrap();
+        assert!(supported.contains(&msr::LSTAR));
+        assert!(supported.contains(&msr::PAT));
+
+        let (_vm, vcpu) = test_vm(IrqChipMode::Kernel, &[]);
+
+        let mut state = MSRState::default();
+        state.sysenter_cs = 0x10;
+        state.star = 0x0023_0010_0000_0000;
+        state.lstar = 0xFFFF_FFFF_8100_0000;
+        state.cstar = 0xFFFF_FFFF_8100_1000;
+        state.fmask = 0x4_7700;
+        state.kernel_gs_base = 0xFFFF_8880_0000_0000;
+        state.pat = 0x0007_0406_0007_0401;
+        state.mtrr.def_type = 0xC06;
+        state.mtrr.fixed[0] = 0x0606_0606_0606_0606;
+        state.mtrr.variable[0] = (0x8000_0000, 0xF_8000_0800);
+
+        vcpu.set_msrs(&state.to_msrs()).unwrap();
+
+        let mut msrs: Vec<_> = MSRState::indices().into_iter().map(|index| Msr::new(index, 0)).collect();
+        vcpu.get_msrs(&mut msrs).unwrap();
+
+        let mut synced = MSRState::default();
+        synced.update(&msrs).unwrap();
+
+        // The time-stamp counter keeps running.
+        state.tsc = synced.tsc;
+        assert_eq!(synced, state);
+
+        let mut msrs = [Msr::new(msr::EFER, 0), Msr::new(0xDEAD_0000, 0)];
+        assert!(vcpu.get_msrs(&mut msrs).is_err());
+        assert!(vcpu.set_msrs(&[Msr::new(msr::LSTAR, 0), Msr::new(0xDEAD_0000, 0)]).is_err());
+    }
+
+    #[test]
+    fn cpuid_table() {
+        let code: &[u8] = &[
+            // mov eax, 0x80000003
+            0x66, 0xB8, 0x03, 0x00, 0x00, 0x80,
+            // cpuid
+            0x0F, 0xA2,
+            // out 0xF4, al
+            0xE6, 0xF4,
+        ];
+
+        let vm = test_guest(IrqChipMode::Kernel, code);
+
+        let mut cpuid = vm.supported_cpuid().unwrap();
+        assert!(cpuid.get(0, 0).is_some());
+
+        // Characters 16 to 31 of the brand string.
+        cpuid.set_brand("vm-rs virtual CPU, with a custom brand");
+
+        let vcpu = vm.create_vcpu(0, Some(&cpuid.for_vcpu(0, &Topology::default())), vm.cb).unwrap();
+
+        assert!(vcpu.run().is_err());
+
+        let mut state = State::default();
+        vcpu.sync(&mut state, false).unwrap();
+
+        let registers = [state.r[0], state.r[3], state.r[1], state.r[2]];
+        let bytes: Vec<u8> = registers.iter().flat_map(|&r| (r as u32).to_le_bytes().to_vec()).collect();
+        assert_eq!(bytes, b"U, with a custom");
+    }
+
+    #[test]
+    fn cpu_model() {
+        use x86::cpuid::models::{CpuModel, MODELS};
+
+        let code: &[u8] = &[
+            // mov eax, 1
+            0x66, 0xB8, 0x01, 0x00, 0x00, 0x00,
+            // cpuid
+            0x0F, 0xA2,
+            // out 0xF4, al
+            0xE6, 0xF4,
+        ];
+
+        let vm = test_guest(IrqChipMode::Kernel, code);
+
+        let supported = vm.supported_cpuid().unwrap();
+
+        // Whether the models are supported depends on the host.
+        for model in MODELS {
+            let missing = model.missing_features(&supported);
+            assert_eq!(model.cpuid(&supported).err().unwrap_or_default(), missing);
+        }
+
+        let model = CpuModel {
+            name: "test",
+            vendor: "GenuineIntel",
+            brand: "Test CPU",
+            family: 6,
+            model: 26,
+            stepping: 3,
+            features: &[&["fpu", "tsc", "msr", "pae", "cx8", "apic", "cx16", "x2apic"]],
+        };
+
+        let cpuid = model.cpuid(&supported).unwrap();
+
+        // Only the features of the model, and the hypervisor bit.
+        assert_eq!(cpuid.get(1, 0).unwrap().r[2], 0x8020_2000);
+        assert_eq!(cpuid.get(1, 0).unwrap().r[3], 0x371);
+        assert_eq!(cpuid.get(7, 0).unwrap().r[1], 0);
+
+        let vcpu = vm.create_vcpu(0, Some(&cpuid.for_vcpu(0, &Topology::default())), vm.cb).unwrap();
+
+        assert!(vcpu.run().is_err());
+
+        let mut state = State::default();
+        vcpu.sync(&mut state, false).unwrap();
+
+        assert_eq!(state.r[0] as u32, 0x106A3);
+    }
+
+    #[test]
+    fn interrupt_injection() {
+        let (vm, vcpu) = test_vm(IrqChipMode::Kernel, INTERRUPT_CODE);
+        let interrupts = || vm.memory.read_obj::<u8>(INTERRUPT_COUNTER).unwrap();
+
+        // The guest is ready.
+        assert!(vcpu.run().is_err());
+        assert_eq!(interrupts(), 0);
+
+        // The line is edge-triggered, so each pulse raises one interrupt.
+        for count in 1..3 {
+            vm.set_irq_line(5, true).unwrap();
+            vm.set_irq_line(5, false).unwrap();
+
+            assert!(vcpu.run().is_err());
+            assert_eq!(interrupts(), count);
+        }
+    }
+
+    #[test]
+    fn split_irqchip() {
+        // Number of interrupts received by the guest.
+        let counter = 0x500;
+
+        let code: &[u8] = &[
+            // cli
+            0xFA,
+            // xor ax, ax; mov ds, ax; mov ss, ax; mov sp, 0x8000
+            0x31, 0xC0, 0x8E, 0xD8, 0x8E, 0xD0, 0xBC, 0x00, 0x80,
+            // Install the handler for vectors 0x40 and 0x41 in the interrupt vector table.
+            // mov word [0x100], 0x1049; mov word [0x102], 0
+            0xC7, 0x06, 0x00, 0x01, 0x49, 0x10, 0xC7, 0x06, 0x02, 0x01, 0x00, 0x00,
+            // mov word [0x104], 0x1049; mov word [0x106], 0
+            0xC7, 0x06, 0x04, 0x01, 0x49, 0x10, 0xC7, 0x06, 0x06, 0x01, 0x00, 0x00,
+            // Software-enable the local APIC, through the x2APIC MSRs.
+            // mov ecx, 0x1B; rdmsr; or ax, 0xC00; wrmsr
+            0x66, 0xB9, 0x1B, 0x00, 0x00, 0x00, 0x0F, 0x32, 0x0D, 0x00, 0x0C, 0x0F, 0x30,
+            // mov ecx, 0x80F; mov eax, 0x1FF; xor edx, edx; wrmsr
+            0x66, 0xB9, 0x0F, 0x08, 0x00, 0x00, 0x66, 0xB8, 0xFF, 0x01, 0x00, 0x00,
+            0x66, 0x31, 0xD2, 0x0F, 0x30,
+            // out 0xF4, al
+            0xE6, 0xF4,
+            // Wait for an interrupt, then exit.
+            // sti; hlt; cli; out 0xF4, al; jmp -7
+            0xFB, 0xF4, 0xFA, 0xE6, 0xF4, 0xEB, 0xF9,
+            // Handler for vectors 0x40 and 0x41:
+            // inc byte [0x500]
+            0xFE, 0x06, 0x00, 0x05,
+            // mov ecx, 0x80B; xor eax, eax; xor edx, edx; wrmsr; iret
+            0x66, 0xB9, 0x0B, 0x08, 0x00, 0x00, 0x66, 0x31, 0xC0, 0x66, 0x31, 0xD2, 0x0F, 0x30, 0xCF,
+        ];
+
+        let (vm, vcpu) = test_vm(IrqChipMode::Split, code);
+
+        // The guest is ready.
+        assert!(vcpu.run().is_err());
+        assert_eq!(vm.memory.read_obj::<u8>(counter).unwrap(), 0);
+
+        // Only the local APICs are emulated by KVM.
+        assert!(vm.get_pic_state(IrqChip::PicMaster).is_err());
+
+        // Pin 5 is edge-triggered with vector 0x40,
+        // and pin 6 is level-triggered with vector 0x41.
+        let mut ioapic = vm.get_ioapic_state().unwrap();
+        assert_eq!(ioapic.base_address, 0xFEC0_0000);
+        assert!(ioapic.redirection[5].masked);
+
+        ioapic.redirection[5] = RedirectionEntry { vector: 0x40, ..Default::default() };
+        ioapic.redirection[6] = RedirectionEntry {
+            vector: 0x41,
+            trigger_mode: TriggerMode::Level,
+            ..Default::default()
+        };
+        vm.set_ioapic_state(&ioapic).unwrap();
+
+        vm.set_irq_line(5, true).unwrap();
+        vm.set_irq_line(5, false).unwrap();
+
+        assert!(vcpu.run().is_err());
+        assert_eq!(vm.memory.read_obj::<u8>(counter).unwrap(), 1);
+
+        // The interrupt of a level-triggered pin is redelivered while the pin is asserted.
+        vm.set_irq_line(6, true).unwrap();
+
+        for count in 2..4 {
+            assert!(vcpu.run().is_err());
+            assert_eq!(vm.memory.read_obj::<u8>(counter).unwrap(), count);
+            assert!(vm.get_ioapic_state().unwrap().redirection[6].remote_irr);
+        }
+
+        // KVM reports the end-of-interrupt when the guest is entered again.
+        vm.set_irq_line(6, false).unwrap();
+        vm.set_irq_line(5, true).unwrap();
+        vm.set_irq_line(5, false).unwrap();
+
+        assert!(vcpu.run().is_err());
+        assert_eq!(vm.memory.read_obj::<u8>(counter).unwrap(), 4);
+        assert!(!vm.get_ioapic_state().unwrap().redirection[6].remote_irr);
+    }
+
+    #[test]
+    fn x2apic_ids() {
+        use kvm::Capability;
+        use x86::apic;
+
+        let global = Global::new().unwrap();
+
+        // Without the x2APIC API, which needs an in-kernel local APIC, APIC IDs are 8-bit.
+        let vm = global.create_vm(IrqChipMode::User).unwrap();
+        let cb = Callbacks::default();
+
+        assert!(vm.max_vcpu_ids().unwrap() <= 255);
+        assert!(vm.create_vcpu(300, None, &cb).is_err());
+
+        if global.check_capability(Capability::X2ApicApi).unwrap_or(0) == 0 {
+            return;
+        }
+
+        // The in-kernel I/O APIC keeps 0xFF as a broadcast, but only to 8-bit APIC IDs.
+        let vm = global.create_vm(IrqChipMode::Kernel).unwrap();
+        assert!(vm.max_vcpu_ids().unwrap() > 300);
+        assert!(vm.create_vcpu(300, None, &cb).is_ok());
+
+        let vm = global.create_vm(IrqChipMode::Split).unwrap();
+        assert!(vm.max_vcpu_ids().unwrap() > 300);
+
+        let vcpu = vm.create_vcpu(300, None, &cb).unwrap();
+
+        // In x2APIC mode, the ID register contains the 32-bit ID.
+        let x2apic = Msr { index: msr::APIC_BASE, value: apic::DEFAULT_BASE | 0xC00 };
+        vcpu.set_msrs(&[x2apic]).unwrap();
+
+        assert_eq!(vcpu.get_lapic().unwrap().read(apic::ID), 300);
+    }
+}
@@ -1,24 +1,84 @@
 use accel;
 use accel::errors::Result;
+use dirty::DirtyRing;
 use global::Global;
+use intc::{self, IoApic};
+use std::cell::{Cell, RefCell};
 use std::fs::File;
 use kvm;
 use kvm::Capability;
+use kvm::structs::irq::{ChipId, IrqChip};
+use routing::GsiRouting;
 use vcpu::VirtualCPU;
+use x86::cpuid::{Cpuid, Topology};
+use x86::irqchip::{IoApicState, PicState, IOAPIC_PINS};
+
+/// Number of vCPUs which can be addressed by 8-bit APIC IDs,
+/// since 0xFF is the broadcast address.
+const MAX_XAPIC_VCPUS: usize = 0xFF;
 
 pub struct VirtualMachine<'a> {
     global: &'a Global,
     file: File,
+    /// Memory slots currently in use.
+    slots: RefCell<Vec<accel::MemoryRegion>>,
+    /// Set if dirty pages must be cleared explicitly.
+    manual_dirty_protect: Cell<bool>,
+    /// Set if APIC IDs are 32-bit, which allows more than 255 vCPUs.
+    x2apic_api: Cell<bool>,
+    /// Number of entries in each vCPU's dirty ring, or 0 if using dirty bitmaps.
+    dirty_ring_entries: Cell<u32>,
+    /// The dirty rings of all vCPUs.
+    dirty_rings: RefCell<Vec<DirtyRing>>,
+    /// Pages harvested from the dirty rings, which were not yet retrieved.
+    harvested: RefCell<Vec<accel::DirtyBitmap>>,
+    /// The routes of the GSIs, as last set in KVM.
+    routing: RefCell<GsiRouting>,
+    /// Which interrupt controllers are emulated by KVM.
+    irqchip: accel::IrqChipMode,
+    /// The I/O APIC, when it is emulated in user space alongside KVM's local APICs.
+    ioapic: RefCell<IoApic>,
 }
 
 impl<'a> VirtualMachine<'a> {
     /// Initializes a new virtual machine.
-    pub fn new(global: &'a Global, file: File) -> Result<Self> {
-        let vm = VirtualMachine { global, file };
+    ///
+    /// The in-kernel interrupt controllers are created in `Kernel` mode,
+    /// and only the local APICs in `Split` mode.
+    pub fn new(global: &'a Global, file: File, irqchip: accel::IrqChipMode) -> Result<Self> {
+        // Without the in-kernel I/O APIC, there are no routes to it.
+        let routing = match irqchip {
+            accel::IrqChipMode::Kernel => GsiRouting::default(),
+            _ => GsiRouting::empty(),
+        };
+
+        let vm = VirtualMachine {
+            global,
+            file,
+            slots: RefCell::new(Vec::new()),
+            manual_dirty_protect: Cell::new(false),
+            x2apic_api: Cell::new(false),
+            dirty_ring_entries: Cell::new(0),
+            dirty_rings: RefCell::new(Vec::new()),
+            harvested: RefCell::new(Vec::new()),
+            routing: RefCell::new(routing),
+            irqchip,
+            ioapic: RefCell::new(IoApic::new()),
+        };
 
         vm.check_required_capabilities()?;
+        vm.enable_manual_dirty_protect()?;
 
-        vm.create_interrupt_controller()?;
+        match irqchip {
+            accel::IrqChipMode::Kernel => vm.create_interrupt_controller()?,
+            accel::IrqChipMode::Split => vm.create_split_interrupt_controller()?,
+            accel::IrqChipMode::User => (),
+        }
+
+        // Only the in-kernel local APICs have APIC IDs.
+        if irqchip != accel::IrqChipMode::User {
+            vm.enable_x2apic_api()?;
+        }
 
         vm.set_identity_mapping()?;
         vm.set_tss_address()?;
@@ -26,6 +86,12 @@ impl<'a> VirtualMachine<'a> {
         Ok(vm)
     }
 
+    /// Retrieves the global KVM object which created this VM.
+    #[inline]
+    pub fn global(&self) -> &Global {
+        self.global
+    }
+
     /// Retrieves the raw file descriptor for this device.
     #[inline]
     fn fd(&self) -> kvm::RawFd {
@@ -36,7 +102,6 @@ impl<'a> VirtualMachine<'a> {
     /// Checks to ensure required capabilities are present.
     fn check_required_capabilities(&self) -> Result<()> {
         const REQUIRED: &[Capability] = &[
-            Capability::IrqChip,
             Capability::UserMemory,
             Capability::ReadOnlyMemory,
             Capability::SetIdentityMapAddress,
@@ -47,6 +112,36 @@ impl<'a> VirtualMachine<'a> {
             self.require_capability(cap)?;
         }
 
+        match self.irqchip {
+            accel::IrqChipMode::Kernel => self.require_capability(Capability::IrqChip)?,
+            accel::IrqChipMode::Split => self.require_capability(Capability::SplitIrqChip)?,
+            accel::IrqChipMode::User => (),
+        }
+
+        Ok(())
+    }
+
+    /// Which interrupt controllers are emulated by KVM.
+    #[inline]
+    pub fn irqchip(&self) -> accel::IrqChipMode {
+        self.irqchip
+    }
+
+    /// Ensures the local APICs are emulated by KVM.
+    fn require_lapic(&self) -> Result<()> {
+        if self.irqchip == accel::IrqChipMode::User {
+            bail!("the interrupt controllers are emulated in user space")
+        }
+
+        Ok(())
+    }
+
+    /// Ensures the PICs and the I/O APIC are emulated by KVM.
+    fn require_irqchip(&self) -> Result<()> {
+        if self.irqchip != accel::IrqChipMode::Kernel {
+            bail!("the PICs and the I/O APIC are emulated in user space")
+        }
+
         Ok(())
     }
 
@@ -69,6 +164,110 @@ impl<'a> VirtualMachine<'a> {
         }
     }
 
+    /// Enables clearing dirty pages separately from retrieving the log, if supported.
+    ///
+    /// This avoids write-protecting the whole slot every time the log is retrieved.
+    fn enable_manual_dirty_protect(&self) -> Result<()> {
+        use kvm::structs::caps::EnableCap;
+
+        const ENABLE: u64 = 1 << 0;
+
+        let flags = self.check_capability(Capability::ManualDirtyLogProtect)?;
+
+        if u64::from(flags) & ENABLE != 0 {
+            let mut cap = EnableCap::new(Capability::ManualDirtyLogProtect as u32, [ENABLE, 0, 0, 0]);
+            unsafe { kvm::ioctl::enable_cap(self.fd(), &mut cap)? };
+            self.manual_dirty_protect.set(true);
+        }
+
+        Ok(())
+    }
+
+    /// Enables 32-bit APIC IDs, if supported.
+    ///
+    /// The broadcast quirk, which makes 0xFF a broadcast to the x2APICs, is only
+    /// disabled in `Split` mode, since the in-kernel I/O APIC relies on it.
+    fn enable_x2apic_api(&self) -> Result<()> {
+        use kvm::structs::caps::EnableCap;
+
+        const USE_32BIT_IDS: u64 = 1 << 0;
+        const DISABLE_BROADCAST_QUIRK: u64 = 1 << 1;
+
+        let flags = match self.irqchip {
+            accel::IrqChipMode::Split => USE_32BIT_IDS | DISABLE_BROADCAST_QUIRK,
+            _ => USE_32BIT_IDS,
+        };
+
+        let supported = self.check_capability(Capability::X2ApicApi)?;
+
+        if u64::from(supported) & flags == flags {
+            let mut cap = EnableCap::new(Capability::X2ApicApi as u32, [flags, 0, 0, 0]);
+            unsafe { kvm::ioctl::enable_cap(self.fd(), &mut cap)? };
+            self.x2apic_api.set(true);
+        }
+
+        Ok(())
+    }
+
+    /// Whether APIC IDs are 32-bit, instead of 8-bit.
+    ///
+    /// Only then can there be more than 255 vCPUs.
+    #[inline]
+    pub fn x2apic_api(&self) -> bool {
+        self.x2apic_api.get()
+    }
+
+    /// Limits a number of vCPUs to the ones which have an APIC ID.
+    fn limit_vcpus(&self, count: usize) -> usize {
+        if self.x2apic_api() {
+            count
+        } else {
+            count.min(MAX_XAPIC_VCPUS)
+        }
+    }
+
+    /// Collects the dirty pages from the rings of all vCPUs, and allows KVM
+    /// to reuse the harvested entries.
+    pub fn harvest_dirty_rings(&self) -> Result<()> {
+        let slots = self.slots.borrow();
+        let mut harvested = self.harvested.borrow_mut();
+
+        for ring in self.dirty_rings.borrow().iter() {
+            ring.harvest(|slot, page| {
+                // Ignore pages of slots which were removed in the meantime.
+                let region = match slots.iter().find(|r| u16::from(r.slot) == slot) {
+                    Some(region) => region,
+                    None => return,
+                };
+
+                let index = match harvested.iter().position(|b| b.slot() == region.slot) {
+                    Some(index) => index,
+                    None => {
+                        let pages = region.size / accel::PAGE_SIZE;
+                        harvested.push(accel::DirtyBitmap::new(region.slot, region.guest, pages));
+                        harvested.len() - 1
+                    }
+                };
+
+                if (page as usize) < harvested[index].pages() {
+                    harvested[index].set_dirty(page as usize);
+                }
+            });
+        }
+
+        unsafe { kvm::ioctl::reset_dirty_rings(self.fd())? };
+
+        Ok(())
+    }
+
+    /// Retrieves a memory slot which is in use.
+    fn slot(&self, slot: u8) -> Result<accel::MemoryRegion> {
+        match self.slots.borrow().iter().find(|r| r.slot == slot) {
+            Some(region) => Ok(*region),
+            None => bail!("memory slot {} does not exist", slot),
+        }
+    }
+
     /// Creates an in-kernel interrupt controler model.
     ///
     /// # Architecture specific details
@@ -83,6 +282,160 @@ impl<'a> VirtualMachine<'a> {
         Ok(())
     }
 
+    /// Creates the in-kernel local APICs, without the PICs and the I/O APIC.
+    ///
+    /// KVM reserves the first GSIs for the pins of the user space I/O APIC,
+    /// and exits when the guest signals the end of their level-triggered interrupts.
+    ///
+    /// This must be done before creating any virtual CPU.
+    fn create_split_interrupt_controller(&self) -> Result<()> {
+        use kvm::structs::caps::EnableCap;
+
+        let pins = IOAPIC_PINS as u64;
+
+        let mut cap = EnableCap::new(Capability::SplitIrqChip as u32, [pins, 0, 0, 0]);
+        unsafe { kvm::ioctl::enable_cap(self.fd(), &mut cap)? };
+
+        Ok(())
+    }
+
+    /// Handles an access to the registers of the user space I/O APIC.
+    ///
+    /// Returns `false` if the address does not belong to the I/O APIC.
+    pub fn ioapic_mmio(&self, addr: u64, is_write: bool, data: &mut [u8]) -> Result<bool> {
+        if self.irqchip != accel::IrqChipMode::Split || !self.ioapic.borrow().contains(addr) {
+            return Ok(false);
+        }
+
+        let mut ioapic = self.ioapic.borrow_mut();
+        let offset = addr - ioapic.state().base_address;
+
+        // The registers are 32-bit and little-endian.
+        if is_write {
+            let value = data.iter().rev().fold(0, |value, &byte| value << 8 | u32::from(byte));
+            ioapic.write(offset, value, self)?;
+        } else {
+            let value = ioapic.read(offset);
+
+            for (i, byte) in data.iter_mut().enumerate() {
+                *byte = value.checked_shr(i as u32 * 8).unwrap_or(0) as u8;
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Signals the end of a level-triggered interrupt to the user space I/O APIC.
+    pub fn ioapic_eoi(&self, vector: u8) -> Result<()> {
+        self.ioapic.borrow_mut().eoi(vector, self)
+    }
+
+    /// Sets the level of a GSI through KVM.
+    fn irq_line(&self, gsi: u32, level: bool) -> Result<()> {
+        use kvm::structs::irq::IrqLevel;
+
+        let mut irq = IrqLevel {
+            irq: gsi,
+            level: level as u32,
+        };
+
+        unsafe { kvm::ioctl::irq_line(self.fd(), &mut irq)? };
+
+        Ok(())
+    }
+
+    /// Replaces the GSI routing table.
+    ///
+    /// The table is only kept if KVM accepts it.
+    fn set_gsi_routing(&self, routing: GsiRouting) -> Result<()> {
+        self.require_lapic()?;
+        self.require_capability(Capability::IrqRouting)?;
+
+        let mut table = routing.to_kvm();
+
+        unsafe { kvm::ioctl::set_gsi_routing(self.fd(), table.as_mut_ptr())? };
+
+        *self.routing.borrow_mut() = routing;
+
+        Ok(())
+    }
+
+    /// Retrieves the state of one of the in-kernel IRQ chips.
+    fn get_irq_chip(&self, id: ChipId) -> Result<IrqChip> {
+        self.require_irqchip()?;
+
+        let mut chip = IrqChip::new(id);
+
+        unsafe { kvm::ioctl::get_irq_chip(self.fd(), &mut chip)? };
+
+        Ok(chip)
+    }
+
+    /// Replaces the state of one of the in-kernel IRQ chips.
+    fn set_irq_chip(&self, mut chip: IrqChip) -> Result<()> {
+        self.require_irqchip()?;
+
+        unsafe { kvm::ioctl::set_irq_chip(self.fd(), &mut chip)? };
+
+        Ok(())
+    }
+
+    /// Adds or removes an ioeventfd.
+    fn set_ioeventfd(&self, io: accel::IoEvent, event: &accel::EventFd, assign: bool) -> Result<()> {
+        use kvm::structs::event::{IoEventFd, IoEventFdFlags};
+        use std::os::unix::io::AsRawFd;
+
+        self.require_capability(Capability::IoEventFd)?;
+
+        let mut ioeventfd = IoEventFd::default();
+
+        match io.address {
+            accel::IoAddress::Port(port) => {
+                ioeventfd.addr = u64::from(port);
+                ioeventfd.flags |= IoEventFdFlags::PIO;
+            }
+            accel::IoAddress::Memory(address) => ioeventfd.addr = address,
+        }
+
+        if let Some(value) = io.datamatch {
+            ioeventfd.datamatch = value;
+            ioeventfd.flags |= IoEventFdFlags::DATAMATCH;
+        }
+
+        if !assign {
+            ioeventfd.flags |= IoEventFdFlags::DEASSIGN;
+        }
+
+        ioeventfd.len = io.len;
+        ioeventfd.fd = event.as_raw_fd();
+
+        unsafe { kvm::ioctl::ioeventfd(self.fd(), &mut ioeventfd)? };
+
+        Ok(())
+    }
+
+    /// Adds or removes an irqfd.
+    fn set_irqfd(&self, event: &accel::EventFd, gsi: u32, assign: bool) -> Result<()> {
+        use kvm::structs::event::{IrqFd, IrqFdFlags};
+        use std::os::unix::io::AsRawFd;
+
+        self.require_lapic()?;
+        self.require_capability(Capability::IrqFd)?;
+
+        let mut irqfd = IrqFd::default();
+
+        irqfd.fd = event.as_raw_fd() as u32;
+        irqfd.gsi = gsi;
+
+        if !assign {
+            irqfd.flags |= IrqFdFlags::DEASSIGN;
+        }
+
+        unsafe { kvm::ioctl::irqfd(self.fd(), &mut irqfd)? };
+
+        Ok(())
+    }
+
     /// Returns the base address of the EPT.
     fn ept_address(&self) -> u64 {
         // Reserve up to 16-MiB of memory for the BIOS.
@@ -128,16 +481,21 @@ impl<'a> accel::VirtualMachine<'a> for VirtualMachine<'a> {
 
     fn max_vcpus(&self) -> Result<usize> {
         self.check_capability(Capability::MaxVCpus)
-            .map(|value| value as usize)
+            .map(|value| self.limit_vcpus(value as usize))
             .or_else(|_| self.max_recommended_vcpus())
     }
 
     fn max_vcpu_ids(&self) -> Result<usize> {
         self.check_capability(Capability::MaxVCpuId)
-            .map(|value| value as usize)
+            .map(|value| self.limit_vcpus(value as usize))
             .or_else(|_| self.max_vcpus())
     }
 
+    fn max_memory_slots(&self) -> Result<usize> {
+        self.check_capability(Capability::MaxMemSlots)
+            .map(|value| value as usize)
+    }
+
     fn allocate_memory(&self, memory: accel::MemoryRegion) -> Result<()> {
         use kvm::structs::mem;
 
@@ -146,21 +504,292 @@ impl<'a> accel::VirtualMachine<'a> for VirtualMachine<'a> {
         region.slot = memory.slot as u16;
         region.address_space = 0;
 
-        region.host_virt_addr = memory.host.as_ptr() as u64;
-        region.guest_phys_addr = memory.guest as u64;
+        if memory.flags.contains(accel::MemoryFlags::READ_ONLY) {
+            region.flags |= mem::Flags::READ_ONLY;
+        }
+
+        if memory.flags.contains(accel::MemoryFlags::LOG_DIRTY_PAGES) {
+            region.flags |= mem::Flags::LOG_DIRTY_PAGES;
+        }
 
-        region.size = memory.host.len() as u64;
+        region.host_virt_addr = memory.host as u64;
+        region.guest_phys_addr = memory.guest;
+
+        region.size = memory.size as u64;
 
         unsafe { kvm::ioctl::set_memory_region(self.fd(), &mut region)? };
 
+        let mut slots = self.slots.borrow_mut();
+        slots.retain(|r| r.slot != memory.slot);
+        slots.push(memory);
+
+        Ok(())
+    }
+
+    fn free_memory(&self, slot: u8) -> Result<()> {
+        use kvm::structs::mem;
+
+        let mut region = mem::MemoryRegion::default();
+
+        // Setting a slot's size to 0 deletes it.
+        region.slot = u16::from(slot);
+
+        unsafe { kvm::ioctl::set_memory_region(self.fd(), &mut region)? };
+
+        self.slots.borrow_mut().retain(|r| r.slot != slot);
+
+        Ok(())
+    }
+
+    fn enable_dirty_ring(&self, entries: u32) -> Result<()> {
+        use kvm::structs::caps::EnableCap;
+        use kvm::structs::mem::DirtyGfn;
+        use std::mem;
+
+        let size = entries as usize * mem::size_of::<DirtyGfn>();
+        let max_size = self.check_capability(Capability::DirtyLogRing)? as usize;
+
+        if !entries.is_power_of_two() || size < accel::PAGE_SIZE || size > max_size {
+            bail!("unsupported dirty ring size: {} entries", entries);
+        }
+
+        let mut cap = EnableCap::new(Capability::DirtyLogRing as u32, [size as u64, 0, 0, 0]);
+        unsafe { kvm::ioctl::enable_cap(self.fd(), &mut cap)? };
+
+        self.dirty_ring_entries.set(entries);
+
+        Ok(())
+    }
+
+    fn get_dirty_log(&self, slot: u8) -> Result<accel::DirtyBitmap> {
+        use kvm::structs::mem;
+
+        let region = self.slot(slot)?;
+        let pages = region.size / accel::PAGE_SIZE;
+        let mut bitmap = accel::DirtyBitmap::new(slot, region.guest, pages);
+
+        if self.dirty_ring_entries.get() != 0 {
+            self.harvest_dirty_rings()?;
+
+            let mut harvested = self.harvested.borrow_mut();
+
+            if let Some(index) = harvested.iter().position(|b| b.slot() == slot) {
+                bitmap.merge(&harvested.remove(index));
+            }
+
+            return Ok(bitmap);
+        }
+
+        let mut log = mem::DirtyLog::default();
+
+        log.slot = u16::from(slot);
+        log.dirty_bitmap = bitmap.as_raw_mut().as_mut_ptr() as u64;
+
+        unsafe { kvm::ioctl::get_dirty_log(self.fd(), &mut log)? };
+
+        Ok(bitmap)
+    }
+
+    fn clear_dirty_log(&self, bitmap: &accel::DirtyBitmap) -> Result<()> {
+        use kvm::structs::mem;
+
+        // Without manual protection, retrieving the log already cleared it.
+        // Dirty ring entries are reset as soon as they are harvested.
+        if !self.manual_dirty_protect.get() || self.dirty_ring_entries.get() != 0 {
+            return Ok(());
+        }
+
+        let mut clear = mem::ClearDirtyLog::default();
+
+        clear.slot = u16::from(bitmap.slot());
+        clear.num_pages = bitmap.pages() as u32;
+        clear.first_page = 0;
+        clear.dirty_bitmap = bitmap.as_raw().as_ptr() as u64;
+
+        unsafe { kvm::ioctl::clear_dirty_log(self.fd(), &mut clear)? };
+
+        Ok(())
+    }
+
+    fn set_irq_line(&self, gsi: u32, level: bool) -> Result<()> {
+        self.require_lapic()?;
+
+        if self.irqchip == accel::IrqChipMode::Split && (gsi as usize) < IOAPIC_PINS {
+            return self.ioapic.borrow_mut().set_irq(gsi, level, self);
+        }
+
+        self.irq_line(gsi, level)
+    }
+
+    fn send_msi(&self, address: u64, data: u32) -> Result<()> {
+        use kvm::structs::irq::Msi;
+
+        self.require_lapic()?;
+        self.require_capability(Capability::SignalMsi)?;
+
+        let mut msi = Msi::default();
+
+        msi.address_lo = address as u32;
+        msi.address_hi = (address >> 32) as u32;
+        msi.data = data;
+
+        // Returns 0 if the guest blocked the interrupt, which is not an error.
+        unsafe { kvm::ioctl::signal_msi(self.fd(), &mut msi)? };
+
         Ok(())
     }
 
+    fn add_irq_route(&self, gsi: u32, route: accel::IrqRoute) -> Result<()> {
+        let mut routing = self.routing.borrow().clone();
+        routing.add(gsi, route);
+        self.set_gsi_routing(routing)
+    }
+
+    fn remove_irq_routes(&self, gsi: u32) -> Result<()> {
+        let mut routing = self.routing.borrow().clone();
+        routing.remove(gsi);
+        self.set_gsi_routing(routing)
+    }
+
+    fn get_pic_state(&self, chip: accel::arch::IrqChip) -> Result<PicState> {
+        let chip = self.get_irq_chip(pic_id(chip)?)?;
+        let pic = unsafe { chip.state.pic_state };
+
+        Ok(PicState {
+            last_irr: pic.last_irr,
+            irr: pic.irr,
+            imr: pic.imr,
+            isr: pic.isr,
+            priority_add: pic.priority,
+            irq_base: pic.irq_base,
+            read_isr: pic.read_reg_select != 0,
+            poll: pic.poll != 0,
+            special_mask: pic.special_mask != 0,
+            init_state: pic.init_state,
+            auto_eoi: pic.auto_eoi != 0,
+            rotate_on_auto_eoi: pic.rotate_on_auto_eoi != 0,
+            special_fully_nested: pic.special_fully_nested_mode != 0,
+            init4: pic.init4,
+            elcr: pic.elcr,
+            elcr_mask: pic.elcr_mask,
+        })
+    }
+
+    fn set_pic_state(&self, chip: accel::arch::IrqChip, state: &PicState) -> Result<()> {
+        let mut pic = kvm::structs::irq::PicState::default();
+
+        pic.last_irr = state.last_irr;
+        pic.irr = state.irr;
+        pic.imr = state.imr;
+        pic.isr = state.isr;
+        pic.priority = state.priority_add;
+        pic.irq_base = state.irq_base;
+        pic.read_reg_select = state.read_isr as u8;
+        pic.poll = state.poll as u8;
+        pic.special_mask = state.special_mask as u8;
+        pic.init_state = state.init_state;
+        pic.auto_eoi = state.auto_eoi as u8;
+        pic.rotate_on_auto_eoi = state.rotate_on_auto_eoi as u8;
+        pic.special_fully_nested_mode = state.special_fully_nested as u8;
+        pic.init4 = state.init4;
+        pic.elcr = state.elcr;
+        pic.elcr_mask = state.elcr_mask;
+
+        let mut chip = IrqChip::new(pic_id(chip)?);
+        chip.state.pic_state = pic;
+
+        self.set_irq_chip(chip)
+    }
+
+    fn get_ioapic_state(&self) -> Result<IoApicState> {
+        if self.irqchip == accel::IrqChipMode::Split {
+            return Ok(self.ioapic.borrow().state());
+        }
+
+        let chip = self.get_irq_chip(ChipId::IOAPIC)?;
+        let ioapic = unsafe { chip.state.ioapic_state };
+
+        let mut state = IoApicState::default();
+
+        state.base_address = ioapic.base_address;
+        state.register_select = ioapic.reg_sel;
+        state.id = ioapic.id;
+        state.irr = ioapic.irr;
+
+        for (entry, &kvm_entry) in state.redirection.iter_mut().zip(ioapic.redir_tbl.iter()) {
+            *entry = kvm_entry.into();
+        }
+
+        Ok(state)
+    }
+
+    fn set_ioapic_state(&self, state: &IoApicState) -> Result<()> {
+        if self.irqchip == accel::IrqChipMode::Split {
+            self.ioapic.borrow_mut().set_state(state);
+            return Ok(());
+        }
+
+        let mut ioapic = kvm::structs::irq::IoApicState::default();
+
+        ioapic.base_address = state.base_address;
+        ioapic.reg_sel = state.register_select;
+        ioapic.id = state.id;
+        ioapic.irr = state.irr;
+
+        for (kvm_entry, &entry) in ioapic.redir_tbl.iter_mut().zip(state.redirection.iter()) {
+            *kvm_entry = entry.into();
+        }
+
+        let mut chip = IrqChip::new(ChipId::IOAPIC);
+        chip.state.ioapic_state = ioapic;
+
+        self.set_irq_chip(chip)
+    }
+
+    fn register_ioevent(&self, io: accel::IoEvent, event: &accel::EventFd) -> Result<()> {
+        self.set_ioeventfd(io, event, true)
+    }
+
+    fn unregister_ioevent(&self, io: accel::IoEvent, event: &accel::EventFd) -> Result<()> {
+        self.set_ioeventfd(io, event, false)
+    }
+
+    fn register_irqfd(&self, event: &accel::EventFd, gsi: u32) -> Result<()> {
+        self.set_irqfd(event, gsi, true)
+    }
+
+    fn unregister_irqfd(&self, event: &accel::EventFd, gsi: u32) -> Result<()> {
+        self.set_irqfd(event, gsi, false)
+    }
+
+    fn supported_cpuid(&self) -> Result<Cpuid> {
+        self.global.supported_cpuid()
+    }
+
     fn create_vcpu<'b>(
         &'b self,
         slot: usize,
+        cpuid: Option<&Cpuid>,
         cb: &'b accel::CpuCallbacks,
     ) -> Result<Box<accel::VirtualCPU<'b> + 'b>> {
+        // KVM uses the vCPU's ID as its APIC ID.
+        if !self.x2apic_api() && slot >= MAX_XAPIC_VCPUS {
+            bail!("vCPU ID {} does not fit in an 8-bit APIC ID, without the x2APIC API", slot);
+        }
+
+        let cpuid = match cpuid {
+            Some(cpuid) => cpuid.clone(),
+            None => {
+                let mut supported = self.supported_cpuid()?;
+
+                if self.x2apic_api() {
+                    supported.enable_x2apic();
+                }
+
+                supported.for_vcpu(slot as u32, &Topology::default())
+            }
+        };
+
         let slot = slot as i32;
 
         let fd = unsafe { kvm::ioctl::create_vcpu(self.fd(), slot)? };
@@ -168,14 +797,194 @@ impl<'a> accel::VirtualMachine<'a> for VirtualMachine<'a> {
         use std::os::unix::io::FromRawFd;
         let file = unsafe { File::from_raw_fd(fd as i32) };
 
-        let vcpu = VirtualCPU::new(self, file, cb)?;
+        let entries = self.dirty_ring_entries.get();
+
+        if entries != 0 {
+            self.dirty_rings.borrow_mut().push(DirtyRing::new(&file, entries)?);
+        }
+
+        let vcpu = VirtualCPU::new(self, file, &cpuid, cb)?;
 
         Ok(Box::new(vcpu))
     }
 }
 
+impl<'a> intc::MsiSink for VirtualMachine<'a> {
+    /// Delivers an interrupt of the user space I/O APIC through the GSI of its pin.
+    ///
+    /// KVM only reports the end of level-triggered interrupts which
+    /// have a route from one of the I/O APIC's GSIs.
+    fn send_msi(&self, pin: u32, address: u64, data: u32) -> Result<()> {
+        let route = accel::IrqRoute::Msi { address, data };
+
+        if self.routing.borrow().routes(pin) != [route] {
+            let mut routing = self.routing.borrow().clone();
+            routing.remove(pin);
+            routing.add(pin, route);
+            self.set_gsi_routing(routing)?;
+        }
+
+        // Only the rising edge of an MSI route is delivered.
+        self.irq_line(pin, true)
+    }
+}
+
+/// Returns the ID of a PIC.
+fn pic_id(chip: accel::arch::IrqChip) -> Result<ChipId> {
+    match chip {
+        accel::arch::IrqChip::PicMaster => Ok(ChipId::PIC1),
+        accel::arch::IrqChip::PicSlave => Ok(ChipId::PIC2),
+        accel::arch::IrqChip::IoApic => bail!("the I/O APIC is not a PIC"),
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::*;
+    use accel::{Accelerator, GuestMemory, IrqChipMode, MemoryFlags, MemoryRegion};
+    use accel::arch::IrqChip;
+    use accel::test_util::TestVcpu;
+    use global::Global;
+    use memmap as mm;
+    use test_util::{test_vm, Vm, INTERRUPT_CODE, INTERRUPT_COUNTER};
+    use x86::apic::{Lvt, VectorBitmap};
+    use x86::irqchip::{DeliveryMode, RedirectionEntry, TriggerMode};
+
+    /// Creates a VM with an in-kernel interrupt controller, and runs its vCPU
+    /// until the guest is ready for interrupts.
+    fn interrupt_vm() -> (Vm, TestVcpu) {
+        let (vm, vcpu) = test_vm(IrqChipMode::Kernel, INTERRUPT_CODE);
+        assert!(vcpu.run().is_err());
+        (vm, vcpu)
+    }
+
+    /// Number of interrupts received by the guest.
+    fn interrupts(vm: &Vm) -> u8 {
+        vm.memory.read_obj(INTERRUPT_COUNTER).unwrap()
+    }
+
+    #[test]
+    fn resize_memory() {
+        let global = Global::new().unwrap();
+        let vm = global.create_vm(IrqChipMode::User).unwrap();
+
+        let mut memory = GuestMemory::new(&[(0, 0x1000)]).unwrap();
+        memory.register(&*vm).unwrap();
+        memory.write_obj(0x10, 0x1234u16).unwrap();
+
+        memory.resize_region(&*vm, 0, 0x2000).unwrap();
+        assert_eq!(memory.read_obj::<u16>(0x10).unwrap(), 0x1234);
+
+        // A slot unknown to the guest memory is in the way of the new size.
+        let mut mapping = mm::Mmap::anonymous(0x1000, mm::Protection::ReadWrite).unwrap();
+        let other = MemoryRegion {
+            slot: 1,
+            guest: 0x3000,
+            host: mapping.mut_ptr(),
+            size: 0x1000,
+            flags: MemoryFlags::empty(),
+        };
+        vm.allocate_memory(other).unwrap();
+
+        // The old memory stays mapped when the new one cannot be.
+        assert!(memory.resize_region(&*vm, 0, 0x4000).is_err());
+        assert_eq!(memory.size(), 0x2000);
+        assert_eq!(memory.read_obj::<u16>(0x10).unwrap(), 0x1234);
+        memory.resize_region(&*vm, 0, 0x3000).unwrap();
+
+        vm.free_memory(1).unwrap();
+        memory.resize_region(&*vm, 0, 0x4000).unwrap();
+        assert_eq!(memory.read_obj::<u16>(0x10).unwrap(), 0x1234);
+    }
+
+    #[test]
+    fn irqchip_state() {
+        let (vm, _vcpu) = interrupt_vm();
+
+        let pic = vm.get_pic_state(IrqChip::PicMaster).unwrap();
+        assert_eq!((pic.irq_base, pic.imr), (0x20, 0xDF));
+        assert_eq!(pic.init_state, 0);
+
+        // Restoring the state does not change it.
+        vm.set_pic_state(IrqChip::PicMaster, &pic).unwrap();
+        assert_eq!(vm.get_pic_state(IrqChip::PicMaster).unwrap(), pic);
+        assert!(vm.get_pic_state(IrqChip::IoApic).is_err());
+
+        // Configure a pin of the I/O APIC, which the guest does not use.
+        let mut ioapic = vm.get_ioapic_state().unwrap();
+        assert_eq!(ioapic.base_address, 0xFEC0_0000);
+        assert!(ioapic.redirection[10].masked);
+
+        ioapic.redirection[10] = RedirectionEntry {
+            vector: 0x3A,
+            trigger_mode: TriggerMode::Level,
+            destination: 1,
+            ..Default::default()
+        };
+        vm.set_ioapic_state(&ioapic).unwrap();
+        assert_eq!(vm.get_ioapic_state().unwrap(), ioapic);
+    }
+
+    #[test]
+    fn msi_routing() {
+        let (vm, vcpu) = interrupt_vm();
 
+        // A fixed interrupt with vector 0x40, to the local APIC with ID 0.
+        vm.send_msi(0xFEE0_0000, 0x40).unwrap();
+
+        assert!(vcpu.run().is_err());
+        assert_eq!(interrupts(&vm), 1);
+
+        // The same interrupt, through a GSI routed to an MSI.
+        let route = accel::IrqRoute::Msi { address: 0xFEE0_0000, data: 0x40 };
+        vm.add_irq_route(30, route).unwrap();
+        vm.set_irq_line(30, true).unwrap();
+        vm.set_irq_line(30, false).unwrap();
+
+        assert!(vcpu.run().is_err());
+        assert_eq!(interrupts(&vm), 2);
+
+        // The PIC is still connected after replacing the routing table.
+        vm.remove_irq_routes(30).unwrap();
+        vm.set_irq_line(5, true).unwrap();
+        vm.set_irq_line(5, false).unwrap();
+
+        assert!(vcpu.run().is_err());
+        assert_eq!(interrupts(&vm), 3);
+    }
+
+    #[test]
+    fn irqfd() {
+        let (vm, vcpu) = interrupt_vm();
+
+        // Signaling an irqfd pulses the line.
+        let event = accel::EventFd::new().unwrap();
+        vm.register_irqfd(&event, 5).unwrap();
+        event.write(1).unwrap();
+
+        assert!(vcpu.run().is_err());
+        assert_eq!(interrupts(&vm), 1);
+
+        vm.unregister_irqfd(&event, 5).unwrap();
+    }
+
+    #[test]
+    fn lapic_state() {
+        let (vm, vcpu) = interrupt_vm();
+
+        let mut lapic = vcpu.get_lapic().unwrap();
+        assert!(lapic.software_enabled());
+        assert_eq!(lapic.spurious_vector(), 0xFF);
+        assert_eq!(lapic.lvt(Lvt::Lint0).delivery_mode, DeliveryMode::ExtInt);
+        assert_eq!(lapic.isr(), VectorBitmap::default());
+
+        // Restoring a pending interrupt delivers it.
+        let mut irr = lapic.irr();
+        irr.set(0x40, true);
+        lapic.set_irr(&irr);
+        vcpu.set_lapic(&lapic).unwrap();
+
+        assert!(vcpu.run().is_err());
+        assert_eq!(interrupts(&vm), 1);
+    }
 }
+